@@ -8,8 +8,26 @@ extern "C" {
         stream: *mut core::ffi::c_void,
     ) -> i32;
     fn printf(fmt: *const core::ffi::c_char, ...) -> i32;
+    fn strlen(s: *const core::ffi::c_char) -> usize;
+    fn fprintf(
+        stream: *mut core::ffi::c_void,
+        fmt: *const core::ffi::c_char,
+        ...
+    ) -> i32;
+    fn fflush(stream: *mut core::ffi::c_void) -> i32;
+    fn getc(stream: *mut core::ffi::c_void) -> i32;
+    fn ungetc(c: i32, stream: *mut core::ffi::c_void) -> i32;
+    fn fopen(
+        path: *const core::ffi::c_char,
+        mode: *const core::ffi::c_char,
+    ) -> *mut core::ffi::c_void;
+    fn fclose(stream: *mut core::ffi::c_void) -> i32;
+    fn fputc(c: i32, stream: *mut core::ffi::c_void) -> i32;
+    fn exit(status: i32) -> !;
 
+    static stdin: *mut core::ffi::c_void;
     static stdout: *mut core::ffi::c_void;
+    static stderr: *mut core::ffi::c_void;
 }
 
 #[no_mangle]
@@ -42,3 +60,254 @@ pub unsafe extern "C" fn spkl_print_f32(n: f32) {
 pub unsafe extern "C" fn spkl_println_f32(n: f32) {
     printf(b"%g\n\0".as_ptr().cast(), n as f64);
 }
+
+#[no_mangle]
+pub unsafe extern "C" fn spkl_print_bool(b: bool) {
+    let text: &[u8] = if b { b"true\0" } else { b"false\0" };
+    printf(text.as_ptr().cast());
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn spkl_println_bool(b: bool) {
+    let text: &[u8] = if b { b"true\n\0" } else { b"false\n\0" };
+    printf(text.as_ptr().cast());
+}
+
+/// Prints a type, given the names of the types that aren't pointers as
+/// null-terminated strings one after another. The lowest 24 bits of `typ`
+/// say which of those it is and the rest how many pointers are around it.
+#[no_mangle]
+pub unsafe extern "C" fn spkl_print_type(
+    names: *const core::ffi::c_char,
+    typ: i32,
+) {
+    let mut name = names;
+    for _ in 0..typ & 0xff_ffff {
+        name = name.add(strlen(name) + 1);
+    }
+    printf(b"%s\0".as_ptr().cast(), name);
+    for _ in 0..typ >> 24 {
+        printf(b" ptr\0".as_ptr().cast());
+    }
+}
+
+/// Reads a byte from stdin, flushing stdout first so that prompts show up
+/// before waiting for input.
+unsafe fn read_byte() -> Option<u8> {
+    fflush(stdout);
+    let c = getc(stdin);
+    (c >= 0).then_some(c as u8)
+}
+
+/// Reads a UTF-8 encoded character, turning invalid sequences into
+/// `U+FFFD REPLACEMENT CHARACTER`.
+#[no_mangle]
+pub unsafe extern "C" fn spkl_read_char(out: *mut u32) -> bool {
+    let Some(first) = read_byte() else {
+        *out = 0;
+        return false;
+    };
+    // Nothing from `core` that isn't inlined can be used since it isn't
+    // linked, so this decodes UTF-8 by hand instead of using `from_utf8`.
+    let (len, min, bits) = match first {
+        0x00..=0x7f => {
+            *out = first.into();
+            return true;
+        }
+        0xc0..=0xdf => (2, 0x80, first & 0x1f),
+        0xe0..=0xef => (3, 0x800, first & 0x0f),
+        0xf0..=0xf7 => (4, 0x10000, first & 0x07),
+        _ => {
+            *out = char::REPLACEMENT_CHARACTER as u32;
+            return true;
+        }
+    };
+    let mut code_point = u32::from(bits);
+    for _ in 1..len {
+        let c = getc(stdin);
+        if c & 0xc0 != 0x80 {
+            if c >= 0 {
+                ungetc(c, stdin);
+            }
+            *out = char::REPLACEMENT_CHARACTER as u32;
+            return true;
+        }
+        code_point = code_point << 6 | (c as u32 & 0x3f);
+    }
+    *out = if code_point < min {
+        char::REPLACEMENT_CHARACTER as u32
+    } else {
+        char::from_u32(code_point).unwrap_or(char::REPLACEMENT_CHARACTER) as u32
+    };
+    true
+}
+
+/// Reads a line containing nothing but an integer and possibly some
+/// whitespace around it.
+#[no_mangle]
+pub unsafe extern "C" fn spkl_read_i32(out: *mut i32) -> bool {
+    *out = 0;
+    let Some(mut c) = read_byte() else {
+        return false;
+    };
+    let mut valid = true;
+    let mut started = false;
+    let mut ended = false;
+    let mut negative = false;
+    let mut digits = 0;
+    let mut magnitude = 0_i64;
+    loop {
+        if c == b'\n' {
+            break;
+        } else if c.is_ascii_whitespace() {
+            ended |= started;
+        } else if ended {
+            valid = false;
+        } else if !started && (c == b'+' || c == b'-') {
+            started = true;
+            negative = c == b'-';
+        } else if c.is_ascii_digit() {
+            started = true;
+            digits += 1;
+            // Clamping keeps this from overflowing while still being out of
+            // range for an `i32`.
+            magnitude = (magnitude * 10 + i64::from(c - b'0')).min(1 << 32);
+        } else {
+            valid = false;
+        }
+        match read_byte() {
+            Some(next) => c = next,
+            None => break,
+        }
+    }
+    let n = if negative { -magnitude } else { magnitude };
+    if !valid || digits == 0 || n < i32::MIN as i64 || n > i32::MAX as i64 {
+        return false;
+    }
+    *out = n as i32;
+    true
+}
+
+/// Skips the rest of the current line, returning whether there was one.
+#[no_mangle]
+pub unsafe extern "C" fn spkl_read_line() -> bool {
+    let Some(mut c) = read_byte() else {
+        return false;
+    };
+    while c != b'\n' {
+        match read_byte() {
+            Some(next) => c = next,
+            None => break,
+        }
+    }
+    true
+}
+
+const MAX_FILES: usize = 64;
+
+/// The files opened by `open`, which Spackel programs refer to by their index
+/// so that using a closed file can't do anything worse than fail.
+static mut FILES: [*mut core::ffi::c_void; MAX_FILES] =
+    [core::ptr::null_mut(); MAX_FILES];
+
+/// Gets the slot of an open file, or `None` if there is no such file.
+unsafe fn file_slot(handle: i32) -> Option<*mut *mut core::ffi::c_void> {
+    if handle < 0 || handle as usize >= MAX_FILES {
+        return None;
+    }
+    let slot = (core::ptr::addr_of_mut!(FILES) as *mut *mut core::ffi::c_void)
+        .add(handle as usize);
+    if (*slot).is_null() {
+        None
+    } else {
+        Some(slot)
+    }
+}
+
+/// Opens a file for reading, or for writing if `write` is true, returning its
+/// handle or -1 if that didn't work.
+#[no_mangle]
+pub unsafe extern "C" fn spkl_fopen(
+    path: *const core::ffi::c_char,
+    write: bool,
+) -> i32 {
+    let files = core::ptr::addr_of_mut!(FILES) as *mut *mut core::ffi::c_void;
+    let mut handle = 0;
+    while handle < MAX_FILES && !(*files.add(handle)).is_null() {
+        handle += 1;
+    }
+    if path.is_null() || handle == MAX_FILES {
+        return -1;
+    }
+    let mode: &[u8] = if write { b"wb\0" } else { b"rb\0" };
+    let file = fopen(path, mode.as_ptr().cast());
+    if file.is_null() {
+        return -1;
+    }
+    *files.add(handle) = file;
+    handle as i32
+}
+
+/// Reads a byte from a file.
+#[no_mangle]
+pub unsafe extern "C" fn spkl_fread(handle: i32, out: *mut i32) -> bool {
+    *out = 0;
+    let Some(slot) = file_slot(handle) else {
+        return false;
+    };
+    let c = getc(*slot);
+    if c < 0 {
+        return false;
+    }
+    *out = c;
+    true
+}
+
+/// Writes the lowest byte of `byte` to a file.
+#[no_mangle]
+pub unsafe extern "C" fn spkl_fwrite(handle: i32, byte: i32) {
+    if let Some(slot) = file_slot(handle) {
+        fputc(byte & 0xff, *slot);
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn spkl_fclose(handle: i32) {
+    if let Some(slot) = file_slot(handle) {
+        fclose(*slot);
+        *slot = core::ptr::null_mut();
+    }
+}
+
+/// Where a runtime error happened, as generated by the compiler.
+#[repr(C)]
+pub struct Location {
+    file: *const core::ffi::c_char,
+    line: u32,
+    column: u32,
+}
+
+/// Reports a runtime error and where in the source code it happened, then
+/// exits.
+#[no_mangle]
+pub unsafe extern "C" fn spkl_panic(
+    msg: *const core::ffi::c_char,
+    location: *const Location,
+) -> ! {
+    fflush(stdout);
+    let location = &*location;
+    fprintf(
+        stderr,
+        b"%s at %s:%u:%u\n\0".as_ptr().cast(),
+        msg,
+        location.file,
+        location.line,
+        location.column,
+    );
+    exit(1);
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn spkl_exit(code: i32) -> ! {
+    exit(code);
+}
@@ -1,6 +1,17 @@
-use crate::ssa::{Op, ValueGenerator};
-use petgraph::{prelude::DiGraph, Direction};
-use std::{collections::BTreeMap, convert::Infallible, ops::ControlFlow};
+use crate::{
+    ssa::{equivalence, Op, ValueGenerator},
+    timing::Timings,
+    typ::{FunctionSignature, Type},
+};
+use petgraph::{
+    prelude::{DiGraph, NodeIndex},
+    Direction,
+};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    convert::Infallible,
+    ops::ControlFlow,
+};
 
 pub type CallGraph<'src> = DiGraph<Function<'src>, ()>;
 
@@ -8,9 +19,21 @@ pub type CallGraph<'src> = DiGraph<Function<'src>, ()>;
 pub struct Function<'src> {
     pub name: &'src str,
     pub body: crate::ssa::Graph,
+    /// Whether the function is marked `inline`.
+    pub inline: bool,
+    /// Whether the function can be called from outside the program, like
+    /// `main` or the functions of a library, which means that it must stay
+    /// as it is.
+    pub exported: bool,
 }
 
-pub fn of(mut function_bodies: BTreeMap<&str, crate::ssa::Graph>) -> CallGraph {
+/// Builds the call graph of a program, where `exported` are the functions
+/// besides `main` that must be kept.
+pub fn of<'src>(
+    mut function_bodies: BTreeMap<&'src str, crate::ssa::Graph>,
+    inline_functions: &BTreeSet<&str>,
+    exported: &BTreeSet<&str>,
+) -> CallGraph<'src> {
     let mut graph = DiGraph::new();
 
     let nodes = function_bodies
@@ -20,8 +43,12 @@ pub fn of(mut function_bodies: BTreeMap<&str, crate::ssa::Graph>) -> CallGraph {
 
     for (caller, body) in &function_bodies {
         let start = nodes[&**caller];
+        // Taking a pointer to a function counts as calling it since the
+        // pointer may be called from anywhere.
         body.each_op(&mut |op| {
-            if let Op::Call(called_function) = op {
+            if let Op::Call(called_function)
+            | Op::FunctionPointer(called_function) = op
+            {
                 let end = nodes[&**called_function];
                 graph.update_edge(start, end, ());
             }
@@ -33,20 +60,134 @@ pub fn of(mut function_bodies: BTreeMap<&str, crate::ssa::Graph>) -> CallGraph {
         |_, &name| Function {
             name,
             body: function_bodies.remove(name).unwrap(),
+            inline: inline_functions.contains(name),
+            exported: name == "main" || exported.contains(name),
         },
         |_, ()| (),
     )
 }
 
-pub fn optimize(graph: &mut CallGraph, value_generator: &mut ValueGenerator) {
-    while graph
-        .node_weights_mut()
-        .any(|function| crate::ssa::propagate_drops(&mut function.body))
-        | inline(graph, value_generator)
-    {}
+#[derive(Clone, Copy)]
+pub struct Options {
+    /// Functions with at most this many operations are inlined everywhere.
+    pub inline_threshold: usize,
+    /// Whether to inline at all, including functions marked `inline`.
+    pub inline: bool,
+    pub merge_identical_functions: bool,
+    /// Evaluates every function that a pass changes on random inputs before
+    /// and after the pass, panicking if the results differ.
+    pub check_equivalence: bool,
 }
 
-fn inline(graph: &mut CallGraph, value_generator: &mut ValueGenerator) -> bool {
+impl Options {
+    pub const SPEED: Self = Self {
+        inline_threshold: 10,
+        inline: true,
+        merge_identical_functions: false,
+        check_equivalence: false,
+    };
+
+    pub const SIZE: Self = Self {
+        inline_threshold: 3,
+        inline: true,
+        merge_identical_functions: true,
+        check_equivalence: false,
+    };
+}
+
+pub fn optimize(
+    graph: &mut CallGraph,
+    function_signatures: &BTreeMap<&str, FunctionSignature>,
+    value_generator: &mut ValueGenerator,
+    options: Options,
+    timings: &mut Timings,
+) {
+    let mut checker = options
+        .check_equivalence
+        .then(|| equivalence::Checker::new(function_signatures));
+    let mut run_pass =
+        |pass: &'static str,
+         graph: &mut CallGraph,
+         run: &mut dyn FnMut(&mut CallGraph) -> bool| {
+            let did_something = if let Some(checker) = &mut checker {
+                let before = bodies(graph);
+                let did_something = timings.time(pass, || run(graph));
+                checker.check(pass, &before, &bodies(graph));
+                did_something
+            } else {
+                timings.time(pass, || run(graph))
+            };
+            if cfg!(debug_assertions) {
+                validate(pass, graph);
+            }
+            did_something
+        };
+
+    loop {
+        let mut did_something = run_pass("simplify", graph, &mut |graph| {
+            graph.node_weights_mut().any(|function| {
+                crate::ssa::forward_pointers(&mut function.body)
+                    | crate::ssa::devirtualize(&mut function.body)
+                    | crate::ssa::fold_constants(&mut function.body)
+                    | crate::ssa::propagate_drops(&mut function.body)
+            })
+        });
+        did_something |=
+            run_pass("eliminate common subexpressions", graph, &mut |graph| {
+                graph.node_weights_mut().fold(
+                    false,
+                    |did_something, function| {
+                        crate::ssa::eliminate_common_subexpressions(
+                            &mut function.body,
+                            value_generator,
+                        ) | did_something
+                    },
+                )
+            });
+        did_something |= options.inline
+            && run_pass("inline", graph, &mut |graph| {
+                inline(graph, value_generator, options)
+            });
+        did_something |= options.merge_identical_functions
+            && run_pass("merge identical functions", graph, &mut |graph| {
+                merge_identical_functions(graph, function_signatures)
+            });
+        if !did_something {
+            break;
+        }
+    }
+
+    // This has to come last since a function that jumps back to its own start
+    // can't be inlined.
+    run_pass("eliminate self tail calls", graph, &mut |graph| {
+        for function in graph.node_weights_mut() {
+            function.body.eliminate_self_tail_calls(function.name);
+        }
+        false
+    });
+}
+
+/// Panics if `pass` left a function with a malformed body.
+fn validate(pass: &str, graph: &CallGraph) {
+    for function in graph.node_weights() {
+        if let Err(err) = function.body.validate() {
+            panic!("`{pass}` broke `{}`: {err}", function.name);
+        }
+    }
+}
+
+fn bodies<'src>(graph: &CallGraph<'src>) -> equivalence::Bodies<'src> {
+    graph
+        .node_weights()
+        .map(|function| (function.name, function.body.clone()))
+        .collect()
+}
+
+fn inline(
+    graph: &mut CallGraph,
+    value_generator: &mut ValueGenerator,
+    options: Options,
+) -> bool {
     let mut did_something = false;
 
     // Find a function to inline.
@@ -55,13 +196,29 @@ fn inline(graph: &mut CallGraph, value_generator: &mut ValueGenerator) -> bool {
         .externals(Direction::Outgoing)
         .find(|&node| {
             let function = &graph[node];
-            // Don't inline `main`; how would that even work?
-            function.name != "main"
-            // Don't inline functions that are too large.
-            && (function.body.is_small_enough_to_inline()
+            // Don't inline `main` or other exported functions, since they
+            // have to stay around.
+            !function.exported
+            // Functions that never return can't be inlined since the rest of
+            // the caller would become unreachable.
+            && !function.body.diverges()
+            // Neither can functions that return early, since that would
+            // return from the caller.
+            && !function.body.returns_early()
+            // Functions that are pointed to have to stay around.
+            && !is_pointed_to(graph, node)
+            // Don't inline functions that are too large...
+            && (function
+                .body
+                .contains_at_most_n_ops(options.inline_threshold)
+            // ...unless they are marked `inline`...
+            || function.inline
             // ...unless they are called in at most one place, meaning that
             // there will be no code size increase.
-            || graph.edges(node).nth(1).is_none())
+            || graph
+                .edges_directed(node, Direction::Incoming)
+                .nth(1)
+                .is_none())
         })
     {
         did_something = true;
@@ -83,3 +240,91 @@ fn inline(graph: &mut CallGraph, value_generator: &mut ValueGenerator) -> bool {
 
     did_something
 }
+
+/// Whether any function takes a pointer to the function at `node`.
+fn is_pointed_to(graph: &CallGraph, node: NodeIndex) -> bool {
+    let name = graph[node].name;
+    graph
+        .neighbors_directed(node, Direction::Incoming)
+        .any(|caller| {
+            graph[caller]
+                .body
+                .each_op(&mut |op| match op {
+                    Op::FunctionPointer(other) if **other == *name => {
+                        ControlFlow::Break(())
+                    }
+                    _ => ControlFlow::Continue(()),
+                })
+                .is_break()
+        })
+}
+
+/// Merges functions that have the same body and compile to the same signature,
+/// redirecting all calls to one of them. Instances of a generic function
+/// usually only differ in their signatures, like `first` for `i32 [→]` and
+/// `i32 [i32 → i32]`, which both take an `i32` and a pointer.
+fn merge_identical_functions(
+    graph: &mut CallGraph,
+    function_signatures: &BTreeMap<&str, FunctionSignature>,
+) -> bool {
+    let mut did_something = false;
+
+    while let Some((duplicate, original)) =
+        find_identical_functions(graph, function_signatures)
+    {
+        did_something = true;
+
+        let original_name = graph[original].name;
+        let duplicate_name = graph[duplicate].name;
+        let mut callers = graph
+            .neighbors_directed(duplicate, Direction::Incoming)
+            .detach();
+        while let Some(caller) = callers.next_node(graph) {
+            graph[caller].body.each_op_mut(&mut |op| {
+                if let Op::Call(name) | Op::FunctionPointer(name) = op {
+                    if **name == *duplicate_name {
+                        *name = original_name.into();
+                    }
+                }
+            });
+            graph.update_edge(caller, original, ());
+        }
+        graph.remove_node(duplicate);
+    }
+
+    did_something
+}
+
+fn find_identical_functions(
+    graph: &CallGraph,
+    function_signatures: &BTreeMap<&str, FunctionSignature>,
+) -> Option<(NodeIndex, NodeIndex)> {
+    let mut seen = Vec::<(NodeIndex, String)>::new();
+    for node in graph.node_indices() {
+        let function = &graph[node];
+        // Exported functions, like the entry point, must stay as they are.
+        if function.exported {
+            continue;
+        }
+        let signature = &function_signatures[function.name];
+        let canonical_form = function.body.canonical_form();
+        let original = seen.iter().find(|(other, other_canonical_form)| {
+            let other_signature = &function_signatures[graph[*other].name];
+            *other_canonical_form == canonical_form
+                && compile_alike(
+                    &other_signature.parameters,
+                    &signature.parameters,
+                )
+                && compile_alike(&other_signature.returns, &signature.returns)
+        });
+        if let Some(&(original, _)) = original {
+            return Some((node, original));
+        }
+        seen.push((node, canonical_form));
+    }
+    None
+}
+
+fn compile_alike(a: &[Type], b: &[Type]) -> bool {
+    a.len() == b.len() && std::iter::zip(a, b).all(|(a, b)| a.compiles_like(b))
+}
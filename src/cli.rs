@@ -0,0 +1,697 @@
+use crate::{
+    call_graph, compiler,
+    diagnostics::{self, Diagnostic, LintLevel, LintLevels},
+    diff, docs, formatter, include, interpreter, ir, lsp, parser, project,
+    repl, selftest, server, shuffle, ssa,
+    target::Target,
+    test_runner,
+    timing::Timings,
+    typ, Limits, PRELUDE,
+};
+use anyhow::{bail, ensure, Context, Result};
+use codemap::CodeMap;
+use compiler::{OptLevel, Stage};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::Write as _,
+    path::{Path, PathBuf},
+    process::ExitCode,
+    sync::Arc,
+};
+
+/// Runs the command line interface with the arguments the process was started
+/// with.
+///
+/// # Errors
+///
+/// Diagnostics are printed here, but other errors are returned.
+pub fn main() -> Result<ExitCode> {
+    let mut code_map = CodeMap::new();
+
+    real_main(&mut code_map).or_else(|err| {
+        err.downcast::<diagnostics::Error>()
+            .map(|diagnostic| diagnostic.emit(&code_map))
+    })
+}
+
+#[expect(
+    clippy::struct_excessive_bools,
+    reason = "each of them is a separate command line flag"
+)]
+pub(crate) struct Flags {
+    pub(crate) prelude: bool,
+    pub(crate) target: Target,
+    pub(crate) opt_level: OptLevel,
+    pub(crate) optimize_for_size: bool,
+    pub(crate) debug_info: bool,
+    /// Makes compiled programs stop when integer arithmetic overflows instead
+    /// of wrapping around.
+    pub(crate) overflow_checks: bool,
+    /// Prints how long each part of compiling took.
+    pub(crate) time_passes: bool,
+    /// Runs Cranelift's verifier on every function and points its errors at
+    /// the words that the rejected instructions came from.
+    pub(crate) verify_clif: bool,
+    /// Evaluates every function that an optimization pass changes before and
+    /// after the pass, panicking if the results differ.
+    pub(crate) check_optimizations: bool,
+    pub(crate) crate_type: CrateType,
+    /// Overrides how large functions may be to be inlined everywhere.
+    pub(crate) inline_threshold: Option<usize>,
+    /// Where to keep compiled functions between runs.
+    pub(crate) cache_dir: Option<PathBuf>,
+    pub(crate) emit: Emit,
+    pub(crate) doc_format: docs::Format,
+    /// Intermediate forms of the program to write out while compiling.
+    pub(crate) emit_stages: compiler::EmitStages,
+    /// Cranelift settings that override the defaults, as names and values.
+    pub(crate) cranelift_settings: Vec<(String, String)>,
+    pub(crate) lints: LintLevels,
+    /// Everything after `--`, which is passed on to the program by `run`.
+    pub(crate) program_args: Vec<String>,
+    /// The C compiler that `selftest` links programs with.
+    pub(crate) linker: String,
+    /// The object file with the runtime, which `selftest` links programs with.
+    pub(crate) runtime: PathBuf,
+    /// What `selftest` passes to the linker after the object files. Without
+    /// any, that is `-lm` for the C math library.
+    pub(crate) link_args: Vec<String>,
+}
+
+/// Whether `compile` produces a program or a library, whose functions can be
+/// called from other languages.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum CrateType {
+    Executable,
+    Library,
+}
+
+/// What `compile` produces.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Emit {
+    Object,
+    Docs,
+    /// The object file, along with a listing of which CLIF and machine code
+    /// each word turned into.
+    Annotated,
+}
+
+impl Default for Flags {
+    fn default() -> Self {
+        Self {
+            prelude: true,
+            target: Target::default(),
+            opt_level: OptLevel::O2,
+            optimize_for_size: false,
+            debug_info: false,
+            overflow_checks: false,
+            time_passes: false,
+            verify_clif: false,
+            check_optimizations: false,
+            crate_type: CrateType::Executable,
+            inline_threshold: None,
+            cache_dir: None,
+            emit: Emit::Object,
+            doc_format: docs::Format::default(),
+            emit_stages: BTreeMap::new(),
+            cranelift_settings: Vec::new(),
+            lints: LintLevels::default(),
+            program_args: Vec::new(),
+            linker: "cc".to_owned(),
+            runtime: "runtime.o".into(),
+            link_args: Vec::new(),
+        }
+    }
+}
+
+fn parse_flags() -> Result<(Vec<String>, Flags)> {
+    let mut args = Vec::new();
+    let mut flags = Flags::default();
+    let mut all_args = std::env::args().skip(1);
+    while let Some(arg) = all_args.next() {
+        match &*arg {
+            "--no-prelude" => flags.prelude = false,
+            "-O0" => flags.opt_level = OptLevel::O0,
+            "-O1" => flags.opt_level = OptLevel::O1,
+            "-O2" => flags.opt_level = OptLevel::O2,
+            "--debug-info" => flags.debug_info = true,
+            "--overflow-checks" => flags.overflow_checks = true,
+            "--time-passes" => flags.time_passes = true,
+            "--verify-clif" => flags.verify_clif = true,
+            "--check-optimizations" => flags.check_optimizations = true,
+            "--target" => {
+                flags.target =
+                    flag_value(&mut all_args, &arg, "a target triple")?
+                        .parse()?;
+            }
+            "--opt" => {
+                flags.optimize_for_size = match all_args.next().as_deref() {
+                    Some("speed") => false,
+                    Some("size") => true,
+                    _ => bail!("`--opt` must be `speed` or `size`"),
+                };
+            }
+            "--inline-threshold" => {
+                flags.inline_threshold = Some(
+                    all_args
+                        .next()
+                        .and_then(|threshold| threshold.parse().ok())
+                        .context(
+                            "`--inline-threshold` must be followed by a number of operations",
+                        )?,
+                );
+            }
+            "--cache" => {
+                flags.cache_dir = Some(
+                    flag_value(&mut all_args, &arg, "a directory")?.into(),
+                );
+            }
+            "--linker" => {
+                flags.linker = flag_value(&mut all_args, &arg, "a command")?;
+            }
+            "--runtime" => {
+                flags.runtime =
+                    flag_value(&mut all_args, &arg, "a path")?.into();
+            }
+            "--link-arg" => {
+                let link_arg = flag_value(&mut all_args, &arg, "an argument")?;
+                flags.link_args.push(link_arg);
+            }
+            "--crate-type" => {
+                flags.crate_type = match all_args.next().as_deref() {
+                    Some("executable") => CrateType::Executable,
+                    Some("library") => CrateType::Library,
+                    _ => bail!(
+                        "`--crate-type` must be `executable` or `library`"
+                    ),
+                };
+            }
+            "--emit" => {
+                parse_emit(&all_args.next().unwrap_or_default(), &mut flags)?;
+            }
+            "--format" => {
+                flags.doc_format =
+                    all_args.next().unwrap_or_default().parse()?;
+            }
+            "--cranelift-setting" => {
+                let setting = all_args
+                    .next()
+                    .as_deref()
+                    .and_then(|setting| setting.split_once('='))
+                    .map(|(name, value)| (name.to_owned(), value.to_owned()))
+                    .context(
+                        "`--cranelift-setting` must be followed by `name=value`",
+                    )?;
+                flags.cranelift_settings.push(setting);
+            }
+            "-A" | "--allow" | "-W" | "--warn" | "-D" | "--deny" => {
+                let level = match &*arg {
+                    "-A" | "--allow" => LintLevel::Allow,
+                    "-W" | "--warn" => LintLevel::Warn,
+                    _ => LintLevel::Deny,
+                };
+                let lint = all_args.next().with_context(|| {
+                    format!("`{arg}` must be followed by a lint name")
+                })?;
+                flags.lints.set(&lint, level)?;
+            }
+            "--" => flags.program_args.extend(all_args.by_ref()),
+            _ if arg.starts_with("--") => bail!("unknown flag {arg:?}"),
+            _ => args.push(arg),
+        }
+    }
+    Ok((args, flags))
+}
+
+/// Takes the argument that a flag must be followed by, which is `what`.
+fn flag_value(
+    args: &mut impl Iterator<Item = String>,
+    flag: &str,
+    what: &str,
+) -> Result<String> {
+    args.next()
+        .with_context(|| format!("`{flag}` must be followed by {what}"))
+}
+
+/// Parses the argument of `--emit`, which is either a stage, optionally with a
+/// path, or what `compile` produces.
+fn parse_emit(emit: &str, flags: &mut Flags) -> Result<()> {
+    let (name, path) = emit
+        .split_once('=')
+        .map_or((emit, None), |(name, path)| (name, Some(path.into())));
+    if let Some(stage) =
+        Stage::ALL.into_iter().find(|stage| stage.name() == name)
+    {
+        flags.emit_stages.insert(stage, path);
+        return Ok(());
+    }
+    flags.emit = match emit {
+        "object" => Emit::Object,
+        "docs" => Emit::Docs,
+        "annotated" => Emit::Annotated,
+        _ => bail!(
+            "`--emit` must be `object`, `docs`, `annotated` or one of {}, optionally followed by `=path`",
+            Stage::ALL
+                .iter()
+                .map(|stage| format!("`{}`", stage.name()))
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    };
+    Ok(())
+}
+
+/// Uses the main file of the current project if no file was provided.
+fn source_path(arg: Option<String>) -> Result<PathBuf> {
+    arg.map_or_else(project::main_file, |arg| Ok(arg.into()))
+}
+
+pub(crate) fn load_program(
+    code_map: &mut CodeMap,
+    source_path: &Path,
+    flags: &Flags,
+) -> Result<Vec<Arc<codemap::File>>> {
+    let mut files = Vec::new();
+    if flags.prelude {
+        files.push(
+            code_map.add_file("<prelude>".to_owned(), PRELUDE.to_owned()),
+        );
+    }
+    files.extend(include::load(code_map, source_path)?);
+    Ok(files)
+}
+
+fn real_main(code_map: &mut CodeMap) -> Result<ExitCode> {
+    let (args, flags) = parse_flags()?;
+    let mut args = args.into_iter();
+    let command = args.next().context("no command provided")?;
+    // `diff` takes two files, `effect` takes code and a file and some commands
+    // take nothing.
+    let max_args = match &*command {
+        "diff" | "effect" => 2,
+        "repl" | "serve" | "lsp" | "targets" | "format" => 0,
+        _ => 1,
+    };
+    ensure!(args.len() <= max_args, "too many command line arguments");
+
+    match &*command {
+        "run" => run(code_map, args.next(), flags),
+        "compile" => {
+            compile_to_file(code_map, args.next(), &flags)?;
+            Ok(ExitCode::SUCCESS)
+        }
+        "doc" => {
+            print_docs(code_map, args.next(), &flags)?;
+            Ok(ExitCode::SUCCESS)
+        }
+        "diff" => {
+            let (Some(old_path), Some(new_path)) = (args.next(), args.next())
+            else {
+                bail!("`diff` needs an old and a new file");
+            };
+            print_diff(code_map, old_path.as_ref(), new_path.as_ref(), &flags)?;
+            Ok(ExitCode::SUCCESS)
+        }
+        "new" => {
+            let name = args.next().context("no project name provided")?;
+            project::new(&name)?;
+            Ok(ExitCode::SUCCESS)
+        }
+        "effect" => {
+            let snippet = args.next().context("no code provided")?;
+            let files = match args.next() {
+                Some(path) => load_program(code_map, path.as_ref(), &flags)?,
+                None if flags.prelude => vec![code_map
+                    .add_file("<prelude>".to_owned(), PRELUDE.to_owned())],
+                None => Vec::new(),
+            };
+            repl::print_effects(code_map, files, snippet)?;
+            Ok(ExitCode::SUCCESS)
+        }
+        "repl" => repl::run(code_map, &flags),
+        "serve" => {
+            server::serve()?;
+            Ok(ExitCode::SUCCESS)
+        }
+        "lsp" => {
+            lsp::serve()?;
+            Ok(ExitCode::SUCCESS)
+        }
+        "test" => {
+            let source_path = source_path(args.next())?;
+            let files = load_program(code_map, &source_path, &flags)?;
+            test_runner::run(code_map, &files, &flags)
+        }
+        "selftest" => {
+            let dir = args.next().unwrap_or_else(|| "examples".to_owned());
+            selftest::run(code_map, dir.as_ref(), &flags)
+        }
+        "shuffle" => {
+            let effect = args.next().context("no stack effect provided")?;
+            let words = shuffle::suggest(&effect)?;
+            if words.is_empty() {
+                println!("the stack is already in that order");
+            } else {
+                println!("{}", words.join(" "));
+            }
+            Ok(ExitCode::SUCCESS)
+        }
+        "targets" => {
+            print_targets();
+            Ok(ExitCode::SUCCESS)
+        }
+        "fmt" => {
+            let source_path = source_path(args.next())?;
+            for file in include::load(code_map, &source_path)? {
+                let formatted = formatter::format(file.source());
+                if formatted != file.source() {
+                    std::fs::write(file.name(), formatted).with_context(
+                        || format!("failed to write {}", file.name()),
+                    )?;
+                }
+            }
+            Ok(ExitCode::SUCCESS)
+        }
+        "format" => {
+            let source_code = std::io::read_to_string(std::io::stdin().lock())
+                .context("failed to read stdin")?;
+            print!("{}", formatter::format(&source_code));
+            Ok(ExitCode::SUCCESS)
+        }
+        _ => bail!(
+            "command must be `run`, `compile`, `diff`, `doc`, `effect`, `new`, `fmt`, `format`, `lsp`, `repl`, `selftest`, `serve`, `shuffle`, `targets` or `test`, not {command:?}"
+        ),
+    }
+}
+
+/// Compiles a program to `main.o`, or prints its documentation with
+/// `--emit docs`. Without a file, `main.o` goes in the root of the project.
+fn compile_to_file(
+    code_map: &mut CodeMap,
+    arg: Option<String>,
+    flags: &Flags,
+) -> Result<()> {
+    let object_path = if arg.is_some() {
+        PathBuf::from("main.o")
+    } else {
+        project::root()?.join("main.o")
+    };
+    let source_path = source_path(arg)?;
+    let files = load_program(code_map, &source_path, flags)?;
+
+    if flags.emit == Emit::Docs {
+        let mut program = parser::parse(&files)?;
+        let words = docs::Words::take(&mut program, None);
+        let program = typ::check(program)?;
+        print!(
+            "{}",
+            words.render(
+                &program.function_signatures,
+                flags.doc_format,
+                &source_path.display().to_string(),
+            )
+        );
+        return Ok(());
+    }
+    let (output, warnings) = compile(&files, flags)?;
+    diagnostics::emit(&warnings, code_map);
+    std::fs::write(&object_path, &output.object).with_context(|| {
+        format!("failed to write {}", object_path.display())
+    })?;
+    print!("{}", output.annotated);
+    if flags.optimize_for_size {
+        print_sizes(&output.sizes);
+    }
+    Ok(())
+}
+
+/// Prints a reference of the functions and macros that a program or library
+/// defines, leaving out the prelude.
+fn print_docs(
+    code_map: &mut CodeMap,
+    arg: Option<String>,
+    flags: &Flags,
+) -> Result<()> {
+    let source_path = source_path(arg)?;
+    let files = load_program(code_map, &source_path, flags)?;
+    let mut program = parser::parse(&files)?;
+    let words =
+        docs::Words::take(&mut program, flags.prelude.then(|| files[0].span));
+    let program = typ::check_library(program, false)?;
+    print!(
+        "{}",
+        words.render(
+            &program.function_signatures,
+            flags.doc_format,
+            &source_path.display().to_string(),
+        )
+    );
+    Ok(())
+}
+
+/// Interprets a program, passing on the arguments after `--`.
+fn run(
+    code_map: &mut CodeMap,
+    arg: Option<String>,
+    flags: Flags,
+) -> Result<ExitCode> {
+    let source_path = source_path(arg)?;
+    let files = load_program(code_map, &source_path, &flags)?;
+
+    let program = parser::parse(&files)?;
+    let mut program = typ::check(program)?;
+    let warnings = lint(&mut program, &files, &flags)?;
+    diagnostics::emit(&warnings, code_map);
+    let program_args = std::iter::once(source_path.display().to_string())
+        .chain(flags.program_args)
+        .collect::<Vec<_>>();
+    let (result, _) = interpreter::interpret(
+        &program,
+        &program_args,
+        &mut std::io::stdin().lock(),
+        &mut std::io::stdout().lock(),
+        &Limits::UNLIMITED,
+    );
+    match result {
+        Ok(()) => Ok(ExitCode::SUCCESS),
+        #[expect(
+            clippy::cast_possible_truncation,
+            clippy::cast_sign_loss,
+            reason = "exit codes are truncated like on Unix"
+        )]
+        Err(interpreter::Halt::Exit(code)) => Ok(ExitCode::from(code as u8)),
+        Err(halt) => Err(halt.into_error()),
+    }
+}
+
+fn print_diff(
+    code_map: &mut CodeMap,
+    old_path: &Path,
+    new_path: &Path,
+    flags: &Flags,
+) -> Result<()> {
+    let old_files = load_program(code_map, old_path, flags)?;
+    let new_files = load_program(code_map, new_path, flags)?;
+    let old = typ::check(parser::parse(&old_files)?)?;
+    let new = typ::check(parser::parse(&new_files)?)?;
+    print!("{}", diff::diff(old, new));
+    Ok(())
+}
+
+fn print_sizes(sizes: &BTreeMap<&str, usize>) {
+    for (name, size) in sizes {
+        println!("{name}: {size} bytes");
+    }
+    println!("total: {} bytes", sizes.values().sum::<usize>());
+}
+
+fn print_targets() {
+    let width = Target::ALL
+        .iter()
+        .map(|target| target.triple().len())
+        .max()
+        .unwrap_or(0);
+    for target in Target::ALL {
+        let triple = target.triple();
+        let object_format = target.object_format();
+        let call_conv = target.call_conv().to_string();
+        if !target.is_included() {
+            println!(
+                "{triple:width$}  {object_format:6}  {call_conv:16}  not included in this build of Cranelift"
+            );
+            continue;
+        }
+        println!(
+            "{triple:width$}  {object_format:6}  {call_conv:16}  {}{}",
+            if target.has_runtime() {
+                "runtime available"
+            } else {
+                "no runtime"
+            },
+            if target == Target::default() {
+                " (default)"
+            } else {
+                ""
+            },
+        );
+    }
+}
+
+/// Compiles a loaded program to an object file, also returning the warnings
+/// about it.
+pub(crate) fn compile<'src>(
+    files: &'src [Arc<codemap::File>],
+    flags: &Flags,
+) -> Result<(compiler::Output<'src>, Vec<Diagnostic>)> {
+    let stages = &flags.emit_stages;
+    let mut timings = Timings::default();
+    let program = parser::parse_timed(files, &mut timings)?;
+    compiler::emit_stage(stages, Stage::Parsed, || format!("{program:#?}\n"))?;
+    let annotate = flags.emit == Emit::Annotated;
+    // Each of these needs to know which word every instruction came from.
+    let locations = annotate
+        || flags.debug_info
+        || flags.verify_clif
+        || stages.contains_key(&Stage::SourceMap);
+    let library_functions = library_functions(&program, files, flags);
+    let mut program = timings.time("type checking", || {
+        if flags.crate_type == CrateType::Library {
+            typ::check_library(program, locations)
+        } else if locations {
+            typ::check_annotated(program)
+        } else {
+            typ::check(program)
+        }
+    })?;
+    timings.count("functions", program.function_bodies.len());
+    timings.count("instructions", program.instruction_count());
+    let warnings = lint(&mut program, files, flags)?;
+    compiler::emit_stage(stages, Stage::Typed, || format!("{program:#?}\n"))?;
+    let mut value_generator = ssa::ValueGenerator::default();
+    let checksum = program.checksum();
+    let program = timings.time("SSA construction", || {
+        ssa::convert(program, &mut value_generator)
+    });
+    // Generic functions have no machine code of their own.
+    let exported = library_functions
+        .into_iter()
+        .filter(|name| program.function_bodies.contains_key(name))
+        .collect();
+    let mut graph = timings.time("SSA construction", || {
+        call_graph::of(
+            program.function_bodies,
+            &program.inline_functions,
+            &exported,
+        )
+    });
+    compiler::emit_stage(stages, Stage::Ssa, || format_ssa(checksum, &graph))?;
+
+    if flags.opt_level > OptLevel::O0 {
+        call_graph::optimize(
+            &mut graph,
+            &program.function_signatures,
+            &mut value_generator,
+            optimization_options(flags),
+            &mut timings,
+        );
+    }
+    timings.count(
+        "SSA operations",
+        graph
+            .node_weights()
+            .map(|function| function.body.op_count())
+            .sum(),
+    );
+    timings.count("SSA values", value_generator.count() as usize);
+
+    compiler::emit_stage(stages, Stage::OptimizedSsa, || {
+        format_ssa(checksum, &graph)
+    })?;
+
+    let compilation_options = compiler::Options {
+        target: flags.target,
+        files,
+        opt_level: flags.opt_level,
+        optimize_for_size: flags.optimize_for_size,
+        cranelift_settings: &flags.cranelift_settings,
+        annotate,
+        debug_info: flags.debug_info,
+        overflow_checks: flags.overflow_checks,
+        verify_clif: flags.verify_clif,
+        cache_dir: flags.cache_dir.as_deref(),
+        emit_stages: stages,
+    };
+    let output = compiler::compile(
+        &graph,
+        &program.function_signatures,
+        &program.variables,
+        &program.data,
+        &compilation_options,
+        &mut timings,
+    )?;
+    if flags.time_passes {
+        eprint!("{timings}");
+    }
+    Ok((output, warnings))
+}
+
+fn optimization_options(flags: &Flags) -> call_graph::Options {
+    let defaults = if flags.optimize_for_size {
+        call_graph::Options::SIZE
+    } else {
+        call_graph::Options::SPEED
+    };
+    call_graph::Options {
+        inline_threshold: flags
+            .inline_threshold
+            .unwrap_or(defaults.inline_threshold),
+        inline: flags.opt_level == OptLevel::O2,
+        merge_identical_functions: defaults.merge_identical_functions
+            && flags.opt_level == OptLevel::O2,
+        check_equivalence: flags.check_optimizations,
+    }
+}
+
+/// Takes the warnings about a program that should be reported, leaving out
+/// those in the prelude. Denied warnings make this fail with all of them.
+pub(crate) fn lint(
+    program: &mut typ::CheckedProgram,
+    files: &[Arc<codemap::File>],
+    flags: &Flags,
+) -> Result<Vec<Diagnostic>, diagnostics::Error> {
+    let prelude = flags.prelude.then(|| files[0].span);
+    flags
+        .lints
+        .apply(std::mem::take(&mut program.warnings), prelude)
+}
+
+/// The functions that a library exports, which are those that aren't from
+/// the prelude.
+fn library_functions<'src>(
+    program: &ir::Program<'src>,
+    files: &[Arc<codemap::File>],
+    flags: &Flags,
+) -> BTreeSet<&'src str> {
+    if flags.crate_type == CrateType::Executable {
+        return BTreeSet::new();
+    }
+    let prelude = flags.prelude.then(|| files[0].span);
+    program
+        .functions
+        .iter()
+        .filter(|(_, function)| {
+            prelude.is_none_or(|prelude| {
+                !prelude.contains(function.declaration_span)
+            })
+        })
+        .map(|(&name, _)| name)
+        .collect()
+}
+
+fn format_ssa(checksum: u64, graph: &call_graph::CallGraph) -> String {
+    let mut out = format!("checksum: {checksum:016x}\n");
+    for function in graph.node_weights() {
+        writeln!(out, "{}: {:#?}", function.name, function.body).unwrap();
+    }
+    out
+}
@@ -1,43 +1,182 @@
 use crate::{
     call_graph::CallGraph,
-    ir::{BinLogicOp, BinMathOp, Comparison},
-    ssa::{self, Op},
-    typ::{FunctionSignature, Type},
+    debug_info::DebugInfo,
+    diagnostics::{self, internal_error, primary_label},
+    ir::{
+        BinLogicOp, BinMathOp, BitwiseOp, Comparison, MathFunction, Rounding,
+    },
+    ssa::{self, interval::Intervals, Op},
+    target::Target,
+    timing::Timings,
+    typ::{DataTable, FunctionSignature, Type},
 };
-use anyhow::Result;
+use anyhow::{Context as _, Result};
+use codemap::Span;
 use cranelift::prelude::{
     codegen::{
-        ir::{Function, Inst, UserFuncName},
-        Context,
+        ir::{
+            entities::AnyEntity, Endianness, Function, Inst, SourceLoc,
+            UserFuncName,
+        },
+        verifier::VerifierErrors,
+        CodegenError, CompiledCode, Context,
     },
     isa::TargetIsa,
     settings,
-    types::{F32, I32, I8},
-    AbiParam, Configurable, FunctionBuilder, FunctionBuilderContext,
-    InstBuilder, IntCC, MemFlags, Signature, StackSlotData, StackSlotKind,
-    Value,
+    types::{F32, I32, I64, I8},
+    AbiParam, Block, Configurable, FloatCC, FunctionBuilder,
+    FunctionBuilderContext, InstBuilder, IntCC, JumpTableData, MemFlags,
+    Signature, StackSlotData, StackSlotKind, TrapCode, Value,
 };
-use cranelift_module::{FuncId, Linkage, Module};
+use cranelift_codegen::incremental_cache::CacheKvStore;
+use cranelift_module::{DataContext, DataId, FuncId, Linkage, Module};
 use cranelift_object::{ObjectBuilder, ObjectModule};
-use std::{collections::BTreeMap, fs::File, io::Write, path::Path};
+use itertools::Itertools;
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
+use std::{
+    borrow::{Borrow, Cow},
+    collections::{BTreeMap, BTreeSet},
+    fmt::Write as _,
+    ops::ControlFlow,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Instant, SystemTime},
+};
 
+#[expect(
+    clippy::struct_excessive_bools,
+    reason = "each of them is a separate command line flag"
+)]
 pub struct Options<'a> {
-    pub target_triple: &'a str,
-    pub out_path: &'a Path,
+    pub target: Target,
+    /// The source files, which runtime errors refer to.
+    pub files: &'a [Arc<codemap::File>],
+    /// `-O0` turns Cranelift's optimizations off.
+    pub opt_level: OptLevel,
+    /// Makes Cranelift weigh code size along with speed.
+    pub optimize_for_size: bool,
+    /// Cranelift settings such as `enable_alias_analysis`, as names and
+    /// values, which are applied after the defaults and can thus override
+    /// them.
+    pub cranelift_settings: &'a [(String, String)],
+    /// Produces the listing for `--emit annotated`, which needs the program
+    /// to be type checked with `typ::check_annotated`.
+    pub annotate: bool,
+    /// Produces DWARF line and function information so that debuggers can
+    /// step through the source code, which needs the program to be type
+    /// checked with `typ::check_annotated`.
+    pub debug_info: bool,
+    /// Makes `i32` addition, subtraction and multiplication stop the program
+    /// when they overflow instead of wrapping around.
+    pub overflow_checks: bool,
+    /// Runs Cranelift's verifier on every function, even if the Cranelift
+    /// settings turn it off. Errors point at the words that the offending
+    /// instructions came from if the program was type checked with
+    /// `typ::check_annotated`.
+    pub verify_clif: bool,
+    /// A directory to keep the machine code of each function in between runs,
+    /// so that only functions that changed are compiled again.
+    pub cache_dir: Option<&'a Path>,
+    /// Where to write each intermediate form of the program that was asked
+    /// for. Only CLIF, disassembly and the source map are written by the
+    /// compiler, where the source map needs the program to be type checked
+    /// with `typ::check_annotated`.
+    pub emit_stages: &'a EmitStages,
 }
 
-pub fn compile(
-    functions: &CallGraph,
-    function_signatures: &BTreeMap<&str, FunctionSignature>,
-    options: &Options,
+/// How much to optimize a program, from `-O0` to `-O2`.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum OptLevel {
+    /// No optimizations, neither on SSA nor by Cranelift.
+    O0,
+    /// Simplifies each function on its own, without inlining.
+    O1,
+    /// Every optimization.
+    O2,
+}
+
+/// An intermediate form of the program that `--emit` can write out.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum Stage {
+    Parsed,
+    Typed,
+    Ssa,
+    OptimizedSsa,
+    /// CLIF before Cranelift optimizes it.
+    Clif,
+    Disassembly,
+    /// JSON describing which word each range of machine code came from.
+    SourceMap,
+}
+
+impl Stage {
+    pub const ALL: [Self; 7] = [
+        Self::Parsed,
+        Self::Typed,
+        Self::Ssa,
+        Self::OptimizedSsa,
+        Self::Clif,
+        Self::Disassembly,
+        Self::SourceMap,
+    ];
+
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::Parsed => "parsed",
+            Self::Typed => "typed",
+            Self::Ssa => "ssa",
+            Self::OptimizedSsa => "optimized-ssa",
+            Self::Clif => "clif",
+            Self::Disassembly => "disassembly",
+            Self::SourceMap => "source-map",
+        }
+    }
+}
+
+/// The stages to write out, each to a file or to stdout if there is no path.
+pub type EmitStages = BTreeMap<Stage, Option<PathBuf>>;
+
+/// Writes out a stage if it was asked for, only producing its contents then.
+pub fn emit_stage(
+    emit_stages: &EmitStages,
+    stage: Stage,
+    contents: impl FnOnce() -> String,
 ) -> Result<()> {
-    let mut shared_builder = settings::builder();
-    shared_builder.enable("is_pic")?;
-    shared_builder.set("opt_level", "speed_and_size")?;
+    match emit_stages.get(&stage) {
+        None => {}
+        Some(None) => print!("{}", contents()),
+        Some(Some(path)) => {
+            std::fs::write(path, contents()).with_context(|| {
+                format!(
+                    "failed to write {} to {}",
+                    stage.name(),
+                    path.display()
+                )
+            })?;
+        }
+    }
+    Ok(())
+}
 
-    let shared_flags = settings::Flags::new(shared_builder);
-    let isa = cranelift::codegen::isa::lookup_by_name(options.target_triple)?
-        .finish(shared_flags)?;
+/// What compiling a program produces.
+pub struct Output<'src> {
+    pub object: Vec<u8>,
+    /// The size of the machine code generated for each function.
+    pub sizes: BTreeMap<&'src str, usize>,
+    /// The listing for `--emit annotated`, or nothing if it wasn't asked for.
+    pub annotated: String,
+}
+
+/// Writes an object file.
+pub fn compile<'src>(
+    functions: &CallGraph<'src>,
+    function_signatures: &BTreeMap<&str, FunctionSignature>,
+    variables: &BTreeMap<&str, Type>,
+    data: &BTreeMap<&str, DataTable>,
+    options: &Options,
+    timings: &mut Timings,
+) -> Result<Output<'src>> {
+    let isa = isa(options)?;
     let extern_function_signatures = extern_function_signatures(&*isa);
 
     let object_builder = ObjectBuilder::new(
@@ -47,10 +186,224 @@ pub fn compile(
     )?;
     let mut object_module = ObjectModule::new(object_builder);
 
-    let clif_function_signatures = function_signatures
+    let (clif_function_signatures, function_ids) =
+        declare_functions(functions, function_signatures, &mut object_module)?;
+
+    let extern_functions = declare_extern_functions(
+        functions,
+        &mut object_module,
+        options.target,
+    )?;
+
+    // `main` stores its `argc` and `argv` here for `arg-count` and `arg`.
+    let mut zeroed_data = |size: u32| -> Result<DataId> {
+        let mut data_ctx = DataContext::new();
+        data_ctx.define_zeroinit(size as usize);
+        let data_id = object_module.declare_anonymous_data(true, false)?;
+        object_module.define_data(data_id, &data_ctx)?;
+        Ok(data_id)
+    };
+    let args_data = [
+        zeroed_data(I32.bytes())?,
+        zeroed_data(isa.pointer_type().bytes())?,
+    ];
+
+    let type_names = object_module.declare_anonymous_data(false, false)?;
+
+    let (variables, data) =
+        define_globals(variables, data, &mut object_module, &*isa)?;
+
+    let mut compiler = Compiler {
+        function_ids,
+        clif_function_signatures,
+        ssa_values: BTreeMap::new(),
+        isa: &*isa,
+        object_module,
+        extern_functions,
+        extern_function_signatures,
+        loops: Vec::new(),
+        quotations: Vec::new(),
+        declared_quotations: BTreeSet::new(),
+        function_start: None,
+        return_block: None,
+        in_main: false,
+        files: options.files,
+        strings: BTreeMap::new(),
+        locations: BTreeMap::new(),
+        types: vec![Type::Bool, Type::I32, Type::F32, Type::Type, Type::File],
+        type_names,
+        argc: args_data[0],
+        argv: args_data[1],
+        variables,
+        data,
+        annotate: options.annotate,
+        overflow_checks: options.overflow_checks,
+        intervals: Intervals::default(),
+        cache_dir: options.cache_dir,
+        words: Vec::new(),
+        annotated: String::new(),
+        clif: options
+            .emit_stages
+            .contains_key(&Stage::Clif)
+            .then(String::new),
+        disassembly: options
+            .emit_stages
+            .contains_key(&Stage::Disassembly)
+            .then(String::new),
+        debug_info: (options.debug_info
+            || options.emit_stages.contains_key(&Stage::SourceMap))
+        .then(DebugInfo::new),
+    };
+    let sizes = compiler.compile(functions, timings)?;
+    compiler.define_type_names()?;
+    if let Some(clif) = compiler.clif.take() {
+        emit_stage(options.emit_stages, Stage::Clif, || clif)?;
+    }
+    if let Some(disassembly) = compiler.disassembly.take() {
+        emit_stage(options.emit_stages, Stage::Disassembly, || disassembly)?;
+    }
+
+    let start = Instant::now();
+    let mut product = compiler.object_module.finish();
+    if let Some(debug_info) = &compiler.debug_info {
+        emit_stage(options.emit_stages, Stage::SourceMap, || {
+            debug_info.source_map(&product, options.files)
+        })?;
+        if options.debug_info {
+            debug_info.write(&mut product, options.files, &*isa)?;
+        }
+    }
+    let object = product.emit()?;
+    timings.add("writing the object file", start.elapsed());
+    Ok(Output {
+        object,
+        sizes,
+        annotated: compiler.annotated,
+    })
+}
+
+/// Puts each variable and data table in its own data object.
+#[expect(clippy::type_complexity, reason = "the maps have the same type")]
+fn define_globals(
+    variables: &BTreeMap<&str, Type>,
+    data: &BTreeMap<&str, DataTable>,
+    object_module: &mut ObjectModule,
+    isa: &dyn TargetIsa,
+) -> Result<(BTreeMap<Box<str>, DataId>, BTreeMap<Box<str>, DataId>)> {
+    let variables = variables
+        .iter()
+        .map(|(&name, typ)| {
+            let mut data_ctx = DataContext::new();
+            data_ctx.define_zeroinit(typ.to_clif(isa)?.bytes() as usize);
+            let data_id = object_module.declare_data(
+                &format!("var.{name}"),
+                Linkage::Local,
+                true,
+                false,
+            )?;
+            object_module.define_data(data_id, &data_ctx)?;
+            Ok((name.into(), data_id))
+        })
+        .collect::<Result<_>>()?;
+    let data = data
         .iter()
-        .map(|(name, signature)| (&**name, signature.to_clif(name, &*isa)))
-        .collect::<BTreeMap<_, _>>();
+        .map(|(&name, table)| {
+            let mut data_ctx = DataContext::new();
+            data_ctx.define(data_bytes(table, isa.endianness()));
+            data_ctx
+                .set_align(table.element_type().to_clif(isa)?.bytes().into());
+            // Data tables are read-only.
+            let data_id = object_module.declare_data(
+                &format!("data.{name}"),
+                Linkage::Local,
+                false,
+                false,
+            )?;
+            object_module.define_data(data_id, &data_ctx)?;
+            Ok((name.into(), data_id))
+        })
+        .collect::<Result<_>>()?;
+    Ok((variables, data))
+}
+
+/// The contents of a data table in the byte order of the target.
+fn data_bytes(table: &DataTable, endianness: Endianness) -> Box<[u8]> {
+    let words = match table {
+        DataTable::I32(elements) => elements.to_vec(),
+        #[expect(clippy::cast_possible_wrap, reason = "only the bits matter")]
+        DataTable::F32(elements) => {
+            elements.iter().map(|n| n.to_bits() as i32).collect()
+        }
+        DataTable::Bool(elements) => {
+            return elements.iter().map(|&b| u8::from(b)).collect();
+        }
+    };
+    words
+        .into_iter()
+        .flat_map(|n| match endianness {
+            Endianness::Little => n.to_le_bytes(),
+            Endianness::Big => n.to_be_bytes(),
+        })
+        .collect()
+}
+
+/// Configures Cranelift for the target.
+fn isa(options: &Options) -> Result<Arc<dyn TargetIsa>> {
+    let mut shared_builder = settings::builder();
+    shared_builder.enable("is_pic")?;
+    shared_builder.set(
+        "opt_level",
+        match options.opt_level {
+            OptLevel::O0 => "none",
+            OptLevel::O1 | OptLevel::O2 if options.optimize_for_size => {
+                "speed_and_size"
+            }
+            OptLevel::O1 | OptLevel::O2 => "speed",
+        },
+    )?;
+    for (name, value) in options.cranelift_settings {
+        shared_builder.set(name, value).with_context(|| {
+            format!("invalid Cranelift setting `{name}={value}`")
+        })?;
+    }
+    if options.verify_clif {
+        shared_builder.enable("enable_verifier")?;
+    }
+
+    options.target.isa(settings::Flags::new(shared_builder))
+}
+
+/// Declares the functions of the program, giving their CLIF signatures and
+/// IDs.
+#[expect(clippy::type_complexity, reason = "the maps have the same keys")]
+fn declare_functions<'src>(
+    functions: &CallGraph<'src>,
+    function_signatures: &BTreeMap<&str, FunctionSignature>,
+    object_module: &mut ObjectModule,
+) -> Result<(BTreeMap<&'src str, Signature>, BTreeMap<&'src str, FuncId>)> {
+    let isa = object_module.isa();
+
+    // Functions that have been inlined everywhere no longer exist and must
+    // not be declared, since they would never be defined.
+    let clif_function_signatures = functions
+        .node_weights()
+        .map(|function| {
+            let signature =
+                function_signatures.get(function.name).ok_or_else(|| {
+                    internal_error(format!(
+                        "`{}` has no signature",
+                        function.name
+                    ))
+                })?;
+            let is_main = function.name == "main";
+            Ok((function.name, signature.to_clif(is_main, isa)?))
+        })
+        .collect::<Result<BTreeMap<_, _>>>()?;
+    let exported = functions
+        .node_weights()
+        .filter(|function| function.exported)
+        .map(|function| function.name)
+        .collect::<BTreeSet<_>>();
     let function_ids = clif_function_signatures
         .iter()
         .map(|(&name, signature)| {
@@ -60,30 +413,99 @@ pub fn compile(
                     Linkage::Export,
                     signature,
                 )
+            } else if exported.contains(name) {
+                object_module.declare_function(
+                    &symbol_name(name),
+                    Linkage::Export,
+                    signature,
+                )
             } else {
                 object_module.declare_anonymous_function(signature)
+            }?;
+            Ok((name, func_id))
+        })
+        .collect::<Result<_>>()?;
+    Ok((clif_function_signatures, function_ids))
+}
+
+/// Declares every function declared with `extern fn` that is called, which
+/// is done up front since not every calling convention exists on every
+/// target.
+fn declare_extern_functions(
+    functions: &CallGraph,
+    object_module: &mut ObjectModule,
+    target: Target,
+) -> Result<BTreeMap<Box<str>, FuncId>> {
+    let mut signatures = BTreeMap::new();
+    let flow = functions.node_weights().try_for_each(|function| {
+        function.body.each_op(&mut |op| {
+            if let Op::CallExtern(name, abi, signature) = op {
+                let call_conv = match target.extern_call_conv(*abi) {
+                    Ok(call_conv) => call_conv,
+                    Err(error) => return ControlFlow::Break(error),
+                };
+                let mut signature =
+                    match signature.to_clif(false, object_module.isa()) {
+                        Ok(signature) => signature,
+                        Err(error) => return ControlFlow::Break(error),
+                    };
+                signature.call_conv = call_conv;
+                signatures.insert(name.clone(), signature);
             }
-            .unwrap();
-            (name, func_id)
+            ControlFlow::Continue(())
+        })
+    });
+    if let ControlFlow::Break(error) = flow {
+        return Err(error);
+    }
+    signatures
+        .into_iter()
+        .map(|(name, signature)| {
+            // Unlike the runtime, C libraries are usually linked dynamically.
+            let func_id = object_module.declare_function(
+                &name,
+                Linkage::Import,
+                &signature,
+            )?;
+            Ok((name, func_id))
         })
-        .collect();
+        .collect()
+}
 
-    let mut compiler = Compiler {
-        function_ids,
-        clif_function_signatures,
-        ssa_values: BTreeMap::new(),
-        isa: &*isa,
-        object_module,
-        extern_functions: BTreeMap::new(),
-        extern_function_signatures,
-    };
-    compiler.compile(functions)?;
+/// The symbol that a function of a library is exported as, which stays the
+/// same as long as the function keeps its name. Characters that can't be in
+/// C identifiers are written as their code point in hexadecimal between
+/// underscores, and underscores are doubled so that the two can't be
+/// confused.
+pub fn symbol_name(function: &str) -> String {
+    let mut symbol = "spackel_".to_owned();
+    for c in function.chars() {
+        match c {
+            '_' => symbol.push_str("__"),
+            _ if c.is_ascii_alphanumeric() => symbol.push(c),
+            _ => write!(symbol, "_{:x}_", u32::from(c)).unwrap(),
+        }
+    }
+    symbol
+}
 
-    let object_bytes = compiler.object_module.finish().emit()?;
-    let mut object_file = File::create(options.out_path)?;
-    object_file.write_all(&object_bytes)?;
+/// Where the number of pointers starts in the runtime representation of a
+/// type.
+const POINTER_DEPTH_SHIFT: i32 = 24;
 
-    Ok(())
+/// A function whose CLIF has been built but that Cranelift hasn't compiled
+/// yet.
+struct Lowered<'src> {
+    /// The function that the sizes count this one towards.
+    owner: &'src str,
+    /// What debug info calls the function.
+    name: String,
+    func_id: FuncId,
+    func: Function,
+    /// The CLIF as it was built, if it was asked for.
+    clif: Option<String>,
+    /// The CLIF that each word turned into, if annotating.
+    clif_by_word: Option<Vec<(SourceLoc, Vec<String>)>>,
 }
 
 struct Compiler<'a> {
@@ -92,15 +514,79 @@ struct Compiler<'a> {
     ssa_values: BTreeMap<ssa::Value, Value>,
     isa: &'a dyn TargetIsa,
     object_module: ObjectModule,
-    extern_functions: BTreeMap<&'static str, FuncId>,
+    /// Functions from the runtime that have been called so far, as well as
+    /// every function declared with `extern fn` that is called anywhere.
+    extern_functions: BTreeMap<Box<str>, FuncId>,
     extern_function_signatures: BTreeMap<&'static str, Signature>,
+    /// The blocks that `continue` and `break` jump to for each loop that is
+    /// currently being compiled.
+    loops: Vec<(Block, Block)>,
+    /// Quotations that have been declared but not yet compiled.
+    quotations: Vec<(FuncId, Signature, ssa::Graph)>,
+    /// Every quotation that has been compiled or is about to be.
+    declared_quotations: BTreeSet<FuncId>,
+    /// The block that `Op::Recurse` jumps to in the current function.
+    function_start: Option<Block>,
+    /// The block that `Op::Return` jumps to in the current function, which
+    /// only exists if the function returns early.
+    return_block: Option<Block>,
+    /// Whether the current function is `main`, which `exit` can simply
+    /// return from.
+    in_main: bool,
+    files: &'a [Arc<codemap::File>],
+    /// Null-terminated strings that have been put in the object file.
+    strings: BTreeMap<String, DataId>,
+    /// Records of source locations that have been put in the object file,
+    /// keyed by file index, line and column.
+    locations: BTreeMap<(usize, usize, usize), DataId>,
+    /// The types that aren't pointers, whose indices are how types are
+    /// represented at runtime. The bits of an `i32` from `POINTER_DEPTH_SHIFT`
+    /// upwards say how many pointers there are around the type that the
+    /// lower bits index.
+    types: Vec<Type>,
+    /// The names of `types` as null-terminated strings, one after another,
+    /// for `spkl_print_type`.
+    type_names: DataId,
+    argc: DataId,
+    argv: DataId,
+    variables: BTreeMap<Box<str>, DataId>,
+    /// The read-only data tables.
+    data: BTreeMap<Box<str>, DataId>,
+    annotate: bool,
+    overflow_checks: bool,
+    /// What is known about the `i32`s in the function that is being
+    /// compiled, which can make checks for overflow unnecessary.
+    intervals: Intervals,
+    cache_dir: Option<&'a Path>,
+    /// The words that source locations in CLIF are indices into.
+    words: Vec<Span>,
+    annotated: String,
+    /// The CLIF of every function so far, if it was asked for.
+    clif: Option<String>,
+    /// The machine code of every function so far, if it was asked for.
+    disassembly: Option<String>,
+    debug_info: Option<DebugInfo>,
 }
 
 impl Compiler<'_> {
-    fn take(&mut self, value: ssa::Value) -> Value {
-        self.ssa_values
-            .remove(&value)
-            .unwrap_or_else(|| panic!("{value:?} is not defined"))
+    fn take(&mut self, value: ssa::Value) -> Result<Value> {
+        self.ssa_values.remove(&value).ok_or_else(|| {
+            internal_error(format!("`{value:?}` is used but not defined"))
+        })
+    }
+
+    fn get(&self, value: ssa::Value) -> Result<Value> {
+        self.ssa_values.get(&value).copied().ok_or_else(|| {
+            internal_error(format!("`{value:?}` is used but not defined"))
+        })
+    }
+
+    fn take_all(&mut self, values: &[ssa::Value]) -> Result<Vec<Value>> {
+        values.iter().map(|&value| self.take(value)).collect()
+    }
+
+    fn function_id(&self, name: &str) -> Result<FuncId> {
+        lookup(&self.function_ids, name, "function")
     }
 
     fn set(&mut self, value: ssa::Value, clif_value: Value) {
@@ -112,113 +598,943 @@ impl Compiler<'_> {
         func_name: &'static str,
         args: &[Value],
         fb: &mut FunctionBuilder,
-    ) -> Inst {
+    ) -> Result<Inst> {
         let func_id =
-            *self.extern_functions.entry(func_name).or_insert_with(|| {
-                let Some(signature) =
-                    self.extern_function_signatures.get(func_name)
-                else {
-                    panic!("extern function `{func_name}` missing signature");
+            if let Some(&func_id) = self.extern_functions.get(func_name) {
+                func_id
+            } else {
+                let signature = lookup(
+                    &self.extern_function_signatures,
+                    func_name,
+                    "runtime function",
+                )?;
+                // The math library is linked dynamically, unlike the runtime.
+                let linkage = if func_name.starts_with("spkl_") {
+                    Linkage::Hidden
+                } else {
+                    Linkage::Import
                 };
-                self.object_module
-                    .declare_function(func_name, Linkage::Hidden, signature)
-                    .unwrap()
-            });
+                let func_id = self
+                    .object_module
+                    .declare_function(func_name, linkage, &signature)?;
+                self.extern_functions.insert(func_name.into(), func_id);
+                func_id
+            };
         let func_ref =
             self.object_module.declare_func_in_func(func_id, fb.func);
-        fb.ins().call(func_ref, args)
+        Ok(fb.ins().call(func_ref, args))
     }
 
-    fn compile(&mut self, functions: &CallGraph) -> Result<()> {
-        let mut ctx = Context::new();
-        let mut func_ctx = FunctionBuilderContext::new();
+    /// Calls an extern function that writes an `i32` through a pointer passed
+    /// after `args` and returns whether that succeeded.
+    fn call_reading_extern(
+        &mut self,
+        func_name: &'static str,
+        args: &[Value],
+        fb: &mut FunctionBuilder,
+    ) -> Result<(Value, Value)> {
+        let stack_slot = fb.create_sized_stack_slot(StackSlotData {
+            kind: StackSlotKind::ExplicitSlot,
+            size: I32.bytes(),
+        });
+        let ptr = fb.ins().stack_addr(self.isa.pointer_type(), stack_slot, 0);
+        let args = args.iter().copied().chain([ptr]).collect::<Vec<_>>();
+        let call = self.call_extern(func_name, &args, fb)?;
+        let success = fb.inst_results(call)[0];
+        Ok((fb.ins().stack_load(I32, stack_slot, 0), success))
+    }
+
+    /// Gets a pointer to a null-terminated copy of `string`, only putting it
+    /// in the object file the first time.
+    fn string(
+        &mut self,
+        string: String,
+        fb: &mut FunctionBuilder,
+    ) -> Result<Value> {
+        let data_id = self.string_data(string)?;
+        Ok(self.data_address(data_id, fb))
+    }
 
+    fn string_data(&mut self, string: String) -> Result<DataId> {
+        if let Some(&data_id) = self.strings.get(&string) {
+            return Ok(data_id);
+        }
+        let mut data_ctx = DataContext::new();
+        data_ctx.define(
+            string
+                .bytes()
+                .chain([0])
+                .collect::<Vec<_>>()
+                .into_boxed_slice(),
+        );
+        let data_id =
+            self.object_module.declare_anonymous_data(false, false)?;
+        self.object_module.define_data(data_id, &data_ctx)?;
+        self.strings.insert(string, data_id);
+        Ok(data_id)
+    }
+
+    /// Gets a pointer to a record of where `span` starts, which `spkl_panic`
+    /// reports. It consists of a pointer to the file name followed by the
+    /// line and column as `u32`s, both starting at 1.
+    fn location(
+        &mut self,
+        span: Span,
+        fb: &mut FunctionBuilder,
+    ) -> Result<Value> {
+        let (index, file) = self
+            .files
+            .iter()
+            .enumerate()
+            .find(|(_, file)| file.span.contains(span))
+            .ok_or_else(|| internal_error("span outside of every file"))?;
+        let position = file.find_line_col(span.low());
+        let key = (index, position.line + 1, position.column + 1);
+        let data_id = if let Some(&data_id) = self.locations.get(&key) {
+            data_id
+        } else {
+            let name = self.string_data(file.name().to_owned())?;
+            let pointer_size = usize::from(self.isa.pointer_bytes());
+            let mut bytes = vec![0; pointer_size];
+            for n in [key.1, key.2] {
+                let n = u32::try_from(n).map_err(internal_error)?;
+                bytes.extend(match self.isa.endianness() {
+                    Endianness::Little => n.to_le_bytes(),
+                    Endianness::Big => n.to_be_bytes(),
+                });
+            }
+            let mut data_ctx = DataContext::new();
+            data_ctx.define(bytes.into_boxed_slice());
+            let name =
+                self.object_module.declare_data_in_data(name, &mut data_ctx);
+            data_ctx.write_data_addr(0, name, 0);
+            let data_id =
+                self.object_module.declare_anonymous_data(false, false)?;
+            self.object_module.define_data(data_id, &data_ctx)?;
+            self.locations.insert(key, data_id);
+            data_id
+        };
+        Ok(self.data_address(data_id, fb))
+    }
+
+    /// The runtime representation of a type.
+    fn type_tag(&mut self, mut typ: &Type) -> Result<i32> {
+        let mut pointer_depth = 0;
+        while let Type::Ptr(pointee) = typ {
+            typ = pointee;
+            pointer_depth += 1;
+        }
+        let index = self
+            .types
+            .iter()
+            .position(|known| known == typ)
+            .unwrap_or_else(|| {
+                self.types.push(typ.clone());
+                self.types.len() - 1
+            });
+        let index = i32::try_from(index)
+            .map_err(|_| internal_error("too many types"))?;
+        Ok(index | pointer_depth << POINTER_DEPTH_SHIFT)
+    }
+
+    fn define_type_names(&mut self) -> Result<()> {
+        let mut names = String::new();
+        for typ in &self.types {
+            write!(names, "{typ}\0")?;
+        }
+        let mut data_ctx = DataContext::new();
+        data_ctx.define(names.into_bytes().into_boxed_slice());
+        self.object_module.define_data(self.type_names, &data_ctx)?;
+        Ok(())
+    }
+
+    fn data_address(&self, data_id: DataId, fb: &mut FunctionBuilder) -> Value {
+        let global_value =
+            self.object_module.declare_data_in_func(data_id, fb.func);
+        fb.ins().symbol_value(self.isa.pointer_type(), global_value)
+    }
+
+    /// Sign-extends an `i32` to the size of a pointer.
+    fn pointer_width(&self, n: Value, fb: &mut FunctionBuilder) -> Value {
+        if self.isa.pointer_type() == I32 {
+            n
+        } else {
+            fb.ins().sextend(self.isa.pointer_type(), n)
+        }
+    }
+
+    fn load_argc(&self, fb: &mut FunctionBuilder) -> Value {
+        let address = self.data_address(self.argc, fb);
+        fb.ins().load(I32, MemFlags::trusted(), address, 0)
+    }
+
+    /// Calls `spkl_panic` with `message` and the location of `span` if
+    /// `condition` is false.
+    fn compile_check(
+        &mut self,
+        condition: Value,
+        message: &str,
+        span: Span,
+        fb: &mut FunctionBuilder,
+    ) -> Result<()> {
+        let failed_block = fb.create_block();
+        let after_block = fb.create_block();
+        fb.ins()
+            .brif(condition, after_block, &[], failed_block, &[]);
+        fb.seal_block(failed_block);
+        fb.seal_block(after_block);
+
+        fb.switch_to_block(failed_block);
+        fb.set_cold_block(failed_block);
+        let message = self.string(message.to_owned(), fb)?;
+        let location = self.location(span, fb)?;
+        self.call_extern("spkl_panic", &[message, location], fb)?;
+        // `spkl_panic` never returns.
+        fb.ins().trap(TrapCode::UnreachableCodeReached);
+
+        fb.switch_to_block(after_block);
+        Ok(())
+    }
+
+    /// Checks that an `i32` division neither divides by zero nor overflows,
+    /// which would otherwise crash the program with a signal.
+    fn compile_division_checks(
+        &mut self,
+        a: Value,
+        b: Value,
+        span: Span,
+        fb: &mut FunctionBuilder,
+    ) -> Result<()> {
+        let nonzero = fb.ins().icmp_imm(IntCC::NotEqual, b, 0);
+        self.compile_check(nonzero, "division by zero", span, fb)?;
+        let not_min =
+            fb.ins().icmp_imm(IntCC::NotEqual, a, i64::from(i32::MIN));
+        let not_minus_one = fb.ins().icmp_imm(IntCC::NotEqual, b, -1);
+        let no_overflow = fb.ins().bor(not_min, not_minus_one);
+        self.compile_check(no_overflow, "division overflowed", span, fb)
+    }
+
+    /// Computes a binary operation, leaving out the checks for overflow and
+    /// division by zero unless `checked`.
+    #[expect(
+        clippy::too_many_arguments,
+        reason = "the operation, its operands and where it comes from"
+    )]
+    fn compile_bin_math(
+        &mut self,
+        operation: BinMathOp,
+        typ: &Type,
+        a: Value,
+        b: Value,
+        checked: bool,
+        span: Span,
+        fb: &mut FunctionBuilder,
+    ) -> Result<Value> {
+        if checked
+            && matches!(operation, BinMathOp::Div | BinMathOp::Rem)
+            && *typ == Type::I32
+        {
+            self.compile_division_checks(a, b, span, fb)?;
+        }
+        Ok(match (operation, typ) {
+            (BinMathOp::Add | BinMathOp::Sub | BinMathOp::Mul, Type::I32)
+                if checked && self.overflow_checks =>
+            {
+                self.compile_checked_arithmetic(operation, a, b, span, fb)?
+            }
+            (BinMathOp::Add, Type::I32) | (BinMathOp::WrappingAdd, _) => {
+                fb.ins().iadd(a, b)
+            }
+            (BinMathOp::Sub, Type::I32) | (BinMathOp::WrappingSub, _) => {
+                fb.ins().isub(a, b)
+            }
+            (BinMathOp::Mul, Type::I32) | (BinMathOp::WrappingMul, _) => {
+                fb.ins().imul(a, b)
+            }
+            (BinMathOp::Div, Type::I32) => fb.ins().sdiv(a, b),
+            (BinMathOp::Rem, Type::I32) => fb.ins().srem(a, b),
+            (BinMathOp::SillyAdd, _) => {
+                self.compile_silly_add(typ, a, b, span, fb)?
+            }
+            (
+                BinMathOp::SaturatingAdd
+                | BinMathOp::SaturatingSub
+                | BinMathOp::SaturatingMul,
+                _,
+            ) => saturating_arithmetic(operation, a, b, fb)?,
+            (BinMathOp::Add, Type::F32) => fb.ins().fadd(a, b),
+            (BinMathOp::Sub, Type::F32) => fb.ins().fsub(a, b),
+            (BinMathOp::Mul, Type::F32) => fb.ins().fmul(a, b),
+            (BinMathOp::Div, Type::F32) => fb.ins().fdiv(a, b),
+            _ => {
+                return Err(internal_error(format!(
+                    "`{operation:?}` on `{typ}`"
+                )))
+            }
+        })
+    }
+
+    /// Adds two numbers, except that 9 + 10 and 10 + 9 are 21 and 1 + 1 is 1.
+    fn compile_silly_add(
+        &mut self,
+        typ: &Type,
+        a: Value,
+        b: Value,
+        span: Span,
+        fb: &mut FunctionBuilder,
+    ) -> Result<Value> {
+        let sum =
+            self.compile_bin_math(BinMathOp::Add, typ, a, b, true, span, fb)?;
+        let is = |value, n, fb: &mut FunctionBuilder| {
+            let n = number(typ, n, fb);
+            compare(Comparison::Eq, typ, value, n, fb)
+        };
+        let a_is_nine = is(a, 9, fb);
+        let b_is_ten = is(b, 10, fb);
+        let a_is_ten = is(a, 10, fb);
+        let b_is_nine = is(b, 9, fb);
+        let nine_plus_ten = fb.ins().band(a_is_nine, b_is_ten);
+        let ten_plus_nine = fb.ins().band(a_is_ten, b_is_nine);
+        let is_twenty_one = fb.ins().bor(nine_plus_ten, ten_plus_nine);
+        let a_is_one = is(a, 1, fb);
+        let b_is_one = is(b, 1, fb);
+        let is_one = fb.ins().band(a_is_one, b_is_one);
+        let one = number(typ, 1, fb);
+        let twenty_one = number(typ, 21, fb);
+        let sum = fb.ins().select(is_one, one, sum);
+        Ok(fb.ins().select(is_twenty_one, twenty_one, sum))
+    }
+
+    fn compile_math(
+        &mut self,
+        function: MathFunction,
+        typ: &Type,
+        operands: &[Value],
+        fb: &mut FunctionBuilder,
+    ) -> Result<Value> {
+        let is_i32 = *typ == Type::I32;
+        let libm_function = match (function, operands) {
+            (MathFunction::Abs, &[n]) if is_i32 => return Ok(fb.ins().iabs(n)),
+            (MathFunction::Abs, &[n]) => return Ok(fb.ins().fabs(n)),
+            (MathFunction::Min, &[a, b]) if is_i32 => {
+                return Ok(fb.ins().smin(a, b))
+            }
+            (MathFunction::Min, &[a, b]) => return Ok(fb.ins().fmin(a, b)),
+            (MathFunction::Max, &[a, b]) if is_i32 => {
+                return Ok(fb.ins().smax(a, b))
+            }
+            (MathFunction::Max, &[a, b]) => return Ok(fb.ins().fmax(a, b)),
+            (MathFunction::Pow, _) => "powf",
+            (MathFunction::Sin, _) => "sinf",
+            (MathFunction::Cos, _) => "cosf",
+            (MathFunction::Ln, _) => "logf",
+            (MathFunction::Exp, _) => "expf",
+            _ => {
+                return Err(internal_error(format!(
+                    "`{function:?}` with {} operands",
+                    operands.len()
+                )))
+            }
+        };
+        let call = self.call_extern(libm_function, operands, fb)?;
+        Ok(fb.inst_results(call)[0])
+    }
+
+    /// Computes an `i32` addition, subtraction or multiplication that stops
+    /// the program if the result doesn't fit instead of wrapping around.
+    fn compile_checked_arithmetic(
+        &mut self,
+        operation: BinMathOp,
+        a: Value,
+        b: Value,
+        span: Span,
+        fb: &mut FunctionBuilder,
+    ) -> Result<Value> {
+        let (result, fits) = match operation {
+            // The result has the wrong sign exactly when both operands have
+            // the same sign as each other but not as the result.
+            BinMathOp::Add => {
+                let result = fb.ins().iadd(a, b);
+                let a_flipped = fb.ins().bxor(a, result);
+                let b_flipped = fb.ins().bxor(b, result);
+                let flipped = fb.ins().band(a_flipped, b_flipped);
+                let fits = fb.ins().icmp_imm(
+                    IntCC::SignedGreaterThanOrEqual,
+                    flipped,
+                    0,
+                );
+                (result, fits)
+            }
+            BinMathOp::Sub => {
+                let result = fb.ins().isub(a, b);
+                let different_signs = fb.ins().bxor(a, b);
+                let a_flipped = fb.ins().bxor(a, result);
+                let flipped = fb.ins().band(different_signs, a_flipped);
+                let fits = fb.ins().icmp_imm(
+                    IntCC::SignedGreaterThanOrEqual,
+                    flipped,
+                    0,
+                );
+                (result, fits)
+            }
+            // The full product of two `i32`s always fits in an `i64`.
+            BinMathOp::Mul => {
+                let wide_a = fb.ins().sextend(I64, a);
+                let wide_b = fb.ins().sextend(I64, b);
+                let product = fb.ins().imul(wide_a, wide_b);
+                let result = fb.ins().ireduce(I32, product);
+                let extended = fb.ins().sextend(I64, result);
+                let fits = fb.ins().icmp(IntCC::Equal, extended, product);
+                (result, fits)
+            }
+            _ => {
+                return Err(internal_error(format!(
+                    "`{operation:?}` with overflow checks"
+                )))
+            }
+        };
+        self.compile_check(fits, "integer overflow", span, fb)?;
+        Ok(result)
+    }
+
+    fn compile<'src>(
+        &mut self,
+        functions: &CallGraph<'src>,
+        timings: &mut Timings,
+    ) -> Result<BTreeMap<&'src str, usize>> {
+        let lowered = timings
+            .time("lowering to CLIF", || self.lower_functions(functions))?;
+
+        // Building CLIF puts things in the object file as it goes, so only
+        // Cranelift's part is done in parallel. The results are still put in
+        // the object file in order, which keeps the output the same.
+        let isa = self.isa;
+        let disasm = self.annotate || self.disassembly.is_some();
+        // Cached machine code doesn't come with a disassembly.
+        let cache_dir = self.cache_dir.filter(|_| !disasm);
+        if let Some(dir) = cache_dir {
+            std::fs::create_dir_all(dir).with_context(|| {
+                format!("failed to create cache directory {}", dir.display())
+            })?;
+        }
+        let words = &self.words;
+        let start = Instant::now();
+        let compiled = lowered
+            .into_par_iter()
+            .map_init(Context::new, |ctx, mut function| {
+                ctx.clear();
+                ctx.func = function.func;
+                ctx.set_disasm(disasm);
+                let code = if let Some(dir) = cache_dir {
+                    // Functions found in the cache aren't compiled, so the
+                    // verifier wouldn't see them otherwise.
+                    if isa.flags().enable_verifier() {
+                        ctx.verify(isa).map_err(|errors| {
+                            verifier_error(
+                                &function.name,
+                                &ctx.func,
+                                &errors,
+                                words,
+                            )
+                        })?;
+                    }
+                    ctx.compile_with_cache(isa, &mut DiskCache { dir })
+                        .map(|(code, _)| code)
+                } else {
+                    ctx.compile(isa)
+                }
+                .map_err(|err| match err.inner {
+                    CodegenError::Verifier(errors) => {
+                        verifier_error(&function.name, err.func, &errors, words)
+                    }
+                    inner => inner.into(),
+                })?
+                .clone();
+                function.func =
+                    std::mem::replace(&mut ctx.func, Function::new());
+                Ok((function, code))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        if let Some(dir) = cache_dir {
+            evict_cache_entries(dir);
+        }
+        timings.add("Cranelift codegen", start.elapsed());
+
+        let start = Instant::now();
+        let mut sizes = BTreeMap::new();
+        for (function, code) in compiled {
+            self.define_function(&function, &code)?;
+            *sizes.entry(function.owner).or_default() +=
+                code.code_buffer().len();
+        }
+        timings.add("defining functions", start.elapsed());
+        timings.count("bytes of machine code", sizes.values().sum());
+        Ok(sizes)
+    }
+
+    /// Builds the CLIF of every function, each followed by the quotations in
+    /// it.
+    fn lower_functions<'src>(
+        &mut self,
+        functions: &CallGraph<'src>,
+    ) -> Result<Vec<Lowered<'src>>> {
+        let mut func_ctx = FunctionBuilderContext::new();
+        let mut lowered = Vec::new();
         for function in functions.node_weights() {
-            self.compile_function(
+            let context = || format!("failed to compile `{}`", function.name);
+            function
+                .body
+                .validate()
+                .map_err(internal_error)
+                .with_context(context)?;
+            let signature = lookup(
+                &self.clif_function_signatures,
                 function.name,
-                &function.body,
-                &mut ctx,
-                &mut func_ctx,
-            )?;
+                "function signature",
+            )
+            .with_context(context)?;
+            let func = self
+                .lower_function(
+                    signature,
+                    &function.body,
+                    function.name == "main",
+                    &mut func_ctx,
+                )
+                .with_context(context)?;
+            lowered.push(self.lowered(
+                function.name,
+                function.name.to_owned(),
+                self.function_id(function.name)?,
+                func,
+            ));
+            // Quotations count towards the function that they are written in.
+            while let Some((func_id, signature, body)) = self.quotations.pop() {
+                let func = self
+                    .lower_function(signature, &body, false, &mut func_ctx)
+                    .with_context(|| {
+                        format!(
+                            "failed to compile a quotation in `{}`",
+                            function.name
+                        )
+                    })?;
+                lowered.push(self.lowered(
+                    function.name,
+                    format!("quotation in {}", function.name),
+                    func_id,
+                    func,
+                ));
+            }
+        }
+        Ok(lowered)
+    }
+
+    fn lowered<'src>(
+        &self,
+        owner: &'src str,
+        name: String,
+        func_id: FuncId,
+        func: Function,
+    ) -> Lowered<'src> {
+        Lowered {
+            owner,
+            name,
+            func_id,
+            // Before Cranelift optimizes it, so that it's clear which CLIF
+            // each word turns into.
+            clif: self.clif.is_some().then(|| func.display().to_string()),
+            clif_by_word: self.annotate.then(|| clif_by_word(&func)),
+            func,
         }
+    }
 
+    /// Puts a compiled function in the object file, along with whatever else
+    /// was asked for about it.
+    fn define_function(
+        &mut self,
+        function: &Lowered,
+        code: &CompiledCode,
+    ) -> Result<()> {
+        if function.name == function.owner {
+            self.heading(&format!("fn {}", function.name));
+        } else {
+            self.heading(&function.name);
+        }
+        if let (Some(out), Some(clif)) = (&mut self.clif, &function.clif) {
+            writeln!(out, "{clif}")?;
+        }
+        self.object_module.define_function_bytes(
+            function.func_id,
+            &function.func,
+            code.alignment.into(),
+            code.code_buffer(),
+            code.buffer.relocs(),
+        )?;
+        if let (Some(out), Some(vcode)) = (&mut self.disassembly, &code.vcode) {
+            writeln!(out, "{vcode}")?;
+        }
+        if let Some(clif) = &function.clif_by_word {
+            self.annotate_function(clif, code)?;
+        }
+        // Records where the machine code came from, if debug info or a
+        // source map was asked for.
+        if let Some(debug_info) = &mut self.debug_info {
+            debug_info.add_function(
+                function.name.clone(),
+                function.func_id,
+                code,
+                &self.words,
+            );
+        }
         Ok(())
     }
 
-    fn compile_function(
+    /// Says which function the output that follows belongs to.
+    fn heading(&mut self, heading: &str) {
+        if self.annotate {
+            writeln!(self.annotated, "{heading}").unwrap();
+        }
+        for out in [&mut self.clif, &mut self.disassembly]
+            .into_iter()
+            .flatten()
+        {
+            writeln!(out, "; {heading}").unwrap();
+        }
+    }
+
+    fn lower_function(
         &mut self,
-        name: &str,
+        signature: Signature,
         body: &ssa::Graph,
-        ctx: &mut Context,
+        is_main: bool,
         func_ctx: &mut FunctionBuilderContext,
-    ) -> Result<()> {
-        let signature = self.clif_function_signatures[name].clone();
-        let func_id = self.function_ids[name];
-        ctx.clear();
-        ctx.func =
+    ) -> Result<Function> {
+        let mut func =
             Function::with_name_signature(UserFuncName::default(), signature);
+        self.intervals = Intervals::of(body, self.overflow_checks);
 
-        let mut fb = FunctionBuilder::new(&mut ctx.func, func_ctx);
-        let block = fb.create_block();
-        fb.append_block_params_for_function_params(block);
-        for (ssa_value, &param) in
-            std::iter::zip(body.inputs, fb.block_params(block))
-        {
-            self.set(ssa_value, param);
+        let mut fb = FunctionBuilder::new(&mut func, func_ctx);
+        let entry_block = fb.create_block();
+        fb.append_block_params_for_function_params(entry_block);
+        fb.switch_to_block(entry_block);
+        fb.seal_block(entry_block);
+        // Tail calls to the function itself jump to a separate block since the
+        // entry block can't have any predecessors.
+        let start_block = fb.create_block();
+        let mut params = fb.block_params(entry_block).to_vec();
+        if is_main {
+            let (Some(array), Some(count)) = (params.pop(), params.pop())
+            else {
+                return Err(internal_error("`main` has no `argc` and `argv`"));
+            };
+            let array_address = self.data_address(self.argv, &mut fb);
+            fb.ins().store(MemFlags::trusted(), array, array_address, 0);
+            let count_address = self.data_address(self.argc, &mut fb);
+            fb.ins().store(MemFlags::trusted(), count, count_address, 0);
         }
-        fb.switch_to_block(block);
-        fb.seal_block(block);
+        for (ssa_value, &param) in std::iter::zip(body.inputs, &params) {
+            let typ = fb.func.dfg.value_type(param);
+            self.set(ssa_value, fb.append_block_param(start_block, typ));
+        }
+        fb.ins().jump(start_block, &params);
+        fb.switch_to_block(start_block);
+        self.function_start = Some(start_block);
+        self.in_main = is_main;
+        // Every way of returning jumps to the same block, which passes the
+        // outputs on as its parameters.
+        self.return_block = body.returns_early().then(|| {
+            let block = fb.create_block();
+            let output_types = fb.func.signature.returns[..body.outputs.len()]
+                .iter()
+                .map(|param| param.value_type)
+                .collect::<Vec<_>>();
+            for typ in output_types {
+                fb.append_block_param(block, typ);
+            }
+            block
+        });
 
         for assignment in &body.assignments {
-            self.compile_assignment(assignment, &mut fb);
+            self.compile_assignment(assignment, &mut fb)?;
         }
+        fb.seal_block(start_block);
 
-        let outputs = body
-            .outputs
-            .iter()
-            .map(|output| self.ssa_values[output])
-            // Exit code
-            .chain((name == "main").then(|| fb.ins().iconst(I32, 0)))
-            .collect::<Vec<_>>();
-        fb.ins().return_(&outputs);
+        if !body.diverges() {
+            // Returning doesn't belong to the last word.
+            fb.set_srcloc(SourceLoc::default());
+            let outputs = body
+                .outputs
+                .iter()
+                .map(|&output| self.get(output))
+                .collect::<Result<Vec<_>>>()?;
+            self.return_(&outputs, &mut fb);
+        }
+        if let Some(return_block) = self.return_block.take() {
+            fb.switch_to_block(return_block);
+            fb.seal_block(return_block);
+            fb.set_srcloc(SourceLoc::default());
+            let outputs = fb.block_params(return_block).to_vec();
+            self.return_(&outputs, &mut fb);
+        }
 
         fb.finalize();
-        self.object_module.define_function(func_id, ctx)?;
+        Ok(func)
+    }
+
+    /// Returns from the current function, or jumps to where it does so if it
+    /// returns early too.
+    fn return_(&self, outputs: &[Value], fb: &mut FunctionBuilder) {
+        if let Some(return_block) = self.return_block {
+            fb.ins().jump(return_block, outputs);
+        } else {
+            let outputs = outputs
+                .iter()
+                .copied()
+                // Exit code, unless `main` returns one.
+                .chain(
+                    (self.in_main && outputs.is_empty())
+                        .then(|| fb.ins().iconst(I32, 0)),
+                )
+                .collect::<Vec<_>>();
+            fb.ins().return_(&outputs);
+        }
+    }
+
+    /// Writes the words of a function along with the CLIF and machine code
+    /// that each of them produced, followed by the disassembled machine code.
+    fn annotate_function(
+        &mut self,
+        clif: &[(SourceLoc, Vec<String>)],
+        compiled_code: &CompiledCode,
+    ) -> Result<()> {
+        let code = compiled_code.code_buffer();
+        let mut machine_code = BTreeMap::<u32, Vec<u8>>::new();
+        for srcloc in compiled_code.buffer.get_srclocs_sorted() {
+            if !srcloc.loc.is_default() {
+                machine_code
+                    .entry(srcloc.loc.bits())
+                    .or_default()
+                    .extend(&code[srcloc.start as usize..srcloc.end as usize]);
+            }
+        }
+        let attributed = machine_code.values().map(Vec::len).sum::<usize>();
 
+        let out = &mut self.annotated;
+        for (srcloc, lines) in clif {
+            if srcloc.is_default() {
+                writeln!(out, "  (function entry and exit)")?;
+            } else {
+                let span = *self
+                    .words
+                    .get(srcloc.bits() as usize)
+                    .ok_or_else(|| internal_error("unknown source location"))?;
+                let file = self
+                    .files
+                    .iter()
+                    .find(|file| file.span.contains(span))
+                    .ok_or_else(|| {
+                        internal_error("span outside of every file")
+                    })?;
+                let position = file.find_line_col(span.low());
+                writeln!(
+                    out,
+                    "  `{}` at {}:{}:{}",
+                    file.source_slice(span),
+                    file.name(),
+                    position.line + 1,
+                    position.column + 1
+                )?;
+            }
+            for line in lines {
+                writeln!(out, "      {line}")?;
+            }
+            if let Some(bytes) = machine_code.remove(&srcloc.bits()) {
+                writeln!(
+                    out,
+                    "      {} bytes: {:02x}",
+                    bytes.len(),
+                    bytes.iter().format(" ")
+                )?;
+            }
+        }
+        writeln!(
+            out,
+            "  {} of {} bytes come from no particular word",
+            code.len() - attributed,
+            code.len()
+        )?;
+        if let Some(vcode) = &compiled_code.vcode {
+            writeln!(out, "  machine instructions:")?;
+            for line in vcode.lines() {
+                writeln!(out, "    {line}")?;
+            }
+        }
         Ok(())
     }
 
+    #[expect(
+        clippy::too_many_lines,
+        reason = "every operation is lowered in its own arm"
+    )]
     fn compile_assignment(
         &mut self,
         assignment: &ssa::Assignment,
         fb: &mut FunctionBuilder,
-    ) {
+    ) -> Result<()> {
         let to = assignment.to;
         let args = &assignment.args;
         match &assignment.op {
             Op::Call(name) => {
-                let func_id = self.function_ids[&**name];
+                let func_id = self.function_id(name)?;
+                let func_ref =
+                    self.object_module.declare_func_in_func(func_id, fb.func);
+                let call_args = self.take_all(args)?;
+                let inst = fb.ins().call(func_ref, &call_args);
+                for (value, &res) in std::iter::zip(to, fb.inst_results(inst)) {
+                    self.set(value, res);
+                }
+            }
+            Op::CallExtern(name, ..) => {
+                let func_id =
+                    lookup(&self.extern_functions, name, "extern function")?;
                 let func_ref =
                     self.object_module.declare_func_in_func(func_id, fb.func);
-                let call_args =
-                    args.iter().map(|&arg| self.take(arg)).collect::<Vec<_>>();
+                let call_args = self.take_all(args)?;
                 let inst = fb.ins().call(func_ref, &call_args);
                 for (value, &res) in std::iter::zip(to, fb.inst_results(inst)) {
                     self.set(value, res);
                 }
             }
-            Op::Then(body) => self.compile_then(to, args, body, fb),
+            Op::Quotation(signature, body) => {
+                // Quotations are named after what they do, so that identical
+                // ones are only compiled once.
+                let name = format!(
+                    "quotation.{:016x}",
+                    crate::stable_hash(&format!(
+                        "{signature} {}",
+                        body.canonical_form()
+                    ))
+                );
+                let signature = signature.to_clif(false, self.isa)?;
+                let func_id = self.object_module.declare_function(
+                    &name,
+                    Linkage::Local,
+                    &signature,
+                )?;
+                if self.declared_quotations.insert(func_id) {
+                    self.quotations.push((
+                        func_id,
+                        signature,
+                        (**body).clone(),
+                    ));
+                }
+                let func_ref =
+                    self.object_module.declare_func_in_func(func_id, fb.func);
+                self.set(
+                    to + 0,
+                    fb.ins().func_addr(self.isa.pointer_type(), func_ref),
+                );
+            }
+            Op::Location(span) => {
+                let index = u32::try_from(self.words.len())
+                    .map_err(|_| internal_error("too many words"))?;
+                fb.set_srcloc(SourceLoc::new(index));
+                self.words.push(*span);
+            }
+            Op::FunctionPointer(name) => {
+                let func_id = self.function_id(name)?;
+                let func_ref =
+                    self.object_module.declare_func_in_func(func_id, fb.func);
+                self.set(
+                    to + 0,
+                    fb.ins().func_addr(self.isa.pointer_type(), func_ref),
+                );
+            }
+            Op::Data(name) => {
+                let address = self
+                    .data_address(lookup(&self.data, name, "data table")?, fb);
+                self.set(to + 0, address);
+            }
+            Op::CallQuotation(signature) => {
+                let (&quotation, args) =
+                    args.split_last().ok_or_else(|| {
+                        internal_error("quotation call without a quotation")
+                    })?;
+                let call_args = self.take_all(args)?;
+                let callee = self.take(quotation)?;
+                let signature =
+                    fb.import_signature(signature.to_clif(false, self.isa)?);
+                let inst =
+                    fb.ins().call_indirect(signature, callee, &call_args);
+                for (value, &res) in std::iter::zip(to, fb.inst_results(inst)) {
+                    self.set(value, res);
+                }
+            }
+            Op::Then(body) => self.compile_then(to, args, body, fb)?,
             Op::ThenElse(then, else_) => {
-                self.compile_then_else(to, args, then, else_, fb);
+                self.compile_then_else(to, args, then, else_, fb)?;
+            }
+            Op::Match(cases) => self.compile_match(to, args, cases, fb)?,
+            Op::Switch(values, cases) => {
+                self.compile_switch(to, args, values, cases, fb)?;
+            }
+            Op::Repeat(body) => self.compile_repeat(to, args, body, fb)?,
+            Op::While(condition, body) => {
+                self.compile_while(to, args, condition, body, fb)?;
+            }
+            &Op::Break(depth) | &Op::Continue(depth) => {
+                let (header_block, after_block) =
+                    *self.loops.iter().rev().nth(depth).ok_or_else(|| {
+                        internal_error("`break` or `continue` outside a loop")
+                    })?;
+                let target = if matches!(assignment.op, Op::Break(_)) {
+                    after_block
+                } else {
+                    header_block
+                };
+                // Counted loops in between keep their indices and limits
+                // below the values that the target loop carries.
+                let args = self.take_all(args)?;
+                let carried = args
+                    .len()
+                    .checked_sub(fb.func.dfg.num_block_params(target))
+                    .ok_or_else(|| {
+                        internal_error(
+                            "`break` or `continue` with too few values",
+                        )
+                    })?;
+                fb.ins().jump(target, &args[carried..]);
+            }
+            Op::Exit => {
+                let code = self.take(*args.last().ok_or_else(|| {
+                    internal_error("`exit` without an exit code")
+                })?)?;
+                if self.in_main {
+                    fb.ins().return_(&[code]);
+                } else {
+                    self.call_extern("spkl_exit", &[code], fb)?;
+                    // `spkl_exit` never returns.
+                    fb.ins().trap(TrapCode::UnreachableCodeReached);
+                }
+            }
+            Op::Return => {
+                let return_block = self.return_block.ok_or_else(|| {
+                    internal_error("`return` outside a function")
+                })?;
+                fb.ins().jump(return_block, &self.take_all(args)?);
+            }
+            Op::Recurse => {
+                let function_start = self.function_start.ok_or_else(|| {
+                    internal_error("`recurse` outside a function")
+                })?;
+                fb.ins().jump(function_start, &self.take_all(args)?);
             }
-            Op::Repeat(body) => self.compile_repeat(to, args, body, fb),
             Op::Dup => {
-                let v = self.take(args[0]);
+                let v = self.take(args[0])?;
                 self.ssa_values.insert(to + 0, v);
                 self.ssa_values.insert(to + 1, v);
             }
             Op::Drop => {
-                self.take(args[0]);
+                self.take(args[0])?;
             }
             Op::I32(number) => {
                 self.set(to + 0, fb.ins().iconst(I32, i64::from(*number)));
@@ -229,92 +1545,206 @@ impl Compiler<'_> {
             Op::Bool(b) => {
                 self.set(to + 0, fb.ins().iconst(I8, i64::from(*b)));
             }
-            Op::Type | Op::TypeOf | Op::Ptr => todo!(),
+            Op::Type(typ) | Op::TypeOf(typ) => {
+                if matches!(assignment.op, Op::TypeOf(_)) {
+                    self.take(args[0])?;
+                }
+                let tag = self.type_tag(typ)?;
+                self.set(to + 0, fb.ins().iconst(I32, i64::from(tag)));
+            }
+            Op::Ptr => {
+                let pointee = self.take(args[0])?;
+                let typ = fb.ins().iadd_imm(pointee, 1 << POINTER_DEPTH_SHIFT);
+                self.set(to + 0, typ);
+            }
+            Op::TypeEq => {
+                let a = self.take(args[0])?;
+                let b = self.take(args[1])?;
+                self.set(to + 0, fb.ins().icmp(IntCC::Equal, a, b));
+            }
+            Op::PrintType => {
+                let typ = self.take(args[0])?;
+                let names = self.data_address(self.type_names, fb);
+                self.call_extern("spkl_print_type", &[names, typ], fb)?;
+            }
             Op::PrintChar => {
-                let n = self.take(args[0]);
-                self.call_extern("spkl_print_char", &[n], fb);
+                let n = self.take(args[0])?;
+                self.call_extern("spkl_print_char", &[n], fb)?;
+            }
+            Op::ReadChar | Op::ReadI32 => {
+                let (value, success) = self.call_reading_extern(
+                    if matches!(assignment.op, Op::ReadChar) {
+                        "spkl_read_char"
+                    } else {
+                        "spkl_read_i32"
+                    },
+                    &[],
+                    fb,
+                )?;
+                self.set(to + 0, value);
+                self.set(to + 1, success);
+            }
+            Op::Open => {
+                let path = self.take(args[0])?;
+                let write = self.take(args[1])?;
+                let call =
+                    self.call_extern("spkl_fopen", &[path, write], fb)?;
+                let file = fb.inst_results(call)[0];
+                self.set(to + 0, file);
+                self.set(
+                    to + 1,
+                    fb.ins().icmp_imm(IntCC::SignedGreaterThanOrEqual, file, 0),
+                );
+            }
+            Op::Read => {
+                let file = self.take(args[0])?;
+                let (byte, success) =
+                    self.call_reading_extern("spkl_fread", &[file], fb)?;
+                self.set(to + 0, file);
+                self.set(to + 1, byte);
+                self.set(to + 2, success);
+            }
+            Op::Write => {
+                let file = self.take(args[0])?;
+                let byte = self.take(args[1])?;
+                self.call_extern("spkl_fwrite", &[file, byte], fb)?;
+                self.set(to + 0, file);
+            }
+            Op::Close => {
+                let file = self.take(args[0])?;
+                self.call_extern("spkl_fclose", &[file], fb)?;
+            }
+            Op::ArgCount => {
+                let count = self.load_argc(fb);
+                self.set(to + 0, count);
+            }
+            Op::Arg => {
+                let pointer_type = self.isa.pointer_type();
+                let n = self.take(args[0])?;
+                let count = self.load_argc(fb);
+                // Negative indices are out of bounds as well when compared as
+                // unsigned numbers.
+                let in_bounds =
+                    fb.ins().icmp(IntCC::UnsignedLessThan, n, count);
+                let zero = fb.ins().iconst(I32, 0);
+                let index = fb.ins().select(in_bounds, n, zero);
+                let index = if pointer_type == I32 {
+                    index
+                } else {
+                    fb.ins().uextend(pointer_type, index)
+                };
+                let offset =
+                    fb.ins().imul_imm(index, i64::from(pointer_type.bytes()));
+                let array_address = self.data_address(self.argv, fb);
+                let array = fb.ins().load(
+                    pointer_type,
+                    MemFlags::trusted(),
+                    array_address,
+                    0,
+                );
+                let element = fb.ins().iadd(array, offset);
+                let arg = fb.ins().load(
+                    pointer_type,
+                    MemFlags::trusted(),
+                    element,
+                    0,
+                );
+                let null = fb.ins().iconst(pointer_type, 0);
+                self.set(to + 0, fb.ins().select(in_bounds, arg, null));
+            }
+            Op::ReadLine => {
+                let call = self.call_extern("spkl_read_line", &[], fb)?;
+                self.set(to + 0, fb.inst_results(call)[0]);
             }
             Op::PrintI32 => {
-                let n = self.take(args[0]);
-                self.call_extern("spkl_print_i32", &[n], fb);
+                let n = self.take(args[0])?;
+                self.call_extern("spkl_print_i32", &[n], fb)?;
             }
             Op::PrintF32 => {
-                let n = self.take(args[0]);
-                self.call_extern("spkl_print_f32", &[n], fb);
+                let n = self.take(args[0])?;
+                self.call_extern("spkl_print_f32", &[n], fb)?;
+            }
+            Op::PrintBool => {
+                let b = self.take(args[0])?;
+                self.call_extern("spkl_print_bool", &[b], fb)?;
             }
             Op::PrintlnI32 => {
-                let n = self.take(args[0]);
-                self.call_extern("spkl_println_i32", &[n], fb);
+                let n = self.take(args[0])?;
+                self.call_extern("spkl_println_i32", &[n], fb)?;
             }
             Op::PrintlnF32 => {
-                let n = self.take(args[0]);
-                self.call_extern("spkl_println_f32", &[n], fb);
+                let n = self.take(args[0])?;
+                self.call_extern("spkl_println_f32", &[n], fb)?;
             }
-            Op::BinMath { operation, typ } => {
-                let a = self.take(args[0]);
-                let b = self.take(args[1]);
-                self.set(
-                    to + 0,
-                    match (operation, typ) {
-                        (BinMathOp::Add, Some(Type::I32)) => {
-                            fb.ins().iadd(a, b)
-                        }
-                        (BinMathOp::Sub, Some(Type::I32)) => {
-                            fb.ins().isub(a, b)
-                        }
-                        (BinMathOp::Mul, Some(Type::I32)) => {
-                            fb.ins().imul(a, b)
-                        }
-                        (BinMathOp::Div, Some(Type::I32)) => {
-                            fb.ins().sdiv(a, b)
-                        }
-                        (BinMathOp::Rem, _) => fb.ins().srem(a, b),
-                        (BinMathOp::SillyAdd, _) => todo!(),
-                        (BinMathOp::Add, Some(Type::F32)) => {
-                            fb.ins().fadd(a, b)
-                        }
-                        (BinMathOp::Sub, Some(Type::F32)) => {
-                            fb.ins().fsub(a, b)
-                        }
-                        (BinMathOp::Mul, Some(Type::F32)) => {
-                            fb.ins().fmul(a, b)
-                        }
-                        (BinMathOp::Div, Some(Type::F32)) => {
-                            fb.ins().fdiv(a, b)
-                        }
-                        _ => unreachable!(),
-                    },
-                );
+            Op::PrintlnBool => {
+                let b = self.take(args[0])?;
+                self.call_extern("spkl_println_bool", &[b], fb)?;
+            }
+            Op::Assert(span) => {
+                let condition = self.take(args[0])?;
+                self.compile_check(condition, "assertion failed", *span, fb)?;
+            }
+            Op::BinMath {
+                operation,
+                typ,
+                span,
+            } => {
+                let a = self.take(args[0])?;
+                let b = self.take(args[1])?;
+                let checked = !self.intervals.proves_infallible(assignment);
+                let result = self.compile_bin_math(
+                    *operation, typ, a, b, checked, *span, fb,
+                )?;
+                self.set(to + 0, result);
             }
             Op::Sqrt => {
-                let n = self.take(args[0]);
+                let n = self.take(args[0])?;
                 self.set(to + 0, fb.ins().sqrt(n));
             }
-            Op::Compare(comparison) => {
-                let a = self.take(args[0]);
-                let b = self.take(args[1]);
-                self.set(
-                    to + 0,
-                    fb.ins().icmp(
-                        match comparison {
-                            Comparison::Lt => IntCC::SignedLessThan,
-                            Comparison::Le => IntCC::SignedLessThanOrEqual,
-                            Comparison::Eq => IntCC::Equal,
-                            Comparison::Ge => IntCC::SignedGreaterThanOrEqual,
-                            Comparison::Gt => IntCC::SignedGreaterThan,
-                        },
-                        a,
-                        b,
-                    ),
-                );
+            Op::Math { function, typ } => {
+                let operands = self.take_all(args)?;
+                let result =
+                    self.compile_math(*function, typ, &operands, fb)?;
+                self.set(to + 0, result);
+            }
+            Op::Round(rounding) => {
+                let n = self.take(args[0])?;
+                self.set(to + 0, round(*rounding, n, fb));
+            }
+            Op::I32ToF32 => {
+                let n = self.take(args[0])?;
+                self.set(to + 0, fb.ins().fcvt_from_sint(F32, n));
+            }
+            Op::F32ToI32(rounding) => {
+                let n = self.take(args[0])?;
+                // Converting already rounds towards zero.
+                let rounded = match rounding {
+                    Rounding::Trunc => n,
+                    _ => round(*rounding, n, fb),
+                };
+                self.set(to + 0, fb.ins().fcvt_to_sint_sat(I32, rounded));
+            }
+            Op::Compare { comparison, typ } => {
+                let a = self.take(args[0])?;
+                let b = self.take(args[1])?;
+                self.set(to + 0, compare(*comparison, typ, a, b, fb));
             }
             Op::Not => {
-                let b = self.take(args[0]);
+                let b = self.take(args[0])?;
                 self.set(to + 0, fb.ins().bxor_imm(b, 1));
             }
+            Op::BitNot => {
+                let n = self.take(args[0])?;
+                self.set(to + 0, fb.ins().bnot(n));
+            }
+            Op::Bitwise(op) => {
+                let a = self.take(args[0])?;
+                let b = self.take(args[1])?;
+                self.set(to + 0, bitwise(*op, a, b, fb));
+            }
             Op::BinLogic(op) => {
-                let a = self.take(args[0]);
-                let b = self.take(args[1]);
+                let a = self.take(args[0])?;
+                let b = self.take(args[1])?;
                 self.set(
                     to + 0,
                     match op {
@@ -337,12 +1767,12 @@ impl Compiler<'_> {
                 );
             }
             Op::AddrOf(typ) => {
-                let typ = typ.to_clif(self.isa).unwrap();
+                let typ = typ.to_clif(self.isa)?;
                 let stack_slot = fb.create_sized_stack_slot(StackSlotData {
                     kind: StackSlotKind::ExplicitSlot,
                     size: typ.bytes(),
                 });
-                let v = self.take(args[0]);
+                let v = self.take(args[0])?;
                 self.set(to + 0, v);
                 fb.ins().stack_store(v, stack_slot, 0);
                 self.set(
@@ -350,15 +1780,109 @@ impl Compiler<'_> {
                     fb.ins().stack_addr(self.isa.pointer_type(), stack_slot, 0),
                 );
             }
+            Op::Null => {
+                let null = fb.ins().iconst(self.isa.pointer_type(), 0);
+                self.set(to + 0, null);
+            }
+            Op::PtrAdd | Op::PtrSub => {
+                let ptr = self.take(args[0])?;
+                let offset = self.take(args[1])?;
+                let offset = self.pointer_width(offset, fb);
+                let moved = if matches!(assignment.op, Op::PtrAdd) {
+                    fb.ins().iadd(ptr, offset)
+                } else {
+                    fb.ins().isub(ptr, offset)
+                };
+                self.set(to + 0, moved);
+            }
+            Op::PtrDiff => {
+                let a = self.take(args[0])?;
+                let b = self.take(args[1])?;
+                let mut difference = fb.ins().isub(a, b);
+                if self.isa.pointer_type() != I32 {
+                    difference = fb.ins().ireduce(I32, difference);
+                }
+                self.set(to + 0, difference);
+            }
             Op::ReadPtr(typ) => {
-                let ptr = self.take(args[0]);
-                let typ = typ.to_clif(self.isa).unwrap();
+                let ptr = self.take(args[0])?;
+                let typ = typ.to_clif(self.isa)?;
                 self.set(
                     to + 0,
                     fb.ins().load(typ, MemFlags::trusted(), ptr, 0),
                 );
             }
+            Op::ReadSmall(int) => {
+                let ptr = self.take(args[0])?;
+                let flags = MemFlags::trusted();
+                let value = match (int.bits(), int.is_signed()) {
+                    (8, false) => fb.ins().uload8(I32, flags, ptr, 0),
+                    (8, true) => fb.ins().sload8(I32, flags, ptr, 0),
+                    (_, false) => fb.ins().uload16(I32, flags, ptr, 0),
+                    (_, true) => fb.ins().sload16(I32, flags, ptr, 0),
+                };
+                self.set(to + 0, value);
+            }
+            Op::WritePtr => {
+                let value = self.take(args[0])?;
+                let ptr = self.take(args[1])?;
+                fb.ins().store(MemFlags::trusted(), value, ptr, 0);
+            }
+            Op::WriteSmall(int) => {
+                let value = self.take(args[0])?;
+                let ptr = self.take(args[1])?;
+                let flags = MemFlags::trusted();
+                if int.bits() == 8 {
+                    fb.ins().istore8(flags, value, ptr, 0);
+                } else {
+                    fb.ins().istore16(flags, value, ptr, 0);
+                }
+            }
+            Op::MemCopy => {
+                let from = self.take(args[0])?;
+                let to = self.take(args[1])?;
+                let size = self.take(args[2])?;
+                let size = self.pointer_width(size, fb);
+                fb.call_memcpy(self.isa.frontend_config(), to, from, size);
+            }
+            Op::MemFill => {
+                let ptr = self.take(args[0])?;
+                let size = self.take(args[1])?;
+                let size = self.pointer_width(size, fb);
+                let byte = self.take(args[2])?;
+                let byte = fb.ins().ireduce(I8, byte);
+                fb.call_memset(self.isa.frontend_config(), ptr, byte, size);
+            }
+            Op::MemCompare => {
+                let a = self.take(args[0])?;
+                let b = self.take(args[1])?;
+                let size = self.take(args[2])?;
+                let size = self.pointer_width(size, fb);
+                let ordering =
+                    fb.call_memcmp(self.isa.frontend_config(), a, b, size);
+                self.set(to + 0, ordering);
+            }
+            Op::Load(name, typ) => {
+                let address = self.data_address(
+                    lookup(&self.variables, name, "variable")?,
+                    fb,
+                );
+                let typ = typ.to_clif(self.isa)?;
+                self.set(
+                    to + 0,
+                    fb.ins().load(typ, MemFlags::trusted(), address, 0),
+                );
+            }
+            Op::Store(name) => {
+                let value = self.take(args[0])?;
+                let address = self.data_address(
+                    lookup(&self.variables, name, "variable")?,
+                    fb,
+                );
+                fb.ins().store(MemFlags::trusted(), value, address, 0);
+            }
         }
+        Ok(())
     }
 
     fn compile_then(
@@ -367,51 +1891,40 @@ impl Compiler<'_> {
         args: &[ssa::Value],
         body: &ssa::Graph,
         fb: &mut FunctionBuilder,
-    ) {
-        let (&condition, args) = args.split_last().unwrap();
+    ) -> Result<()> {
+        let (&condition, args) = args
+            .split_last()
+            .ok_or_else(|| internal_error("`then` without a condition"))?;
 
-        for (arg, input) in std::iter::zip(args, body.inputs) {
-            let clif_value = self.ssa_values[arg];
+        for (&arg, input) in std::iter::zip(args, body.inputs) {
+            let clif_value = self.get(arg)?;
             self.set(input, clif_value);
         }
 
         let then = fb.create_block();
         let after = fb.create_block();
 
-        let condition = self.take(condition);
-        fb.ins().brif(
-            condition,
-            then,
-            &[],
-            after,
-            &args.iter().map(|&arg| self.take(arg)).collect::<Vec<_>>(),
-        );
+        for (value, &arg) in std::iter::zip(to, args) {
+            let typ = fb.func.dfg.value_type(self.get(arg)?);
+            self.set(value, fb.append_block_param(after, typ));
+        }
+
+        let condition = self.take(condition)?;
+        fb.ins()
+            .brif(condition, then, &[], after, &self.take_all(args)?);
         fb.seal_block(then);
 
         fb.switch_to_block(then);
         for assignment in &body.assignments {
-            self.compile_assignment(assignment, fb);
+            self.compile_assignment(assignment, fb)?;
         }
-        for (value, out) in std::iter::zip(to, &body.outputs) {
-            self.set(
-                value,
-                fb.append_block_param(
-                    after,
-                    fb.func.dfg.value_type(self.ssa_values[out]),
-                ),
-            );
+        if !body.diverges() {
+            fb.ins().jump(after, &self.take_all(&body.outputs)?);
         }
-        fb.ins().jump(
-            after,
-            &body
-                .outputs
-                .iter()
-                .map(|&out| self.take(out))
-                .collect::<Vec<_>>(),
-        );
         fb.seal_block(after);
 
         fb.switch_to_block(after);
+        Ok(())
     }
 
     fn compile_then_else(
@@ -421,14 +1934,17 @@ impl Compiler<'_> {
         then: &ssa::Graph,
         else_: &ssa::Graph,
         fb: &mut FunctionBuilder,
-    ) {
-        let (&condition, args) = args.split_last().unwrap();
+    ) -> Result<()> {
+        let (&condition, args) = args
+            .split_last()
+            .ok_or_else(|| internal_error("`then else` without a condition"))?;
 
-        for (arg, input) in std::iter::zip(args, then.inputs) {
-            self.set(input, self.ssa_values[arg]);
+        for (&arg, input) in std::iter::zip(args, then.inputs) {
+            let clif_value = self.get(arg)?;
+            self.set(input, clif_value);
         }
         for (&arg, input) in std::iter::zip(args, else_.inputs) {
-            let clif_value = self.take(arg);
+            let clif_value = self.take(arg)?;
             self.set(input, clif_value);
         }
 
@@ -436,46 +1952,177 @@ impl Compiler<'_> {
         let else_block = fb.create_block();
         let after_block = fb.create_block();
 
-        let condition = self.take(condition);
+        let condition = self.take(condition)?;
         fb.ins().brif(condition, then_block, &[], else_block, &[]);
         fb.seal_block(then_block);
         fb.seal_block(else_block);
 
-        fb.switch_to_block(then_block);
-        for assignment in &then.assignments {
-            self.compile_assignment(assignment, fb);
+        let mut has_block_params = false;
+        for (block, graph) in [(then_block, then), (else_block, else_)] {
+            fb.switch_to_block(block);
+            for assignment in &graph.assignments {
+                self.compile_assignment(assignment, fb)?;
+            }
+            if graph.diverges() {
+                continue;
+            }
+            if !has_block_params {
+                has_block_params = true;
+                for (value, &out) in std::iter::zip(to, &graph.outputs) {
+                    let typ = fb.func.dfg.value_type(self.get(out)?);
+                    self.set(value, fb.append_block_param(after_block, typ));
+                }
+            }
+            fb.ins().jump(after_block, &self.take_all(&graph.outputs)?);
         }
-        for (value, out) in std::iter::zip(to, &then.outputs) {
-            let v = self.ssa_values[out];
-            self.set(
-                value,
-                fb.append_block_param(after_block, fb.func.dfg.value_type(v)),
-            );
+        fb.seal_block(after_block);
+
+        fb.switch_to_block(after_block);
+        Ok(())
+    }
+
+    fn compile_match(
+        &mut self,
+        to: ssa::ValueSequence,
+        args: &[ssa::Value],
+        cases: &[ssa::Graph],
+        fb: &mut FunctionBuilder,
+    ) -> Result<()> {
+        let (&tag, args) = args
+            .split_last()
+            .ok_or_else(|| internal_error("`match` without a tag"))?;
+        self.pass_inputs(args, cases)?;
+
+        let case_blocks =
+            cases.iter().map(|_| fb.create_block()).collect::<Vec<_>>();
+        // Tags always belong to a variant, so the default is never taken.
+        let invalid_block = fb.create_block();
+
+        let tag = self.take(tag)?;
+        let default = fb.func.dfg.block_call(invalid_block, &[]);
+        let table = case_blocks
+            .iter()
+            .map(|&block| fb.func.dfg.block_call(block, &[]))
+            .collect::<Vec<_>>();
+        let jump_table =
+            fb.create_jump_table(JumpTableData::new(default, &table));
+        fb.ins().br_table(tag, jump_table);
+        for &block in case_blocks.iter().chain([&invalid_block]) {
+            fb.seal_block(block);
         }
-        fb.ins().jump(
-            after_block,
-            &then
-                .outputs
-                .iter()
-                .map(|&out| self.take(out))
-                .collect::<Vec<_>>(),
-        );
 
-        fb.switch_to_block(else_block);
-        for assignment in &else_.assignments {
-            self.compile_assignment(assignment, fb);
+        fb.switch_to_block(invalid_block);
+        fb.ins().trap(TrapCode::UnreachableCodeReached);
+
+        self.compile_branches(to, &case_blocks, cases, fb)
+    }
+
+    /// Compiles a `case` statement to a jump table if its values are close
+    /// together and to a chain of comparisons otherwise.
+    fn compile_switch(
+        &mut self,
+        to: ssa::ValueSequence,
+        args: &[ssa::Value],
+        values: &[i32],
+        cases: &[ssa::Graph],
+        fb: &mut FunctionBuilder,
+    ) -> Result<()> {
+        let (&value, args) = args
+            .split_last()
+            .ok_or_else(|| internal_error("`case` without a value"))?;
+        self.pass_inputs(args, cases)?;
+
+        let case_blocks =
+            cases.iter().map(|_| fb.create_block()).collect::<Vec<_>>();
+        let (&else_block, arm_blocks) = case_blocks
+            .split_last()
+            .ok_or_else(|| internal_error("`case` without `else`"))?;
+
+        let value = self.take(value)?;
+        if let Some((min, len)) = jump_table(values) {
+            let index = fb.ins().iadd_imm(value, -i64::from(min));
+            let default = fb.func.dfg.block_call(else_block, &[]);
+            let mut table = vec![default; len];
+            for (&value, &block) in std::iter::zip(values, arm_blocks) {
+                let offset = i64::from(value) - i64::from(min);
+                table[usize::try_from(offset)?] =
+                    fb.func.dfg.block_call(block, &[]);
+            }
+            let jump_table =
+                fb.create_jump_table(JumpTableData::new(default, &table));
+            fb.ins().br_table(index, jump_table);
+        } else {
+            for (&arm_value, &block) in std::iter::zip(values, arm_blocks) {
+                let next_block = fb.create_block();
+                let is_equal = fb.ins().icmp_imm(
+                    IntCC::Equal,
+                    value,
+                    i64::from(arm_value),
+                );
+                fb.ins().brif(is_equal, block, &[], next_block, &[]);
+                fb.seal_block(next_block);
+                fb.switch_to_block(next_block);
+            }
+            fb.ins().jump(else_block, &[]);
+        }
+        for &block in &case_blocks {
+            fb.seal_block(block);
+        }
+
+        self.compile_branches(to, &case_blocks, cases, fb)
+    }
+
+    /// Gives each graph of a `match` or `case` statement the arguments that
+    /// aren't the tag or value.
+    fn pass_inputs(
+        &mut self,
+        args: &[ssa::Value],
+        cases: &[ssa::Graph],
+    ) -> Result<()> {
+        for case in cases {
+            for (&arg, input) in std::iter::zip(args, case.inputs) {
+                let clif_value = self.get(arg)?;
+                self.set(input, clif_value);
+            }
+        }
+        for &arg in args {
+            self.take(arg)?;
+        }
+        Ok(())
+    }
+
+    /// Compiles the graphs of a `match` or `case` statement into the blocks
+    /// that have been branched to for them, continuing after the statement.
+    fn compile_branches(
+        &mut self,
+        to: ssa::ValueSequence,
+        case_blocks: &[Block],
+        cases: &[ssa::Graph],
+        fb: &mut FunctionBuilder,
+    ) -> Result<()> {
+        let after_block = fb.create_block();
+        let mut has_block_params = false;
+        for (&block, graph) in std::iter::zip(case_blocks, cases) {
+            fb.switch_to_block(block);
+            for assignment in &graph.assignments {
+                self.compile_assignment(assignment, fb)?;
+            }
+            if graph.diverges() {
+                continue;
+            }
+            if !has_block_params {
+                has_block_params = true;
+                for (value, &out) in std::iter::zip(to, &graph.outputs) {
+                    let typ = fb.func.dfg.value_type(self.get(out)?);
+                    self.set(value, fb.append_block_param(after_block, typ));
+                }
+            }
+            fb.ins().jump(after_block, &self.take_all(&graph.outputs)?);
         }
-        fb.ins().jump(
-            after_block,
-            &else_
-                .outputs
-                .iter()
-                .map(|&out| self.take(out))
-                .collect::<Vec<_>>(),
-        );
         fb.seal_block(after_block);
 
         fb.switch_to_block(after_block);
+        Ok(())
     }
 
     fn compile_repeat(
@@ -484,128 +2131,485 @@ impl Compiler<'_> {
         args: &[ssa::Value],
         body: &ssa::Graph,
         fb: &mut FunctionBuilder,
-    ) {
+    ) -> Result<()> {
         let loop_block = fb.create_block();
         let after_block = fb.create_block();
 
-        for (arg, input) in std::iter::zip(args, body.inputs) {
-            let v = self.ssa_values[arg];
-            self.set(
-                input,
-                fb.append_block_param(loop_block, fb.func.dfg.value_type(v)),
-            );
+        for ((&arg, input), value) in std::iter::zip(args, body.inputs).zip(to)
+        {
+            let typ = fb.func.dfg.value_type(self.get(arg)?);
+            self.set(input, fb.append_block_param(loop_block, typ));
+            self.set(value, fb.append_block_param(after_block, typ));
         }
 
-        fb.ins().jump(
-            loop_block,
-            &args.iter().map(|&arg| self.take(arg)).collect::<Vec<_>>(),
-        );
+        fb.ins().jump(loop_block, &self.take_all(args)?);
         fb.switch_to_block(loop_block);
+        self.loops.push((loop_block, after_block));
         for assignment in &body.assignments {
-            self.compile_assignment(assignment, fb);
+            self.compile_assignment(assignment, fb)?;
         }
-        let (&condition, outputs) = body.outputs.split_last().unwrap();
-        for (value, out) in std::iter::zip(to, outputs) {
-            self.set(value, self.ssa_values[out]);
+        self.loops.pop();
+        if !body.diverges() {
+            let (&condition, outputs) =
+                body.outputs.split_last().ok_or_else(|| {
+                    internal_error("`repeat` body without a condition")
+                })?;
+            let outputs = self.take_all(outputs)?;
+            fb.ins().brif(
+                self.take(condition)?,
+                loop_block,
+                &outputs,
+                after_block,
+                &outputs,
+            );
         }
-        fb.ins().brif(
-            self.take(condition),
-            loop_block,
-            &outputs
-                .iter()
-                .map(|&out| self.take(out))
-                .collect::<Vec<_>>(),
-            after_block,
-            &[],
-        );
         fb.seal_block(loop_block);
         fb.seal_block(after_block);
 
         fb.switch_to_block(after_block);
+        Ok(())
+    }
+
+    fn compile_while(
+        &mut self,
+        to: ssa::ValueSequence,
+        args: &[ssa::Value],
+        condition: &ssa::Graph,
+        body: &ssa::Graph,
+        fb: &mut FunctionBuilder,
+    ) -> Result<()> {
+        let header_block = fb.create_block();
+        let body_block = fb.create_block();
+        let after_block = fb.create_block();
+
+        for (i, &arg) in args.iter().enumerate() {
+            let typ = fb.func.dfg.value_type(self.get(arg)?);
+            let i = u8::try_from(i)?;
+            self.set(
+                condition.inputs + i,
+                fb.append_block_param(header_block, typ),
+            );
+            self.set(body.inputs + i, fb.append_block_param(body_block, typ));
+            self.set(to + i, fb.append_block_param(after_block, typ));
+        }
+
+        fb.ins().jump(header_block, &self.take_all(args)?);
+
+        fb.switch_to_block(header_block);
+        for assignment in &condition.assignments {
+            self.compile_assignment(assignment, fb)?;
+        }
+        let (&keep_going, outputs) =
+            condition.outputs.split_last().ok_or_else(|| {
+                internal_error("`while` condition without a result")
+            })?;
+        let keep_going = self.take(keep_going)?;
+        let outputs = self.take_all(outputs)?;
+        fb.ins()
+            .brif(keep_going, body_block, &outputs, after_block, &outputs);
+        fb.seal_block(body_block);
+
+        fb.switch_to_block(body_block);
+        self.loops.push((header_block, after_block));
+        for assignment in &body.assignments {
+            self.compile_assignment(assignment, fb)?;
+        }
+        self.loops.pop();
+        if !body.diverges() {
+            let outputs = self.take_all(&body.outputs)?;
+            fb.ins().jump(header_block, &outputs);
+        }
+        fb.seal_block(header_block);
+        fb.seal_block(after_block);
+
+        fb.switch_to_block(after_block);
+        Ok(())
+    }
+}
+
+/// Points at the words that the instructions which Cranelift's verifier
+/// rejected came from, where that is known.
+fn verifier_error(
+    name: &str,
+    func: &Function,
+    errors: &VerifierErrors,
+    words: &[Span],
+) -> anyhow::Error {
+    let mut labels = Vec::new();
+    let mut notes = Vec::new();
+    for error in &errors.0 {
+        let span = match error.location {
+            AnyEntity::Inst(inst) if !func.srcloc(inst).is_default() => {
+                words.get(func.srcloc(inst).bits() as usize)
+            }
+            _ => None,
+        };
+        match span {
+            Some(&span) => labels.push(primary_label(span, &*error.message)),
+            None => notes.push(error.to_string()),
+        }
+    }
+    notes.into_iter().fold(
+        diagnostics::error(
+            format!(
+                "internal compiler error: Cranelift's verifier rejected `{name}`"
+            ),
+            labels,
+        ),
+        diagnostics::Error::note,
+    )
+    .into()
+}
+
+/// A Rust type that functions in the runtime take or return.
+trait HostType {
+    /// The Cranelift type that values of this type are passed as.
+    fn clif_type(isa: &dyn TargetIsa) -> cranelift::prelude::Type;
+}
+
+impl HostType for i32 {
+    fn clif_type(_: &dyn TargetIsa) -> cranelift::prelude::Type {
+        I32
+    }
+}
+
+impl HostType for u32 {
+    fn clif_type(_: &dyn TargetIsa) -> cranelift::prelude::Type {
+        I32
+    }
+}
+
+impl HostType for f32 {
+    fn clif_type(_: &dyn TargetIsa) -> cranelift::prelude::Type {
+        F32
+    }
+}
+
+impl HostType for bool {
+    fn clif_type(_: &dyn TargetIsa) -> cranelift::prelude::Type {
+        I8
+    }
+}
+
+impl<T> HostType for *const T {
+    fn clif_type(isa: &dyn TargetIsa) -> cranelift::prelude::Type {
+        isa.pointer_type()
+    }
+}
+
+impl<T> HostType for *mut T {
+    fn clif_type(isa: &dyn TargetIsa) -> cranelift::prelude::Type {
+        isa.pointer_type()
+    }
+}
+
+/// Derives the name and signature of a function in the runtime from its Rust
+/// parameter and return types, exactly as they are written in `runtime.rs`.
+/// Functions that never return are declared without a return type.
+macro_rules! host_fn {
+    ($isa:expr, $name:ident($($param:ty),* $(,)?) $(-> $ret:ty)?) => {
+        (
+            stringify!($name),
+            Signature {
+                params: vec![
+                    $(AbiParam::new(<$param as HostType>::clif_type($isa))),*
+                ],
+                returns: vec![
+                    $(AbiParam::new(<$ret as HostType>::clif_type($isa)))?
+                ],
+                call_conv: $isa.default_call_conv(),
+            },
+        )
+    };
+}
+
+/// Does `i32` arithmetic on `i64`s, where the result always fits, and clamps
+/// it to the range of an `i32`.
+fn saturating_arithmetic(
+    operation: BinMathOp,
+    a: Value,
+    b: Value,
+    fb: &mut FunctionBuilder,
+) -> Result<Value> {
+    let a = fb.ins().sextend(I64, a);
+    let b = fb.ins().sextend(I64, b);
+    let result = match operation {
+        BinMathOp::SaturatingAdd => fb.ins().iadd(a, b),
+        BinMathOp::SaturatingSub => fb.ins().isub(a, b),
+        BinMathOp::SaturatingMul => fb.ins().imul(a, b),
+        _ => {
+            return Err(internal_error(format!(
+                "`{operation:?}` isn't saturating arithmetic"
+            )))
+        }
+    };
+    let max = fb.ins().iconst(I64, i64::from(i32::MAX));
+    let min = fb.ins().iconst(I64, i64::from(i32::MIN));
+    let result = fb.ins().smin(result, max);
+    let result = fb.ins().smax(result, min);
+    Ok(fb.ins().ireduce(I32, result))
+}
+
+/// A constant of a number type.
+fn number(typ: &Type, n: i8, fb: &mut FunctionBuilder) -> Value {
+    if *typ == Type::F32 {
+        fb.ins().f32const(f32::from(n))
+    } else {
+        fb.ins().iconst(I32, i64::from(n))
+    }
+}
+
+/// Compares `f32`s with ordered comparisons, which are false if either
+/// operand is NaN.
+fn compare(
+    comparison: Comparison,
+    typ: &Type,
+    a: Value,
+    b: Value,
+    fb: &mut FunctionBuilder,
+) -> Value {
+    if *typ == Type::F32 {
+        let condition = match comparison {
+            Comparison::Lt => FloatCC::LessThan,
+            Comparison::Le => FloatCC::LessThanOrEqual,
+            Comparison::Eq => FloatCC::Equal,
+            Comparison::Ge => FloatCC::GreaterThanOrEqual,
+            Comparison::Gt => FloatCC::GreaterThan,
+        };
+        return fb.ins().fcmp(condition, a, b);
+    }
+    let condition = match comparison {
+        Comparison::Lt => IntCC::SignedLessThan,
+        Comparison::Le => IntCC::SignedLessThanOrEqual,
+        Comparison::Eq => IntCC::Equal,
+        Comparison::Ge => IntCC::SignedGreaterThanOrEqual,
+        Comparison::Gt => IntCC::SignedGreaterThan,
+    };
+    fb.ins().icmp(condition, a, b)
+}
+
+fn round(rounding: Rounding, n: Value, fb: &mut FunctionBuilder) -> Value {
+    match rounding {
+        Rounding::Nearest => fb.ins().nearest(n),
+        Rounding::Floor => fb.ins().floor(n),
+        Rounding::Ceil => fb.ins().ceil(n),
+        Rounding::Trunc => fb.ins().trunc(n),
     }
 }
 
+/// Cranelift's shifts and rotations already only use as many bits of the
+/// amount as `BitwiseOp::apply` does.
+fn bitwise(
+    operation: BitwiseOp,
+    a: Value,
+    b: Value,
+    fb: &mut FunctionBuilder,
+) -> Value {
+    match operation {
+        BitwiseOp::And => fb.ins().band(a, b),
+        BitwiseOp::Or => fb.ins().bor(a, b),
+        BitwiseOp::Xor => fb.ins().bxor(a, b),
+        BitwiseOp::Shl => fb.ins().ishl(a, b),
+        BitwiseOp::Shr => fb.ins().ushr(a, b),
+        BitwiseOp::Sar => fb.ins().sshr(a, b),
+        BitwiseOp::Rotl => fb.ins().rotl(a, b),
+        BitwiseOp::Rotr => fb.ins().rotr(a, b),
+    }
+}
+
+/// The smallest value of a `case` statement and how many entries a jump table
+/// for it would have, if the values are dense enough for one. A few
+/// comparisons are cheaper than an indirect jump.
+fn jump_table(values: &[i32]) -> Option<(i32, usize)> {
+    let min = *values.iter().min()?;
+    let max = *values.iter().max()?;
+    let len = usize::try_from(i64::from(max) - i64::from(min) + 1).ok()?;
+    (values.len() >= 4 && len <= values.len() * 2).then_some((min, len))
+}
+
 fn extern_function_signatures(
     isa: &dyn TargetIsa,
 ) -> BTreeMap<&'static str, Signature> {
-    let call_conv = isa.default_call_conv();
+    use std::ffi::c_char;
 
     BTreeMap::from([
-        (
-            "spkl_print_char",
-            Signature {
-                params: vec![AbiParam::new(I32)],
-                returns: Vec::new(),
-                call_conv,
-            },
-        ),
-        (
-            "spkl_print_i32",
-            Signature {
-                params: vec![AbiParam::new(I32)],
-                returns: Vec::new(),
-                call_conv,
-            },
-        ),
-        (
-            "spkl_println_i32",
-            Signature {
-                params: vec![AbiParam::new(I32)],
-                returns: Vec::new(),
-                call_conv,
-            },
-        ),
-        (
-            "spkl_print_f32",
-            Signature {
-                params: vec![AbiParam::new(F32)],
-                returns: Vec::new(),
-                call_conv,
-            },
-        ),
-        (
-            "spkl_println_f32",
-            Signature {
-                params: vec![AbiParam::new(F32)],
-                returns: Vec::new(),
-                call_conv,
-            },
-        ),
+        host_fn!(isa, spkl_print_char(u32)),
+        host_fn!(isa, spkl_print_i32(i32)),
+        host_fn!(isa, spkl_println_i32(i32)),
+        host_fn!(isa, spkl_print_f32(f32)),
+        host_fn!(isa, spkl_println_f32(f32)),
+        host_fn!(isa, spkl_print_bool(bool)),
+        host_fn!(isa, spkl_println_bool(bool)),
+        host_fn!(isa, spkl_print_type(*const c_char, i32)),
+        host_fn!(isa, spkl_read_char(*mut u32) -> bool),
+        host_fn!(isa, spkl_read_i32(*mut i32) -> bool),
+        host_fn!(isa, spkl_read_line() -> bool),
+        host_fn!(isa, spkl_fopen(*const c_char, bool) -> i32),
+        host_fn!(isa, spkl_fread(i32, *mut i32) -> bool),
+        host_fn!(isa, spkl_fwrite(i32, i32)),
+        host_fn!(isa, spkl_fclose(i32)),
+        host_fn!(isa, spkl_exit(i32)),
+        // The location is a pointer to a `Location` struct.
+        host_fn!(isa, spkl_panic(*const c_char, *const u8)),
+        // From the C math library.
+        host_fn!(isa, powf(f32, f32) -> f32),
+        host_fn!(isa, sinf(f32) -> f32),
+        host_fn!(isa, cosf(f32) -> f32),
+        host_fn!(isa, logf(f32) -> f32),
+        host_fn!(isa, expf(f32) -> f32),
     ])
 }
 
 impl Type {
-    fn to_clif(&self, isa: &dyn TargetIsa) -> Option<cranelift::prelude::Type> {
-        Some(match self {
+    fn to_clif(&self, isa: &dyn TargetIsa) -> Result<cranelift::prelude::Type> {
+        Ok(match self {
             Self::Bool => I8,
-            Self::I32 => I32,
+            // Files are indices into a table in the runtime, and types are
+            // described by `Compiler::types`.
+            Self::I32 | Self::File | Self::Type => I32,
             Self::F32 => F32,
-            Self::Type => return None,
-            Self::Ptr(_) => isa.pointer_type(),
+            Self::Ptr(_) | Self::Quotation(_) => isa.pointer_type(),
+            Self::Struct(_) | Self::Enum(_) => {
+                return Err(internal_error(format!(
+                    "`{self}` should have been flattened"
+                )))
+            }
+            Self::Var(_) => {
+                return Err(internal_error(format!(
+                    "`{self}` should have been instantiated"
+                )))
+            }
         })
     }
 }
 
 impl FunctionSignature {
-    fn to_clif(&self, name: &str, isa: &dyn TargetIsa) -> Signature {
-        let params = self
+    fn to_clif(&self, is_main: bool, isa: &dyn TargetIsa) -> Result<Signature> {
+        let mut params = self
             .parameters
             .iter()
-            .map(|typ| AbiParam::new(typ.to_clif(isa).unwrap()))
-            .collect();
+            .map(|typ| Ok(AbiParam::new(typ.to_clif(isa)?)))
+            .collect::<Result<Vec<_>>>()?;
         let mut returns = self
             .returns
             .iter()
-            .map(|typ| AbiParam::new(typ.to_clif(isa).unwrap()))
-            .collect::<Vec<_>>();
-        if name == "main" {
-            returns.push(AbiParam::new(I32));
+            .map(|typ| Ok(AbiParam::new(typ.to_clif(isa)?)))
+            .collect::<Result<Vec<_>>>()?;
+        if is_main {
+            // `main` is called like in C, with `argc` and `argv`, and
+            // returns 0 unless it returns an exit code of its own.
+            params.extend([
+                AbiParam::new(I32),
+                AbiParam::new(isa.pointer_type()),
+            ]);
+            if returns.is_empty() {
+                returns.push(AbiParam::new(I32));
+            }
         }
 
-        Signature {
+        Ok(Signature {
             params,
             returns,
             call_conv: isa.default_call_conv(),
+        })
+    }
+}
+
+/// Looks up something that type checking has made sure exists.
+fn lookup<K: Borrow<str> + Ord, V: Clone>(
+    map: &BTreeMap<K, V>,
+    name: &str,
+    what: &str,
+) -> Result<V> {
+    map.get(name)
+        .cloned()
+        .ok_or_else(|| internal_error(format!("unknown {what} `{name}`")))
+}
+
+/// How many bytes of machine code the cache directory may hold before the
+/// entries that were used least recently are removed.
+const CACHE_SIZE_LIMIT: u64 = 256 << 20;
+
+/// Cranelift's incremental compilation cache, kept as a file per function in
+/// a directory. The keys are hashes of the CLIF and the target, so entries
+/// never go stale, but they are removed once the directory grows too large.
+struct DiskCache<'a> {
+    dir: &'a Path,
+}
+
+impl DiskCache<'_> {
+    fn path(&self, key: &[u8]) -> PathBuf {
+        self.dir.join(format!("{:02x}", key.iter().format("")))
+    }
+}
+
+impl CacheKvStore for DiskCache<'_> {
+    fn get(&self, key: &[u8]) -> Option<Cow<'_, [u8]>> {
+        let path = self.path(key);
+        let entry = std::fs::read(&path).ok()?;
+        // The modification time says when the entry was last used, which
+        // decides what to remove first.
+        if let Ok(file) = std::fs::File::options().append(true).open(&path) {
+            let _ = file.set_modified(SystemTime::now());
+        }
+        Some(Cow::Owned(entry))
+    }
+
+    fn insert(&mut self, key: &[u8], val: Vec<u8>) {
+        // Written elsewhere first so that other threads and processes never
+        // see half of an entry. Failing to cache a function only makes the
+        // next build slower, so errors are ignored.
+        let path = self.path(key);
+        let temporary = path.with_extension(format!(
+            "{}-{}",
+            std::process::id(),
+            rayon::current_thread_index().unwrap_or_default()
+        ));
+        if std::fs::write(&temporary, val).is_ok() {
+            let _ = std::fs::rename(&temporary, path);
+        }
+    }
+}
+
+/// Removes the entries of a cache directory that were used least recently
+/// until it holds at most `CACHE_SIZE_LIMIT` bytes. Like with writing entries,
+/// failing to do so is harmless, so errors are ignored.
+fn evict_cache_entries(dir: &Path) {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return;
+    };
+    let mut entries = entries
+        .filter_map(|entry| {
+            let entry = entry.ok()?;
+            let metadata = entry.metadata().ok()?;
+            Some((metadata.modified().ok()?, metadata.len(), entry.path()))
+        })
+        .collect::<Vec<_>>();
+    let mut size = entries.iter().map(|&(_, len, _)| len).sum::<u64>();
+    entries.sort();
+    for (_, len, path) in entries {
+        if size <= CACHE_SIZE_LIMIT {
+            break;
+        }
+        if std::fs::remove_file(path).is_ok() {
+            size -= len;
+        }
+    }
+}
+
+/// Splits the instructions of a function into runs that come from the same
+/// word, in layout order.
+fn clif_by_word(func: &Function) -> Vec<(SourceLoc, Vec<String>)> {
+    let mut runs = Vec::<(SourceLoc, Vec<String>)>::new();
+    for block in func.layout.blocks() {
+        for inst in func.layout.block_insts(block) {
+            let srcloc = func.srcloc(inst);
+            let line = func.dfg.display_inst(inst).to_string();
+            match runs.last_mut() {
+                Some((last, lines)) if *last == srcloc => lines.push(line),
+                _ => runs.push((srcloc, vec![line])),
+            }
         }
     }
+    runs
 }
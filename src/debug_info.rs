@@ -0,0 +1,425 @@
+use anyhow::Result;
+use codemap::Span;
+use cranelift::prelude::{
+    codegen::{ir::Endianness, CompiledCode},
+    isa::TargetIsa,
+};
+use cranelift_module::FuncId;
+use cranelift_object::{
+    object::{
+        write::{Relocation, SectionId as ObjectSectionId},
+        RelocationEncoding, RelocationKind, SectionKind,
+    },
+    ObjectProduct,
+};
+use gimli::{
+    constants,
+    write::{
+        Address, AttributeValue, DwarfUnit, EndianVec, FileId, LineProgram,
+        LineString, Range, RangeList, Sections, Writer,
+    },
+    Encoding, Format, LineEncoding, RunTimeEndian, SectionId,
+};
+use serde_json::json;
+use std::{collections::BTreeMap, ops, sync::Arc};
+
+/// Line numbers and functions for debuggers, collected while compiling.
+pub struct DebugInfo {
+    functions: Vec<Function>,
+}
+
+struct Function {
+    name: String,
+    func_id: FuncId,
+    size: u32,
+    /// Where the machine code of each word is, in order.
+    words: Vec<(ops::Range<u32>, Span)>,
+}
+
+impl DebugInfo {
+    pub const fn new() -> Self {
+        Self {
+            functions: Vec::new(),
+        }
+    }
+
+    /// Records a function that has just been compiled, where `words` are what
+    /// the source locations in its CLIF refer to.
+    pub fn add_function(
+        &mut self,
+        name: String,
+        func_id: FuncId,
+        compiled_code: &CompiledCode,
+        words: &[Span],
+    ) {
+        let words = compiled_code
+            .buffer
+            .get_srclocs_sorted()
+            .iter()
+            .filter(|srcloc| !srcloc.loc.is_default())
+            .map(|srcloc| {
+                (srcloc.start..srcloc.end, words[srcloc.loc.bits() as usize])
+            })
+            .collect();
+        self.functions.push(Function {
+            name,
+            func_id,
+            size: compiled_code.code_buffer().len().try_into().unwrap(),
+            words,
+        });
+    }
+
+    /// Adds DWARF sections describing the recorded functions to an object
+    /// file.
+    pub fn write(
+        &self,
+        product: &mut ObjectProduct,
+        files: &[Arc<codemap::File>],
+        isa: &dyn TargetIsa,
+    ) -> Result<()> {
+        let encoding = Encoding {
+            format: Format::Dwarf32,
+            version: 4,
+            address_size: isa.pointer_bytes(),
+        };
+        let mut dwarf = self.unit(files, encoding)?;
+
+        let endian = match isa.endianness() {
+            Endianness::Big => RunTimeEndian::Big,
+            Endianness::Little => RunTimeEndian::Little,
+        };
+        let mut sections = Sections::new(RelocatingWriter {
+            data: EndianVec::new(endian),
+            relocations: Vec::new(),
+        });
+        dwarf.write(&mut sections)?;
+
+        let mut section_ids = BTreeMap::new();
+        sections.for_each(|id, section| -> Result<()> {
+            if !section.data.slice().is_empty() {
+                section_ids.insert(id.name(), add_section(product, id));
+            }
+            Ok(())
+        })?;
+        sections.for_each(|id, section| -> Result<()> {
+            let Some(&section_id) = section_ids.get(id.name()) else {
+                return Ok(());
+            };
+            product.object.set_section_data(
+                section_id,
+                section.data.slice().to_vec(),
+                1,
+            );
+            for relocation in &section.relocations {
+                let symbol = match relocation.target {
+                    Target::Function(index) => {
+                        product.function_symbol(self.functions[index].func_id)
+                    }
+                    Target::Section(id) => {
+                        product.object.section_symbol(section_ids[id.name()])
+                    }
+                };
+                product.object.add_relocation(
+                    section_id,
+                    Relocation {
+                        offset: relocation.offset,
+                        size: relocation.size * 8,
+                        kind: RelocationKind::Absolute,
+                        encoding: RelocationEncoding::Generic,
+                        symbol,
+                        addend: relocation.addend,
+                    },
+                )?;
+            }
+            Ok(())
+        })?;
+        Ok(())
+    }
+
+    /// Describes which word the machine code of every recorded function came
+    /// from as JSON, so that addresses can be symbolized without a debugger.
+    /// Offsets are from the start of the object file's text section.
+    pub fn source_map(
+        &self,
+        product: &ObjectProduct,
+        files: &[Arc<codemap::File>],
+    ) -> String {
+        let functions = self
+            .functions
+            .iter()
+            .map(|function| {
+                let symbol = product
+                    .object
+                    .symbol(product.function_symbol(function.func_id));
+                let start = symbol.value;
+                let words = function
+                    .words
+                    .iter()
+                    .map(|(offsets, span)| {
+                        let (index, position) = locate(files, *span);
+                        json!({
+                            "start": start + u64::from(offsets.start),
+                            "end": start + u64::from(offsets.end),
+                            "file": index,
+                            "line": position.line + 1,
+                            "column": position.column + 1,
+                            "word": files[index].source_slice(*span),
+                        })
+                    })
+                    .collect::<Vec<_>>();
+                json!({
+                    "name": function.name,
+                    "symbol": String::from_utf8_lossy(&symbol.name),
+                    "start": start,
+                    "end": start + u64::from(function.size),
+                    "words": words,
+                })
+            })
+            .collect::<Vec<_>>();
+        let files = files.iter().map(|file| file.name()).collect::<Vec<_>>();
+        let source_map = json!({ "files": files, "functions": functions });
+        serde_json::to_string_pretty(&source_map).unwrap() + "\n"
+    }
+
+    /// Describes the recorded functions and which lines their machine code
+    /// comes from.
+    fn unit(
+        &self,
+        files: &[Arc<codemap::File>],
+        encoding: Encoding,
+    ) -> Result<DwarfUnit> {
+        let mut dwarf = DwarfUnit::new(encoding);
+
+        let comp_dir = std::env::current_dir()?.display().to_string();
+        let main_file = files.last().map_or("", |file| file.name());
+        dwarf.unit.line_program = LineProgram::new(
+            encoding,
+            LineEncoding::default(),
+            LineString::String(comp_dir.clone().into_bytes()),
+            LineString::String(main_file.into()),
+            None,
+        );
+        let directory = dwarf.unit.line_program.default_directory();
+        let file_ids = files
+            .iter()
+            .map(|file| {
+                dwarf.unit.line_program.add_file(
+                    LineString::String(file.name().into()),
+                    directory,
+                    None,
+                )
+            })
+            .collect::<Vec<_>>();
+        let locate = |span: Span| -> (FileId, u64, u64) {
+            let (index, position) = locate(files, span);
+            (
+                file_ids[index],
+                position.line as u64 + 1,
+                position.column as u64 + 1,
+            )
+        };
+
+        let root = dwarf.unit.root();
+        let entry = dwarf.unit.get_mut(root);
+        entry.set(
+            constants::DW_AT_producer,
+            AttributeValue::String("spackel".into()),
+        );
+        entry.set(
+            constants::DW_AT_name,
+            AttributeValue::String(main_file.into()),
+        );
+        entry.set(
+            constants::DW_AT_comp_dir,
+            AttributeValue::String(comp_dir.into_bytes()),
+        );
+        entry.set(
+            constants::DW_AT_low_pc,
+            AttributeValue::Address(Address::Constant(0)),
+        );
+
+        let mut ranges = Vec::new();
+        for (symbol, function) in self.functions.iter().enumerate() {
+            let start = Address::Symbol { symbol, addend: 0 };
+            ranges.push(Range::StartLength {
+                begin: start,
+                length: function.size.into(),
+            });
+
+            let line_program = &mut dwarf.unit.line_program;
+            line_program.begin_sequence(Some(start));
+            for (offsets, span) in &function.words {
+                let (file, line, column) = locate(*span);
+                let row = line_program.row();
+                row.address_offset = offsets.start.into();
+                row.file = file;
+                row.line = line;
+                row.column = column;
+                line_program.generate_row();
+            }
+            line_program.end_sequence(function.size.into());
+
+            let declaration = function.words.first().map(|(_, span)| {
+                let (file, line, _) = locate(*span);
+                (file, line)
+            });
+            add_subprogram(&mut dwarf, function, start, declaration);
+        }
+        let ranges = dwarf.unit.ranges.add(RangeList(ranges));
+        dwarf.unit.get_mut(root).set(
+            constants::DW_AT_ranges,
+            AttributeValue::RangeListRef(ranges),
+        );
+        Ok(dwarf)
+    }
+}
+
+/// Which of the files a span is in, and the position where it starts.
+fn locate(
+    files: &[Arc<codemap::File>],
+    span: Span,
+) -> (usize, codemap::LineCol) {
+    let index = files
+        .iter()
+        .position(|file| file.span.contains(span))
+        .unwrap();
+    (index, files[index].find_line_col(span.low()))
+}
+
+fn add_subprogram(
+    dwarf: &mut DwarfUnit,
+    function: &Function,
+    start: Address,
+    declaration: Option<(FileId, u64)>,
+) {
+    let id = dwarf
+        .unit
+        .add(dwarf.unit.root(), constants::DW_TAG_subprogram);
+    let entry = dwarf.unit.get_mut(id);
+    entry.set(
+        constants::DW_AT_name,
+        AttributeValue::String(function.name.clone().into_bytes()),
+    );
+    entry.set(constants::DW_AT_low_pc, AttributeValue::Address(start));
+    entry.set(
+        constants::DW_AT_high_pc,
+        AttributeValue::Udata(function.size.into()),
+    );
+    if let Some((file, line)) = declaration {
+        entry.set(
+            constants::DW_AT_decl_file,
+            AttributeValue::FileIndex(Some(file)),
+        );
+        entry.set(constants::DW_AT_decl_line, AttributeValue::Udata(line));
+    }
+    if function.name == "main" {
+        entry.set(constants::DW_AT_external, AttributeValue::Flag(true));
+    }
+}
+
+fn add_section(product: &mut ObjectProduct, id: SectionId) -> ObjectSectionId {
+    let object = &mut product.object;
+    let segment = object
+        .segment_name(cranelift_object::object::write::StandardSegment::Debug)
+        .to_vec();
+    object.add_section(segment, id.name().into(), SectionKind::Debug)
+}
+
+/// What a relocation in a DWARF section points to.
+#[derive(Clone, Copy)]
+enum Target {
+    /// An index into `DebugInfo::functions`.
+    Function(usize),
+    Section(SectionId),
+}
+
+#[derive(Clone)]
+struct DebugRelocation {
+    offset: u64,
+    /// In bytes.
+    size: u8,
+    target: Target,
+    addend: i64,
+}
+
+/// Records the places that refer to functions or other sections, since those
+/// are only known once the object file is linked.
+#[derive(Clone)]
+struct RelocatingWriter {
+    data: EndianVec<RunTimeEndian>,
+    relocations: Vec<DebugRelocation>,
+}
+
+impl Writer for RelocatingWriter {
+    type Endian = RunTimeEndian;
+
+    fn endian(&self) -> Self::Endian {
+        self.data.endian()
+    }
+
+    fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> gimli::write::Result<()> {
+        self.data.write(bytes)
+    }
+
+    fn write_at(
+        &mut self,
+        offset: usize,
+        bytes: &[u8],
+    ) -> gimli::write::Result<()> {
+        self.data.write_at(offset, bytes)
+    }
+
+    fn write_address(
+        &mut self,
+        address: Address,
+        size: u8,
+    ) -> gimli::write::Result<()> {
+        match address {
+            Address::Constant(value) => self.write_udata(value, size),
+            Address::Symbol { symbol, addend } => {
+                self.relocations.push(DebugRelocation {
+                    offset: self.len() as u64,
+                    size,
+                    target: Target::Function(symbol),
+                    addend,
+                });
+                self.write_udata(0, size)
+            }
+        }
+    }
+
+    fn write_offset(
+        &mut self,
+        value: usize,
+        section: SectionId,
+        size: u8,
+    ) -> gimli::write::Result<()> {
+        self.relocations.push(DebugRelocation {
+            offset: self.len() as u64,
+            size,
+            target: Target::Section(section),
+            addend: i64::try_from(value).unwrap(),
+        });
+        self.write_udata(0, size)
+    }
+
+    fn write_offset_at(
+        &mut self,
+        offset: usize,
+        value: usize,
+        section: SectionId,
+        size: u8,
+    ) -> gimli::write::Result<()> {
+        self.relocations.push(DebugRelocation {
+            offset: offset as u64,
+            size,
+            target: Target::Section(section),
+            addend: i64::try_from(value).unwrap(),
+        });
+        self.write_udata_at(offset, 0, size)
+    }
+}
@@ -1,50 +1,309 @@
+use anyhow::{anyhow, bail, Result};
 use codemap::{CodeMap, Span};
-use codemap_diagnostic::{ColorConfig, Emitter, SpanLabel, SpanStyle};
-use std::{fmt, process::ExitCode};
+use codemap_diagnostic::{ColorConfig, Emitter, Level, SpanLabel, SpanStyle};
+use std::{collections::BTreeMap, fmt, process::ExitCode};
 
-pub struct Error(pub Vec<codemap_diagnostic::Diagnostic>);
+/// Something that is wrong with a program, pointing out where in the source
+/// code it happens.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Diagnostic {
+    pub severity: Level,
+    /// Which lint a warning comes from.
+    pub lint: Option<Lint>,
+    pub message: String,
+    /// The parts of the source code that the diagnostic is about, with the
+    /// main one labeled as primary.
+    pub spans: Vec<SpanLabel>,
+    pub notes: Vec<String>,
+}
+
+impl Diagnostic {
+    pub fn note(mut self, message: impl Into<String>) -> Self {
+        self.notes.push(message.into());
+        self
+    }
+
+    pub fn primary_span(&self) -> Option<Span> {
+        self.spans
+            .iter()
+            .find(|label| label.style == SpanStyle::Primary)
+            .map(|label| label.span)
+    }
 
-impl fmt::Debug for Error {
-    fn fmt(&self, _f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        unimplemented!()
+    /// The form that `codemap_diagnostic` prints, where each note is a
+    /// diagnostic of its own.
+    fn to_codemap(
+        &self,
+    ) -> impl Iterator<Item = codemap_diagnostic::Diagnostic> + '_ {
+        std::iter::once(codemap_diagnostic::Diagnostic {
+            level: self.severity,
+            message: self.message.clone(),
+            code: self.lint.map(|lint| lint.name().to_owned()),
+            spans: self.spans.clone(),
+        })
+        .chain(self.notes.iter().map(|note| {
+            codemap_diagnostic::Diagnostic {
+                level: Level::Note,
+                message: note.clone(),
+                code: None,
+                spans: Vec::new(),
+            }
+        }))
     }
 }
 
+/// One or more diagnostics that stopped a program from being checked, all of
+/// which are reported together.
+#[derive(Debug)]
+pub struct Error(pub Vec<Diagnostic>);
+
 impl fmt::Display for Error {
-    fn fmt(&self, _f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        unimplemented!()
+    /// Only the messages, since printing the source code needs the code map.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, diagnostic) in self.0.iter().enumerate() {
+            if i != 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{}", diagnostic.severity)?;
+            if let Some(lint) = diagnostic.lint {
+                write!(f, "[{}]", lint.name())?;
+            }
+            write!(f, ": {}", diagnostic.message)?;
+            for note in &diagnostic.notes {
+                write!(f, "\nnote: {note}")?;
+            }
+        }
+        Ok(())
     }
 }
 
 impl std::error::Error for Error {}
 
 impl Error {
+    /// Adds a note to the last diagnostic.
     pub fn note(mut self, message: impl Into<String>) -> Self {
-        self.0.push(codemap_diagnostic::Diagnostic {
-            level: codemap_diagnostic::Level::Note,
-            message: message.into(),
-            code: None,
-            spans: Vec::new(),
-        });
+        if let Some(diagnostic) = self.0.last_mut() {
+            diagnostic.notes.push(message.into());
+        }
         self
     }
 
     pub fn emit(&self, code_map: &CodeMap) -> ExitCode {
-        let mut emitter = Emitter::stderr(ColorConfig::Auto, Some(code_map));
-        emitter.emit(&self.0);
+        emit(&self.0, code_map);
         ExitCode::FAILURE
     }
 }
 
+/// Prints diagnostics to stderr.
+pub fn emit(diagnostics: &[Diagnostic], code_map: &CodeMap) {
+    if diagnostics.is_empty() {
+        return;
+    }
+    let mut emitter = Emitter::stderr(ColorConfig::Auto, Some(code_map));
+    emitter.emit(
+        &diagnostics
+            .iter()
+            .flat_map(Diagnostic::to_codemap)
+            .collect::<Vec<_>>(),
+    );
+}
+
+/// Gathers the diagnostics of several independent parts of a program, such as
+/// its functions, so that a mistake in one of them doesn't hide the others.
+#[derive(Default)]
+pub struct Collector {
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl Collector {
+    /// Keeps the diagnostics of a failed result. Errors that aren't
+    /// diagnostics can't be reported alongside them, so they are returned
+    /// right away.
+    pub fn record<T>(
+        &mut self,
+        result: anyhow::Result<T>,
+    ) -> anyhow::Result<Option<T>> {
+        match result {
+            Ok(value) => Ok(Some(value)),
+            Err(err) => {
+                self.diagnostics.extend(err.downcast::<Error>()?.0);
+                Ok(None)
+            }
+        }
+    }
+
+    /// Fails with all of the recorded diagnostics, if there are any.
+    pub fn finish(self) -> Result<(), Error> {
+        if self.diagnostics.is_empty() {
+            Ok(())
+        } else {
+            Err(Error(self.diagnostics))
+        }
+    }
+}
+
+/// Renders each diagnostic of an error the way it would be printed to the
+/// terminal, but without colors.
+pub fn render(err: &anyhow::Error, code_map: &CodeMap) -> Vec<String> {
+    let Some(err) = err.downcast_ref::<Error>() else {
+        return vec![format!("error: {err:#}\n")];
+    };
+    render_diagnostics(&err.0, code_map)
+}
+
+pub fn render_diagnostics(
+    diagnostics: &[Diagnostic],
+    code_map: &CodeMap,
+) -> Vec<String> {
+    diagnostics
+        .iter()
+        .map(|diagnostic| {
+            let mut output = Vec::new();
+            Emitter::vec(&mut output, Some(code_map))
+                .emit(&diagnostic.to_codemap().collect::<Vec<_>>());
+            String::from_utf8_lossy(&output).into_owned()
+        })
+        .collect()
+}
+
 pub fn error(message: String, spans: Vec<SpanLabel>) -> Error {
-    Error(vec![codemap_diagnostic::Diagnostic {
-        level: codemap_diagnostic::Level::Error,
+    Error(vec![Diagnostic {
+        severity: Level::Error,
+        lint: None,
         message,
-        code: None,
         spans,
+        notes: Vec::new(),
     }])
 }
 
+/// An error for something that type checking should have ruled out, which
+/// means that there is a bug in Spackel rather than in the program.
+pub fn internal_error(message: impl fmt::Display) -> anyhow::Error {
+    anyhow!("internal compiler error: {message}")
+}
+
+pub const fn warning(
+    lint: Lint,
+    message: String,
+    spans: Vec<SpanLabel>,
+) -> Diagnostic {
+    Diagnostic {
+        severity: Level::Warning,
+        lint: Some(lint),
+        message,
+        spans,
+        notes: Vec::new(),
+    }
+}
+
+/// Something that is likely a mistake even though the program still works,
+/// which is reported as a warning unless told otherwise.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum Lint {
+    UnusedMacro,
+    UnreachableCode,
+    UnusedValue,
+    ShadowedMacro,
+}
+
+impl Lint {
+    pub const ALL: [Self; 4] = [
+        Self::UnusedMacro,
+        Self::UnreachableCode,
+        Self::UnusedValue,
+        Self::ShadowedMacro,
+    ];
+
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::UnusedMacro => "unused-macro",
+            Self::UnreachableCode => "unreachable-code",
+            Self::UnusedValue => "unused-value",
+            Self::ShadowedMacro => "shadowed-macro",
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum LintLevel {
+    Allow,
+    Warn,
+    Deny,
+}
+
+/// How each lint is reported, as set by `--allow`, `--warn` and `--deny`.
+/// Lints that haven't been set are warnings.
+#[derive(Clone, Default, Debug)]
+pub struct LintLevels(BTreeMap<Lint, LintLevel>);
+
+impl LintLevels {
+    /// Sets the level of the lint with the name, or of every lint if the name
+    /// is `all`. Later settings override earlier ones.
+    pub fn set(&mut self, name: &str, level: LintLevel) -> Result<()> {
+        if name == "all" {
+            self.0 = Lint::ALL.into_iter().map(|lint| (lint, level)).collect();
+            return Ok(());
+        }
+        let Some(lint) = Lint::ALL.into_iter().find(|lint| lint.name() == name)
+        else {
+            bail!(
+                "unknown lint `{name}`, the lints are {} and `all`",
+                Lint::ALL
+                    .iter()
+                    .map(|lint| format!("`{}`", lint.name()))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+        };
+        self.0.insert(lint, level);
+        Ok(())
+    }
+
+    fn get(&self, lint: Lint) -> LintLevel {
+        self.0.get(&lint).copied().unwrap_or(LintLevel::Warn)
+    }
+
+    /// Leaves out the allowed warnings and those within `ignored`. If any of
+    /// the remaining ones are denied, they are turned into errors and all of
+    /// them are returned as one.
+    pub fn apply(
+        &self,
+        warnings: Vec<Diagnostic>,
+        ignored: Option<Span>,
+    ) -> Result<Vec<Diagnostic>, Error> {
+        let mut denied = false;
+        let warnings = warnings
+            .into_iter()
+            .filter(|warning| {
+                ignored.is_none_or(|ignored| {
+                    warning
+                        .primary_span()
+                        .is_none_or(|span| !ignored.contains(span))
+                })
+            })
+            .filter_map(|mut warning| {
+                match warning
+                    .lint
+                    .map_or(LintLevel::Warn, |lint| self.get(lint))
+                {
+                    LintLevel::Allow => return None,
+                    LintLevel::Warn => {}
+                    LintLevel::Deny => {
+                        warning.severity = Level::Error;
+                        denied = true;
+                    }
+                }
+                Some(warning)
+            })
+            .collect::<Vec<_>>();
+        if denied {
+            Err(Error(warnings))
+        } else {
+            Ok(warnings)
+        }
+    }
+}
+
 pub fn primary_label(span: Span, label: impl Into<String>) -> SpanLabel {
     SpanLabel {
         span,
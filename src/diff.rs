@@ -0,0 +1,144 @@
+//! Compares two versions of a program function by function, so that a change
+//! can be reviewed by what it does to the program rather than to the text.
+
+use crate::{
+    ssa::{self, Graph, Op},
+    typ::CheckedProgram,
+};
+use std::fmt::Write;
+
+/// Lists the functions that were added or removed, the signatures that
+/// changed and the differences between the SSA of functions with the same
+/// name. Functions are compared before optimization so that changes to one
+/// function don't show up in the ones it gets inlined into.
+pub fn diff(old: CheckedProgram, new: CheckedProgram) -> String {
+    let old_signatures = old.function_signatures.clone();
+    let new_signatures = new.function_signatures.clone();
+    let old = ssa::convert(old, &mut ssa::ValueGenerator::default());
+    let new = ssa::convert(new, &mut ssa::ValueGenerator::default());
+
+    let mut output = String::new();
+    for (name, signature) in &old_signatures {
+        if !new_signatures.contains_key(name) {
+            writeln!(output, "- {}", signature.declaration(name)).unwrap();
+        }
+    }
+    for (name, signature) in &new_signatures {
+        let Some(old_signature) = old_signatures.get(name) else {
+            writeln!(output, "+ {}", signature.declaration(name)).unwrap();
+            continue;
+        };
+        let signature_changed = old_signature != signature;
+        // Generic functions have no body, only their instances do.
+        let old_lines = old
+            .function_bodies
+            .get(name)
+            .map(render)
+            .unwrap_or_default();
+        let new_lines = new
+            .function_bodies
+            .get(name)
+            .map(render)
+            .unwrap_or_default();
+        let changed_lines = diff_lines(&old_lines, &new_lines);
+        if !signature_changed && changed_lines.is_empty() {
+            continue;
+        }
+        writeln!(output, "~ {}", signature.declaration(name)).unwrap();
+        if signature_changed {
+            writeln!(output, "    was {}", old_signature.declaration(name))
+                .unwrap();
+        }
+        for (sign, line) in changed_lines {
+            writeln!(output, "  {sign} {line}").unwrap();
+        }
+    }
+    output
+}
+
+/// Renders a graph one assignment per line, with nested graphs indented below
+/// the operation they belong to. Source locations are left out since moving
+/// code around doesn't change what it does.
+fn render(graph: &Graph) -> Vec<String> {
+    let mut lines = Vec::new();
+    render_graph(&graph.renumbered(), 0, &mut lines);
+    lines
+}
+
+fn render_graph(graph: &Graph, depth: usize, lines: &mut Vec<String>) {
+    let indentation = "  ".repeat(depth);
+    lines.push(format!("{indentation}in {:?}", graph.inputs));
+    for assignment in &graph.assignments {
+        let (op, bodies) = match &assignment.op {
+            Op::Then(body) => ("Then".to_owned(), vec![&**body]),
+            Op::ThenElse(then, else_) => {
+                ("ThenElse".to_owned(), vec![&**then, &**else_])
+            }
+            Op::Match(cases) => ("Match".to_owned(), cases.iter().collect()),
+            Op::Switch(values, cases) => {
+                (format!("Switch({values:?})"), cases.iter().collect())
+            }
+            Op::Repeat(body) => ("Repeat".to_owned(), vec![&**body]),
+            Op::Quotation(signature, body) => {
+                (format!("Quotation({signature})"), vec![&**body])
+            }
+            Op::CallQuotation(signature) => {
+                (format!("CallQuotation({signature})"), Vec::new())
+            }
+            Op::While(condition, body) => {
+                ("While".to_owned(), vec![&**condition, &**body])
+            }
+            Op::Assert(_) => ("Assert".to_owned(), Vec::new()),
+            Op::BinMath { operation, typ, .. } => {
+                (format!("BinMath({operation:?}, {typ})"), Vec::new())
+            }
+            op => (format!("{op:?}"), Vec::new()),
+        };
+        lines.push(format!(
+            "{indentation}{:?} <- {:?} {op}",
+            assignment.to, assignment.args
+        ));
+        for body in bodies {
+            render_graph(body, depth + 1, lines);
+        }
+    }
+    lines.push(format!("{indentation}out {:?}", graph.outputs));
+}
+
+/// The lines that were removed (`-`) or added (`+`) according to a longest
+/// common subsequence, in order.
+fn diff_lines<'a>(
+    old: &'a [String],
+    new: &'a [String],
+) -> Vec<(char, &'a str)> {
+    // `common[i][j]` is the length of the longest common subsequence of
+    // `old[i..]` and `new[j..]`.
+    let mut common = vec![vec![0_usize; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            common[i][j] = if old[i] == new[j] {
+                common[i + 1][j + 1] + 1
+            } else {
+                common[i + 1][j].max(common[i][j + 1])
+            };
+        }
+    }
+
+    let mut changes = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            i += 1;
+            j += 1;
+        } else if j == new.len()
+            || (i < old.len() && common[i + 1][j] >= common[i][j + 1])
+        {
+            changes.push(('-', &*old[i]));
+            i += 1;
+        } else {
+            changes.push(('+', &*new[j]));
+            j += 1;
+        }
+    }
+    changes
+}
@@ -0,0 +1,180 @@
+use crate::{
+    ir::{Macro, Program},
+    typ::FunctionSignature,
+};
+use anyhow::{bail, Result};
+use codemap::Span;
+use std::{collections::BTreeMap, fmt::Write, str::FromStr};
+
+/// The documentation of a program, taken out of it before type checking.
+pub struct Words<'src> {
+    functions: BTreeMap<&'src str, Box<str>>,
+    macros: BTreeMap<&'src str, Macro>,
+}
+
+impl<'src> Words<'src> {
+    /// Takes the documentation of every function and macro, except for those
+    /// defined within `ignored`.
+    pub fn take(program: &mut Program<'src>, ignored: Option<Span>) -> Self {
+        let is_documented =
+            |span| ignored.is_none_or(|ignored| !ignored.contains(span));
+        Self {
+            functions: program
+                .functions
+                .iter_mut()
+                .filter(|(_, function)| {
+                    is_documented(function.declaration_span)
+                })
+                .map(|(&name, function)| {
+                    (name, std::mem::take(&mut function.doc))
+                })
+                .collect(),
+            macros: std::mem::take(&mut program.macros)
+                .into_iter()
+                .filter(|(_, macro_)| is_documented(macro_.declaration_span))
+                .collect(),
+        }
+    }
+
+    /// Renders a reference of every function and macro, listing the stack
+    /// effects of functions and the expansions of macros.
+    pub fn render(
+        &self,
+        function_signatures: &BTreeMap<&str, FunctionSignature>,
+        format: Format,
+        title: &str,
+    ) -> String {
+        let words = self.entries(function_signatures);
+        match format {
+            Format::Markdown => render_markdown(&words),
+            Format::Html => render_html(&words, title),
+        }
+    }
+
+    /// The name, definition and documentation of each word.
+    fn entries(
+        &self,
+        function_signatures: &BTreeMap<&str, FunctionSignature>,
+    ) -> Vec<(&str, String, &str)> {
+        let functions = self.functions.iter().map(|(&name, doc)| {
+            let signature = &function_signatures[name];
+            (name, signature.declaration(name), &**doc)
+        });
+        let macros = self.macros.iter().map(|(&name, macro_)| {
+            let definition = if macro_.body.is_empty() {
+                format!("macro {name} end")
+            } else {
+                format!("macro {name} {} end", macro_.body)
+            };
+            (name, definition, &*macro_.doc)
+        });
+        functions.chain(macros).collect()
+    }
+}
+
+/// What `spackel doc` renders documentation as.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum Format {
+    #[default]
+    Markdown,
+    /// A standalone page with a list of links to every word.
+    Html,
+}
+
+impl FromStr for Format {
+    type Err = anyhow::Error;
+
+    fn from_str(format: &str) -> Result<Self> {
+        match format {
+            "markdown" => Ok(Self::Markdown),
+            "html" => Ok(Self::Html),
+            _ => bail!("`--format` must be `markdown` or `html`"),
+        }
+    }
+}
+
+fn render_markdown(words: &[(&str, String, &str)]) -> String {
+    let mut output = String::new();
+    for (name, definition, doc) in words {
+        if !output.is_empty() {
+            output.push('\n');
+        }
+        writeln!(output, "## `{name}`\n\n```spackel\n{definition}\n```")
+            .unwrap();
+        if !doc.is_empty() {
+            writeln!(output, "\n{doc}").unwrap();
+        }
+    }
+    output
+}
+
+/// Renders a page where each word gets a section, whose ID is its index since
+/// names can contain characters that would need escaping in a URL.
+fn render_html(words: &[(&str, String, &str)], title: &str) -> String {
+    let title = escape_html(title);
+    let mut output = format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n\
+         <title>{title}</title>\n</head>\n<body>\n<h1>{title}</h1>\n<ul>\n"
+    );
+    for (i, (name, _, _)) in words.iter().enumerate() {
+        writeln!(
+            output,
+            "<li><a href=\"#word-{i}\"><code>{}</code></a></li>",
+            escape_html(name)
+        )
+        .unwrap();
+    }
+    output.push_str("</ul>\n");
+    for (i, (name, definition, doc)) in words.iter().enumerate() {
+        writeln!(
+            output,
+            "<section id=\"word-{i}\">\n<h2><code>{}</code></h2>\n\
+             <pre><code>{}</code></pre>",
+            escape_html(name),
+            escape_html(definition),
+        )
+        .unwrap();
+        for paragraph in doc.split("\n\n").filter(|p| !p.trim().is_empty()) {
+            writeln!(output, "<p>{}</p>", render_inline_code(paragraph))
+                .unwrap();
+        }
+        output.push_str("</section>\n");
+    }
+    output.push_str("</body>\n</html>\n");
+    output
+}
+
+/// Escapes text and turns the parts of it between backticks into code, which
+/// is the only Markdown that doc comments usually use.
+fn render_inline_code(text: &str) -> String {
+    let mut output = String::new();
+    let mut parts = text.split('`').peekable();
+    let mut is_code = false;
+    while let Some(part) = parts.next() {
+        // An unmatched backtick is kept as it is.
+        if is_code && parts.peek().is_none() {
+            write!(output, "`{}", escape_html(part)).unwrap();
+        } else if is_code {
+            write!(output, "<code>{}</code>", escape_html(part)).unwrap();
+        } else {
+            output.push_str(&escape_html(part));
+        }
+        is_code = !is_code;
+    }
+    output
+}
+
+fn escape_html(text: &str) -> String {
+    let mut output = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => output.push_str("&amp;"),
+            '<' => output.push_str("&lt;"),
+            '>' => output.push_str("&gt;"),
+            '"' => output.push_str("&quot;"),
+            '\'' => output.push_str("&#39;"),
+            _ => output.push(c),
+        }
+    }
+    output
+}
@@ -1,4 +1,7 @@
-use crate::unicode::prettify_token;
+use crate::{
+    lexer::{split_label, split_line},
+    unicode::prettify_token,
+};
 
 const INDENT_WIDTH: usize = 2;
 
@@ -6,42 +9,102 @@ pub fn format(source_code: &str) -> String {
     let mut formatter = Formatter {
         output: String::new(),
         indentation: 0,
+        quotations: Vec::new(),
     };
-    for token in lex_including_trivia(source_code).map(prettify_token) {
-        formatter.emit_token(token);
+    let tokens = lex_including_trivia(source_code)
+        .map(prettify_token)
+        .collect::<Vec<_>>();
+    for (i, token) in tokens.iter().enumerate() {
+        let next = tokens[i + 1..]
+            .iter()
+            .find(|next| **next != "\n" && !next.starts_with('#'))
+            .copied();
+        formatter.emit_token(token, next);
     }
     if formatter.output.ends_with("\n\n") {
         formatter.output.pop();
     }
-    formatter.output
+    align_comments(&formatter.output)
 }
 
-fn lex_including_trivia(source_code: &str) -> impl Iterator<Item = &str> {
-    source_code.lines().flat_map(|line| {
-        let (line, comment) =
-            line.find('#').map_or((line, None), |comment_index| {
-                let (line, comment) = line.split_at(comment_index);
-                (line, Some(comment))
-            });
-        line.split_whitespace().chain(comment).chain(["\n"])
+/// Lines up the comments after code on consecutive lines, one space after the
+/// longest code among them.
+fn align_comments(output: &str) -> String {
+    let lines = output.lines().map(split_comment).collect::<Vec<_>>();
+    let mut aligned = String::new();
+    for run in lines.chunk_by(|a, b| a.1.is_some() == b.1.is_some()) {
+        let width = run
+            .iter()
+            .map(|(code, _)| code.chars().count())
+            .max()
+            .unwrap_or(0);
+        for &(code, comment) in run {
+            aligned.push_str(code);
+            if let Some(comment) = comment {
+                let padding = width - code.chars().count() + 1;
+                aligned.extend(std::iter::repeat_n(' ', padding));
+                aligned.push_str(comment);
+            }
+            aligned.push('\n');
+        }
+    }
+    aligned
+}
+
+/// Splits a formatted line into code and the comment after it, if there is
+/// both.
+fn split_comment(line: &str) -> (&str, Option<&str>) {
+    let comment = split_line(line)
+        .skip(1)
+        .find(|token| token.starts_with('#'));
+    comment.map_or((line, None), |comment| {
+        let start = comment.as_ptr() as usize - line.as_ptr() as usize;
+        (line[..start].trim_end(), Some(comment))
     })
 }
 
+fn lex_including_trivia(source_code: &str) -> impl Iterator<Item = &str> {
+    source_code
+        .lines()
+        .flat_map(|line| split_line(line).chain(["\n"]))
+}
+
 struct Formatter {
     output: String,
     indentation: usize,
+    /// The indentation outside of each quotation that is currently open,
+    /// which `]` goes back to whether or not the quotation had a body.
+    quotations: Vec<usize>,
 }
 
 impl Formatter {
-    fn emit_token(&mut self, token: &str) {
+    /// Adds a token given the next one that isn't a newline or a comment.
+    fn emit_token(&mut self, token: &str, next: Option<&str>) {
         if token == "\n"
             && (self.output.is_empty() || self.output.ends_with("\n\n"))
         {
             return;
         }
-        if matches!(token, "end" | "else") {
+        // `case` followed by a number starts a `case` statement rather than a
+        // case of a `match`, so it only indents the arms after it.
+        let starts_case_statement = token == "case"
+            && next.is_some_and(|next| next.parse::<i32>().is_ok());
+        // The body of an `unsafe fn` is indented by its `do` instead, and the
+        // fields of an `extern struct` by the `struct`.
+        let previous = self.output.split_whitespace().next_back();
+        if matches!(token, "end" | "else" | "case") && !starts_case_statement
+            || matches!(
+                (previous, token),
+                (Some("unsafe"), "fn") | (Some("extern"), "struct")
+            )
+        {
             self.indentation = self.indentation.saturating_sub(1);
         }
+        if token == "]" {
+            if let Some(indentation) = self.quotations.pop() {
+                self.indentation = indentation;
+            }
+        }
         if token != "\n" {
             if self.output.ends_with('\n') {
                 self.output.extend(std::iter::repeat_n(
@@ -53,11 +116,32 @@ impl Formatter {
             }
         }
         if matches!(
-            token,
-            "else" | "macro" | "do" | "then" | "repeat" | "unsafe"
+            split_label(token).0,
+            "else"
+                | "case"
+                | "macro"
+                | "do"
+                | "then"
+                | "repeat"
+                | "times"
+                | "for"
+                | "unsafe"
+                | "stash"
+                | "let"
+                | "const"
+                | "var"
+                | "data"
+                | "extern"
+                | "test"
+                | "struct"
+                | "enum"
+                | "match"
         ) {
             self.indentation += 1;
         }
+        if token == "[" {
+            self.quotations.push(self.indentation);
+        }
         self.output.push_str(token);
     }
 }
@@ -0,0 +1,129 @@
+use crate::{
+    diagnostics::{self, primary_label},
+    lexer::lex,
+};
+use anyhow::{bail, Context, Result};
+use codemap::{CodeMap, File, Span};
+use itertools::Itertools;
+use std::{
+    collections::BTreeSet,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+/// Loads a program along with every file it includes, transitively. Each file
+/// is only loaded once and comes after all of the files it includes.
+pub fn load(code_map: &mut CodeMap, path: &Path) -> Result<Vec<Arc<File>>> {
+    let source_code =
+        std::fs::read_to_string(path).context("failed to read source file")?;
+    load_source(code_map, path, source_code)
+}
+
+/// Like `load`, but with source code that may differ from what is on disk,
+/// such as a file that is being edited. The file doesn't have to exist on
+/// disk.
+pub fn load_source(
+    code_map: &mut CodeMap,
+    path: &Path,
+    source_code: String,
+) -> Result<Vec<Arc<File>>> {
+    let canonical_path =
+        path.canonicalize().unwrap_or_else(|_| path.to_owned());
+    let file = code_map.add_file(path.display().to_string(), source_code);
+
+    let mut loader = Loader {
+        code_map,
+        files: Vec::new(),
+        loaded: BTreeSet::new(),
+        including: Vec::new(),
+    };
+    loader.load(file, canonical_path)?;
+    Ok(loader.files)
+}
+
+struct Loader<'a> {
+    code_map: &'a mut CodeMap,
+    files: Vec<Arc<File>>,
+    loaded: BTreeSet<PathBuf>,
+    including: Vec<(PathBuf, Arc<File>)>,
+}
+
+impl Loader<'_> {
+    fn load(&mut self, file: Arc<File>, path: PathBuf) -> Result<()> {
+        let directives = directives(&file)?;
+        let directory = path.parent().unwrap_or_else(|| Path::new(""));
+        self.including.push((path.clone(), file.clone()));
+
+        for (span, relative_path) in directives {
+            let included_path = directory.join(&relative_path);
+            let read_error = |err: std::io::Error| {
+                diagnostics::error(
+                    format!("failed to read `{relative_path}`: {err}"),
+                    vec![primary_label(span, "")],
+                )
+            };
+            let canonical_path =
+                included_path.canonicalize().map_err(read_error)?;
+
+            if let Some(cycle_start) = self
+                .including
+                .iter()
+                .position(|(path, _)| *path == canonical_path)
+            {
+                let cycle = self.including[cycle_start..]
+                    .iter()
+                    .map(|(_, file)| file.name())
+                    .chain([self.including[cycle_start].1.name()])
+                    .format(" → ");
+                bail!(diagnostics::error(
+                    format!("cyclic include of `{relative_path}`"),
+                    vec![primary_label(span, "")],
+                )
+                .note(format!(
+                    "the files include each other in a cycle: {cycle}"
+                )));
+            }
+            if self.loaded.contains(&canonical_path) {
+                continue;
+            }
+
+            let source_code =
+                std::fs::read_to_string(&canonical_path).map_err(read_error)?;
+            let included = self
+                .code_map
+                .add_file(included_path.display().to_string(), source_code);
+            self.load(included, canonical_path)?;
+        }
+
+        self.including.pop();
+        self.loaded.insert(path);
+        self.files.push(file);
+        Ok(())
+    }
+}
+
+/// Finds every `include "path"` directive in a file.
+pub fn directives(file: &File) -> Result<Vec<(Span, String)>> {
+    let mut tokens = lex(file);
+    let mut directives = Vec::new();
+    while let Some(token) = tokens.next() {
+        if &*token != "include" {
+            continue;
+        }
+        let path = tokens
+            .next()
+            .and_then(|path| {
+                let relative_path =
+                    path.strip_prefix('"')?.strip_suffix('"')?;
+                Some((token.span.merge(path.span), relative_path.to_owned()))
+            })
+            .ok_or_else(|| {
+                diagnostics::error(
+                    "`include` must be followed by a path in quotes".to_owned(),
+                    vec![primary_label(token.span, "")],
+                )
+            })?;
+        directives.push(path);
+    }
+    Ok(directives)
+}
@@ -1,45 +1,386 @@
 use crate::{
-    ir::{BinLogicOp, BinMathOp, Block, Comparison, Instruction},
+    diagnostics::{self, primary_label},
+    ir::{self, BinLogicOp, BinMathOp, Block, Instruction},
     typ::{Generics, Type},
+    Limits,
+};
+use codemap::Span;
+use std::{
+    fmt,
+    io::{BufRead, Write},
 };
 
-pub fn interpret(program: &crate::typ::CheckedProgram) {
-    Interpreter {
-        stack: Vec::new(),
+/// Runs a program, returning whatever was left on the stack when it finished
+/// or was halted.
+pub fn interpret(
+    program: &crate::typ::CheckedProgram,
+    args: &[String],
+    input: &mut dyn BufRead,
+    output: &mut dyn Write,
+    limits: &Limits,
+) -> (Result<(), Halt>, Vec<Value>) {
+    let mut interpreter = Interpreter::new(
         program,
-    }
-    .interpret();
+        Snapshot::default(),
+        args,
+        input,
+        output,
+        limits,
+    );
+    let result = interpreter.interpret();
+    (result, interpreter.state.stack.into_iter().collect())
 }
 
-#[derive(Clone)]
-enum Value {
+/// Runs a single function starting from `state`, such as a line entered into
+/// the REPL, whose stack already has the arguments. The state is returned as
+/// it was when the function finished or was halted.
+pub fn interpret_function<'src>(
+    program: &'src crate::typ::CheckedProgram<'src>,
+    name: &'src str,
+    state: Snapshot,
+    input: &mut dyn BufRead,
+    output: &mut dyn Write,
+) -> (Result<(), Halt>, Snapshot) {
+    let mut interpreter = Interpreter::new(
+        program,
+        state,
+        &[],
+        input,
+        output,
+        &Limits::UNLIMITED,
+    );
+    let result = interpreter.call(name);
+    (result, interpreter.state)
+}
+
+/// Everything that running a program can change besides its output. The
+/// collections are persistent, so cloning a snapshot is cheap and the copies
+/// share whatever stays the same afterwards, which is how the REPL keeps one
+/// around for every line that can be undone.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Snapshot {
+    pub stack: im::Vector<Value>,
+    pub variables: im::OrdMap<String, Value>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
     Bool(bool),
     I32(i32),
     F32(f32),
     Type(Type),
+    /// An index into the quotations that the interpreter has come across.
+    Quotation(usize),
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Bool(b) => b.fmt(f),
+            Self::I32(n) => n.fmt(f),
+            Self::F32(n) => f.write_str(&format_f32(*n)),
+            Self::Type(typ) => typ.fmt(f),
+            Self::Quotation(_) => f.write_str("quotation"),
+        }
+    }
+}
+
+/// Formats a float like `printf("%g")` in C, which is how the runtime prints
+/// them: rounded to 6 significant digits without trailing zeros, and in
+/// scientific notation if the exponent is less than -4 or at least 6.
+fn format_f32(n: f32) -> String {
+    const PRECISION: i32 = 6;
+
+    if n.is_nan() {
+        return if n.is_sign_negative() { "-nan" } else { "nan" }.to_owned();
+    }
+    if n.is_infinite() {
+        return if n < 0.0 { "-inf" } else { "inf" }.to_owned();
+    }
+    let n = f64::from(n);
+    // The exponent is the one after rounding, which can be one more than
+    // before, like for 999999.5.
+    let scientific = format!("{:.*e}", (PRECISION - 1) as usize, n);
+    let (mantissa, exponent) = scientific.split_once('e').unwrap();
+    let exponent = exponent.parse::<i32>().unwrap();
+    if (-4..PRECISION).contains(&exponent) {
+        let decimals = usize::try_from(PRECISION - 1 - exponent).unwrap();
+        without_trailing_zeros(&format!("{n:.decimals$}")).to_owned()
+    } else {
+        let sign = if exponent < 0 { '-' } else { '+' };
+        format!(
+            "{}e{sign}{:02}",
+            without_trailing_zeros(mantissa),
+            exponent.abs()
+        )
+    }
+}
+
+fn without_trailing_zeros(number: &str) -> &str {
+    if number.contains('.') {
+        number.trim_end_matches('0').trim_end_matches('.')
+    } else {
+        number
+    }
+}
+
+/// Why a program stopped before reaching the end of `main`.
+#[derive(Debug)]
+pub enum Halt {
+    StepLimit,
+    CallDepthLimit,
+    OutputLimit,
+    DivisionByZero(Span),
+    DivisionOverflow(Span),
+    Unsupported(&'static str),
+    AssertionFailed(Span),
+    /// The program called `exit` with this exit code.
+    Exit(i32),
+}
+
+impl Halt {
+    /// The instruction that caused a runtime error.
+    pub const fn span(&self) -> Option<Span> {
+        match self {
+            Self::AssertionFailed(span)
+            | Self::DivisionByZero(span)
+            | Self::DivisionOverflow(span) => Some(*span),
+            _ => None,
+        }
+    }
+
+    /// Turns runtime errors that have a location into diagnostics pointing at
+    /// the instruction that caused them.
+    pub fn into_error(self) -> anyhow::Error {
+        match self.span() {
+            Some(span) => diagnostics::error(
+                self.to_string(),
+                vec![primary_label(span, "")],
+            )
+            .into(),
+            None => self.into(),
+        }
+    }
+}
+
+impl fmt::Display for Halt {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::StepLimit => f.write_str("step limit exceeded"),
+            Self::CallDepthLimit => f.write_str("call depth limit exceeded"),
+            Self::OutputLimit => f.write_str("output limit exceeded"),
+            Self::DivisionByZero(_) => f.write_str("division by zero"),
+            Self::DivisionOverflow(_) => f.write_str("division overflowed"),
+            Self::Unsupported(instruction) => {
+                write!(f, "`{instruction}` is not supported by the interpreter")
+            }
+            Self::AssertionFailed(_) => f.write_str("assertion failed"),
+            Self::Exit(code) => write!(f, "exited with code {code}"),
+        }
+    }
+}
+
+impl std::error::Error for Halt {}
+
+/// How control leaves a block.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Flow<'src> {
+    Normal,
+    /// A `break` out of the loop that is the given number of loops around the
+    /// innermost one.
+    Break(usize),
+    Continue(usize),
+    /// A `return`, which leaves the rest of the function.
+    Return,
+    /// A call in tail position, which is left for the caller to make so that
+    /// tail recursion doesn't use up the native stack.
+    TailCall(&'src str),
 }
 
-struct Interpreter<'src> {
+impl Flow<'_> {
+    /// How control leaves the loop that was left with this.
+    const fn outer(self) -> Self {
+        match self {
+            Self::Break(depth) => Self::Break(depth - 1),
+            Self::Continue(depth) => Self::Continue(depth - 1),
+            flow => flow,
+        }
+    }
+}
+
+struct Interpreter<'src, 'o> {
     program: &'src crate::typ::CheckedProgram<'src>,
-    stack: Vec<Value>,
+    /// Variables that the snapshot given to the interpreter doesn't have
+    /// start out as zero, like in compiled programs.
+    state: Snapshot,
+    /// The command line arguments, starting with the name of the program.
+    args: &'o [String],
+    input: &'o mut dyn BufRead,
+    output: &'o mut dyn Write,
+    limits: &'o Limits,
+    steps: u64,
+    call_depth: usize,
+    output_len: usize,
+    /// The bodies of the quotations that have been pushed so far.
+    quotations: Vec<&'src Block<Generics>>,
 }
 
-impl Interpreter<'_> {
+impl<'src, 'o> Interpreter<'src, 'o> {
+    fn new(
+        program: &'src crate::typ::CheckedProgram<'src>,
+        mut state: Snapshot,
+        args: &'o [String],
+        input: &'o mut dyn BufRead,
+        output: &'o mut dyn Write,
+        limits: &'o Limits,
+    ) -> Self {
+        for (&name, typ) in &program.variables {
+            if !state.variables.contains_key(name) {
+                let value = match typ {
+                    Type::I32 => Value::I32(0),
+                    Type::F32 => Value::F32(0.0),
+                    Type::Bool => Value::Bool(false),
+                    _ => unreachable!(),
+                };
+                state.variables.insert(name.to_owned(), value);
+            }
+        }
+        Self {
+            program,
+            state,
+            args,
+            input,
+            output,
+            limits,
+            steps: 0,
+            call_depth: 0,
+            output_len: 0,
+            quotations: Vec::new(),
+        }
+    }
+
     fn push(&mut self, element: Value) {
-        self.stack.push(element);
+        self.state.stack.push_back(element);
     }
 
     fn pop(&mut self) -> Value {
-        self.stack.pop().unwrap()
+        self.state.stack.pop_back().unwrap()
+    }
+
+    /// Runs `main`, whose return value is the exit code if it has one.
+    fn interpret(&mut self) -> Result<(), Halt> {
+        self.call("main")?;
+        if self.program.function_signatures["main"].returns.is_empty() {
+            Ok(())
+        } else {
+            Err(Halt::Exit(self.pop_i32()))
+        }
+    }
+
+    fn call(&mut self, name: &'src str) -> Result<(), Halt> {
+        self.call_body(&self.program.function_bodies[name])
+    }
+
+    /// Pushes a quotation, reusing its index if it has been pushed before.
+    fn push_quotation(&mut self, body: &'src Block<Generics>) {
+        let index = self
+            .quotations
+            .iter()
+            .position(|&other| std::ptr::eq(other, body))
+            .unwrap_or_else(|| {
+                self.quotations.push(body);
+                self.quotations.len() - 1
+            });
+        self.push(Value::Quotation(index));
     }
 
-    fn interpret(&mut self) {
-        self.interpret_block(&self.program.function_bodies["main"]);
+    /// Runs the body of a function or quotation.
+    fn call_body(
+        &mut self,
+        mut body: &'src Block<Generics>,
+    ) -> Result<(), Halt> {
+        if self.call_depth == self.limits.max_call_depth {
+            return Err(Halt::CallDepthLimit);
+        }
+        self.call_depth += 1;
+        while let Flow::TailCall(callee) = self.interpret_block(body, true)? {
+            body = &self.program.function_bodies[callee];
+        }
+        self.call_depth -= 1;
+        Ok(())
+    }
+
+    /// Runs a block, where `tail` says whether the function returns right
+    /// after it.
+    fn interpret_block(
+        &mut self,
+        block: &'src Block<Generics>,
+        tail: bool,
+    ) -> Result<Flow<'src>, Halt> {
+        for (i, instruction) in block.iter().enumerate() {
+            let tail = tail && i + 1 == block.len();
+            let flow = self.interpret_instruction(instruction, tail)?;
+            if flow != Flow::Normal {
+                return Ok(flow);
+            }
+        }
+        Ok(Flow::Normal)
+    }
+
+    fn print(&mut self, value: impl fmt::Display) -> Result<(), Halt> {
+        let text = value.to_string();
+        self.output_len += text.len();
+        if self.output_len > self.limits.max_output_len {
+            return Err(Halt::OutputLimit);
+        }
+        self.output.write_all(text.as_bytes()).unwrap();
+        Ok(())
+    }
+
+    fn read_byte(&mut self) -> Option<u8> {
+        // Prompts should show up before waiting for input.
+        self.output.flush().unwrap();
+        let byte = *self.input.fill_buf().ok()?.first()?;
+        self.input.consume(1);
+        Some(byte)
+    }
+
+    /// Reads a UTF-8 encoded character, turning invalid sequences into
+    /// `U+FFFD REPLACEMENT CHARACTER` like the runtime does.
+    fn read_char(&mut self) -> Option<char> {
+        let first = self.read_byte()?;
+        let len = match first {
+            0x00..=0x7f => 1,
+            0xc0..=0xdf => 2,
+            0xe0..=0xef => 3,
+            0xf0..=0xf7 => 4,
+            _ => 0,
+        };
+        let mut bytes = vec![first];
+        while bytes.len() < len {
+            match self.input.fill_buf() {
+                Ok(&[byte, ..]) if byte & 0xc0 == 0x80 => {
+                    bytes.push(byte);
+                    self.input.consume(1);
+                }
+                _ => break,
+            }
+        }
+        Some(
+            std::str::from_utf8(&bytes)
+                .map_or(char::REPLACEMENT_CHARACTER, |c| {
+                    c.chars().next().unwrap()
+                }),
+        )
     }
 
-    fn interpret_block(&mut self, block: &Block<Generics>) {
-        for instruction in block {
-            self.interpret_instruction(instruction);
+    /// Reads a line, or returns `None` at the end of the input.
+    fn read_line(&mut self) -> Option<Vec<u8>> {
+        self.output.flush().unwrap();
+        let mut line = Vec::new();
+        match self.input.read_until(b'\n', &mut line) {
+            Ok(0) | Err(_) => None,
+            Ok(_) => Some(line),
         }
     }
 
@@ -71,30 +412,122 @@ impl Interpreter<'_> {
         }
     }
 
+    #[expect(
+        clippy::too_many_lines,
+        reason = "every instruction is interpreted in its own arm"
+    )]
     fn interpret_instruction(
         &mut self,
-        (instruction, generics): &(Instruction<Generics>, Generics),
-    ) {
+        (instruction, generics): &'src (Instruction<Generics>, Generics),
+        tail: bool,
+    ) -> Result<Flow<'src>, Halt> {
+        self.steps += 1;
+        if self.steps > self.limits.max_steps {
+            return Err(Halt::StepLimit);
+        }
         match instruction {
-            Instruction::Call(name) => {
-                self.interpret_block(&self.program.function_bodies[&**name]);
+            Instruction::Call(name) if tail => {
+                return Ok(Flow::TailCall(name));
+            }
+            Instruction::Call(name) => self.call(name)?,
+            Instruction::CallExtern(..) => {
+                return Err(Halt::Unsupported("extern fn"));
+            }
+            Instruction::Quotation(quotation) => {
+                self.push_quotation(quotation.body.as_deref().unwrap());
+            }
+            Instruction::FunctionPointer(name) => {
+                let program = self.program;
+                self.push_quotation(&program.function_bodies[&**name]);
+            }
+            Instruction::Data(_) => return Err(Halt::Unsupported("data")),
+            Instruction::CallQuotation => {
+                let Value::Quotation(index) = self.pop() else {
+                    unreachable!()
+                };
+                self.call_body(self.quotations[index])?;
             }
             Instruction::Then(body) => {
                 if self.pop_bool() {
-                    self.interpret_block(body);
+                    return self.interpret_block(body, tail);
                 }
             }
             Instruction::ThenElse(then, else_) => {
                 let block = if self.pop_bool() { then } else { else_ };
-                self.interpret_block(block);
+                return self.interpret_block(block, tail);
             }
-            Instruction::Repeat { body, .. } => {
+            Instruction::Match(cases) => {
+                let tag = usize::try_from(self.pop_i32()).unwrap();
+                return self.interpret_block(&cases[tag].body, tail);
+            }
+            Instruction::Switch { arms, else_ } => {
+                let value = self.pop_i32();
+                let body = arms
+                    .iter()
+                    .find(|arm| arm.value == value)
+                    .map_or(else_, |arm| &arm.body);
+                return self.interpret_block(body, tail);
+            }
+            Instruction::Repeat { body, .. } => loop {
+                match self.interpret_block(body, false)? {
+                    Flow::Normal if self.pop_bool() => {}
+                    Flow::Normal | Flow::Break(0) => break,
+                    Flow::Continue(0) => {}
+                    flow => return Ok(flow.outer()),
+                }
+            },
+            Instruction::While {
+                condition, body, ..
+            } => {
                 while {
-                    self.interpret_block(body);
+                    self.interpret_block(condition, false)?;
                     self.pop_bool()
-                } {}
+                } {
+                    match self.interpret_block(body, false)? {
+                        Flow::Normal | Flow::Continue(0) => {}
+                        Flow::Break(0) => break,
+                        flow => return Ok(flow.outer()),
+                    }
+                }
             }
-            Instruction::Unsafe(body) => self.interpret_block(body),
+            Instruction::Times {
+                body, pushes_index, ..
+            } => {
+                for index in 0..self.pop_i32() {
+                    if *pushes_index {
+                        self.push(Value::I32(index));
+                    }
+                    match self.interpret_block(body, false)? {
+                        Flow::Normal | Flow::Continue(0) => {}
+                        Flow::Break(0) => break,
+                        flow => return Ok(flow.outer()),
+                    }
+                }
+            }
+            Instruction::Unsafe(body) | Instruction::Stash { body, .. } => {
+                return self.interpret_block(body, tail);
+            }
+            Instruction::ToStash(_)
+            | Instruction::FromStash(_)
+            | Instruction::Let { .. }
+            | Instruction::Local(_) => {
+                unreachable!(
+                    "stash operations and locals are resolved by the type checker"
+                )
+            }
+            Instruction::Combinator(_)
+            | Instruction::Invoke(_)
+            | Instruction::MacroExpansion { .. } => {
+                unreachable!(
+                    "combinators and macro expansions are lowered by the type checker"
+                )
+            }
+            Instruction::Location(_) => {}
+            Instruction::Break { depth, .. } => return Ok(Flow::Break(*depth)),
+            Instruction::Continue { depth, .. } => {
+                return Ok(Flow::Continue(*depth));
+            }
+            Instruction::Return => return Ok(Flow::Return),
             Instruction::PushI32(number) => self.push(Value::I32(*number)),
             Instruction::PushF32(number) => self.push(Value::F32(*number)),
             Instruction::PushBool(b) => self.push(Value::Bool(*b)),
@@ -107,25 +540,60 @@ impl Interpreter<'_> {
                 self.pop();
                 self.push(Value::Type(generics[0].clone()));
             }
-            Instruction::Print if generics[0] == Type::F32 => {
-                print!("{}", self.pop_f32());
+            Instruction::TypeEq => {
+                let b = self.pop_type();
+                let a = self.pop_type();
+                self.push(Value::Bool(a == b));
+            }
+            Instruction::PrintType => {
+                let typ = self.pop_type();
+                self.print(typ)?;
             }
-            Instruction::Println if generics[0] == Type::F32 => {
-                println!("{}", self.pop_f32());
+            Instruction::Print => {
+                let value = self.pop();
+                self.print(value)?;
+            }
+            Instruction::Println => {
+                let value = self.pop();
+                self.print(format_args!("{value}\n"))?;
             }
-            Instruction::Print => print!("{}", self.pop_i32()),
-            Instruction::Println => println!("{}", self.pop_i32()),
             #[expect(
                 clippy::cast_sign_loss,
                 reason = "Spackel doesn't have an unsigned integer type"
             )]
-            Instruction::PrintChar => print!(
-                "{}",
-                (self.pop_i32() as u32)
+            Instruction::PrintChar => {
+                let c = (self.pop_i32() as u32)
                     .try_into()
-                    .unwrap_or(char::REPLACEMENT_CHARACTER)
-            ),
-            Instruction::BinMathOp(op)
+                    .unwrap_or(char::REPLACEMENT_CHARACTER);
+                self.print(c)?;
+            }
+            Instruction::ReadChar => {
+                let c = self.read_char();
+                self.push(Value::I32(c.map_or(0, |c| c as i32)));
+                self.push(Value::Bool(c.is_some()));
+            }
+            Instruction::ReadI32 => {
+                let n = self.read_line().and_then(|line| {
+                    std::str::from_utf8(&line)
+                        .ok()?
+                        .trim_matches(|c: char| c.is_ascii_whitespace())
+                        .parse()
+                        .ok()
+                });
+                self.push(Value::I32(n.unwrap_or(0)));
+                self.push(Value::Bool(n.is_some()));
+            }
+            Instruction::ReadLine => {
+                let line = self.read_line();
+                self.push(Value::Bool(line.is_some()));
+            }
+            Instruction::Exit => return Err(Halt::Exit(self.pop_i32())),
+            Instruction::Assert(span) => {
+                if !self.pop_bool() {
+                    return Err(Halt::AssertionFailed(*span));
+                }
+            }
+            Instruction::BinMathOp(op, _)
                 if generics.first() == Some(&Type::F32) =>
             {
                 let b = self.pop_f32();
@@ -135,39 +603,78 @@ impl Interpreter<'_> {
                     BinMathOp::Sub => a - b,
                     BinMathOp::Mul => a * b,
                     BinMathOp::Div => a / b,
-                    BinMathOp::Rem | BinMathOp::SillyAdd => unreachable!(),
+                    BinMathOp::SillyAdd => ir::silly_add_f32(a, b),
+                    _ => unreachable!(),
                 }));
             }
-            Instruction::BinMathOp(op) => {
+            Instruction::BinMathOp(op, span) => {
                 let b = self.pop_i32();
                 let a = self.pop_i32();
                 self.push(Value::I32(match op {
-                    BinMathOp::Add => a + b,
-                    BinMathOp::Sub => a - b,
-                    BinMathOp::Mul => a * b,
-                    BinMathOp::Div => a / b,
-                    BinMathOp::Rem => a % b,
+                    BinMathOp::Add | BinMathOp::WrappingAdd => {
+                        a.wrapping_add(b)
+                    }
+                    BinMathOp::Sub | BinMathOp::WrappingSub => {
+                        a.wrapping_sub(b)
+                    }
+                    BinMathOp::Mul | BinMathOp::WrappingMul => {
+                        a.wrapping_mul(b)
+                    }
+                    BinMathOp::Div => divide(a, b, i32::checked_div, *span)?,
+                    BinMathOp::Rem => divide(a, b, i32::checked_rem, *span)?,
                     BinMathOp::SillyAdd => match (a, b) {
                         (9, 10) | (10, 9) => 21,
                         (1, 1) => 1,
-                        _ => a + b,
+                        _ => a.wrapping_add(b),
                     },
+                    BinMathOp::SaturatingAdd => a.saturating_add(b),
+                    BinMathOp::SaturatingSub => a.saturating_sub(b),
+                    BinMathOp::SaturatingMul => a.saturating_mul(b),
                 }));
             }
             Instruction::Sqrt => {
                 let n = self.pop_f32();
                 self.push(Value::F32(n.sqrt()));
             }
+            Instruction::Round(rounding) => {
+                let n = self.pop_f32();
+                self.push(Value::F32(rounding.apply(n)));
+            }
+            Instruction::I32ToF32 => {
+                let n = self.pop_i32();
+                self.push(Value::F32(ir::i32_to_f32(n)));
+            }
+            Instruction::F32ToI32(rounding) => {
+                let n = self.pop_f32();
+                self.push(Value::I32(rounding.to_i32(n)));
+            }
+            Instruction::Math(function)
+                if generics.first() == Some(&Type::I32) =>
+            {
+                let mut operands = (0..function.arity())
+                    .map(|_| self.pop_i32())
+                    .collect::<Vec<_>>();
+                operands.reverse();
+                self.push(Value::I32(function.apply_i32(&operands)));
+            }
+            Instruction::Math(function) => {
+                let mut operands = (0..function.arity())
+                    .map(|_| self.pop_f32())
+                    .collect::<Vec<_>>();
+                operands.reverse();
+                self.push(Value::F32(function.apply_f32(&operands)));
+            }
+            Instruction::Comparison(comparison)
+                if generics.first() == Some(&Type::F32) =>
+            {
+                let b = self.pop_f32();
+                let a = self.pop_f32();
+                self.push(Value::Bool(comparison.apply(a, b)));
+            }
             Instruction::Comparison(comparison) => {
                 let b = self.pop_i32();
                 let a = self.pop_i32();
-                self.push(Value::Bool(match comparison {
-                    Comparison::Lt => a < b,
-                    Comparison::Le => a <= b,
-                    Comparison::Eq => a == b,
-                    Comparison::Ge => a >= b,
-                    Comparison::Gt => a > b,
-                }));
+                self.push(Value::Bool(comparison.apply(a, b)));
             }
             Instruction::Not => {
                 let b = self.pop_bool();
@@ -185,7 +692,57 @@ impl Interpreter<'_> {
                     BinLogicOp::Xnor => !(a ^ b),
                 }));
             }
-            Instruction::AddrOf | Instruction::ReadPtr => todo!(),
+            Instruction::BitNot => {
+                let n = self.pop_i32();
+                self.push(Value::I32(!n));
+            }
+            Instruction::BitwiseOp(op) => {
+                let b = self.pop_i32();
+                let a = self.pop_i32();
+                self.push(Value::I32(op.apply(a, b)));
+            }
+            Instruction::AddrOf => return Err(Halt::Unsupported("addr-of")),
+            Instruction::ReadPtr => return Err(Halt::Unsupported("read-ptr")),
+            Instruction::ReadSmall(int) => {
+                return Err(Halt::Unsupported(int.read_word()))
+            }
+            Instruction::WritePtr => {
+                return Err(Halt::Unsupported("write-ptr"))
+            }
+            Instruction::WriteSmall(int) => {
+                return Err(Halt::Unsupported(int.write_word()))
+            }
+            Instruction::MemCopy => return Err(Halt::Unsupported("copy")),
+            Instruction::MemFill => return Err(Halt::Unsupported("fill")),
+            Instruction::MemCompare => {
+                return Err(Halt::Unsupported("compare"))
+            }
+            Instruction::Null(_) => return Err(Halt::Unsupported("null")),
+            Instruction::PtrAdd => return Err(Halt::Unsupported("ptr+")),
+            Instruction::PtrSub => return Err(Halt::Unsupported("ptr-")),
+            Instruction::PtrDiff => return Err(Halt::Unsupported("ptr-diff")),
+            #[expect(
+                clippy::cast_possible_truncation,
+                clippy::cast_possible_wrap,
+                reason = "there can't be that many arguments"
+            )]
+            Instruction::ArgCount => {
+                self.push(Value::I32(self.args.len() as i32));
+            }
+            Instruction::Load(name) => {
+                self.push(self.state.variables[&**name].clone());
+            }
+            Instruction::Store(name) => {
+                let value = self.pop();
+                *self.state.variables.get_mut(&**name).unwrap() = value;
+            }
+            Instruction::Arg => return Err(Halt::Unsupported("arg")),
+            // Without `open`, there is no way to get a file in the first
+            // place.
+            Instruction::Open
+            | Instruction::Read
+            | Instruction::Write
+            | Instruction::Close => return Err(Halt::Unsupported("open")),
             Instruction::Drop => {
                 self.pop();
             }
@@ -219,6 +776,49 @@ impl Interpreter<'_> {
                 self.push(a);
                 self.push(b);
             }
+            Instruction::Rot => {
+                let c = self.pop();
+                let b = self.pop();
+                let a = self.pop();
+                self.push(b);
+                self.push(c);
+                self.push(a);
+            }
+            Instruction::Pick(depth) => {
+                let index = self.state.stack.len() - 1 - usize::from(*depth);
+                self.push(self.state.stack[index].clone());
+            }
+            Instruction::Roll(depth) => {
+                let index = self.state.stack.len() - 1 - usize::from(*depth);
+                let v = self.state.stack.remove(index);
+                self.push(v);
+            }
+            Instruction::Bury(depth) => {
+                let v = self.pop();
+                let index = self.state.stack.len() - usize::from(*depth);
+                self.state.stack.insert(index, v);
+            }
+            Instruction::RotBack => {
+                let c = self.pop();
+                let b = self.pop();
+                let a = self.pop();
+                self.push(c);
+                self.push(a);
+                self.push(b);
+            }
         }
+        Ok(Flow::Normal)
+    }
+}
+
+fn divide(
+    a: i32,
+    b: i32,
+    op: fn(i32, i32) -> Option<i32>,
+    span: Span,
+) -> Result<i32, Halt> {
+    if b == 0 {
+        return Err(Halt::DivisionByZero(span));
     }
+    op(a, b).ok_or(Halt::DivisionOverflow(span))
 }
@@ -1,17 +1,159 @@
-use crate::{lexer::Token, typ::Type, unicode::prettify_token};
+use crate::{
+    diagnostics::Diagnostic, lexer::Token, typ::Type, unicode::prettify_token,
+};
 use codemap::Span;
-use std::collections::BTreeMap;
+use std::{collections::BTreeMap, sync::Arc};
 
+#[derive(Debug)]
 pub struct Program<'src> {
     pub functions: BTreeMap<&'src str, Function>,
+    /// Structs and enums.
+    pub types: BTreeMap<&'src str, TypeDefinition<'src>>,
+    pub macros: BTreeMap<&'src str, Macro>,
+    /// `const name body end`, where the body computes the value.
+    pub constants: BTreeMap<&'src str, Global>,
+    /// `var name type end`.
+    pub variables: BTreeMap<&'src str, Global>,
+    /// `data name [ elements ] end`, whose body is the elements.
+    pub data: BTreeMap<&'src str, Global>,
+    pub extern_functions: BTreeMap<&'src str, ExternFunction>,
+    /// By their names, quotes included.
+    pub tests: BTreeMap<&'src str, Test>,
+    /// Warnings found while parsing, which the type checker adds to.
+    pub warnings: Vec<Diagnostic>,
+    /// The files that the program was parsed from, so that errors can quote
+    /// it.
+    pub files: Box<[Arc<codemap::File>]>,
 }
 
+impl Program<'_> {
+    /// Where the function, type, constant, variable, data table or extern
+    /// function with the name is defined, not counting macros.
+    pub fn declaration_span(&self, name: &str) -> Option<Span> {
+        self.functions
+            .get(name)
+            .map(|function| function.declaration_span)
+            .or_else(|| {
+                self.types
+                    .get(name)
+                    .map(|definition| definition.declaration_span)
+            })
+            .or_else(|| {
+                self.constants
+                    .get(name)
+                    .map(|constant| constant.declaration_span)
+            })
+            .or_else(|| {
+                self.variables
+                    .get(name)
+                    .map(|variable| variable.declaration_span)
+            })
+            .or_else(|| self.data.get(name).map(|data| data.declaration_span))
+            .or_else(|| {
+                self.extern_functions
+                    .get(name)
+                    .map(|function| function.declaration_span)
+            })
+    }
+}
+
+/// `extern fn name : parameters → returns end`, a C function that is linked
+/// in from elsewhere.
+#[derive(Debug)]
+pub struct ExternFunction {
+    pub declaration_span: Span,
+    pub abi: Abi,
+    pub parameters: Box<Block>,
+    pub returns: Box<Block>,
+}
+
+/// The calling convention of an extern function, written in quotes after
+/// `extern`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Abi {
+    /// The C calling convention of the target, which is the default.
+    C,
+    /// The calling convention of the operating system's API, which is the
+    /// same as C's on every supported target.
+    System,
+    /// The System V calling convention for `x86_64`, used by everything but
+    /// Windows.
+    SysV64,
+    /// The Windows calling convention for `x86_64`.
+    Fastcall,
+}
+
+impl Abi {
+    pub const ALL: [Self; 4] =
+        [Self::C, Self::System, Self::SysV64, Self::Fastcall];
+
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::C => "C",
+            Self::System => "system",
+            Self::SysV64 => "sysv64",
+            Self::Fastcall => "fastcall",
+        }
+    }
+}
+
+/// A constant, a variable or a data table, whose body is its value, its type
+/// or its elements respectively.
+#[derive(Debug)]
+pub struct Global {
+    pub declaration_span: Span,
+    pub body: Box<Block>,
+}
+
+#[derive(Debug)]
 pub struct Function {
     pub declaration_span: Span,
+    pub doc: Box<str>,
     pub parameters: Box<Block>,
     pub returns: Box<Block>,
     pub body: Box<Block>,
     pub end_span: Span,
+    /// Whether the function is marked `inline`, making the optimizer inline
+    /// it no matter how large it is.
+    pub inline: bool,
+    /// Whether the function is declared with `unsafe fn`.
+    pub is_unsafe: bool,
+}
+
+#[derive(Debug)]
+pub struct TypeDefinition<'src> {
+    pub declaration_span: Span,
+    pub is_enum: bool,
+    /// Whether the struct is declared with `extern struct`, which lays it out
+    /// like C does so that pointers to it can be passed to C.
+    pub is_extern: bool,
+    /// The fields of a struct or the variants of an enum.
+    pub fields: Box<[Field<'src>]>,
+}
+
+/// A `name:type` field, where the type may be followed by `ptr`s. Enum
+/// variants without a payload are just `name`, with an empty type.
+#[derive(Debug)]
+pub struct Field<'src> {
+    pub name: &'src str,
+    pub typ: Box<Block>,
+}
+
+/// `test "name" body end`, which only `spackel test` checks and runs. The body
+/// must leave `true` on the stack for the test to pass.
+#[derive(Debug)]
+pub struct Test {
+    pub declaration_span: Span,
+    pub body: Box<Block>,
+    pub end_span: Span,
+}
+
+/// What remains of a macro after expansion, kept around for documentation.
+#[derive(Debug)]
+pub struct Macro {
+    pub declaration_span: Span,
+    pub doc: Box<str>,
+    pub body: Box<str>,
 }
 
 pub type Block<T = Span> = [(Instruction<T>, T)];
@@ -21,57 +163,363 @@ pub enum Instruction<T = Span> {
     Call(Box<str>),
     Then(Box<Block<T>>),
     ThenElse(Box<Block<T>>, Box<Block<T>>),
-    Repeat { body: Box<Block<T>>, end_span: Span },
+    /// Pops an enum and runs the case for its variant with the payload on
+    /// the stack. After type checking, there is one case for every variant,
+    /// in order, and the tag is popped instead.
+    Match(Box<[Case<T>]>),
+    /// `case 0 => ... 1 => ... else ... end`: pops an `i32` and runs the arm
+    /// for its value, or `else_` if no arm has it.
+    Switch {
+        arms: Box<[Arm<T>]>,
+        else_: Box<Block<T>>,
+    },
+    Repeat {
+        body: Box<Block<T>>,
+        end_span: Span,
+        label: Option<Box<str>>,
+    },
+    While {
+        condition: Box<Block<T>>,
+        body: Box<Block<T>>,
+        do_span: Span,
+        label: Option<Box<str>>,
+    },
+    Times {
+        body: Box<Block<T>>,
+        pushes_index: bool,
+        /// The `times` or `for` keyword.
+        span: Span,
+        label: Option<Box<str>>,
+    },
     Unsafe(Box<Block<T>>),
+    /// Pushes a pointer to an anonymous function. After type checking, this
+    /// always has a body and the generic is the type of the quotation.
+    Quotation(Box<Quotation<T>>),
+    /// `call`: pops a quotation and runs it.
+    CallQuotation,
+    /// `[ parameters → returns ] invoke`: like `call`, but with the signature
+    /// of the quotation written out. The type checker turns it into `call`.
+    Invoke(Box<Quotation<T>>),
+    /// Pushes a pointer to a function, which is what the type checker turns
+    /// `&name` into.
+    FunctionPointer(Box<str>),
+    /// Pushes a pointer to the first element of a data table, which is what
+    /// the type checker turns its name into along with its length.
+    Data(Box<str>),
+    /// The expansion of a large macro, which the type checker may turn into a
+    /// call to a function made from the macro.
+    MacroExpansion {
+        name: Box<str>,
+        body: Box<Block<T>>,
+    },
+    /// Marks that the instructions after it come from the word at the span.
+    /// Only inserted by the type checker for `--emit annotated`.
+    Location(Span),
+    /// Runs quotations with values moved out of the way or copied, which the
+    /// type checker lowers to `call`s.
+    Combinator(Combinator),
+    /// A secondary stack that exists within `body`.
+    Stash {
+        name: Box<str>,
+        body: Box<Block<T>>,
+    },
+    /// `let names in body end`: pops one element for each name, the top one
+    /// going to the last name, which `body` can then refer to by name.
+    Let {
+        names: Box<[Box<str>]>,
+        body: Box<Block<T>>,
+    },
+    /// A use of a name bound by `let`, which pushes a copy of its element.
+    Local(Box<str>),
+    /// A call to a function declared with `extern fn`, which is what the type
+    /// checker turns calls to them into.
+    CallExtern(Box<str>, Abi),
+    /// `name load`: pushes the value of a variable.
+    Load(Box<str>),
+    /// `name store`: pops a new value for a variable.
+    Store(Box<str>),
+    /// `>name`: moves the top element onto a stash.
+    ToStash(Box<str>),
+    /// `name>`: moves the top element of a stash onto the stack.
+    FromStash(Box<str>),
+    /// `break` or `break:label`. The type checker sets `depth` to how many
+    /// loops around the innermost one are left as well, which is the loop
+    /// with the label if there is one.
+    Break {
+        label: Option<Box<str>>,
+        depth: usize,
+    },
+    /// `continue` or `continue:label`, with `depth` like for `break`.
+    Continue {
+        label: Option<Box<str>>,
+        depth: usize,
+    },
+    /// Leaves the function early with the values on the stack as its outputs.
+    Return,
     PushI32(i32),
     PushF32(f32),
     PushBool(bool),
     PushType(Type),
     Ptr,
     TypeOf,
+    TypeEq,
+    PrintType,
     Print,
     Println,
     PrintChar,
-    BinMathOp(BinMathOp),
+    ReadChar,
+    ReadI32,
+    ReadLine,
+    ArgCount,
+    Arg,
+    Open,
+    Read,
+    Write,
+    Close,
+    Assert(Span),
+    Exit,
+    /// The span is where runtime errors such as division by zero are
+    /// reported.
+    BinMathOp(BinMathOp, Span),
     Sqrt,
+    /// Rounds an `f32` to a whole number.
+    Round(Rounding),
+    I32ToF32,
+    /// Rounds an `f32` and converts it to the nearest `i32`, where NaN
+    /// becomes 0.
+    F32ToI32(Rounding),
+    Math(MathFunction),
     Comparison(Comparison),
     Not,
     BinLogicOp(BinLogicOp),
+    BitNot,
+    BitwiseOp(BitwiseOp),
     AddrOf,
     ReadPtr,
+    /// `read-u8`, `read-i8`, `read-u16` and `read-i16`: read a small integer
+    /// through a pointer of any type, extending it to an `i32`.
+    ReadSmall(SmallInt),
+    /// Writes a value through a pointer to its type.
+    WritePtr,
+    /// `write-u8` and friends: write the low bits of an `i32` through a
+    /// pointer of any type.
+    WriteSmall(SmallInt),
+    /// `copy`: copies a number of bytes from the first pointer to the second,
+    /// which must not overlap.
+    MemCopy,
+    /// `fill`: sets a number of bytes starting at a pointer to a byte.
+    MemFill,
+    /// `compare`: compares a number of bytes at two pointers, like `memcmp`.
+    MemCompare,
+    /// `type null`: a null pointer to the type written before it, which the
+    /// type checker leaves out afterwards.
+    Null(Box<Block<T>>),
+    /// `ptr+` and `ptr-`: move a pointer forwards or backwards by a number of
+    /// bytes.
+    PtrAdd,
+    PtrSub,
+    /// `ptr-diff`: the number of bytes from the second pointer to the first.
+    PtrDiff,
     Drop,
     Dup,
     Swap,
     Over,
     Nip,
     Tuck,
+    Rot,
+    RotBack,
+    Pick(u8),
+    Roll(u8),
+    /// Moves the top element down below `n` others, undoing `n roll`. This is
+    /// what the type checker turns `>name` into, since stashes are kept below
+    /// the values that were pushed after them.
+    Bury(u8),
+}
+
+#[derive(Clone, Debug)]
+pub struct Case<T = Span> {
+    pub variant: Box<str>,
+    /// The `case` keyword and the variant name.
+    pub span: Span,
+    pub body: Box<Block<T>>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Arm<T = Span> {
+    pub value: i32,
+    /// The value and the `=>` after it.
+    pub span: Span,
+    pub body: Box<Block<T>>,
+}
+
+/// `[ parameters → returns do body ]`, or just `[ parameters → returns ]` for
+/// the type of such a quotation.
+#[derive(Clone, Debug)]
+pub struct Quotation<T = Span> {
+    pub parameters: Box<Block>,
+    pub returns: Box<Block>,
+    pub body: Option<Box<Block<T>>>,
+    pub end_span: Span,
 }
 
+/// The words that `Instruction::from` turns into something other than a call,
+/// apart from numbers.
+pub const BUILT_IN_WORDS: [&str; 107] = [
+    "true",
+    "false",
+    "i32",
+    "f32",
+    "bool",
+    "type",
+    "file",
+    "ptr",
+    "type-of",
+    "type=",
+    "print-type",
+    "break",
+    "continue",
+    "return",
+    "print",
+    "println",
+    "print-char",
+    "read-char",
+    "read-i32",
+    "read-line",
+    "arg-count",
+    "arg",
+    "open",
+    "read",
+    "write",
+    "close",
+    "assert",
+    "exit",
+    "call",
+    "dip",
+    "keep",
+    "bi",
+    "+",
+    "-",
+    "×",
+    "÷",
+    "%",
+    "+🤡",
+    "+sat",
+    "-sat",
+    "×sat",
+    "+wrap",
+    "-wrap",
+    "×wrap",
+    "√",
+    "round",
+    "floor",
+    "ceil",
+    "trunc",
+    "i32->f32",
+    "f32->i32",
+    "f32->i32-round",
+    "abs",
+    "min",
+    "max",
+    "pow",
+    "sin",
+    "cos",
+    "ln",
+    "exp",
+    "<",
+    "≤",
+    "=",
+    ">",
+    "≥",
+    "¬",
+    "∧",
+    "∨",
+    "⊕",
+    "⊼",
+    "⊽",
+    "⊙",
+    "band",
+    "bor",
+    "bxor",
+    "bnot",
+    "shl",
+    "shr",
+    "sar",
+    "rotl",
+    "rotr",
+    "addr-of",
+    "read-ptr",
+    "read-u8",
+    "read-i8",
+    "read-u16",
+    "read-i16",
+    "write-ptr",
+    "write-u8",
+    "write-i8",
+    "write-u16",
+    "write-i16",
+    "ptr+",
+    "ptr-",
+    "ptr-diff",
+    "copy",
+    "fill",
+    "compare",
+    "ß",
+    "drop",
+    "dup",
+    "swap",
+    "over",
+    "nip",
+    "tuck",
+    "rot",
+    "-rot",
+];
+
 impl From<Token<'_>> for Instruction {
     fn from(token: Token) -> Self {
         match prettify_token(token.text) {
             "true" => Self::PushBool(true),
             "false" => Self::PushBool(false),
             "i32" => Self::PushType(Type::I32),
+            "f32" => Self::PushType(Type::F32),
             "bool" => Self::PushType(Type::Bool),
             "type" => Self::PushType(Type::Type),
+            "file" => Self::PushType(Type::File),
             "ptr" => Self::Ptr,
             "type-of" => Self::TypeOf,
+            "type=" => Self::TypeEq,
+            "print-type" => Self::PrintType,
+            "break" => Self::Break {
+                label: None,
+                depth: 0,
+            },
+            "continue" => Self::Continue {
+                label: None,
+                depth: 0,
+            },
+            "return" => Self::Return,
             "print" => Self::Print,
             "println" => Self::Println,
             "print-char" => Self::PrintChar,
-            "+" => Self::BinMathOp(BinMathOp::Add),
-            "-" => Self::BinMathOp(BinMathOp::Sub),
-            "×" => Self::BinMathOp(BinMathOp::Mul),
-            "÷" => Self::BinMathOp(BinMathOp::Div),
-            "%" => Self::BinMathOp(BinMathOp::Rem),
-            "+🤡" => Self::BinMathOp(BinMathOp::SillyAdd),
-            "√" => Self::Sqrt,
+            "read-char" => Self::ReadChar,
+            "read-i32" => Self::ReadI32,
+            "read-line" => Self::ReadLine,
+            "arg-count" => Self::ArgCount,
+            "arg" => Self::Arg,
+            "open" => Self::Open,
+            "read" => Self::Read,
+            "write" => Self::Write,
+            "close" => Self::Close,
+            "assert" => Self::Assert(token.span),
+            "exit" => Self::Exit,
+            "call" => Self::CallQuotation,
+            "dip" => Self::Combinator(Combinator::Dip),
+            "keep" => Self::Combinator(Combinator::Keep),
+            "bi" => Self::Combinator(Combinator::Bi),
             "<" => Self::Comparison(Comparison::Lt),
             "≤" => Self::Comparison(Comparison::Le),
             "=" => Self::Comparison(Comparison::Eq),
-            ">" => Self::Comparison(Comparison::Ge),
-            "≥" => Self::Comparison(Comparison::Gt),
+            ">" => Self::Comparison(Comparison::Gt),
+            "≥" => Self::Comparison(Comparison::Ge),
             "¬" => Self::Not,
             "∧" => Self::BinLogicOp(BinLogicOp::And),
             "∨" => Self::BinLogicOp(BinLogicOp::Or),
@@ -81,6 +529,21 @@ impl From<Token<'_>> for Instruction {
             "⊙" => Self::BinLogicOp(BinLogicOp::Xnor),
             "addr-of" => Self::AddrOf,
             "read-ptr" => Self::ReadPtr,
+            "read-u8" => Self::ReadSmall(SmallInt::U8),
+            "read-i8" => Self::ReadSmall(SmallInt::I8),
+            "read-u16" => Self::ReadSmall(SmallInt::U16),
+            "read-i16" => Self::ReadSmall(SmallInt::I16),
+            "write-ptr" => Self::WritePtr,
+            "write-u8" => Self::WriteSmall(SmallInt::U8),
+            "write-i8" => Self::WriteSmall(SmallInt::I8),
+            "write-u16" => Self::WriteSmall(SmallInt::U16),
+            "write-i16" => Self::WriteSmall(SmallInt::I16),
+            "ptr+" => Self::PtrAdd,
+            "ptr-" => Self::PtrSub,
+            "ptr-diff" => Self::PtrDiff,
+            "copy" => Self::MemCopy,
+            "fill" => Self::MemFill,
+            "compare" => Self::MemCompare,
             "ß" => Self::PushI32(1945),
             "drop" => Self::Drop,
             "dup" => Self::Dup,
@@ -88,10 +551,13 @@ impl From<Token<'_>> for Instruction {
             "over" => Self::Over,
             "nip" => Self::Nip,
             "tuck" => Self::Tuck,
-            _ =>
-            {
+            "rot" => Self::Rot,
+            "-rot" => Self::RotBack,
+            word => {
                 #[expect(clippy::option_if_let_else, reason = "less readable")]
-                if let Ok(number) = token.parse::<i32>() {
+                if let Some(instruction) = Self::arithmetic(word, token.span) {
+                    instruction
+                } else if let Ok(number) = token.parse::<i32>() {
                     Self::PushI32(number)
                 } else if let Ok(number) = token.parse::<f32>() {
                     Self::PushF32(number)
@@ -103,9 +569,112 @@ impl From<Token<'_>> for Instruction {
     }
 }
 
+impl Instruction {
+    /// The built-in words that compute with numbers.
+    fn arithmetic(word: &str, span: Span) -> Option<Self> {
+        Some(match word {
+            "+" => Self::BinMathOp(BinMathOp::Add, span),
+            "-" => Self::BinMathOp(BinMathOp::Sub, span),
+            "×" => Self::BinMathOp(BinMathOp::Mul, span),
+            "÷" => Self::BinMathOp(BinMathOp::Div, span),
+            "%" => Self::BinMathOp(BinMathOp::Rem, span),
+            "+🤡" => Self::BinMathOp(BinMathOp::SillyAdd, span),
+            "+sat" => Self::BinMathOp(BinMathOp::SaturatingAdd, span),
+            "-sat" => Self::BinMathOp(BinMathOp::SaturatingSub, span),
+            "×sat" => Self::BinMathOp(BinMathOp::SaturatingMul, span),
+            "+wrap" => Self::BinMathOp(BinMathOp::WrappingAdd, span),
+            "-wrap" => Self::BinMathOp(BinMathOp::WrappingSub, span),
+            "×wrap" => Self::BinMathOp(BinMathOp::WrappingMul, span),
+            "√" => Self::Sqrt,
+            "round" => Self::Round(Rounding::Nearest),
+            "floor" => Self::Round(Rounding::Floor),
+            "ceil" => Self::Round(Rounding::Ceil),
+            "trunc" => Self::Round(Rounding::Trunc),
+            "i32->f32" => Self::I32ToF32,
+            "f32->i32" => Self::F32ToI32(Rounding::Trunc),
+            "f32->i32-round" => Self::F32ToI32(Rounding::Nearest),
+            "abs" => Self::Math(MathFunction::Abs),
+            "min" => Self::Math(MathFunction::Min),
+            "max" => Self::Math(MathFunction::Max),
+            "pow" => Self::Math(MathFunction::Pow),
+            "sin" => Self::Math(MathFunction::Sin),
+            "cos" => Self::Math(MathFunction::Cos),
+            "ln" => Self::Math(MathFunction::Ln),
+            "exp" => Self::Math(MathFunction::Exp),
+            "band" => Self::BitwiseOp(BitwiseOp::And),
+            "bor" => Self::BitwiseOp(BitwiseOp::Or),
+            "bxor" => Self::BitwiseOp(BitwiseOp::Xor),
+            "bnot" => Self::BitNot,
+            "shl" => Self::BitwiseOp(BitwiseOp::Shl),
+            "shr" => Self::BitwiseOp(BitwiseOp::Shr),
+            "sar" => Self::BitwiseOp(BitwiseOp::Sar),
+            "rotl" => Self::BitwiseOp(BitwiseOp::Rotl),
+            "rotr" => Self::BitwiseOp(BitwiseOp::Rotr),
+            _ => return None,
+        })
+    }
+}
+
 impl<T> Instruction<T> {
     pub const fn is_unsafe(&self) -> bool {
-        matches!(self, Self::ReadPtr)
+        // `open` reads the path from a pointer.
+        matches!(
+            self,
+            Self::ReadPtr
+                | Self::ReadSmall(_)
+                | Self::WritePtr
+                | Self::WriteSmall(_)
+                | Self::MemCopy
+                | Self::MemFill
+                | Self::MemCompare
+                | Self::Null(_)
+                | Self::PtrAdd
+                | Self::PtrSub
+                | Self::PtrDiff
+                | Self::Open
+        )
+    }
+}
+
+/// An integer type narrower than `i32`, which memory can be accessed as even
+/// though Spackel has no such type on the stack.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SmallInt {
+    U8,
+    I8,
+    U16,
+    I16,
+}
+
+impl SmallInt {
+    pub const fn bits(self) -> u8 {
+        match self {
+            Self::U8 | Self::I8 => 8,
+            Self::U16 | Self::I16 => 16,
+        }
+    }
+
+    /// Whether reading it sign-extends rather than zero-extends.
+    pub const fn is_signed(self) -> bool {
+        matches!(self, Self::I8 | Self::I16)
+    }
+
+    pub const fn read_word(self) -> &'static str {
+        match self {
+            Self::U8 => "read-u8",
+            Self::I8 => "read-i8",
+            Self::U16 => "read-u16",
+            Self::I16 => "read-i16",
+        }
+    }
+
+    pub const fn write_word(self) -> &'static str {
+        match self {
+            Self::U8 => "write-u8",
+            Self::I8 => "write-i8",
+            Self::U16 => "write-u16",
+            Self::I16 => "write-i16",
+        }
     }
 }
 
@@ -117,6 +686,174 @@ pub enum BinMathOp {
     Div,
     Rem,
     SillyAdd,
+    /// Clamps results that don't fit in an `i32` to the nearest one that
+    /// does.
+    SaturatingAdd,
+    SaturatingSub,
+    SaturatingMul,
+    /// Wraps around even with `--overflow-checks`.
+    WrappingAdd,
+    WrappingSub,
+    WrappingMul,
+}
+
+impl BinMathOp {
+    /// Whether the order of the operands doesn't matter.
+    pub const fn is_commutative(self) -> bool {
+        matches!(
+            self,
+            Self::Add
+                | Self::Mul
+                | Self::SillyAdd
+                | Self::SaturatingAdd
+                | Self::SaturatingMul
+                | Self::WrappingAdd
+                | Self::WrappingMul
+        )
+    }
+
+    /// Whether the operation can't stop the program, no matter what the
+    /// operands are.
+    pub const fn is_total(self) -> bool {
+        matches!(
+            self,
+            Self::SaturatingAdd
+                | Self::SaturatingSub
+                | Self::SaturatingMul
+                | Self::WrappingAdd
+                | Self::WrappingSub
+                | Self::WrappingMul
+        )
+    }
+}
+
+/// How to round an `f32` to a whole number.
+#[derive(Clone, Copy, Debug)]
+pub enum Rounding {
+    /// Halfway cases are rounded to the even neighbor.
+    Nearest,
+    Floor,
+    Ceil,
+    /// Rounds towards zero.
+    Trunc,
+}
+
+impl Rounding {
+    pub const fn apply(self, n: f32) -> f32 {
+        match self {
+            Self::Nearest => n.round_ties_even(),
+            Self::Floor => n.floor(),
+            Self::Ceil => n.ceil(),
+            Self::Trunc => n.trunc(),
+        }
+    }
+
+    /// Rounds and converts to an `i32` like `f32->i32` does, saturating at
+    /// the bounds of `i32` and turning NaN into 0.
+    #[expect(
+        clippy::cast_possible_truncation,
+        reason = "`as` saturates, which is what's wanted"
+    )]
+    pub const fn to_i32(self, n: f32) -> i32 {
+        self.apply(n) as i32
+    }
+}
+
+/// Functions that `abs`, `min` and `max` compute for both `i32`s and `f32`s,
+/// and the rest only for `f32`s by calling the C math library.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum MathFunction {
+    Abs,
+    Min,
+    Max,
+    Pow,
+    Sin,
+    Cos,
+    /// The natural logarithm.
+    Ln,
+    Exp,
+}
+
+impl MathFunction {
+    pub const fn arity(self) -> usize {
+        match self {
+            Self::Min | Self::Max | Self::Pow => 2,
+            Self::Abs | Self::Sin | Self::Cos | Self::Ln | Self::Exp => 1,
+        }
+    }
+
+    /// Whether the function also works on `i32`s.
+    pub const fn is_generic(self) -> bool {
+        matches!(self, Self::Abs | Self::Min | Self::Max)
+    }
+
+    /// The absolute value of the smallest `i32` is itself, like in compiled
+    /// programs.
+    pub fn apply_i32(self, operands: &[i32]) -> i32 {
+        match (self, operands) {
+            (Self::Abs, [n]) => n.wrapping_abs(),
+            (Self::Min, [a, b]) => *a.min(b),
+            (Self::Max, [a, b]) => *a.max(b),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Computes the function like compiled programs do, where `min` and `max`
+    /// return NaN if either number is NaN and consider -0 to be less than 0.
+    pub fn apply_f32(self, operands: &[f32]) -> f32 {
+        match (self, operands) {
+            (Self::Abs, [n]) => n.abs(),
+            (Self::Min | Self::Max, [a, b]) if a.is_nan() || b.is_nan() => {
+                f32::NAN
+            }
+            (Self::Min, [a, b]) => {
+                if a.total_cmp(b).is_le() {
+                    *a
+                } else {
+                    *b
+                }
+            }
+            (Self::Max, [a, b]) => {
+                if a.total_cmp(b).is_ge() {
+                    *a
+                } else {
+                    *b
+                }
+            }
+            (Self::Pow, [a, b]) => a.powf(*b),
+            (Self::Sin, [n]) => n.sin(),
+            (Self::Cos, [n]) => n.cos(),
+            (Self::Ln, [n]) => n.ln(),
+            (Self::Exp, [n]) => n.exp(),
+            _ => unreachable!(),
+        }
+    }
+}
+
+/// Adds two `f32`s like `+🤡` does, which is like regular addition except
+/// that 9 + 10 and 10 + 9 are 21 and 1 + 1 is 1.
+pub fn silly_add_f32(a: f32, b: f32) -> f32 {
+    match (a, b) {
+        (9.0, 10.0) | (10.0, 9.0) => 21.0,
+        (1.0, 1.0) => 1.0,
+        _ => a + b,
+    }
+}
+
+/// Converts an `i32` to the nearest `f32`, like `i32->f32` does.
+#[expect(
+    clippy::cast_precision_loss,
+    reason = "large numbers can't be represented exactly"
+)]
+pub const fn i32_to_f32(n: i32) -> f32 {
+    n as f32
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum Combinator {
+    Dip,
+    Keep,
+    Bi,
 }
 
 #[derive(Clone, Copy, Debug)]
@@ -128,6 +865,20 @@ pub enum Comparison {
     Gt,
 }
 
+impl Comparison {
+    /// Compares two `i32`s or two `f32`s, where everything is false if
+    /// either `f32` is NaN.
+    pub fn apply<T: PartialOrd + Copy>(self, a: T, b: T) -> bool {
+        match self {
+            Self::Lt => a < b,
+            Self::Le => a <= b,
+            Self::Eq => a == b,
+            Self::Ge => a >= b,
+            Self::Gt => a > b,
+        }
+    }
+}
+
 #[derive(Clone, Copy, Debug)]
 pub enum BinLogicOp {
     And,
@@ -137,3 +888,44 @@ pub enum BinLogicOp {
     Nor,
     Xnor,
 }
+
+/// Operations on the bits of `i32`s. Shifts and rotations only use the lowest
+/// five bits of the amount, so shifting by 32 does nothing.
+#[derive(Clone, Copy, Debug)]
+pub enum BitwiseOp {
+    And,
+    Or,
+    Xor,
+    Shl,
+    /// Shifts in zeros.
+    Shr,
+    /// Shifts in copies of the sign bit.
+    Sar,
+    Rotl,
+    Rotr,
+}
+
+impl BitwiseOp {
+    #[expect(
+        clippy::cast_sign_loss,
+        clippy::cast_possible_wrap,
+        reason = "only the bits matter"
+    )]
+    pub const fn apply(self, a: i32, b: i32) -> i32 {
+        let amount = b as u32;
+        match self {
+            Self::And => a & b,
+            Self::Or => a | b,
+            Self::Xor => a ^ b,
+            Self::Shl => a.wrapping_shl(amount),
+            Self::Shr => (a as u32).wrapping_shr(amount) as i32,
+            Self::Sar => a.wrapping_shr(amount),
+            Self::Rotl => a.rotate_left(amount),
+            Self::Rotr => a.rotate_right(amount),
+        }
+    }
+
+    pub const fn is_commutative(self) -> bool {
+        matches!(self, Self::And | Self::Or | Self::Xor)
+    }
+}
@@ -0,0 +1,103 @@
+//! The base protocol of the Language Server Protocol, where every JSON-RPC
+//! message is preceded by a `Content-Length` header.
+
+use anyhow::{bail, Context, Result};
+use serde_json::{json, Value};
+use std::io::{BufRead, Write};
+
+/// Reads the next message, or returns `None` at the end of the input. Content
+/// that isn't valid JSON has still been consumed, so reading can go on.
+pub fn read_message(
+    input: &mut impl BufRead,
+) -> Result<Option<serde_json::Result<Value>>> {
+    let mut content_length = None;
+    loop {
+        let mut line = String::new();
+        if input.read_line(&mut line)? == 0 {
+            if content_length.is_some() {
+                bail!("input ended in the middle of a message header");
+            }
+            return Ok(None);
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.eq_ignore_ascii_case("Content-Length") {
+                content_length = Some(
+                    value
+                        .trim()
+                        .parse::<usize>()
+                        .context("invalid `Content-Length` header")?,
+                );
+            }
+        }
+    }
+
+    let content_length =
+        content_length.context("message has no `Content-Length` header")?;
+    let mut content = vec![0; content_length];
+    input
+        .read_exact(&mut content)
+        .context("input ended in the middle of a message")?;
+    Ok(Some(serde_json::from_slice(&content)))
+}
+
+pub fn write_message(output: &mut impl Write, message: &Value) -> Result<()> {
+    let content = message.to_string();
+    write!(output, "Content-Length: {}\r\n\r\n{content}", content.len())?;
+    output.flush()?;
+    Ok(())
+}
+
+pub struct Error {
+    code: i64,
+    message: String,
+}
+
+impl Error {
+    pub fn parse(err: &serde_json::Error) -> Self {
+        Self {
+            code: -32700,
+            message: err.to_string(),
+        }
+    }
+
+    pub fn invalid_request() -> Self {
+        Self {
+            code: -32600,
+            message: "request has no method".to_owned(),
+        }
+    }
+
+    pub fn method_not_found(method: &str) -> Self {
+        Self {
+            code: -32601,
+            message: format!("unknown method {method:?}"),
+        }
+    }
+
+    pub const fn invalid_params(message: String) -> Self {
+        Self {
+            code: -32602,
+            message,
+        }
+    }
+}
+
+/// A message that doesn't expect a response.
+pub fn notification(method: &str, params: &Value) -> Value {
+    json!({ "jsonrpc": "2.0", "method": method, "params": params })
+}
+
+pub fn response(id: &Value, result: Result<Value, Error>) -> Value {
+    match result {
+        Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+        Err(Error { code, message }) => json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "error": { "code": code, "message": message },
+        }),
+    }
+}
@@ -20,24 +20,81 @@ impl Deref for Token<'_> {
     }
 }
 
-pub fn lex(file: &codemap::File) -> impl Iterator<Item = Token> {
-    let source = file.source();
+pub fn lex(file: &codemap::File) -> impl Iterator<Item = Token<'_>> {
+    lex_source(file, file.source())
+}
+
+/// Lexes a copy of the source code of a file, so that the tokens can outlive
+/// the file.
+pub fn lex_source<'a>(
+    file: &codemap::File,
+    source: &'a str,
+) -> impl Iterator<Item = Token<'a>> {
+    debug_assert_eq!(source, file.source());
+    let span = file.span;
     source
         .lines()
-        .flat_map(|line| {
-            line.split_once('#')
-                .map_or(line, |(line, _comment)| line)
-                .split_whitespace()
-        })
-        .map(|text| {
+        .flat_map(split_line)
+        .filter(|text| !text.starts_with('#'))
+        .map(move |text| {
             let begin = text.as_ptr() as usize - source.as_ptr() as usize;
             let end = begin + text.len();
             Token {
                 text,
-                span: file.span.subspan(
+                span: span.subspan(
                     begin.try_into().unwrap(),
                     end.try_into().unwrap(),
                 ),
             }
         })
 }
+
+/// Splits a label such as the `outer` in `repeat:outer` off of a loop keyword,
+/// `break` or `continue`. Other tokens are left as they are.
+pub fn split_label(token: &str) -> (&str, Option<&str>) {
+    match token.split_once(':') {
+        Some((
+            keyword @ ("repeat" | "while" | "times" | "for" | "break"
+            | "continue"),
+            label,
+        )) if !label.is_empty() => (keyword, Some(label)),
+        _ => (token, None),
+    }
+}
+
+/// Splits a line into tokens, ending with the comment if there is one. String
+/// literals are kept as single tokens even if they contain whitespace or `#`.
+pub fn split_line(mut line: &str) -> impl Iterator<Item = &str> {
+    std::iter::from_fn(move || {
+        line = line.trim_start();
+        if line.is_empty() {
+            return None;
+        }
+        let len = if line.starts_with('#') {
+            line.len()
+        } else if let Some(string) = line.strip_prefix('"') {
+            string.find('"').map_or(line.len(), |len| len + 2)
+        } else {
+            line.find(|c: char| c.is_whitespace() || c == '#')
+                .unwrap_or(line.len())
+        };
+        let (token, rest) = line.split_at(len);
+        line = rest;
+        Some(token)
+    })
+}
+
+/// Collects the `##` comments on the lines directly above `line`, without the
+/// leading `##` and at most one space after it.
+pub fn doc_comment(file: &codemap::File, line: usize) -> Box<str> {
+    let mut lines = (0..line)
+        .rev()
+        .map_while(|line| {
+            let text = file.source_line(line).trim();
+            let doc = text.strip_prefix("##")?;
+            Some(doc.strip_prefix(' ').unwrap_or(doc))
+        })
+        .collect::<Vec<_>>();
+    lines.reverse();
+    lines.join("\n").into()
+}
@@ -0,0 +1,274 @@
+#![forbid(unsafe_code)]
+#![deny(
+    clippy::allow_attributes,
+    clippy::allow_attributes_without_reason,
+    clippy::iter_over_hash_type
+)]
+#![warn(clippy::nursery, clippy::pedantic)]
+
+mod call_graph;
+pub mod cli;
+mod compiler;
+mod debug_info;
+mod diagnostics;
+mod diff;
+mod docs;
+mod formatter;
+mod include;
+mod interpreter;
+mod ir;
+mod jsonrpc;
+mod lexer;
+mod lsp;
+mod parser;
+mod project;
+mod repl;
+mod selftest;
+mod server;
+mod shuffle;
+mod ssa;
+mod target;
+mod test_runner;
+mod timing;
+mod typ;
+mod unicode;
+
+use anyhow::{anyhow, bail, Context, Result};
+pub use cli::CrateType;
+use codemap::CodeMap;
+pub use compiler::OptLevel;
+pub use interpreter::Value;
+use std::sync::Arc;
+pub use target::Target;
+pub use typ::CheckedProgram;
+
+const PRELUDE: &str = include_str!("prelude.spkl");
+
+/// Resources that an interpreted program may use before being halted.
+#[derive(Clone, Copy, Debug)]
+pub struct Limits {
+    /// How many instructions may be executed.
+    pub max_steps: u64,
+    /// How deeply function calls may be nested.
+    pub max_call_depth: usize,
+    /// How many bytes may be printed.
+    pub max_output_len: usize,
+}
+
+impl Limits {
+    pub const UNLIMITED: Self = Self {
+        max_steps: u64::MAX,
+        max_call_depth: usize::MAX,
+        max_output_len: usize::MAX,
+    };
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_steps: 10_000_000,
+            max_call_depth: 1000,
+            max_output_len: 1 << 16,
+        }
+    }
+}
+
+/// Everything that came out of running a program with [`run_source`].
+#[derive(Debug)]
+pub struct RunResult {
+    /// What the program printed, even if it was halted.
+    pub stdout: String,
+    /// Rendered errors that prevented the program from running or finishing.
+    pub diagnostics: Vec<String>,
+    /// The stack when the program finished or was halted, bottom first.
+    pub final_stack: Vec<Value>,
+    /// The code the program passed to `exit`, or otherwise 0 if it finished
+    /// and 1 if it didn't.
+    pub exit_code: i32,
+}
+
+/// Interprets a program given as source code, along with the prelude, with
+/// `stdin` as its input. Nothing is read from or written to the file system,
+/// so `include` is an error.
+#[must_use]
+pub fn run_source(source_code: &str, stdin: &str, limits: Limits) -> RunResult {
+    let mut code_map = CodeMap::new();
+    let mut output = Vec::new();
+    let mut final_stack = Vec::new();
+    let mut exit_code = 0;
+    let result =
+        run_in_sandbox(&mut code_map, source_code, stdin, limits, &mut output)
+            .and_then(|(result, stack)| {
+                final_stack = stack;
+                match result {
+                    Err(interpreter::Halt::Exit(code)) => exit_code = code,
+                    result => result.map_err(interpreter::Halt::into_error)?,
+                }
+                Ok(())
+            });
+    if result.is_err() {
+        exit_code = 1;
+    }
+    RunResult {
+        stdout: String::from_utf8_lossy(&output).into_owned(),
+        diagnostics: result
+            .err()
+            .map(|err| diagnostics::render(&err, &code_map))
+            .unwrap_or_default(),
+        final_stack,
+        exit_code,
+    }
+}
+
+/// How [`compile_str`] compiles a program, which matches the command line
+/// flags of the same names.
+#[derive(Clone, Copy, Debug)]
+#[expect(
+    clippy::struct_excessive_bools,
+    reason = "each of them is a separate command line flag"
+)]
+pub struct Options {
+    pub target: Target,
+    pub opt_level: OptLevel,
+    pub optimize_for_size: bool,
+    pub debug_info: bool,
+    pub overflow_checks: bool,
+    pub check_optimizations: bool,
+    pub crate_type: CrateType,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            target: Target::default(),
+            opt_level: OptLevel::O2,
+            optimize_for_size: false,
+            debug_info: false,
+            overflow_checks: false,
+            check_optimizations: false,
+            crate_type: CrateType::Executable,
+        }
+    }
+}
+
+/// Compiles a program given as source code, along with the prelude, into the
+/// contents of an object file.
+///
+/// Like with [`run_source`], nothing is read from or written to the file
+/// system, so `include` is an error.
+///
+/// # Errors
+///
+/// Invalid programs and targets that can't be compiled for are errors, whose
+/// messages are the rendered diagnostics.
+pub fn compile_str(source_code: &str, options: &Options) -> Result<Vec<u8>> {
+    let flags = cli::Flags {
+        target: options.target,
+        opt_level: options.opt_level,
+        optimize_for_size: options.optimize_for_size,
+        debug_info: options.debug_info,
+        overflow_checks: options.overflow_checks,
+        check_optimizations: options.check_optimizations,
+        crate_type: options.crate_type,
+        ..cli::Flags::default()
+    };
+    let mut code_map = CodeMap::new();
+    add_source_files(&mut code_map, source_code)
+        .and_then(|files| Ok(cli::compile(&files, &flags)?.0.object))
+        .map_err(|err| anyhow!(diagnostics::render(&err, &code_map).concat()))
+}
+
+/// Type checks a program given as source code, along with the prelude,
+/// without touching the file system.
+///
+/// # Errors
+///
+/// Invalid programs are errors, whose messages are the rendered diagnostics.
+pub fn check(source_code: &str) -> Result<CheckedProgram<'_>> {
+    let mut code_map = CodeMap::new();
+    check_in(&mut code_map, source_code)
+        .map_err(|err| anyhow!(diagnostics::render(&err, &code_map).concat()))
+}
+
+/// Type checks a program given as source code, returning each rendered
+/// diagnostic.
+fn check_source(source_code: &str) -> Vec<String> {
+    let mut code_map = CodeMap::new();
+    check_in(&mut code_map, source_code)
+        .err()
+        .map(|err| diagnostics::render(&err, &code_map))
+        .unwrap_or_default()
+}
+
+fn check_in<'src>(
+    code_map: &mut CodeMap,
+    source_code: &'src str,
+) -> Result<CheckedProgram<'src>> {
+    let files = add_source_files(code_map, source_code)?;
+    typ::check(parser::parse_sources(&files, &[PRELUDE, source_code])?)
+}
+
+/// Adds the prelude and a program given as source code. Since there is no file
+/// system to include files from, `include` is an error.
+fn add_source_files(
+    code_map: &mut CodeMap,
+    source_code: &str,
+) -> Result<[Arc<codemap::File>; 2]> {
+    let files = [
+        code_map.add_file("<prelude>".to_owned(), PRELUDE.to_owned()),
+        code_map.add_file("<input>".to_owned(), source_code.to_owned()),
+    ];
+    let includes = include::directives(&files[1])?;
+    if let Some((span, _)) = includes.first() {
+        bail!(diagnostics::error(
+            "files cannot be included without a file system".to_owned(),
+            vec![diagnostics::primary_label(*span, "")],
+        ));
+    }
+    Ok(files)
+}
+
+fn run_in_sandbox(
+    code_map: &mut CodeMap,
+    source_code: &str,
+    stdin: &str,
+    limits: Limits,
+    output: &mut Vec<u8>,
+) -> Result<(Result<(), interpreter::Halt>, Vec<Value>)> {
+    let files = add_source_files(code_map, source_code)?;
+    let program = parser::parse(&files)?;
+    let program = typ::check(program)?;
+
+    // Each nested call takes up a few kilobytes of native stack, so the
+    // interpreter gets a thread with enough of it for the call depth limit.
+    let stack_size = limits
+        .max_call_depth
+        .saturating_mul(16 << 10)
+        .min(1 << 30)
+        .saturating_add(1 << 20);
+    std::thread::scope(|scope| {
+        let interpreter = std::thread::Builder::new()
+            .stack_size(stack_size)
+            .spawn_scoped(scope, || {
+                interpreter::interpret(
+                    &program,
+                    &["<input>".to_owned()],
+                    &mut stdin.as_bytes(),
+                    output,
+                    &limits,
+                )
+            })
+            .context("failed to start the interpreter")?;
+        Ok(interpreter
+            .join()
+            .unwrap_or_else(|panic| std::panic::resume_unwind(panic)))
+    })
+}
+
+/// A hash of some text that stays the same between runs and platforms, unlike
+/// `DefaultHasher`. This is 64-bit FNV-1a.
+fn stable_hash(text: &str) -> u64 {
+    text.bytes().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
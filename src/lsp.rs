@@ -0,0 +1,457 @@
+//! `spackel lsp`, a language server for editors. It checks documents as they
+//! change, shows the stack effects of words on hover, along with the layouts of
+//! extern structs, goes to the definitions of functions and macros and
+//! completes built-in words.
+
+use crate::{
+    diagnostics::{self, Diagnostic, LintLevels},
+    include,
+    ir::{Program, BUILT_IN_WORDS},
+    jsonrpc::{self, Error},
+    lexer::{self, Token},
+    parser, repl,
+    typ::{self, Type},
+    PRELUDE,
+};
+use anyhow::Result;
+use codemap::{CodeMap, File, Pos, Span};
+use codemap_diagnostic::{Level, SpanStyle};
+use serde_json::{json, Value};
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+/// Answers an editor on stdin and stdout until it sends an `exit`
+/// notification or the input ends.
+pub fn serve() -> Result<()> {
+    let mut input = std::io::stdin().lock();
+    let mut output = std::io::stdout().lock();
+    let mut server = Server::default();
+
+    while let Some(message) = jsonrpc::read_message(&mut input)? {
+        let message = match message {
+            Ok(message) => message,
+            Err(err) => {
+                let response =
+                    jsonrpc::response(&Value::Null, Err(Error::parse(&err)));
+                jsonrpc::write_message(&mut output, &response)?;
+                continue;
+            }
+        };
+        let method = message.get("method").and_then(Value::as_str);
+        let params = &message["params"];
+        if let Some(id) = message.get("id") {
+            let result = method.map_or_else(
+                || Err(Error::invalid_request()),
+                |method| server.handle(method, params),
+            );
+            jsonrpc::write_message(
+                &mut output,
+                &jsonrpc::response(id, result),
+            )?;
+        } else if method == Some("exit") {
+            break;
+        } else if let Some(notification) =
+            method.and_then(|method| server.notify(method, params))
+        {
+            jsonrpc::write_message(&mut output, &notification)?;
+        }
+    }
+    Ok(())
+}
+
+#[derive(Default)]
+struct Server {
+    /// The text of each open document, by URI.
+    documents: BTreeMap<String, String>,
+}
+
+impl Server {
+    fn handle(&self, method: &str, params: &Value) -> Result<Value, Error> {
+        match method {
+            "initialize" => Ok(json!({
+                "capabilities": {
+                    // The whole document is sent on every change.
+                    "textDocumentSync": 1,
+                    "hoverProvider": true,
+                    "definitionProvider": true,
+                    "completionProvider": {},
+                },
+                "serverInfo": { "name": "spackel" },
+            })),
+            "textDocument/hover" => {
+                let (uri, text) = self.document(params)?;
+                Ok(hover(uri, text, params).unwrap_or(Value::Null))
+            }
+            "textDocument/definition" => {
+                let (uri, text) = self.document(params)?;
+                Ok(definition(uri, text, params).unwrap_or(Value::Null))
+            }
+            "textDocument/completion" => {
+                let (uri, text) = self.document(params)?;
+                Ok(completion(uri, text))
+            }
+            "shutdown" => Ok(Value::Null),
+            _ => Err(Error::method_not_found(method)),
+        }
+    }
+
+    /// Handles a notification, which may call for the diagnostics of a
+    /// document to be published.
+    fn notify(&mut self, method: &str, params: &Value) -> Option<Value> {
+        let document = params.get("textDocument")?;
+        let uri = document.get("uri")?.as_str()?;
+        let diagnostics = match method {
+            "textDocument/didOpen" => {
+                let text = document.get("text")?.as_str()?;
+                self.documents.insert(uri.to_owned(), text.to_owned());
+                check(uri, text)
+            }
+            "textDocument/didChange" => {
+                let text = params
+                    .get("contentChanges")?
+                    .as_array()?
+                    .last()?
+                    .get("text")?
+                    .as_str()?;
+                self.documents.insert(uri.to_owned(), text.to_owned());
+                check(uri, text)
+            }
+            // The diagnostics of closed documents are cleared.
+            "textDocument/didClose" => {
+                self.documents.remove(uri);
+                Vec::new()
+            }
+            _ => return None,
+        };
+        Some(jsonrpc::notification(
+            "textDocument/publishDiagnostics",
+            &json!({ "uri": uri, "diagnostics": diagnostics }),
+        ))
+    }
+
+    /// The URI and text of the open document that a request is about.
+    fn document<'a>(
+        &'a self,
+        params: &'a Value,
+    ) -> Result<(&'a str, &'a str), Error> {
+        let uri = params
+            .get("textDocument")
+            .and_then(|document| document.get("uri"))
+            .and_then(Value::as_str)
+            .ok_or_else(|| {
+                Error::invalid_params(
+                    "`textDocument.uri` must be a string".to_owned(),
+                )
+            })?;
+        let text = self.documents.get(uri).ok_or_else(|| {
+            Error::invalid_params(format!("document {uri:?} isn't open"))
+        })?;
+        Ok((uri, text))
+    }
+}
+
+/// Loads a document along with the prelude and the files that it includes,
+/// which come from disk. The document is the last file.
+fn load(
+    code_map: &mut CodeMap,
+    uri: &str,
+    text: &str,
+) -> Result<Vec<Arc<File>>> {
+    let mut files =
+        vec![code_map.add_file("<prelude>".to_owned(), PRELUDE.to_owned())];
+    files.extend(include::load_source(
+        code_map,
+        &document_path(uri),
+        text.to_owned(),
+    )?);
+    Ok(files)
+}
+
+/// Type checks a document, returning its errors or warnings as LSP
+/// diagnostics. Documents don't need a `main` function since they may be
+/// included by others.
+fn check(uri: &str, text: &str) -> Vec<Value> {
+    let mut code_map = CodeMap::new();
+    let result = load(&mut code_map, uri, text).and_then(|files| {
+        let mut program = typ::check_library(parser::parse(&files)?, false)?;
+        Ok(LintLevels::default().apply(
+            std::mem::take(&mut program.warnings),
+            Some(files[0].span),
+        )?)
+    });
+    let diagnostics = result.unwrap_or_else(|err| {
+        err.downcast::<diagnostics::Error>().map_or_else(
+            |err| diagnostics::error(format!("{err:#}"), Vec::new()).0,
+            |err| err.0,
+        )
+    });
+    let document = document_path(uri).display().to_string();
+    diagnostics
+        .iter()
+        .map(|diagnostic| to_lsp(diagnostic, &code_map, uri, &document))
+        .collect()
+}
+
+/// Converts a diagnostic for a document. Diagnostics that are about other
+/// files or no place at all are put at the start of the document, and
+/// secondary labels are only kept if they are in the document.
+fn to_lsp(
+    diagnostic: &Diagnostic,
+    code_map: &CodeMap,
+    uri: &str,
+    document: &str,
+) -> Value {
+    let mut message = diagnostic.message.clone();
+    let mut range = json!({
+        "start": { "line": 0, "character": 0 },
+        "end": { "line": 0, "character": 0 },
+    });
+    let mut related_information = Vec::new();
+    for label in &diagnostic.spans {
+        let file = code_map.find_file(label.span.low());
+        let text = label.label.as_deref().unwrap_or_default();
+        if label.style == SpanStyle::Primary {
+            if !text.is_empty() {
+                write!(message, "\n{text}").unwrap();
+            }
+            if file.name() == document {
+                range = lsp_range(file, label.span);
+            } else {
+                let location = code_map.look_up_span(label.span);
+                write!(message, "\nat {location}").unwrap();
+            }
+        } else if file.name() == document {
+            related_information.push(json!({
+                "location": { "uri": uri, "range": lsp_range(file, label.span) },
+                "message": text,
+            }));
+        }
+    }
+    for note in &diagnostic.notes {
+        write!(message, "\nnote: {note}").unwrap();
+    }
+    let severity = match diagnostic.severity {
+        Level::Bug | Level::Error => 1,
+        Level::Warning => 2,
+        Level::Note => 3,
+        Level::Help => 4,
+    };
+    let mut diagnostic_json = json!({
+        "range": range,
+        "severity": severity,
+        "source": "spackel",
+        "message": message,
+        "relatedInformation": related_information,
+    });
+    if let Some(lint) = diagnostic.lint {
+        diagnostic_json["code"] = lint.name().into();
+    }
+    diagnostic_json
+}
+
+/// The stack effects of the word at a position, along with its documentation
+/// if it is a function or a macro and where its fields are if it is an extern
+/// struct. Words in documents that don't type check
+/// only get their effects if the prelude is enough for them.
+fn hover(uri: &str, text: &str, params: &Value) -> Option<Value> {
+    let mut code_map = CodeMap::new();
+    let files = load(&mut code_map, uri, text).ok()?;
+    let document = files.last()?;
+    let word = word_at(document, params)?;
+
+    let effects = repl::effects(&mut code_map, files.clone(), word.to_string())
+        .or_else(|_| {
+            repl::effects(&mut code_map, files[..1].to_vec(), word.to_string())
+        });
+    let program = parser::parse(&files).ok();
+    // Structs with other structs as fields can't be made from the fields
+    // alone, but extern ones still have a layout to show.
+    let layout = program
+        .as_ref()
+        .and_then(|program| describe_layout(program, word.text));
+    if effects.is_err() && layout.is_none() {
+        return None;
+    }
+    let mut contents = String::new();
+    if let Ok(effects) = effects {
+        contents.push_str("```\n");
+        for effect in effects {
+            writeln!(contents, "{word} : {effect}").unwrap();
+        }
+        contents.push_str("```\n\n");
+    }
+    if let Some(program) = &program {
+        let doc = program.macros.get(word.text).map_or_else(
+            || {
+                program
+                    .functions
+                    .get(word.text)
+                    .map(|function| &function.doc)
+            },
+            |macro_| Some(&macro_.doc),
+        );
+        if let Some(doc) = doc.filter(|doc| !doc.is_empty()) {
+            write!(contents, "{doc}\n\n").unwrap();
+        }
+    }
+    contents.extend(layout);
+    Some(json!({
+        "contents": { "kind": "markdown", "value": contents.trim_end() },
+        "range": lsp_range(document, word.span),
+    }))
+}
+
+/// Where the fields of an extern struct are, so that the same struct can be
+/// written in C.
+fn describe_layout(program: &Program, name: &str) -> Option<String> {
+    let Type::Struct(struct_) =
+        typ::resolve_types(program).ok()?.remove(name)?
+    else {
+        return None;
+    };
+    let layout = struct_.layout.as_ref()?;
+    let mut description =
+        format!("size {}, alignment {}\n", layout.size, layout.align);
+    for ((field, typ), offset) in struct_.fields.iter().zip(&layout.offsets) {
+        write!(description, "\n- `{field}:{typ}` at offset {offset}").unwrap();
+    }
+    Some(description)
+}
+
+/// Where the macro, function or other definition named by the word at a
+/// position is. Nothing is found in the prelude since it isn't a file that an
+/// editor could open.
+fn definition(uri: &str, text: &str, params: &Value) -> Option<Value> {
+    let mut code_map = CodeMap::new();
+    let files = load(&mut code_map, uri, text).ok()?;
+    let document = files.last()?;
+    let word = word_at(document, params)?;
+    let program = parser::parse(&files).ok()?;
+    let span = program.macros.get(word.text).map_or_else(
+        || program.declaration_span(word.text),
+        |macro_| Some(macro_.declaration_span),
+    )?;
+
+    let file = code_map.find_file(span.low());
+    let uri = if Arc::ptr_eq(file, document) {
+        uri.to_owned()
+    } else if Arc::ptr_eq(file, &files[0]) {
+        return None;
+    } else {
+        path_to_uri(&Path::new(file.name()).canonicalize().ok()?)
+    };
+    Some(json!({ "uri": uri, "range": lsp_range(file, span) }))
+}
+
+/// The built-in words, followed by the functions and macros of the program if
+/// it can be parsed.
+fn completion(uri: &str, text: &str) -> Value {
+    const FUNCTION: u8 = 3;
+    const KEYWORD: u8 = 14;
+
+    let mut items = BUILT_IN_WORDS
+        .iter()
+        .map(|word| json!({ "label": word, "kind": KEYWORD }))
+        .collect::<Vec<_>>();
+    let mut code_map = CodeMap::new();
+    if let Ok(files) = load(&mut code_map, uri, text) {
+        if let Ok(program) = parser::parse(&files) {
+            items.extend(program.functions.keys().map(|name| {
+                json!({ "label": name, "kind": FUNCTION, "detail": "function" })
+            }));
+            items.extend(program.macros.keys().map(|name| {
+                json!({ "label": name, "kind": FUNCTION, "detail": "macro" })
+            }));
+        }
+    }
+    Value::Array(items)
+}
+
+/// The word at the position of a request. The end of a word counts as being
+/// on it, since that is where the cursor is after typing it.
+fn word_at<'a>(file: &'a File, params: &Value) -> Option<Token<'a>> {
+    let position = params.get("position")?;
+    let line = usize::try_from(position.get("line")?.as_u64()?).ok()?;
+    let character = position.get("character")?.as_u64()?;
+    let pos = if line < file.num_lines() {
+        let line_span = file.line_span(line);
+        let mut units = 0;
+        let mut offset = 0;
+        for c in file.source_line(line).chars() {
+            if units >= character {
+                break;
+            }
+            units += c.len_utf16() as u64;
+            offset += c.len_utf8() as u64;
+        }
+        line_span.low() + offset
+    } else {
+        file.span.high()
+    };
+    lexer::lex(file)
+        .find(|token| token.span.low() <= pos && pos <= token.span.high())
+}
+
+fn lsp_range(file: &File, span: Span) -> Value {
+    json!({
+        "start": lsp_position(file, span.low()),
+        "end": lsp_position(file, span.high()),
+    })
+}
+
+/// A position as a line and a column in UTF-16 code units, which is what
+/// LSP clients count in by default.
+fn lsp_position(file: &File, pos: Pos) -> Value {
+    let line = file.find_line(pos);
+    let start = file.line_span(line).low() - file.span.low();
+    let end = pos - file.span.low();
+    #[expect(
+        clippy::cast_possible_truncation,
+        reason = "files are smaller than 4 GiB"
+    )]
+    let character = file.source()[start as usize..end as usize]
+        .encode_utf16()
+        .count();
+    json!({ "line": line, "character": character })
+}
+
+/// The path of a `file:` URI, or the URI itself for other schemes so that
+/// documents that aren't files can still be checked.
+fn document_path(uri: &str) -> PathBuf {
+    let Some(path) = uri.strip_prefix("file://") else {
+        return PathBuf::from(uri);
+    };
+    let mut bytes = Vec::new();
+    let mut rest = path.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        let escaped = (byte == b'%')
+            .then(|| tail.get(..2))
+            .flatten()
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        if let Some(escaped) = escaped {
+            bytes.push(escaped);
+            rest = &tail[2..];
+        } else {
+            bytes.push(byte);
+            rest = tail;
+        }
+    }
+    PathBuf::from(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+fn path_to_uri(path: &Path) -> String {
+    let mut uri = "file://".to_owned();
+    for &byte in path.to_string_lossy().as_bytes() {
+        if byte.is_ascii_alphanumeric() || b"/-._~".contains(&byte) {
+            uri.push(char::from(byte));
+        } else {
+            write!(uri, "%{byte:02X}").unwrap();
+        }
+    }
+    uri
+}
@@ -1,141 +1,534 @@
 use crate::{
-    diagnostics::{self, primary_label, secondary_label},
-    ir::{Block, Function, Instruction, Program},
-    lexer::{lex, Token},
+    diagnostics::{self, primary_label, secondary_label, Collector, Lint},
+    ir::{
+        self, Abi, Block, ExternFunction, Field, Function, Global, Instruction,
+        Program, Test, TypeDefinition,
+    },
+    lexer::{doc_comment, lex_source, split_label, split_line, Token},
+    timing::Timings,
     unicode::prettify_token,
 };
 use anyhow::{bail, ensure, Result};
 use codemap::Span;
-use itertools::{process_results, Itertools};
-use std::collections::BTreeMap;
+use itertools::Itertools;
+use std::{
+    cell::Cell, collections::BTreeMap, iter::Peekable, sync::Arc, time::Instant,
+};
 
-pub fn parse(file: &codemap::File) -> Result<Program> {
-    let tokens = expand_macros(lex(file));
-    let functions = process_results(tokens, |tokens| {
-        extra_iterators::batching_map(tokens, parse_function)
-            .collect::<Result<_>>()
-    })??;
+pub fn parse(files: &[Arc<codemap::File>]) -> Result<Program<'_>> {
+    parse_timed(files, &mut Timings::default())
+}
 
-    Ok(Program { functions })
+/// Like [`parse`], but records how long lexing and expanding macros took
+/// separately from the rest.
+pub fn parse_timed<'src>(
+    files: &'src [Arc<codemap::File>],
+    timings: &mut Timings,
+) -> Result<Program<'src>> {
+    let sources = files.iter().map(|file| file.source()).collect::<Vec<_>>();
+    parse_sources_timed(files, &sources, timings)
 }
 
-fn expand_macros<'a>(
-    tokens: impl Iterator<Item = Token<'a>>,
-) -> impl Iterator<Item = Result<Token<'a>>> {
-    let mut macros = BTreeMap::new();
+/// Parses copies of the source code of the files, which the program borrows
+/// from instead of the files themselves.
+pub fn parse_sources<'src>(
+    files: &[Arc<codemap::File>],
+    sources: &[&'src str],
+) -> Result<Program<'src>> {
+    parse_sources_timed(files, sources, &mut Timings::default())
+}
 
-    extra_iterators::batching_map(tokens, move |tokens, token| match &*token {
-        "macro" => {
-            let macro_token = token;
-            let name = tokens.next().ok_or_else(|| {
-                diagnostics::error(
-                    "macro definition has no name".to_owned(),
-                    vec![primary_label(token.span, "")],
-                )
-            })?;
-            ensure!(
-                !is_keyword(&name),
-                diagnostics::error(
-                    format!("keyword `{name}` cannot be used as a macro name"),
-                    vec![primary_label(name.span, "")],
-                ),
-            );
-            let mut found_end = false;
-            let mut layers = 0_usize;
-            let body = tokens
-                .by_ref()
-                .map_while(|token| match &*token {
-                    "end" => {
-                        if layers == 0 {
-                            found_end = true;
-                            None
-                        } else {
-                            layers -= 1;
-                            Some(Ok(vec![token]))
-                        }
-                    }
-                    "macro" => Some(Err(diagnostics::error(
-                        "nested macros are not supported".to_owned(),
-                        vec![
-                            primary_label(
-                                token.span,
-                                "inner macro starts here",
-                            ),
-                            secondary_label(
-                                macro_token.span,
-                                "outer macro starts here",
-                            ),
-                        ],
-                    )
-                    .into())),
-                    "then" | "repeat" | "unsafe" => {
-                        layers += 1;
-                        Some(Ok(vec![token]))
-                    }
-                    _ => Some(Ok(macros.get(&*token).map_or_else(
-                        || vec![token],
-                        |macro_: &Macro| macro_.body_with_span(token.span),
-                    ))),
-                })
-                .flatten_ok()
-                .collect::<Result<_>>()?;
-            ensure!(found_end, unterminated("macro definition", token));
-            let prev_definition = macros.insert(
-                name.text,
-                Macro {
-                    declaration_span: macro_token.span.merge(name.span),
-                    body,
-                },
-            );
-            if let Some(prev_definition) = prev_definition {
-                bail!(diagnostics::error(
-                    format!("redefinition of macro `{name}`"),
-                    vec![
-                        primary_label(macro_token.span.merge(name.span), ""),
-                        secondary_label(
-                            prev_definition.declaration_span,
-                            "previously defined here",
-                        )
-                    ],
-                ));
+fn parse_sources_timed<'src>(
+    files: &[Arc<codemap::File>],
+    sources: &[&'src str],
+    timings: &mut Timings,
+) -> Result<Program<'src>> {
+    let mut errors = Collector::default();
+    let mut macros = Macros::default();
+    // Tokens that fail to expand are left out so that the rest of the
+    // program can still be parsed.
+    let tokens = timings.time("lexing and macro expansion", || {
+        expand_macros(
+            &mut macros,
+            std::iter::zip(files, sources)
+                .flat_map(|(file, source)| lex_source(file, source)),
+        )
+        .filter_map(|token| errors.record(token).transpose())
+        .collect::<Result<Vec<_>>>()
+    })?;
+    let start = Instant::now();
+    let mut functions = BTreeMap::new();
+    let mut types = BTreeMap::new();
+    let mut constants = BTreeMap::new();
+    let mut variables = BTreeMap::new();
+    let mut data = BTreeMap::new();
+    let mut extern_functions = BTreeMap::new();
+    let mut tests = BTreeMap::new();
+    let prelude = files
+        .iter()
+        .find(|file| file.name() == "<prelude>")
+        .map(|file| file.span);
+    let mut tokens = tokens.into_iter().peekable();
+    while let Some(token) = tokens.next() {
+        let result = (|| {
+            match &*token {
+                // Definitions are never kept together, so there is nothing to
+                // turn into a function here.
+                MACRO_START => {
+                    tokens.next();
+                }
+                MACRO_END => {}
+                "struct" | "enum" => {
+                    let (name, definition) =
+                        parse_type_definition(&mut tokens, token)?;
+                    let kind =
+                        if definition.is_enum { "enum" } else { "struct" };
+                    define(&mut types, kind, name, definition)?;
+                }
+                "const" => {
+                    let (name, constant) = parse_global(&mut tokens, token)?;
+                    define(&mut constants, "constant", name, constant)?;
+                }
+                "var" => {
+                    let (name, variable) = parse_global(&mut tokens, token)?;
+                    define(&mut variables, "variable", name, variable)?;
+                }
+                "data" => {
+                    let (name, table) = parse_data(&mut tokens, token)?;
+                    define(&mut data, "data table", name, table)?;
+                }
+                "test" => {
+                    let (name, test) = parse_test(&mut tokens, token)?;
+                    define(&mut tests, "test", name, test)?;
+                }
+                "extern"
+                    if tokens
+                        .peek()
+                        .is_some_and(|next| **next == *"struct") =>
+                {
+                    let (name, definition) =
+                        parse_extern_struct(&mut tokens, token)?;
+                    define(&mut types, "struct", name, definition)?;
+                }
+                "extern" => {
+                    let (name, function) =
+                        parse_extern_function(&mut tokens, token)?;
+                    define(&mut extern_functions, "function", name, function)?;
+                }
+                _ => {
+                    let (name, function) = if &*token == "unsafe" {
+                        parse_unsafe_function(&mut tokens, token, files)
+                    } else {
+                        parse_function(&mut tokens, token, files)
+                    }?;
+                    define_function(&mut functions, name, function, prelude)?;
+                }
             }
-            Ok(Vec::new())
+            Ok(())
+        })();
+        // After a mistake, parsing starts over at the next definition, since
+        // the words that start definitions can't appear anywhere else.
+        if errors.record(result)?.is_none() {
+            while tokens.next_if(|token| !starts_definition(token)).is_some() {}
         }
-        _ => Ok(macros.get(&*token).map_or_else(
-            || vec![token],
-            |macro_| macro_.body_with_span(token.span),
-        )),
+    }
+    errors.finish()?;
+    let mut program = Program {
+        functions,
+        types,
+        macros: BTreeMap::new(),
+        constants,
+        variables,
+        data,
+        extern_functions,
+        tests,
+        warnings: Vec::new(),
+        files: files.into(),
+    };
+    lint_macros(&macros, &mut program);
+    program.macros = documented_macros(macros, files);
+    timings.add("parsing", start.elapsed());
+    Ok(program)
+}
+
+/// The macros along with their documentation, which is all that is left of
+/// them after expansion.
+fn documented_macros<'src>(
+    macros: Macros<'src>,
+    files: &[Arc<codemap::File>],
+) -> BTreeMap<&'src str, ir::Macro> {
+    macros
+        .0
+        .into_iter()
+        .map(|(name, macro_)| {
+            (
+                name,
+                ir::Macro {
+                    declaration_span: macro_.declaration_span,
+                    doc: doc_comment_above(files, macro_.declaration_span),
+                    body: macro_.body.iter().format(" ").to_string().into(),
+                },
+            )
+        })
+        .collect()
+}
+
+/// Warns about macros that are never used or that have the same name as a
+/// built-in word or a definition, which they replace wherever they're used.
+fn lint_macros(macros: &Macros, program: &mut Program) {
+    for (&name, macro_) in &macros.0 {
+        if !macro_.used.get() {
+            program.warnings.push(diagnostics::warning(
+                Lint::UnusedMacro,
+                format!("macro `{name}` is never used"),
+                vec![primary_label(macro_.declaration_span, "")],
+            ));
+        }
+
+        let name_token = Token {
+            text: name,
+            span: macro_.declaration_span,
+        };
+        if let Some(definition_span) = program.declaration_span(name) {
+            program.warnings.push(diagnostics::warning(
+                Lint::ShadowedMacro,
+                format!("macro `{name}` shadows a definition with the same name"),
+                vec![
+                    primary_label(macro_.declaration_span, ""),
+                    secondary_label(definition_span, "shadowed definition"),
+                ],
+            ).note("uses of the name after the macro definition expand the macro"));
+        } else if !matches!(Instruction::from(name_token), Instruction::Call(_))
+        {
+            program.warnings.push(diagnostics::warning(
+                Lint::ShadowedMacro,
+                format!("macro `{name}` shadows a built-in word"),
+                vec![primary_label(macro_.declaration_span, "")],
+            ));
+        }
+    }
+}
+
+/// Finds the doc comment of the definition starting at `span`.
+fn doc_comment_above(files: &[Arc<codemap::File>], span: Span) -> Box<str> {
+    files
+        .iter()
+        .find(|file| file.span.contains(span))
+        .map(|file| doc_comment(file, file.find_line(span.low())))
+        .unwrap_or_default()
+}
+
+fn expand_macros<'a, 'm>(
+    macros: &'m mut Macros<'a>,
+    tokens: impl Iterator<Item = Token<'a>> + 'm,
+) -> impl Iterator<Item = Result<Token<'a>>> + 'm {
+    extra_iterators::batching_map(tokens, move |tokens, token| {
+        let mut expansion = Vec::new();
+        if *token == *"macro" {
+            macros.define(token, tokens)?;
+        } else if *token == *"include" {
+            // The included file has already been loaded by `include::load`.
+            tokens.next();
+        } else {
+            macros.expand(
+                token,
+                token.span,
+                &mut Vec::new(),
+                &mut expansion,
+            )?;
+        }
+        Ok(expansion)
     })
     .flatten_ok()
 }
 
+/// Expansions of macros with at least this many tokens are kept together so
+/// that the type checker can turn them into functions.
+const OUTLINE_MIN_TOKENS: usize = 16;
+
+/// Marks the start of an expansion that is kept together, followed by the name
+/// of the macro. Tokens never contain whitespace, so it can't be written.
+const MACRO_START: &str = "macro expansion";
+/// Marks the end of an expansion that is kept together.
+const MACRO_END: &str = "end of macro expansion";
+
+#[derive(Default)]
+struct Macros<'a>(BTreeMap<&'a str, Macro<'a>>);
+
 struct Macro<'a> {
     declaration_span: Span,
     body: Vec<Token<'a>>,
+    /// Whether the macro has been expanded anywhere.
+    used: Cell<bool>,
+}
+
+impl<'a> Macros<'a> {
+    fn define(
+        &mut self,
+        macro_token: Token<'a>,
+        tokens: &mut impl Iterator<Item = Token<'a>>,
+    ) -> Result<()> {
+        let name = tokens.next().ok_or_else(|| {
+            diagnostics::error(
+                "macro definition has no name".to_owned(),
+                vec![primary_label(macro_token.span, "")],
+            )
+        })?;
+
+        let mut closers = Vec::new();
+        let mut body = Vec::new();
+        loop {
+            let token = tokens
+                .next()
+                .ok_or_else(|| unterminated("macro definition", macro_token))?;
+            match &*token {
+                // Nested macros are defined along with the outer macro.
+                "macro" => self.define(token, tokens)?,
+                "include" => bail!(diagnostics::error(
+                    "files cannot be included inside of macros".to_owned(),
+                    vec![primary_label(token.span, "")],
+                )),
+                "end" if closers.is_empty() => break,
+                _ => {
+                    track_nesting(&mut closers, &token);
+                    body.push(token);
+                }
+            }
+        }
+        // Checked after the body so that parsing can go on after it.
+        ensure!(
+            !is_keyword(&name),
+            diagnostics::error(
+                format!("keyword `{name}` cannot be used as a macro name"),
+                vec![primary_label(name.span, "")],
+            ),
+        );
+
+        let prev_definition = self.0.insert(
+            name.text,
+            Macro {
+                declaration_span: macro_token.span.merge(name.span),
+                body,
+                used: Cell::new(false),
+            },
+        );
+        if let Some(prev_definition) = prev_definition {
+            bail!(diagnostics::error(
+                format!("redefinition of macro `{name}`"),
+                vec![
+                    primary_label(macro_token.span.merge(name.span), ""),
+                    secondary_label(
+                        prev_definition.declaration_span,
+                        "previously defined here",
+                    )
+                ],
+            ));
+        }
+        Ok(())
+    }
+
+    /// Expands `token` if it names a macro, giving all of the resulting tokens
+    /// the span of the outermost macro usage. Macros used within the body are
+    /// expanded when the outer macro is, so they may be defined later on.
+    fn expand(
+        &self,
+        token: Token<'a>,
+        span: Span,
+        expanding: &mut Vec<&'a str>,
+        expansion: &mut Vec<Token<'a>>,
+    ) -> Result<()> {
+        let Some((&name, macro_)) = self.0.get_key_value(&*token) else {
+            expansion.push(Token { span, ..token });
+            return Ok(());
+        };
+
+        if expanding.contains(&name) {
+            bail!(diagnostics::error(
+                format!("recursive expansion of macro `{name}`"),
+                vec![
+                    primary_label(span, "while expanding this"),
+                    secondary_label(macro_.declaration_span, "defined here"),
+                ],
+            )
+            .note(format!(
+                "the macros expand in a cycle: {} → {name}",
+                expanding.iter().format(" → ")
+            )));
+        }
+
+        macro_.used.set(true);
+        expanding.push(name);
+        let start = expansion.len();
+        for &body_token in &macro_.body {
+            self.expand(body_token, span, expanding, expansion)?;
+        }
+        expanding.pop();
+        if expansion.len() - start >= OUTLINE_MIN_TOKENS
+            && is_self_contained(&expansion[start..])
+        {
+            expansion.splice(
+                start..start,
+                [
+                    Token {
+                        text: MACRO_START,
+                        span,
+                    },
+                    Token { text: name, span },
+                ],
+            );
+            expansion.push(Token {
+                text: MACRO_END,
+                span,
+            });
+        }
+        Ok(())
+    }
 }
 
-impl<'a> Macro<'a> {
-    fn body_with_span(&self, span: Span) -> Vec<Token<'a>> {
-        self.body
-            .iter()
-            .map(|&token| Token { span, ..token })
-            .collect()
+/// Keeps track of what closes each of the constructs that a token is inside
+/// of, innermost last. The `do` of a quotation is closed by its `]` rather
+/// than by an `end`.
+fn track_nesting(closers: &mut Vec<&str>, token: &str) {
+    match split_label(token).0 {
+        // `case` followed by a number starts a `case` statement, while a
+        // `case` followed by a variant is part of a `match` statement.
+        token if closers.last() == Some(&"case") => {
+            closers.pop();
+            if token.parse::<i32>().is_ok() {
+                closers.push("end");
+            }
+        }
+        "case" => closers.push("case"),
+        "end" | "]" => {
+            closers.pop();
+        }
+        "do" if closers.last() == Some(&"do ]") => {
+            *closers.last_mut().unwrap() = "]";
+        }
+        // `unsafe fn` is closed by the `end` after its `do`.
+        "fn" if closers.last() == Some(&"unsafe end") => {
+            closers.pop();
+        }
+        "unsafe" => closers.push("unsafe end"),
+        "then" | "repeat" | "times" | "for" | "stash" | "let" | "struct"
+        | "enum" | "const" | "var" | "data" | "extern" | "match" | "do"
+        | "macro" | "test" => closers.push("end"),
+        "[" => closers.push("do ]"),
+        _ => {}
+    }
+}
+
+/// Whether source code stops in the middle of a definition or block, so that
+/// the REPL knows to keep reading lines.
+pub fn is_incomplete(source: &str) -> bool {
+    let mut closers = Vec::new();
+    for token in source
+        .lines()
+        .flat_map(split_line)
+        .filter(|token| !token.starts_with('#'))
+    {
+        track_nesting(&mut closers, token);
+    }
+    !closers.is_empty()
+}
+
+/// Whether the tokens of a macro expansion parse into instructions on their
+/// own, without needing anything around them or vice versa.
+fn is_self_contained(tokens: &[Token]) -> bool {
+    // `pick`, `roll`, `invoke` and `null` take their operand from the
+    // instructions before them.
+    if tokens.first().is_some_and(|token| {
+        matches!(&**token, "pick" | "roll" | "invoke" | "null")
+    }) || tokens
+        .last()
+        .is_some_and(|token| **token == *"]" || token.parse::<i32>().is_ok())
+    {
+        return false;
+    }
+
+    // What closes each of the constructs that the tokens are inside of,
+    // innermost last.
+    let mut closers = Vec::new();
+    let mut tokens = tokens.iter();
+    while let Some(token) = tokens.next() {
+        let top = closers.last().copied();
+        match prettify_token(split_label(token).0) {
+            MACRO_START => {
+                tokens.next();
+            }
+            MACRO_END => {}
+            "then" | "repeat" | "times" | "for" | "unsafe" | "stash"
+            | "match" => closers.push("end"),
+            "while" => closers.push("do"),
+            "let" => closers.push("in"),
+            "[" => closers.push("]"),
+            "case"
+                if tokens
+                    .as_slice()
+                    .first()
+                    .is_some_and(|value| value.parse::<i32>().is_ok()) =>
+            {
+                closers.push("end");
+            }
+            "do" if top == Some("do") => *closers.last_mut().unwrap() = "end",
+            "in" if top == Some("in") => *closers.last_mut().unwrap() = "end",
+            "do" | ":" | "→" if top == Some("]") => {}
+            "else" | "case" | "=>" if top == Some("end") => {}
+            closer @ ("end" | "]") if top == Some(closer) => {
+                closers.pop();
+            }
+            "end" | "]" | "do" | "in" | ":" | "→" | "else" | "case" | "=>"
+            | "fn" | "struct" | "enum" | "const" | "var" | "data"
+            | "extern" | "macro" | "include" | "test" => {
+                return false;
+            }
+            _ => {}
+        }
     }
+    closers.is_empty()
+}
+
+fn parse_macro_expansion<'a>(
+    tokens: &mut Peekable<impl Iterator<Item = Token<'a>>>,
+    start: Token,
+) -> Result<(Instruction, Span)> {
+    let name = tokens.next().unwrap();
+    // Only expansions that parse on their own are kept together, so this
+    // always ends at the matching `MACRO_END`.
+    let (body, _) = instructions_until_terminator(tokens)?;
+    Ok((
+        Instruction::MacroExpansion {
+            name: name.text.into(),
+            body,
+        },
+        start.span,
+    ))
 }
 
 fn instructions_until_terminator<'a>(
-    tokens: &mut impl Iterator<Item = Token<'a>>,
+    tokens: &mut Peekable<impl Iterator<Item = Token<'a>>>,
 ) -> Result<(Box<Block>, Option<Token<'a>>)> {
     let mut terminator = None;
     let instructions = extra_iterators::try_from_fn(|| {
         let Some(token) = tokens.next() else {
             return Ok(None);
         };
-        Ok(Some(match prettify_token(token.text) {
-            "end" | "else" | "do" | ":" | "→" => {
+        let (keyword, label) = split_label(token.text);
+        let label = label.map(Box::from);
+        Ok(Some(match prettify_token(keyword) {
+            "case"
+                if tokens
+                    .peek()
+                    .is_some_and(|value| value.parse::<i32>().is_ok()) =>
+            {
+                parse_case(tokens, token)?
+            }
+            "end" | "else" | "case" | "do" | ":" | "→" | "=>" | "]"
+            | MACRO_END => {
                 terminator = Some(token);
                 return Ok(None);
             }
+            MACRO_START => parse_macro_expansion(tokens, token)?,
             "then" => {
                 let (body, terminator) = instructions_until_terminator(tokens)?;
                 let terminator = terminator
@@ -169,47 +562,487 @@ fn instructions_until_terminator<'a>(
                 }
             }
             "repeat" => {
-                let (body, terminator) = instructions_until_terminator(tokens)?;
-                let terminator = terminator
-                    .ok_or_else(|| unterminated("`repeat` loop", token))?;
-                match &*terminator {
-                    "end" => (
-                        Instruction::Repeat {
-                            body,
-                            end_span: terminator.span,
-                        },
-                        token.span.merge(terminator.span),
-                    ),
-                    _ => bail!(unexpected_token(terminator, "expected `end`",)),
-                }
+                let (body, end) =
+                    block_until_end(tokens, "`repeat` loop", token)?;
+                (
+                    Instruction::Repeat {
+                        body,
+                        end_span: end.span,
+                        label,
+                    },
+                    token.span.merge(end.span),
+                )
+            }
+            "times" | "for" => {
+                let (body, end) =
+                    block_until_end(tokens, "counted loop", token)?;
+                (
+                    Instruction::Times {
+                        body,
+                        pushes_index: keyword == "for",
+                        span: token.span,
+                        label,
+                    },
+                    token.span.merge(end.span),
+                )
+            }
+            "while" => parse_while(tokens, token, label)?,
+            "break" => (Instruction::Break { label, depth: 0 }, token.span),
+            "continue" => {
+                (Instruction::Continue { label, depth: 0 }, token.span)
             }
             "unsafe" => {
-                let (body, terminator) = instructions_until_terminator(tokens)?;
-                let terminator = terminator
-                    .ok_or_else(|| unterminated("`unsafe` block", token))?;
-                match &*terminator {
-                    "end" => (
-                        Instruction::Unsafe(body),
-                        token.span.merge(terminator.span),
-                    ),
-                    _ => bail!(unexpected_token(terminator, "expected `end`",)),
-                }
+                let (body, end) =
+                    block_until_end(tokens, "`unsafe` block", token)?;
+                (Instruction::Unsafe(body), token.span.merge(end.span))
             }
+            "stash" => parse_stash(tokens, token)?,
+            "let" => parse_let(tokens, token)?,
+            "match" => parse_match(tokens, token)?,
+            "[" => parse_quotation(tokens, token)?,
             _ => (token.into(), token.span),
         }))
     })
     .collect::<Result<_>>()?;
 
-    Ok((instructions, terminator))
+    Ok((fold_operands(instructions)?, terminator))
+}
+
+/// Parses the body of a construct that must be terminated by `end`.
+fn block_until_end<'a>(
+    tokens: &mut Peekable<impl Iterator<Item = Token<'a>>>,
+    thing: &str,
+    start: Token,
+) -> Result<(Box<Block>, Token<'a>)> {
+    let (body, terminator) = instructions_until_terminator(tokens)?;
+    let terminator = terminator.ok_or_else(|| unterminated(thing, start))?;
+    ensure!(
+        *terminator == *"end",
+        unexpected_token(terminator, "expected `end`")
+    );
+    Ok((body, terminator))
+}
+
+fn parse_while<'a>(
+    tokens: &mut Peekable<impl Iterator<Item = Token<'a>>>,
+    token: Token,
+    label: Option<Box<str>>,
+) -> Result<(Instruction, Span)> {
+    let (condition, terminator) = instructions_until_terminator(tokens)?;
+    let do_ = terminator.ok_or_else(|| unterminated("`while` loop", token))?;
+    ensure!(*do_ == *"do", unexpected_token(do_, "expected `do`"));
+    let (body, end) = block_until_end(tokens, "`while` loop", token)?;
+    Ok((
+        Instruction::While {
+            condition,
+            body,
+            do_span: do_.span,
+            label,
+        },
+        token.span.merge(end.span),
+    ))
+}
+
+fn parse_match<'a>(
+    tokens: &mut Peekable<impl Iterator<Item = Token<'a>>>,
+    token: Token,
+) -> Result<(Instruction, Span)> {
+    let (before, mut terminator) = instructions_until_terminator(tokens)?;
+    if let Some((_, span)) = before.first() {
+        bail!(diagnostics::error(
+            "instructions before the first case".to_owned(),
+            vec![primary_label(*span, "expected `case`")],
+        ));
+    }
+    let mut cases = Vec::new();
+    let end = loop {
+        let keyword = terminator
+            .ok_or_else(|| unterminated("`match` statement", token))?;
+        match &*keyword {
+            "end" => break keyword,
+            "case" => {
+                let variant = tokens
+                    .next()
+                    .filter(|variant| !is_keyword(variant))
+                    .ok_or_else(|| {
+                        diagnostics::error(
+                            "case has no variant".to_owned(),
+                            vec![primary_label(keyword.span, "")],
+                        )
+                    })?;
+                let body;
+                (body, terminator) = instructions_until_terminator(tokens)?;
+                cases.push(ir::Case {
+                    variant: variant.text.into(),
+                    span: keyword.span.merge(variant.span),
+                    body,
+                });
+            }
+            _ => bail!(unexpected_token(keyword, "expected `case` or `end`")),
+        }
+    };
+    Ok((Instruction::Match(cases.into()), token.span.merge(end.span)))
+}
+
+/// Parses `case 0 => ... 1 => ... else ... end`. The value of each arm after
+/// the first one is parsed as the last instruction of the arm before it.
+fn parse_case<'a>(
+    tokens: &mut Peekable<impl Iterator<Item = Token<'a>>>,
+    token: Token,
+) -> Result<(Instruction, Span)> {
+    let mut arms = Vec::new();
+    // The value of the arm whose body is being parsed, and its span.
+    let mut value = None;
+    loop {
+        let (mut body, terminator) = instructions_until_terminator(tokens)?;
+        let terminator = terminator
+            .ok_or_else(|| unterminated("`case` statement", token))?;
+        let next_value = if *terminator == *"=>" {
+            match body.last() {
+                Some(&(Instruction::PushI32(next), span)) => {
+                    body = body[..body.len() - 1].into();
+                    Some((next, span.merge(terminator.span)))
+                }
+                _ => bail!(diagnostics::error(
+                    "`=>` without a number before it".to_owned(),
+                    vec![primary_label(terminator.span, "")],
+                )),
+            }
+        } else {
+            None
+        };
+        // The first value comes right after `case`, so there is no body
+        // before it.
+        if let Some((value, span)) = value {
+            arms.push(ir::Arm { value, span, body });
+        }
+        let (else_, end) = match &*terminator {
+            "=>" => {
+                value = next_value;
+                continue;
+            }
+            "else" => block_until_end(tokens, "`case` statement", token)?,
+            "end" => (Box::default(), terminator),
+            _ => bail!(unexpected_token(
+                terminator,
+                "expected `=>`, `else` or `end`"
+            )),
+        };
+        return Ok((
+            Instruction::Switch {
+                arms: arms.into(),
+                else_,
+            },
+            token.span.merge(end.span),
+        ));
+    }
+}
+
+fn parse_quotation<'a>(
+    tokens: &mut Peekable<impl Iterator<Item = Token<'a>>>,
+    token: Token,
+) -> Result<(Instruction, Span)> {
+    let (parameters, arrow) = instructions_until_terminator(tokens)?;
+    let arrow = arrow.ok_or_else(|| unterminated("quotation", token))?;
+    ensure!(
+        prettify_token(&arrow) == "→",
+        unexpected_token(arrow, "expected instruction or `→`")
+    );
+    let (returns, terminator) = instructions_until_terminator(tokens)?;
+    let terminator =
+        terminator.ok_or_else(|| unterminated("quotation", token))?;
+    let (body, end) = match &*terminator {
+        "]" => (None, terminator),
+        "do" => {
+            let (body, end) = instructions_until_terminator(tokens)?;
+            let end = end.ok_or_else(|| unterminated("quotation", token))?;
+            ensure!(
+                *end == *"]",
+                unexpected_token(end, "expected instruction or `]`")
+            );
+            (Some(body), end)
+        }
+        _ => bail!(unexpected_token(
+            terminator,
+            "expected instruction, `do` or `]`"
+        )),
+    };
+    Ok((
+        Instruction::Quotation(Box::new(ir::Quotation {
+            parameters,
+            returns,
+            body,
+            end_span: end.span,
+        })),
+        token.span.merge(end.span),
+    ))
+}
+
+fn parse_stash<'a>(
+    tokens: &mut Peekable<impl Iterator<Item = Token<'a>>>,
+    token: Token,
+) -> Result<(Instruction, Span)> {
+    let name = tokens.next().ok_or_else(|| {
+        diagnostics::error(
+            "stash has no name".to_owned(),
+            vec![primary_label(token.span, "")],
+        )
+    })?;
+    ensure!(
+        !is_keyword(&name),
+        diagnostics::error(
+            format!("keyword `{name}` cannot be used as a stash name"),
+            vec![primary_label(name.span, "")],
+        ),
+    );
+    let (mut body, end) = block_until_end(tokens, "`stash` block", token)?;
+    resolve_stash(&mut body, &name);
+    Ok((
+        Instruction::Stash {
+            name: name.text.into(),
+            body,
+        },
+        token.span.merge(end.span),
+    ))
+}
+
+fn parse_let<'a>(
+    tokens: &mut Peekable<impl Iterator<Item = Token<'a>>>,
+    token: Token,
+) -> Result<(Instruction, Span)> {
+    let mut names = Vec::<Token>::new();
+    loop {
+        let name = tokens.next().ok_or_else(|| unterminated("`let`", token))?;
+        if *name == *"in" {
+            break;
+        }
+        ensure!(
+            !is_keyword(&name),
+            diagnostics::error(
+                format!("keyword `{name}` cannot be used as a local name"),
+                vec![primary_label(name.span, "")],
+            ),
+        );
+        if let Some(previous) =
+            names.iter().find(|previous| previous.text == name.text)
+        {
+            bail!(diagnostics::error(
+                format!("`{name}` is bound twice"),
+                vec![
+                    primary_label(name.span, ""),
+                    secondary_label(previous.span, "first bound here"),
+                ],
+            ));
+        }
+        names.push(name);
+    }
+    ensure!(
+        !names.is_empty(),
+        diagnostics::error(
+            "`let` binds no names".to_owned(),
+            vec![primary_label(token.span, "")],
+        )
+    );
+    let (mut body, end) = block_until_end(tokens, "`let` block", token)?;
+    for name in &names {
+        resolve_words(&mut body, &|word| {
+            (word == name.text).then(|| Instruction::Local(word.into()))
+        });
+    }
+    Ok((
+        Instruction::Let {
+            names: names.iter().map(|name| name.text.into()).collect(),
+            body,
+        },
+        token.span.merge(end.span),
+    ))
+}
+
+/// Turns `>name` and `name>` within the scope of a stash into operations on it.
+fn resolve_stash(block: &mut Block, name: &str) {
+    resolve_words(block, &|word| {
+        if word.strip_prefix('>') == Some(name) {
+            Some(Instruction::ToStash(name.into()))
+        } else if word.strip_suffix('>') == Some(name) {
+            Some(Instruction::FromStash(name.into()))
+        } else {
+            None
+        }
+    });
+}
+
+/// Replaces the words that `resolve` recognizes within the scope of a stash
+/// or local. Ones declared inside of it have already been resolved, so they
+/// shadow it. Quotations run elsewhere, so they can't see it.
+fn resolve_words(
+    block: &mut Block,
+    resolve: &dyn Fn(&str) -> Option<Instruction>,
+) {
+    for (instruction, _) in &mut *block {
+        match instruction {
+            Instruction::Call(word) => {
+                if let Some(resolved) = resolve(word) {
+                    *instruction = resolved;
+                }
+            }
+            Instruction::Then(body)
+            | Instruction::Repeat { body, .. }
+            | Instruction::Times { body, .. }
+            | Instruction::Unsafe(body)
+            | Instruction::Stash { body, .. }
+            | Instruction::Let { body, .. }
+            | Instruction::MacroExpansion { body, .. } => {
+                resolve_words(body, resolve);
+            }
+            Instruction::Match(cases) => {
+                for case in &mut **cases {
+                    resolve_words(&mut case.body, resolve);
+                }
+            }
+            Instruction::Switch { arms, else_ } => {
+                for arm in &mut **arms {
+                    resolve_words(&mut arm.body, resolve);
+                }
+                resolve_words(else_, resolve);
+            }
+            Instruction::ThenElse(first, second)
+            | Instruction::While {
+                condition: first,
+                body: second,
+                ..
+            } => {
+                resolve_words(first, resolve);
+                resolve_words(second, resolve);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Turns `n pick`, `n roll`, `[ parameters → returns ] invoke`, `type null`,
+/// `name load` and `name store` into single instructions, since their depth,
+/// signature, type or variable has to be known at compile time.
+fn fold_operands(instructions: Vec<(Instruction, Span)>) -> Result<Box<Block>> {
+    let mut folded =
+        Vec::<(Instruction, Span)>::with_capacity(instructions.len());
+    for (instruction, span) in instructions {
+        if matches!(&instruction, Instruction::Call(name) if **name == *"invoke")
+        {
+            match folded.pop() {
+                Some((Instruction::Quotation(signature), signature_span))
+                    if signature.body.is_none() =>
+                {
+                    folded.push((
+                        Instruction::Invoke(signature),
+                        signature_span.merge(span),
+                    ));
+                    continue;
+                }
+                _ => bail!(diagnostics::error(
+                    "`invoke` must be preceded by a quotation type".to_owned(),
+                    vec![primary_label(span, "")],
+                )),
+            }
+        }
+        if matches!(&instruction, Instruction::Call(name) if **name == *"null")
+        {
+            fold_null(&mut folded, span)?;
+            continue;
+        }
+        if let Instruction::Call(word) = &instruction {
+            let make_instruction: Option<fn(Box<str>) -> Instruction> =
+                match &**word {
+                    "load" => Some(Instruction::Load),
+                    "store" => Some(Instruction::Store),
+                    _ => None,
+                };
+            if let Some(make_instruction) = make_instruction {
+                match folded.pop() {
+                    Some((Instruction::Call(variable), variable_span)) => {
+                        folded.push((
+                            make_instruction(variable),
+                            variable_span.merge(span),
+                        ));
+                        continue;
+                    }
+                    _ => bail!(diagnostics::error(
+                        format!("`{word}` must be preceded by a variable name"),
+                        vec![primary_label(span, "")],
+                    )),
+                }
+            }
+        }
+        let (name, make_instruction): (_, fn(u8) -> Instruction) =
+            match &instruction {
+                Instruction::Call(name) if **name == *"pick" => {
+                    ("pick", Instruction::Pick)
+                }
+                Instruction::Call(name) if **name == *"roll" => {
+                    ("roll", Instruction::Roll)
+                }
+                _ => {
+                    folded.push((instruction, span));
+                    continue;
+                }
+            };
+        let Some(&(Instruction::PushI32(depth), depth_span)) = folded.last()
+        else {
+            bail!(diagnostics::error(
+                format!("`{name}` must be preceded by a constant depth"),
+                vec![primary_label(span, "")],
+            ));
+        };
+        let depth = u8::try_from(depth).map_err(|_| {
+            diagnostics::error(
+                "stack depth must be between 0 and 255".to_owned(),
+                vec![primary_label(depth_span, "")],
+            )
+        })?;
+        folded.pop();
+        folded.push((make_instruction(depth), depth_span.merge(span)));
+    }
+    Ok(folded.into())
+}
+
+/// Turns the type before `null` into part of it.
+fn fold_null(folded: &mut Vec<(Instruction, Span)>, span: Span) -> Result<()> {
+    // The type is a single word followed by any number of `ptr`s.
+    let pointers = folded
+        .iter()
+        .rev()
+        .take_while(|(instruction, _)| matches!(instruction, Instruction::Ptr))
+        .count();
+    let start = folded
+        .len()
+        .checked_sub(pointers + 1)
+        .filter(|&start| match &folded[start].0 {
+            Instruction::PushType(_) | Instruction::Call(_) => true,
+            Instruction::Quotation(quotation) => quotation.body.is_none(),
+            _ => false,
+        })
+        .ok_or_else(|| {
+            diagnostics::error(
+                "`null` must be preceded by a type".to_owned(),
+                vec![primary_label(span, "")],
+            )
+        })?;
+    let typ = folded.split_off(start);
+    let typ_span = typ[0].1.merge(span);
+    folded.push((Instruction::Null(typ.into()), typ_span));
+    Ok(())
 }
 
 fn parse_function<'a>(
-    mut tokens: &mut impl Iterator<Item = Token<'a>>,
+    tokens: &mut Peekable<impl Iterator<Item = Token<'a>>>,
     token: Token,
+    files: &[Arc<codemap::File>],
 ) -> Result<(&'a str, Function)> {
     ensure!(
         *token == *"fn",
-        unexpected_token(token, "expected function or macro definition")
+        unexpected_token(
+            token,
+            "expected function, struct or macro definition"
+        )
     );
 
     let name = tokens.next().ok_or_else(|| {
@@ -226,14 +1059,19 @@ fn parse_function<'a>(
         ),
     );
 
-    let colon = tokens
+    let mut colon = tokens
         .next()
         .ok_or_else(|| unterminated("function definition", token))?;
+    let inline = *colon == *"inline";
+    if inline {
+        colon = tokens
+            .next()
+            .ok_or_else(|| unterminated("function definition", token))?;
+    }
     ensure!(*colon == *":", unexpected_token(colon, "expected `:`"));
 
     let mut instructions_until_specific_terminator = |terminator| {
-        let (instructions, Some(t)) =
-            instructions_until_terminator(&mut tokens)?
+        let (instructions, Some(t)) = instructions_until_terminator(tokens)?
         else {
             bail!(unterminated("function definition", token));
         };
@@ -255,27 +1093,440 @@ fn parse_function<'a>(
         name.text,
         Function {
             declaration_span: token.span.merge(name.span),
+            doc: doc_comment_above(files, token.span),
+            parameters,
+            returns,
+            body,
+            end_span: end.span,
+            inline,
+            is_unsafe: false,
+        },
+    ))
+}
+
+/// Parses `unsafe fn name : parameters → returns do body end`.
+fn parse_unsafe_function<'a>(
+    tokens: &mut Peekable<impl Iterator<Item = Token<'a>>>,
+    token: Token,
+    files: &[Arc<codemap::File>],
+) -> Result<(&'a str, Function)> {
+    let fn_ = tokens
+        .next()
+        .ok_or_else(|| unterminated("unsafe function definition", token))?;
+    ensure!(
+        *fn_ == *"fn",
+        unexpected_token(
+            fn_,
+            "expected `fn`, since `unsafe` blocks can only be used inside of functions"
+        )
+    );
+    let (name, mut function) = parse_function(tokens, fn_, files)?;
+    function.declaration_span = token.span.merge(function.declaration_span);
+    function.is_unsafe = true;
+    Ok((name, function))
+}
+
+/// Parses `extern "abi" fn name : parameters → returns end`, where the ABI is
+/// optional.
+fn parse_extern_function<'a>(
+    tokens: &mut Peekable<impl Iterator<Item = Token<'a>>>,
+    token: Token,
+) -> Result<(&'a str, ExternFunction)> {
+    let mut fn_ = tokens
+        .next()
+        .ok_or_else(|| unterminated("extern function declaration", token))?;
+    let mut abi = Abi::C;
+    if let Some(name) = fn_
+        .strip_prefix('"')
+        .and_then(|name| name.strip_suffix('"'))
+    {
+        abi = Abi::ALL
+            .into_iter()
+            .find(|abi| abi.name() == name)
+            .ok_or_else(|| {
+                diagnostics::error(
+                    format!("unknown calling convention `{name}`"),
+                    vec![primary_label(fn_.span, "")],
+                )
+                .note(format!(
+                    "the supported calling conventions are {}",
+                    Abi::ALL
+                        .iter()
+                        .map(|abi| format!("`\"{}\"`", abi.name()))
+                        .format(", ")
+                ))
+            })?;
+        fn_ = tokens.next().ok_or_else(|| {
+            unterminated("extern function declaration", token)
+        })?;
+    }
+    ensure!(*fn_ == *"fn", unexpected_token(fn_, "expected `fn`"));
+    let name = tokens.next().ok_or_else(|| {
+        diagnostics::error(
+            "extern function has no name".to_owned(),
+            vec![primary_label(fn_.span, "")],
+        )
+    })?;
+    ensure!(
+        !is_keyword(&name),
+        diagnostics::error(
+            format!("keyword `{name}` cannot be used as a function name"),
+            vec![primary_label(name.span, "")],
+        ),
+    );
+    let colon = tokens
+        .next()
+        .ok_or_else(|| unterminated("extern function declaration", token))?;
+    ensure!(*colon == *":", unexpected_token(colon, "expected `:`"));
+
+    let mut instructions_until_specific_terminator = |terminator| {
+        let (instructions, Some(t)) = instructions_until_terminator(tokens)?
+        else {
+            bail!(unterminated("extern function declaration", token));
+        };
+        ensure!(
+            prettify_token(t.text) == terminator,
+            unexpected_token(
+                t,
+                format!("expected instruction or `{terminator}`")
+            )
+        );
+        Ok(instructions)
+    };
+    let parameters = instructions_until_specific_terminator("→")?;
+    let returns = instructions_until_specific_terminator("end")?;
+
+    Ok((
+        name.text,
+        ExternFunction {
+            declaration_span: token.span.merge(name.span),
+            abi,
             parameters,
             returns,
+        },
+    ))
+}
+
+/// Parses a struct or enum, depending on `token`.
+/// Parses `const name body end` or `var name type end`.
+fn parse_global<'a>(
+    tokens: &mut Peekable<impl Iterator<Item = Token<'a>>>,
+    token: Token,
+) -> Result<(&'a str, Global)> {
+    let thing = if *token == *"const" {
+        "constant"
+    } else {
+        "variable"
+    };
+    let name = tokens.next().ok_or_else(|| {
+        diagnostics::error(
+            format!("{thing} has no name"),
+            vec![primary_label(token.span, "")],
+        )
+    })?;
+    ensure!(
+        !is_keyword(&name),
+        diagnostics::error(
+            format!("keyword `{name}` cannot be used as a {thing} name"),
+            vec![primary_label(name.span, "")],
+        ),
+    );
+    let (body, _) =
+        block_until_end(tokens, &format!("{thing} definition"), token)?;
+    Ok((
+        name.text,
+        Global {
+            declaration_span: token.span.merge(name.span),
+            body,
+        },
+    ))
+}
+
+/// Parses `data name [ elements ] end`.
+fn parse_data<'a>(
+    tokens: &mut Peekable<impl Iterator<Item = Token<'a>>>,
+    token: Token,
+) -> Result<(&'a str, Global)> {
+    let name = tokens.next().ok_or_else(|| {
+        diagnostics::error(
+            "data table has no name".to_owned(),
+            vec![primary_label(token.span, "")],
+        )
+    })?;
+    ensure!(
+        !is_keyword(&name),
+        diagnostics::error(
+            format!("keyword `{name}` cannot be used as a data table name"),
+            vec![primary_label(name.span, "")],
+        ),
+    );
+    let open = tokens
+        .next()
+        .ok_or_else(|| unterminated("data table", token))?;
+    ensure!(*open == *"[", unexpected_token(open, "expected `[`"));
+    let (body, close) = instructions_until_terminator(tokens)?;
+    let close = close.ok_or_else(|| unterminated("data table", token))?;
+    ensure!(
+        *close == *"]",
+        unexpected_token(close, "expected instruction or `]`")
+    );
+    let end = tokens
+        .next()
+        .ok_or_else(|| unterminated("data table", token))?;
+    ensure!(*end == *"end", unexpected_token(end, "expected `end`"));
+    Ok((
+        name.text,
+        Global {
+            declaration_span: token.span.merge(name.span),
+            body,
+        },
+    ))
+}
+
+/// Something that is defined at the top level of a program.
+trait Declaration {
+    fn declaration_span(&self) -> Span;
+}
+
+impl Declaration for Function {
+    fn declaration_span(&self) -> Span {
+        self.declaration_span
+    }
+}
+
+impl Declaration for TypeDefinition<'_> {
+    fn declaration_span(&self) -> Span {
+        self.declaration_span
+    }
+}
+
+impl Declaration for Global {
+    fn declaration_span(&self) -> Span {
+        self.declaration_span
+    }
+}
+
+impl Declaration for Test {
+    fn declaration_span(&self) -> Span {
+        self.declaration_span
+    }
+}
+
+impl Declaration for ExternFunction {
+    fn declaration_span(&self) -> Span {
+        self.declaration_span
+    }
+}
+
+/// Adds a definition to those of its kind, unless one with the same name is
+/// already there.
+fn define<'src, T: Declaration>(
+    definitions: &mut BTreeMap<&'src str, T>,
+    kind: &str,
+    name: &'src str,
+    definition: T,
+) -> Result<()> {
+    if let Some(prev_definition) = definitions.get(name) {
+        bail!(diagnostics::error(
+            format!("redefinition of {kind} `{name}`"),
+            vec![
+                primary_label(definition.declaration_span(), ""),
+                secondary_label(
+                    prev_definition.declaration_span(),
+                    "previously defined here"
+                ),
+            ],
+        ));
+    }
+    definitions.insert(name, definition);
+    Ok(())
+}
+
+/// Like `define`, but replaces a function from the prelude, whose file spans
+/// `prelude`, instead of being an error.
+fn define_function<'src>(
+    functions: &mut BTreeMap<&'src str, Function>,
+    name: &'src str,
+    function: Function,
+    prelude: Option<Span>,
+) -> Result<()> {
+    if functions.get(name).is_some_and(|prev_function| {
+        prelude.is_some_and(|prelude| {
+            prelude.contains(prev_function.declaration_span)
+        })
+    }) {
+        functions.remove(name);
+    }
+    define(functions, "function", name, function)
+}
+
+/// Parses `test "name" body end`.
+fn parse_test<'a>(
+    tokens: &mut Peekable<impl Iterator<Item = Token<'a>>>,
+    token: Token,
+) -> Result<(&'a str, Test)> {
+    let name = tokens.next().ok_or_else(|| {
+        diagnostics::error(
+            "test has no name".to_owned(),
+            vec![primary_label(token.span, "")],
+        )
+    })?;
+    ensure!(
+        name.len() >= 2 && name.starts_with('"') && name.ends_with('"'),
+        unexpected_token(name, "expected the name of the test as a string")
+    );
+    let (body, end) = block_until_end(tokens, "test", token)?;
+    Ok((
+        name.text,
+        Test {
+            declaration_span: token.span.merge(name.span),
             body,
             end_span: end.span,
         },
     ))
 }
 
+/// Parses `extern struct name fields end`.
+fn parse_extern_struct<'a>(
+    tokens: &mut Peekable<impl Iterator<Item = Token<'a>>>,
+    token: Token,
+) -> Result<(&'a str, TypeDefinition<'a>)> {
+    let struct_ = tokens
+        .next()
+        .ok_or_else(|| unterminated("extern struct definition", token))?;
+    let (name, mut definition) = parse_type_definition(tokens, struct_)?;
+    definition.declaration_span = token.span.merge(definition.declaration_span);
+    definition.is_extern = true;
+    Ok((name, definition))
+}
+
+fn parse_type_definition<'a>(
+    tokens: &mut Peekable<impl Iterator<Item = Token<'a>>>,
+    token: Token,
+) -> Result<(&'a str, TypeDefinition<'a>)> {
+    let kind = token.text;
+    let is_enum = kind == "enum";
+    let name = tokens.next().ok_or_else(|| {
+        diagnostics::error(
+            format!("{kind} has no name"),
+            vec![primary_label(token.span, "")],
+        )
+    })?;
+    ensure!(
+        !is_keyword(&name),
+        diagnostics::error(
+            format!("keyword `{name}` cannot be used as a {kind} name"),
+            vec![primary_label(name.span, "")],
+        ),
+    );
+
+    let mut fields = Vec::<Field>::new();
+    loop {
+        let field = tokens.next().ok_or_else(|| {
+            unterminated(&format!("{kind} definition"), token)
+        })?;
+        if *field == *"end" {
+            break;
+        }
+        if let (Some(previous), "ptr") =
+            (fields.last_mut(), prettify_token(&field))
+        {
+            let mut typ = std::mem::take(&mut previous.typ).into_vec();
+            typ.push((Instruction::Ptr, field.span));
+            previous.typ = typ.into();
+            continue;
+        }
+        let (field_name, typ) = match field.text.split_once(':') {
+            Some((field_name, typ))
+                if !field_name.is_empty() && !typ.is_empty() =>
+            {
+                (field_name, Some(typ))
+            }
+            None if is_enum && !is_keyword(&field) => (field.text, None),
+            _ if is_enum => bail!(unexpected_token(
+                field,
+                "expected `name`, `name:type` or `end`"
+            )),
+            _ => {
+                bail!(unexpected_token(field, "expected `name:type` or `end`"))
+            }
+        };
+        let what = if is_enum { "variants" } else { "fields" };
+        ensure!(
+            fields.iter().all(|other| other.name != field_name),
+            diagnostics::error(
+                format!(
+                    "{kind} `{name}` has multiple {what} named `{field_name}`"
+                ),
+                vec![primary_label(field.span, "")],
+            ),
+        );
+        let typ = typ.map(|typ| Token {
+            text: typ,
+            span: field.span.subspan(
+                (field_name.len() + 1).try_into().unwrap(),
+                field.text.len().try_into().unwrap(),
+            ),
+        });
+        fields.push(Field {
+            name: field_name,
+            typ: typ.map(|typ| (typ.into(), typ.span)).into_iter().collect(),
+        });
+    }
+
+    Ok((
+        name.text,
+        TypeDefinition {
+            declaration_span: token.span.merge(name.span),
+            is_enum,
+            is_extern: false,
+            fields: fields.into(),
+        },
+    ))
+}
+
+fn starts_definition(token: &str) -> bool {
+    matches!(
+        token,
+        "fn" | "struct" | "enum" | "const" | "var" | "data" | "test" | "extern"
+    )
+}
+
 fn is_keyword(token: &str) -> bool {
     matches!(
-        prettify_token(token),
+        prettify_token(split_label(token).0),
         "macro"
+            | "include"
             | "then"
             | "else"
             | "repeat"
+            | "times"
+            | "for"
+            | "while"
+            | "break"
+            | "continue"
+            | "return"
             | "end"
             | "do"
             | "fn"
+            | "struct"
+            | "enum"
+            | "match"
+            | "case"
+            | "=>"
             | ":"
             | "→"
             | "unsafe"
+            | "stash"
+            | "let"
+            | "in"
+            | "const"
+            | "var"
+            | "data"
+            | "extern"
+            | "test"
+            | "["
+            | "]"
     )
 }
 
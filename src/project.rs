@@ -0,0 +1,87 @@
+use anyhow::{ensure, Context, Result};
+use std::{
+    fmt::Write as _,
+    path::{Path, PathBuf},
+};
+
+const MANIFEST: &str = "spackel.toml";
+const MAIN_FILE: &str = "src/main.spkl";
+
+const HELLO_WORLD: &str = "\
+fn main : → do
+  72 print-char
+  101 print-char
+  108 print-char
+  108 print-char
+  111 print-char
+  44 print-char
+  32 print-char
+  119 print-char
+  111 print-char
+  114 print-char
+  108 print-char
+  100 print-char
+  33 print-char
+  10 print-char
+end
+";
+
+/// Creates a new project in a directory called `name`.
+pub fn new(name: &str) -> Result<()> {
+    let root = Path::new(name);
+    ensure!(!root.exists(), "`{name}` already exists");
+
+    let project_name = root
+        .file_name()
+        .and_then(|file_name| file_name.to_str())
+        .with_context(|| format!("invalid project name {name:?}"))?;
+
+    std::fs::create_dir_all(root.join("src"))
+        .context("failed to create project directory")?;
+    std::fs::write(
+        root.join(MANIFEST),
+        format!("[package]\nname = {}\n", toml_string(project_name)),
+    )
+    .context("failed to create manifest")?;
+    std::fs::write(root.join(MAIN_FILE), HELLO_WORLD)
+        .context("failed to create main file")?;
+    std::fs::write(root.join(".gitignore"), "/main\n/main.o\n")
+        .context("failed to create `.gitignore`")?;
+
+    Ok(())
+}
+
+/// Quotes a string for TOML, escaping the characters that basic strings
+/// can't contain.
+fn toml_string(text: &str) -> String {
+    let mut quoted = String::from('"');
+    for c in text.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            c if c.is_control() => {
+                write!(quoted, "\\u{:04X}", u32::from(c)).unwrap();
+            }
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Finds the root directory of the project containing the current directory,
+/// which is the one with the manifest in it.
+pub fn root() -> Result<PathBuf> {
+    let current_dir = std::env::current_dir()
+        .context("failed to get the current directory")?;
+    current_dir
+        .ancestors()
+        .find(|dir| dir.join(MANIFEST).is_file())
+        .map(Path::to_path_buf)
+        .with_context(|| format!("no file provided and no `{MANIFEST}` found"))
+}
+
+/// Finds the main file of the project containing the current directory.
+pub fn main_file() -> Result<PathBuf> {
+    Ok(root()?.join(MAIN_FILE))
+}
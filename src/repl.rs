@@ -0,0 +1,369 @@
+//! `spackel repl`, which runs each line that is entered on the stack left by
+//! the lines before it and prints the stack afterwards. Definitions are kept
+//! around for the lines after them, and can be saved to a file and loaded
+//! again in a later session. Each line can be undone, going back to the
+//! definitions, stack and variables from before it.
+
+use crate::{
+    cli::Flags,
+    diagnostics::{self, primary_label, Diagnostic, Lint},
+    interpreter::{self, Halt, Snapshot},
+    parser,
+    typ::{self, FunctionSignature, Type},
+    PRELUDE,
+};
+use anyhow::{bail, ensure, Context, Result};
+use codemap::{CodeMap, File};
+use itertools::Itertools;
+use rustyline::{error::ReadlineError, DefaultEditor};
+use std::{
+    io::IsTerminal,
+    path::{Path, PathBuf},
+    process::ExitCode,
+    sync::Arc,
+};
+
+/// The name of the function that each line is checked as, which isn't a name
+/// that anyone would write.
+const LINE: &str = "<line>";
+
+struct Repl {
+    /// The prelude and every definition that has been entered.
+    files: Vec<Arc<File>>,
+    /// Where the definitions start in `files`, after the prelude.
+    definitions_start: usize,
+    /// Wrapped around a line to make it the body of a function.
+    line_start: Arc<File>,
+    line_end: Arc<File>,
+    /// The stack and variables left by the lines so far.
+    state: Snapshot,
+    /// The types of the values on the stack, where structs and enums are
+    /// single types even though they are several values.
+    stack_types: Vec<Type>,
+    /// How things were before each line that can still be undone.
+    checkpoints: Vec<Checkpoint>,
+}
+
+/// What `:undo` goes back to. Snapshots share most of their values with each
+/// other, so keeping one for every line is cheap.
+struct Checkpoint {
+    /// How many files there were, so that definitions can be undone too.
+    files: usize,
+    state: Snapshot,
+    stack_types: Vec<Type>,
+}
+
+/// Reads lines from stdin until the end of the input or until a line calls
+/// `exit`, whose exit code is returned.
+pub fn run(code_map: &mut CodeMap, flags: &Flags) -> Result<ExitCode> {
+    let files = if flags.prelude {
+        vec![code_map.add_file("<prelude>".to_owned(), PRELUDE.to_owned())]
+    } else {
+        Vec::new()
+    };
+    let mut repl = Repl::new(code_map, files);
+
+    let mut editor = DefaultEditor::new()?;
+    // Only what is typed by hand is worth remembering.
+    let history = history_path().filter(|_| std::io::stdin().is_terminal());
+    if let Some(history) = &history {
+        // There is no history file before the first session.
+        editor.load_history(history).ok();
+    }
+
+    let mut source = String::new();
+    let mut inputs = 0;
+    loop {
+        let prompt = if source.is_empty() { "> " } else { "... " };
+        match editor.readline(prompt) {
+            Ok(line) => {
+                source.push_str(&line);
+                source.push('\n');
+            }
+            // Ctrl-C throws away what has been entered so far.
+            Err(ReadlineError::Interrupted) => {
+                source.clear();
+                continue;
+            }
+            Err(ReadlineError::Eof) => return Ok(ExitCode::SUCCESS),
+            Err(err) => return Err(err.into()),
+        }
+        if parser::is_incomplete(&source) {
+            continue;
+        }
+        let source = std::mem::take(&mut source);
+        if source.trim().is_empty() {
+            continue;
+        }
+        editor.add_history_entry(source.trim_end())?;
+        if let Some(history) = &history {
+            editor.append_history(history)?;
+        }
+        inputs += 1;
+        let name = format!("<input {inputs}>");
+        let result = if source.trim() == ":undo" {
+            repl.undo()
+        } else if let Some(snippet) = source.strip_prefix(":t") {
+            let file = code_map.add_file(name, snippet.to_owned());
+            repl.print_effects(&file)
+        } else if let Some(path) = source.strip_prefix(":save") {
+            repl.save(path.trim())
+        } else if let Some(path) = source.strip_prefix(":load") {
+            repl.load(code_map, path.trim(), flags)
+        } else if is_definition(&source) {
+            let file = code_map.add_file(name, source);
+            repl.define(code_map, file, flags)
+        } else {
+            let file = code_map.add_file(name, source);
+            repl.evaluate(code_map, &file, flags)
+        };
+        let Err(err) = result else { continue };
+        match err.downcast::<Halt>() {
+            #[expect(
+                clippy::cast_possible_truncation,
+                clippy::cast_sign_loss,
+                reason = "exit codes are truncated like on Unix"
+            )]
+            Ok(Halt::Exit(code)) => return Ok(ExitCode::from(code as u8)),
+            Ok(halt) => report(&halt.into_error(), code_map),
+            Err(err) => report(&err, code_map),
+        }
+    }
+}
+
+/// Where the lines entered in every session are kept, so that they can be
+/// brought back with the arrow keys in the next one.
+fn history_path() -> Option<PathBuf> {
+    std::env::var_os("HOME")
+        .map(|home| Path::new(&home).join(".spackel_history"))
+}
+
+/// Prints an error without stopping the REPL.
+fn report(err: &anyhow::Error, code_map: &CodeMap) {
+    if let Some(err) = err.downcast_ref::<diagnostics::Error>() {
+        err.emit(code_map);
+    } else {
+        eprintln!("error: {err:#}");
+    }
+}
+
+/// Whether the input defines something rather than being instructions to run.
+fn is_definition(source: &str) -> bool {
+    let mut tokens = source.split_whitespace();
+    tokens.next().is_some_and(|token| {
+        matches!(
+            token,
+            "fn" | "struct"
+                | "enum"
+                | "const"
+                | "var"
+                | "data"
+                | "extern"
+                | "macro"
+                | "include"
+        ) || token == "unsafe" && tokens.next() == Some("fn")
+    })
+}
+
+/// Prints the stack effects that a snippet of code can have, for
+/// `spackel effect`.
+pub fn print_effects(
+    code_map: &mut CodeMap,
+    files: Vec<Arc<File>>,
+    snippet: String,
+) -> Result<()> {
+    for effect in effects(code_map, files, snippet)? {
+        println!("{effect}");
+    }
+    Ok(())
+}
+
+/// The stack effects that a snippet of code can have in a program.
+pub fn effects(
+    code_map: &mut CodeMap,
+    files: Vec<Arc<File>>,
+    snippet: String,
+) -> Result<Vec<FunctionSignature>> {
+    let snippet = code_map.add_file("<snippet>".to_owned(), snippet);
+    Repl::new(code_map, files).effects(&snippet)
+}
+
+impl Repl {
+    fn new(code_map: &mut CodeMap, files: Vec<Arc<File>>) -> Self {
+        Self {
+            definitions_start: files.len(),
+            files,
+            line_start: code_map
+                .add_file("<repl>".to_owned(), format!("fn {LINE} : → do")),
+            line_end: code_map.add_file("<repl>".to_owned(), "end".to_owned()),
+            state: Snapshot::default(),
+            stack_types: Vec::new(),
+            checkpoints: Vec::new(),
+        }
+    }
+
+    fn checkpoint(&self) -> Checkpoint {
+        Checkpoint {
+            files: self.files.len(),
+            state: self.state.clone(),
+            stack_types: self.stack_types.clone(),
+        }
+    }
+
+    /// Goes back to how things were before the last line that defined
+    /// something or ran successfully.
+    fn undo(&mut self) -> Result<()> {
+        let checkpoint =
+            self.checkpoints.pop().context("there is nothing to undo")?;
+        self.files.truncate(checkpoint.files);
+        self.state = checkpoint.state;
+        self.stack_types = checkpoint.stack_types;
+        self.print_stack();
+        Ok(())
+    }
+
+    fn print_stack(&self) {
+        println!("stack: {}", self.state.stack.iter().format(" "));
+    }
+
+    /// The files that make up the program, followed by a line as the body of
+    /// the function `LINE`.
+    fn files_with_line(&self, line: &Arc<File>) -> Vec<Arc<File>> {
+        self.files
+            .iter()
+            .cloned()
+            .chain([
+                self.line_start.clone(),
+                line.clone(),
+                self.line_end.clone(),
+            ])
+            .collect()
+    }
+
+    fn print_effects(&self, snippet: &Arc<File>) -> Result<()> {
+        for effect in self.effects(snippet)? {
+            println!("{effect}");
+        }
+        Ok(())
+    }
+
+    fn effects(&self, snippet: &Arc<File>) -> Result<Vec<FunctionSignature>> {
+        let files = self.files_with_line(snippet);
+        typ::infer_effects(parser::parse(&files)?, LINE)
+    }
+
+    fn define(
+        &mut self,
+        code_map: &CodeMap,
+        file: Arc<File>,
+        flags: &Flags,
+    ) -> Result<()> {
+        ensure!(
+            !file.source().trim_start().starts_with("include"),
+            "files can't be included in the REPL"
+        );
+        let files = self
+            .files
+            .iter()
+            .cloned()
+            .chain([file.clone()])
+            .collect::<Vec<_>>();
+        let mut program = typ::check_library(parser::parse(&files)?, false)?;
+        report_warnings(&mut program, &file, code_map, flags)?;
+        self.checkpoints.push(self.checkpoint());
+        self.files.push(file);
+        Ok(())
+    }
+
+    /// Writes every definition that has been entered to a file, which can
+    /// then be loaded again or used as the start of a program.
+    fn save(&self, path: &str) -> Result<()> {
+        ensure!(!path.is_empty(), "`:save` needs the path of a file");
+        let definitions = self.files[self.definitions_start..]
+            .iter()
+            .map(|file| file.source().trim_end());
+        let source = format!("{}\n", definitions.format("\n"));
+        std::fs::write(path, source)
+            .with_context(|| format!("failed to write `{path}`"))
+    }
+
+    /// Enters the definitions in a file as if they had been typed in.
+    fn load(
+        &mut self,
+        code_map: &mut CodeMap,
+        path: &str,
+        flags: &Flags,
+    ) -> Result<()> {
+        ensure!(!path.is_empty(), "`:load` needs the path of a file");
+        let source = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read `{path}`"))?;
+        let file = code_map.add_file(path.to_owned(), source);
+        self.define(code_map, file, flags)
+    }
+
+    fn evaluate(
+        &mut self,
+        code_map: &CodeMap,
+        file: &Arc<File>,
+        flags: &Flags,
+    ) -> Result<()> {
+        let files = self.files_with_line(file);
+        let mut program =
+            typ::check_line(parser::parse(&files)?, LINE, &self.stack_types)?;
+        report_warnings(&mut program, file, code_map, flags)?;
+        let stack_types = program.function_signatures[LINE].returns.to_vec();
+        if let Some(quotation) = stack_types
+            .iter()
+            .flat_map(Type::flatten)
+            .find(|typ| matches!(typ, Type::Quotation(_)))
+        {
+            bail!(diagnostics::error(
+                format!(
+                    "the line leaves a value of type `{quotation}` on the stack"
+                ),
+                vec![primary_label(
+                    file.span.subspan(0, file.source().trim_end().len() as u64),
+                    "",
+                )],
+            )
+            .note("quotations can't be kept on the stack between lines"));
+        }
+
+        // The stack and variables are left as they were if the line doesn't
+        // finish.
+        let (result, state) = interpreter::interpret_function(
+            &program,
+            LINE,
+            self.state.clone(),
+            &mut std::io::stdin().lock(),
+            &mut std::io::stdout().lock(),
+        );
+        result?;
+        self.checkpoints.push(self.checkpoint());
+        self.state = state;
+        self.stack_types = stack_types;
+        self.print_stack();
+        Ok(())
+    }
+}
+
+/// Prints the warnings about what was just entered. Macros are often defined
+/// before being used, so they aren't warned about for being unused.
+fn report_warnings(
+    program: &mut typ::CheckedProgram,
+    file: &File,
+    code_map: &CodeMap,
+    flags: &Flags,
+) -> Result<()> {
+    let warnings = std::mem::take(&mut program.warnings)
+        .into_iter()
+        .filter(|warning| {
+            warning.lint != Some(Lint::UnusedMacro)
+                && warning
+                    .primary_span()
+                    .is_some_and(|span| file.span.contains(span))
+        })
+        .collect::<Vec<Diagnostic>>();
+    diagnostics::emit(&flags.lints.apply(warnings, None)?, code_map);
+    Ok(())
+}
@@ -0,0 +1,157 @@
+//! `spackel selftest`, which runs every program in a directory with both the
+//! interpreter and the compiler and checks that they print the same thing and
+//! exit with the same code. This catches code generation bugs that the type
+//! checker can't, since the interpreter is much simpler than the backend.
+
+use crate::{
+    cli::{self, Flags},
+    diagnostics, interpreter, parser, typ, Limits,
+};
+use anyhow::{ensure, Context, Result};
+use codemap::CodeMap;
+use std::{
+    path::{Path, PathBuf},
+    process::{Command, ExitCode, Stdio},
+};
+
+/// How a program finished.
+#[derive(PartialEq, Eq)]
+struct Outcome {
+    stdout: Vec<u8>,
+    /// `None` if the program was killed by a signal, such as when it traps.
+    exit_code: Option<i32>,
+}
+
+/// Runs the programs in a directory in name order. Each one gets no input and
+/// no arguments. The runtime has to have been built with `make` beforehand.
+pub fn run(
+    code_map: &mut CodeMap,
+    dir: &Path,
+    flags: &Flags,
+) -> Result<ExitCode> {
+    ensure!(
+        flags.runtime.exists(),
+        "`{}` is missing, run `make` to build it",
+        flags.runtime.display()
+    );
+    let mut paths = std::fs::read_dir(dir)
+        .with_context(|| format!("failed to read {}", dir.display()))?
+        .map(|entry| Ok(entry?.path()))
+        .filter(|path| {
+            path.as_ref()
+                .map_or(true, |path| path.extension() == Some("spkl".as_ref()))
+        })
+        .collect::<Result<Vec<PathBuf>>>()?;
+    paths.sort();
+
+    let build_dir = std::env::temp_dir()
+        .join(format!("spackel-selftest-{}", std::process::id()));
+    std::fs::create_dir_all(&build_dir)
+        .context("failed to create a directory for the executables")?;
+    let mut failed = 0;
+    let mut skipped = 0;
+    for path in &paths {
+        let name = path.display();
+        let files = cli::load_program(code_map, path, flags)?;
+        let program = typ::check(parser::parse(&files)?)?;
+        let exe = build_dir.join("main");
+        let mut stdout = Vec::new();
+        let (result, _) = interpreter::interpret(
+            &program,
+            &[exe.display().to_string()],
+            &mut std::io::empty(),
+            &mut stdout,
+            &Limits::default(),
+        );
+        let exit_code = match result {
+            Ok(()) => Some(0),
+            // Exit codes are truncated like on Unix.
+            Err(interpreter::Halt::Exit(code)) => Some(code & 0xff),
+            Err(
+                halt @ (interpreter::Halt::Unsupported(_)
+                | interpreter::Halt::StepLimit
+                | interpreter::Halt::CallDepthLimit
+                | interpreter::Halt::OutputLimit),
+            ) => {
+                println!("selftest {name} ... skipped ({halt})");
+                skipped += 1;
+                continue;
+            }
+            // Like `spkl_panic` in the runtime.
+            Err(_) => Some(1),
+        };
+        let interpreted = Outcome { stdout, exit_code };
+
+        let (output, warnings) = cli::compile(&files, flags)?;
+        diagnostics::emit(&warnings, code_map);
+        let object = build_dir.join("main.o");
+        std::fs::write(&object, &output.object)
+            .with_context(|| format!("failed to write {}", object.display()))?;
+        let compiled = link_and_run(&object, &exe, flags)?;
+
+        if compiled == interpreted {
+            println!("selftest {name} ... ok");
+            continue;
+        }
+        println!("selftest {name} ... FAILED");
+        if compiled.exit_code != interpreted.exit_code {
+            println!(
+                "  interpreted: {}\n  compiled:    {}",
+                describe_exit(interpreted.exit_code),
+                describe_exit(compiled.exit_code),
+            );
+        }
+        if compiled.stdout != interpreted.stdout {
+            println!(
+                "  interpreted output: {:?}\n  compiled output:    {:?}",
+                String::from_utf8_lossy(&interpreted.stdout),
+                String::from_utf8_lossy(&compiled.stdout),
+            );
+        }
+        failed += 1;
+    }
+    std::fs::remove_dir_all(&build_dir).ok();
+
+    println!(
+        "{} passed, {failed} failed, {skipped} skipped",
+        paths.len() - failed - skipped
+    );
+    Ok(if failed == 0 {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    })
+}
+
+/// Links an object file with the runtime and runs the executable.
+fn link_and_run(object: &Path, exe: &Path, flags: &Flags) -> Result<Outcome> {
+    let link_args = if flags.link_args.is_empty() {
+        &["-lm".to_owned()][..]
+    } else {
+        &flags.link_args
+    };
+    let status = Command::new(&flags.linker)
+        .arg("-o")
+        .arg(exe)
+        .arg(&flags.runtime)
+        .arg(object)
+        .args(link_args)
+        .status()
+        .with_context(|| format!("failed to run `{}`", flags.linker))?;
+    ensure!(status.success(), "linking failed with {status}");
+    let output = Command::new(exe)
+        .stdin(Stdio::null())
+        .output()
+        .with_context(|| format!("failed to run {}", exe.display()))?;
+    Ok(Outcome {
+        stdout: output.stdout,
+        exit_code: output.status.code(),
+    })
+}
+
+fn describe_exit(exit_code: Option<i32>) -> String {
+    exit_code.map_or_else(
+        || "killed by a signal".to_owned(),
+        |code| format!("exited with code {code}"),
+    )
+}
@@ -0,0 +1,188 @@
+//! A long-running process that checks, runs, formats and compiles programs on
+//! request, so that tools don't have to start a new process every time.
+
+use crate::{
+    cli::{self, Flags},
+    diagnostics, formatter,
+    jsonrpc::{self, Error},
+    shuffle, Limits, Target,
+};
+use anyhow::{Context, Result};
+use codemap::CodeMap;
+use serde_json::{json, Value};
+use std::{collections::BTreeMap, path::Path};
+
+/// How many `check` results to remember before starting over.
+const CHECK_CACHE_SIZE: usize = 64;
+
+/// Answers JSON-RPC requests from stdin on stdout until the input ends or a
+/// `shutdown` request arrives.
+pub fn serve() -> Result<()> {
+    let mut input = std::io::stdin().lock();
+    let mut output = std::io::stdout().lock();
+    let mut server = Server::default();
+
+    while let Some(message) = jsonrpc::read_message(&mut input)? {
+        let request = match message {
+            Ok(request) => request,
+            Err(err) => {
+                let response =
+                    jsonrpc::response(&Value::Null, Err(Error::parse(&err)));
+                jsonrpc::write_message(&mut output, &response)?;
+                continue;
+            }
+        };
+        // Notifications don't get a response.
+        let Some(id) = request.get("id").cloned() else {
+            continue;
+        };
+        let method = request.get("method").and_then(Value::as_str);
+        let result = method.map_or_else(
+            || Err(Error::invalid_request()),
+            |method| server.handle(method, &request["params"]),
+        );
+        jsonrpc::write_message(&mut output, &jsonrpc::response(&id, result))?;
+        if method == Some("shutdown") {
+            break;
+        }
+    }
+    Ok(())
+}
+
+#[derive(Default)]
+struct Server {
+    /// The diagnostics of programs that have already been checked, by source
+    /// code.
+    checked: BTreeMap<String, Vec<String>>,
+}
+
+impl Server {
+    fn handle(&mut self, method: &str, params: &Value) -> Result<Value, Error> {
+        match method {
+            "check" => {
+                let source_code = string_param(params, "source")?;
+                if self.checked.len() == CHECK_CACHE_SIZE {
+                    self.checked.clear();
+                }
+                let diagnostics = self
+                    .checked
+                    .entry(source_code.to_owned())
+                    .or_insert_with(|| crate::check_source(source_code));
+                Ok(json!({ "diagnostics": diagnostics }))
+            }
+            "run" => {
+                let source_code = string_param(params, "source")?;
+                let defaults = Limits::default();
+                let limits = Limits {
+                    max_steps: u64_param(params, "max_steps")?
+                        .unwrap_or(defaults.max_steps),
+                    max_call_depth: usize_param(params, "max_call_depth")?
+                        .unwrap_or(defaults.max_call_depth),
+                    max_output_len: usize_param(params, "max_output_len")?
+                        .unwrap_or(defaults.max_output_len),
+                };
+                let stdin = match params.get("stdin") {
+                    None => "",
+                    Some(_) => string_param(params, "stdin")?,
+                };
+                let result = crate::run_source(source_code, stdin, limits);
+                Ok(json!({
+                    "stdout": result.stdout,
+                    "diagnostics": result.diagnostics,
+                    "final_stack": result
+                        .final_stack
+                        .iter()
+                        .map(ToString::to_string)
+                        .collect::<Vec<_>>(),
+                    "exit_code": result.exit_code,
+                }))
+            }
+            "format" => {
+                let source_code = string_param(params, "source")?;
+                Ok(json!({ "source": formatter::format(source_code) }))
+            }
+            "compile" => compile(params),
+            "shuffle" => {
+                let effect = string_param(params, "effect")?;
+                shuffle::suggest(effect)
+                    .map(|words| json!({ "words": words }))
+                    .map_err(|err| Error::invalid_params(err.to_string()))
+            }
+            "shutdown" => Ok(Value::Null),
+            _ => Err(Error::method_not_found(method)),
+        }
+    }
+}
+
+/// Compiles the program at `path`, reporting the sizes of its functions.
+fn compile(params: &Value) -> Result<Value, Error> {
+    let path = string_param(params, "path")?;
+    let out_path = match params.get("out_path") {
+        None => "main.o",
+        Some(_) => string_param(params, "out_path")?,
+    };
+    let optimize_for_size = match params.get("opt").map(Value::as_str) {
+        None | Some(Some("speed")) => false,
+        Some(Some("size")) => true,
+        Some(_) => {
+            return Err(Error::invalid_params(
+                "`opt` must be \"speed\" or \"size\"".to_owned(),
+            ))
+        }
+    };
+
+    let target = match params.get("target") {
+        None => Target::default(),
+        Some(_) => string_param(params, "target")?.parse().map_err(
+            |err: anyhow::Error| Error::invalid_params(err.to_string()),
+        )?,
+    };
+
+    let mut code_map = CodeMap::new();
+    let result = (|| -> Result<_> {
+        let flags = Flags {
+            target,
+            optimize_for_size,
+            ..Flags::default()
+        };
+        let files = cli::load_program(&mut code_map, Path::new(path), &flags)?;
+        let (output, warnings) = cli::compile(&files, &flags)?;
+        std::fs::write(out_path, &output.object)
+            .with_context(|| format!("failed to write {out_path}"))?;
+        Ok((json!(output.sizes), warnings))
+    })();
+    Ok(match result {
+        Ok((sizes, warnings)) => json!({
+            "diagnostics": diagnostics::render_diagnostics(&warnings, &code_map),
+            "sizes": sizes,
+        }),
+        Err(err) => json!({
+            "diagnostics": diagnostics::render(&err, &code_map),
+        }),
+    })
+}
+
+fn string_param<'a>(params: &'a Value, name: &str) -> Result<&'a str, Error> {
+    params.get(name).and_then(Value::as_str).ok_or_else(|| {
+        Error::invalid_params(format!("`{name}` must be a string"))
+    })
+}
+
+fn u64_param(params: &Value, name: &str) -> Result<Option<u64>, Error> {
+    params
+        .get(name)
+        .map(|value| {
+            value.as_u64().ok_or_else(|| {
+                Error::invalid_params(format!(
+                    "`{name}` must be a non-negative integer"
+                ))
+            })
+        })
+        .transpose()
+}
+
+fn usize_param(params: &Value, name: &str) -> Result<Option<usize>, Error> {
+    u64_param(params, name).map(|value| {
+        value.map(|value| usize::try_from(value).unwrap_or(usize::MAX))
+    })
+}
@@ -0,0 +1,106 @@
+//! Finds the shortest sequence of stack shuffling words that rearranges the
+//! stack as described by a stack effect such as `a b c -- c a b`.
+
+use anyhow::{bail, ensure, Context, Result};
+use std::collections::{BTreeSet, VecDeque};
+
+/// The built-in shuffling words, each given as the number of values it takes
+/// and which of those it leaves behind, bottom first. Earlier words are
+/// preferred when several sequences are equally short.
+const WORDS: [(&str, usize, &[usize]); 8] = [
+    ("drop", 1, &[]),
+    ("dup", 1, &[0, 0]),
+    ("swap", 2, &[1, 0]),
+    ("over", 2, &[0, 1, 0]),
+    ("nip", 2, &[1]),
+    ("tuck", 2, &[1, 0, 1]),
+    ("rot", 3, &[1, 2, 0]),
+    ("-rot", 3, &[2, 0, 1]),
+];
+
+/// How many stacks to try before giving up, which takes a few seconds.
+const MAX_STACKS: usize = 1_000_000;
+
+/// Parses a stack effect, where every value on the right must be named on the
+/// left, and returns the words that carry it out.
+pub fn suggest(effect: &str) -> Result<Vec<String>> {
+    let (before, after) = effect
+        .split_once("--")
+        .or_else(|| effect.split_once('→'))
+        .context("the stack effect must be written as `a b -- b a`")?;
+    let before = before.split_whitespace().collect::<Vec<_>>();
+    if let Some((_, name)) = before
+        .iter()
+        .enumerate()
+        .find(|(i, name)| before[..*i].contains(name))
+    {
+        bail!("`{name}` is named more than once before `--`");
+    }
+    let target = after
+        .split_whitespace()
+        .map(|name| {
+            before
+                .iter()
+                .position(|&other| other == name)
+                .with_context(|| format!("`{name}` is not named before `--`"))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    ensure!(
+        before.len() <= 255 && target.len() <= 255,
+        "the stack effect has too many values"
+    );
+    search(before.len(), &target)
+}
+
+/// Breadth-first search from the values `0..inputs` to `target`, also trying
+/// `pick` and `roll` so that there always is a way.
+fn search(inputs: usize, target: &[usize]) -> Result<Vec<String>> {
+    let start = (0..inputs).collect::<Vec<_>>();
+    // Enough room to pick every value of the target before getting rid of the
+    // original ones.
+    let max_len = inputs + target.len();
+    let words = WORDS
+        .iter()
+        .map(|&(name, arity, outputs)| {
+            (name.to_owned(), arity, outputs.to_vec())
+        })
+        .chain((2..max_len).flat_map(|depth| {
+            let pick = (0..=depth).chain([0]).collect();
+            let roll = (1..=depth).chain([0]).collect();
+            [
+                (format!("{depth} pick"), depth + 1, pick),
+                (format!("{depth} roll"), depth + 1, roll),
+            ]
+        }))
+        .collect::<Vec<(String, usize, Vec<usize>)>>();
+
+    let mut seen = BTreeSet::from([start.clone()]);
+    let mut queue = VecDeque::from([(start, Vec::<usize>::new())]);
+    while let Some((stack, path)) = queue.pop_front() {
+        if stack == target {
+            return Ok(path.into_iter().map(|i| words[i].0.clone()).collect());
+        }
+        ensure!(
+            seen.len() <= MAX_STACKS,
+            "gave up after trying {MAX_STACKS} stacks"
+        );
+        for (i, (_, arity, outputs)) in words.iter().enumerate() {
+            let Some(rest) = stack.len().checked_sub(*arity) else {
+                continue;
+            };
+            let mut next = stack[..rest].to_vec();
+            next.extend(outputs.iter().map(|&output| stack[rest + output]));
+            // Values that have been dropped can't come back.
+            if next.len() > max_len
+                || !target.iter().all(|value| next.contains(value))
+                || !seen.insert(next.clone())
+            {
+                continue;
+            }
+            let mut path = path.clone();
+            path.push(i);
+            queue.push_back((next, path));
+        }
+    }
+    unreachable!("`pick`, `roll` and `drop` can produce any stack")
+}
@@ -1,14 +1,23 @@
+pub mod equivalence;
+pub mod interval;
 mod renaming;
+mod validation;
 
 use crate::{
     call_graph::Function,
-    ir::{BinLogicOp, BinMathOp, Block, Comparison, Instruction},
-    typ::{FunctionSignature, Generics, Type},
+    ir::{
+        self, Abi, BinLogicOp, BinMathOp, BitwiseOp, Block, Comparison,
+        Instruction, MathFunction, Rounding, SmallInt,
+    },
+    typ::{DataTable, FunctionSignature, Generics, Type},
 };
+use codemap::Span;
+use interval::Intervals;
 use itertools::Itertools;
 use renaming::Renames;
 use std::{
     collections::{BTreeMap, BTreeSet},
+    convert::Infallible,
     fmt, mem,
     ops::{ControlFlow, Range},
 };
@@ -16,17 +25,25 @@ use std::{
 pub struct Program<'src> {
     pub function_signatures: BTreeMap<&'src str, FunctionSignature>,
     pub function_bodies: BTreeMap<&'src str, Graph>,
+    pub variables: BTreeMap<&'src str, Type>,
+    pub data: BTreeMap<&'src str, DataTable>,
+    pub inline_functions: BTreeSet<&'src str>,
 }
 
 pub fn convert<'src>(
     program: crate::typ::CheckedProgram<'src>,
     value_generator: &mut ValueGenerator,
 ) -> Program<'src> {
+    let function_signatures = program
+        .function_signatures
+        .iter()
+        .map(|(&name, signature)| (name, signature.flatten()))
+        .collect::<BTreeMap<_, _>>();
     let function_bodies = program
         .function_bodies
         .into_iter()
         .map(|(name, body)| {
-            let input_count = program.function_signatures[&name]
+            let input_count = function_signatures[&name]
                 .parameters
                 .len()
                 .try_into()
@@ -34,7 +51,7 @@ pub fn convert<'src>(
             let body = Graph::from_block(
                 body,
                 input_count,
-                &program.function_signatures,
+                &function_signatures,
                 value_generator,
             );
             (name, body)
@@ -42,8 +59,11 @@ pub fn convert<'src>(
         .collect();
 
     Program {
-        function_signatures: program.function_signatures,
+        function_signatures,
         function_bodies,
+        variables: program.variables,
+        data: program.data,
+        inline_functions: program.inline_functions,
     }
 }
 
@@ -137,6 +157,11 @@ impl Iterator for ValueSequenceIter {
 pub struct ValueGenerator(u32);
 
 impl ValueGenerator {
+    /// How many values have been made so far.
+    pub const fn count(&self) -> u32 {
+        self.0
+    }
+
     pub fn new_value_sequence(&mut self, count: u8) -> ValueSequence {
         let start = self.0;
         self.0 += u32::from(count);
@@ -157,6 +182,34 @@ impl Graph {
         input_count: u8,
         function_signatures: &BTreeMap<&str, FunctionSignature>,
         value_generator: &mut ValueGenerator,
+    ) -> Self {
+        Self::from_fn(
+            input_count,
+            value_generator,
+            |graph, renames, value_generator, stack| {
+                for instruction in block {
+                    graph.add_instruction(
+                        instruction,
+                        renames,
+                        value_generator,
+                        function_signatures,
+                        stack,
+                    );
+                }
+            },
+        )
+    }
+
+    /// Builds a graph whose outputs are whatever `build` leaves on the stack.
+    fn from_fn(
+        input_count: u8,
+        value_generator: &mut ValueGenerator,
+        build: impl FnOnce(
+            &mut Self,
+            &mut Renames,
+            &mut ValueGenerator,
+            &mut Vec<Value>,
+        ),
     ) -> Self {
         let inputs = value_generator.new_value_sequence(input_count);
         let mut graph = Self {
@@ -166,15 +219,7 @@ impl Graph {
         };
         let mut stack = inputs.iter().collect();
         let mut renames = Renames::default();
-        for instruction in block {
-            graph.add_instruction(
-                instruction,
-                &mut renames,
-                value_generator,
-                function_signatures,
-                &mut stack,
-            );
-        }
+        build(&mut graph, &mut renames, value_generator, &mut stack);
         renames.apply_to_slice(&mut stack);
         graph.outputs = stack;
         graph
@@ -188,14 +233,131 @@ impl Graph {
             .map(|assignment| &assignment.op)
     }
 
-    pub fn is_small_enough_to_inline(&self) -> bool {
-        self.contains_at_most_n_ops(10)
+    /// The literal that the graph produces as its only output, provided that
+    /// it has no other effects.
+    pub fn as_literal(&self) -> Option<&Op> {
+        match *self.as_literals()? {
+            [op] => Some(op),
+            _ => None,
+        }
+    }
+
+    /// The literals that the graph produces as its outputs, provided that it
+    /// has no other effects.
+    pub fn as_literals(&self) -> Option<Vec<&Op>> {
+        if !self
+            .assignments
+            .iter()
+            .all(|assignment| assignment.op.pure())
+        {
+            return None;
+        }
+        self.outputs
+            .iter()
+            .map(|&output| {
+                self.source_op(output).filter(|op| op.trivially_dupable())
+            })
+            .collect()
+    }
+
+    /// Whether running the graph has no effects besides producing its
+    /// outputs.
+    fn is_pure(&self) -> bool {
+        self.assignments
+            .iter()
+            .all(|assignment| assignment.op.removable())
+    }
+
+    /// Whether the graph always leaves the surrounding loop or function
+    /// instead of producing its outputs.
+    pub fn diverges(&self) -> bool {
+        self.assignments
+            .last()
+            .is_some_and(|assignment| match &assignment.op {
+                Op::Break(_)
+                | Op::Continue(_)
+                | Op::Recurse
+                | Op::Return
+                | Op::Exit => true,
+                Op::ThenElse(then, else_) => {
+                    then.diverges() && else_.diverges()
+                }
+                Op::Match(cases) | Op::Switch(_, cases) => {
+                    cases.iter().all(Self::diverges)
+                }
+                _ => false,
+            })
+    }
+
+    /// Turns calls to the function named `name` whose results are returned
+    /// right away into jumps back to the start of the function, so that tail
+    /// recursion runs in constant stack space. The graph must be in tail
+    /// position within that function.
+    pub fn eliminate_self_tail_calls(&mut self, name: &str) {
+        let Some(last) = self.assignments.last_mut() else {
+            return;
+        };
+        if !last.to.iter().eq(self.outputs.iter().copied()) {
+            return;
+        }
+        match &mut last.op {
+            Op::Call(callee) if **callee == *name => last.op = Op::Recurse,
+            Op::Then(body) => {
+                body.eliminate_self_tail_calls(name);
+                return;
+            }
+            Op::ThenElse(then, else_) => {
+                then.eliminate_self_tail_calls(name);
+                else_.eliminate_self_tail_calls(name);
+                if !(then.diverges() && else_.diverges()) {
+                    return;
+                }
+            }
+            Op::Match(cases) | Op::Switch(_, cases) => {
+                for case in &mut **cases {
+                    case.eliminate_self_tail_calls(name);
+                }
+                if !cases.iter().all(Self::diverges) {
+                    return;
+                }
+            }
+            _ => return,
+        }
+        last.to = ValueSequence::default();
+        self.outputs.clear();
+    }
+
+    /// Whether the graph contains a `return`, which would return from the
+    /// caller instead if the graph was inlined.
+    pub fn returns_early(&self) -> bool {
+        self.each_op(&mut |op| {
+            if matches!(op, Op::Return) {
+                ControlFlow::Break(())
+            } else {
+                ControlFlow::Continue(())
+            }
+        })
+        .is_break()
     }
 
-    fn contains_at_most_n_ops(&self, n: usize) -> bool {
+    /// How many operations there are, including those in nested graphs.
+    pub fn op_count(&self) -> usize {
         let mut op_count = 0;
         self.each_op(&mut |_| {
-            if op_count < n {
+            op_count += 1;
+            ControlFlow::<Infallible>::Continue(())
+        });
+        op_count
+    }
+
+    pub fn contains_at_most_n_ops(&self, n: usize) -> bool {
+        let mut op_count = 0;
+        self.each_op(&mut |op| {
+            // Locations don't end up as code, so they shouldn't affect what
+            // gets inlined.
+            if matches!(op, Op::Location(_)) {
+                ControlFlow::Continue(())
+            } else if op_count < n {
                 op_count += 1;
                 ControlFlow::Continue(())
             } else {
@@ -217,7 +379,16 @@ impl Graph {
                     then.each_op(f)?;
                     else_.each_op(f)?;
                 }
-                Op::Then(body) | Op::Repeat(body) => {
+                Op::Match(cases) | Op::Switch(_, cases) => {
+                    for case in &**cases {
+                        case.each_op(f)?;
+                    }
+                }
+                Op::Then(body) | Op::Repeat(body) | Op::Quotation(_, body) => {
+                    body.each_op(f)?;
+                }
+                Op::While(condition, body) => {
+                    condition.each_op(f)?;
                     body.each_op(f)?;
                 }
                 _ => {}
@@ -226,6 +397,75 @@ impl Graph {
         ControlFlow::Continue(())
     }
 
+    pub fn each_op_mut(&mut self, f: &mut impl FnMut(&mut Op)) {
+        for assignment in &mut self.assignments {
+            let op = &mut assignment.op;
+            f(op);
+            match op {
+                Op::ThenElse(then, else_) => {
+                    then.each_op_mut(f);
+                    else_.each_op_mut(f);
+                }
+                Op::Match(cases) | Op::Switch(_, cases) => {
+                    for case in &mut **cases {
+                        case.each_op_mut(f);
+                    }
+                }
+                Op::Then(body) | Op::Repeat(body) | Op::Quotation(_, body) => {
+                    body.each_op_mut(f);
+                }
+                Op::While(condition, body) => {
+                    condition.each_op_mut(f);
+                    body.each_op_mut(f);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// A textual representation of the graph in which values are numbered
+    /// from zero, so that two graphs have the same canonical form if they only
+    /// differ in the numbering of their values or in where they came from.
+    pub fn canonical_form(&self) -> String {
+        let mut graph = self.renumbered();
+        let is_location =
+            |assignment: &Assignment| matches!(assignment.op, Op::Location(_));
+        graph
+            .assignments
+            .retain(|assignment| !is_location(assignment));
+        graph.each_op_mut(&mut |op| {
+            let bodies = match op {
+                Op::ThenElse(then, else_) => vec![&mut **then, &mut **else_],
+                Op::Match(cases) | Op::Switch(_, cases) => {
+                    cases.iter_mut().collect()
+                }
+                Op::Then(body) | Op::Repeat(body) | Op::Quotation(_, body) => {
+                    vec![&mut **body]
+                }
+                Op::While(condition, body) => {
+                    vec![&mut **condition, &mut **body]
+                }
+                _ => Vec::new(),
+            };
+            for body in bodies {
+                body.assignments
+                    .retain(|assignment| !is_location(assignment));
+            }
+        });
+        format!("{graph:?}")
+    }
+
+    /// A copy of the graph in which values are numbered from zero.
+    pub fn renumbered(&self) -> Self {
+        let mut graph = self.clone();
+        refresh_graph(&mut graph, &mut ValueGenerator::default(), true);
+        graph
+    }
+
+    #[expect(
+        clippy::too_many_lines,
+        reason = "every instruction is translated in its own arm"
+    )]
     fn add_instruction(
         &mut self,
         (instruction, generics): (Instruction<Generics>, Generics),
@@ -243,6 +483,14 @@ impl Graph {
                     Op::Call(name),
                 )
             }
+            Instruction::CallExtern(name, abi) => {
+                let signature = function_signatures[&*name].clone();
+                (
+                    signature.returns.len(),
+                    signature.parameters.len(),
+                    Op::CallExtern(name, abi, signature),
+                )
+            }
             Instruction::Then(body) => {
                 let body_graph = Self::from_block(
                     body,
@@ -265,12 +513,79 @@ impl Graph {
                     function_signatures,
                     value_generator,
                 );
+                let outputs = if then_graph.diverges() {
+                    &else_graph.outputs
+                } else {
+                    &then_graph.outputs
+                };
                 (
-                    then_graph.outputs.len(),
+                    outputs.len(),
                     stack.len(),
                     Op::ThenElse(Box::new(then_graph), Box::new(else_graph)),
                 )
             }
+            Instruction::Match(cases) => {
+                let case_graphs = Box::into_iter(cases)
+                    .map(|case| {
+                        Self::from_block(
+                            case.body,
+                            (stack.len() - 1).try_into().unwrap(),
+                            function_signatures,
+                            value_generator,
+                        )
+                    })
+                    .collect::<Box<_>>();
+                let output_count = case_graphs
+                    .iter()
+                    .find(|graph| !graph.diverges())
+                    .map_or(0, |graph| graph.outputs.len());
+                (output_count, stack.len(), Op::Match(case_graphs))
+            }
+            Instruction::Switch { arms, else_ } => {
+                let values = arms.iter().map(|arm| arm.value).collect();
+                let graphs = Vec::from(arms)
+                    .into_iter()
+                    .map(|arm| arm.body)
+                    .chain([else_])
+                    .map(|body| {
+                        Self::from_block(
+                            body,
+                            (stack.len() - 1).try_into().unwrap(),
+                            function_signatures,
+                            value_generator,
+                        )
+                    })
+                    .collect::<Box<_>>();
+                let output_count = graphs
+                    .iter()
+                    .find(|graph| !graph.diverges())
+                    .map_or(0, |graph| graph.outputs.len());
+                (output_count, stack.len(), Op::Switch(values, graphs))
+            }
+            Instruction::Quotation(quotation) => {
+                let Type::Quotation(signature) = &generics[0] else {
+                    unreachable!()
+                };
+                let signature = signature.flatten();
+                let body_graph = Self::from_block(
+                    quotation.body.unwrap(),
+                    signature.parameters.len().try_into().unwrap(),
+                    function_signatures,
+                    value_generator,
+                );
+                (1, 0, Op::Quotation(signature, Box::new(body_graph)))
+            }
+            Instruction::CallQuotation => {
+                let Type::Quotation(signature) = &generics[0] else {
+                    unreachable!()
+                };
+                let signature = signature.flatten();
+                (
+                    signature.returns.len(),
+                    signature.parameters.len() + 1,
+                    Op::CallQuotation(signature),
+                )
+            }
             Instruction::Repeat { body, .. } => {
                 let body_graph = Self::from_block(
                     body,
@@ -280,7 +595,53 @@ impl Graph {
                 );
                 (stack.len(), stack.len(), Op::Repeat(Box::new(body_graph)))
             }
-            Instruction::Unsafe(body) => {
+            Instruction::While {
+                condition, body, ..
+            } => {
+                let condition_graph = Self::from_block(
+                    condition,
+                    stack.len().try_into().unwrap(),
+                    function_signatures,
+                    value_generator,
+                );
+                let body_graph = Self::from_block(
+                    body,
+                    stack.len().try_into().unwrap(),
+                    function_signatures,
+                    value_generator,
+                );
+                (
+                    stack.len(),
+                    stack.len(),
+                    Op::While(Box::new(condition_graph), Box::new(body_graph)),
+                )
+            }
+            Instruction::Times {
+                body,
+                pushes_index,
+                span,
+                ..
+            } => {
+                self.add_counted_loop(
+                    body,
+                    pushes_index,
+                    span,
+                    renames,
+                    value_generator,
+                    function_signatures,
+                    stack,
+                );
+                return;
+            }
+            Instruction::Break { depth, .. } => {
+                (0, stack.len(), Op::Break(depth))
+            }
+            Instruction::Continue { depth, .. } => {
+                (0, stack.len(), Op::Continue(depth))
+            }
+            Instruction::Return => (0, generics.len(), Op::Return),
+            Instruction::Exit => (0, stack.len(), Op::Exit),
+            Instruction::Unsafe(body) | Instruction::Stash { body, .. } => {
                 for instruction in body {
                     self.add_instruction(
                         instruction,
@@ -297,14 +658,31 @@ impl Graph {
             Instruction::PushI32(n) => (1, 0, Op::I32(n)),
             Instruction::PushF32(n) => (1, 0, Op::F32(n)),
             Instruction::PushBool(b) => (1, 0, Op::Bool(b)),
-            Instruction::PushType(_) => (1, 0, Op::Type),
+            Instruction::PushType(typ) => (1, 0, Op::Type(typ)),
             Instruction::PrintChar => (0, 1, Op::PrintChar),
+            Instruction::ReadChar => (2, 0, Op::ReadChar),
+            Instruction::ReadI32 => (2, 0, Op::ReadI32),
+            Instruction::ReadLine => (1, 0, Op::ReadLine),
+            Instruction::ArgCount => (1, 0, Op::ArgCount),
+            Instruction::Load(name) => (
+                1,
+                0,
+                Op::Load(name, Box::into_iter(generics).next().unwrap()),
+            ),
+            Instruction::Store(name) => (0, 1, Op::Store(name)),
+            Instruction::Arg => (1, 1, Op::Arg),
+            Instruction::Open => (2, 2, Op::Open),
+            Instruction::Read => (3, 1, Op::Read),
+            Instruction::Write => (1, 2, Op::Write),
+            Instruction::Close => (0, 1, Op::Close),
+            Instruction::Assert(span) => (0, 1, Op::Assert(span)),
             Instruction::Print => (
                 0,
                 1,
                 match generics[0] {
                     Type::I32 => Op::PrintI32,
                     Type::F32 => Op::PrintF32,
+                    Type::Bool => Op::PrintBool,
                     _ => unreachable!(),
                 },
             ),
@@ -314,31 +692,67 @@ impl Graph {
                 match generics[0] {
                     Type::I32 => Op::PrintlnI32,
                     Type::F32 => Op::PrintlnF32,
+                    Type::Bool => Op::PrintlnBool,
                     _ => unreachable!(),
                 },
             ),
             Instruction::Not => (1, 1, Op::Not),
             Instruction::Sqrt => (1, 1, Op::Sqrt),
-            Instruction::TypeOf => (1, 1, Op::TypeOf),
+            Instruction::Round(rounding) => (1, 1, Op::Round(rounding)),
+            Instruction::I32ToF32 => (1, 1, Op::I32ToF32),
+            Instruction::F32ToI32(rounding) => (1, 1, Op::F32ToI32(rounding)),
+            Instruction::Math(function) => (
+                1,
+                function.arity(),
+                Op::Math {
+                    function,
+                    typ: Box::into_iter(generics).next().unwrap_or(Type::F32),
+                },
+            ),
+            Instruction::TypeOf => {
+                (1, 1, Op::TypeOf(Box::into_iter(generics).next().unwrap()))
+            }
             Instruction::Ptr => (1, 1, Op::Ptr),
+            Instruction::TypeEq => (1, 2, Op::TypeEq),
+            Instruction::PrintType => (0, 1, Op::PrintType),
             Instruction::AddrOf => {
-                (1, 1, Op::AddrOf(Box::into_iter(generics).next().unwrap()))
+                (2, 1, Op::AddrOf(Box::into_iter(generics).next().unwrap()))
             }
             Instruction::ReadPtr => {
                 (1, 1, Op::ReadPtr(Box::into_iter(generics).next().unwrap()))
             }
-            Instruction::BinMathOp(operation) => (
+            Instruction::ReadSmall(int) => (1, 1, Op::ReadSmall(int)),
+            Instruction::WritePtr => (0, 2, Op::WritePtr),
+            Instruction::WriteSmall(int) => (0, 2, Op::WriteSmall(int)),
+            Instruction::MemCopy => (0, 3, Op::MemCopy),
+            Instruction::MemFill => (0, 3, Op::MemFill),
+            Instruction::MemCompare => (1, 3, Op::MemCompare),
+            Instruction::Null(_) => (1, 0, Op::Null),
+            Instruction::PtrAdd => (1, 2, Op::PtrAdd),
+            Instruction::PtrSub => (1, 2, Op::PtrSub),
+            Instruction::PtrDiff => (1, 2, Op::PtrDiff),
+            Instruction::BinMathOp(operation, span) => (
                 1,
                 2,
                 Op::BinMath {
                     operation,
-                    typ: Box::into_iter(generics).next(),
+                    // Only `+`, `-`, `×` and `÷` also work on `f32`, so the
+                    // others don't have a generic.
+                    typ: Box::into_iter(generics).next().unwrap_or(Type::I32),
+                    span,
+                },
+            ),
+            Instruction::Comparison(comparison) => (
+                1,
+                2,
+                Op::Compare {
+                    comparison,
+                    typ: Box::into_iter(generics).next().unwrap(),
                 },
             ),
-            Instruction::Comparison(comparison) => {
-                (1, 2, Op::Compare(comparison))
-            }
             Instruction::BinLogicOp(op) => (1, 2, Op::BinLogic(op)),
+            Instruction::BitNot => (1, 1, Op::BitNot),
+            Instruction::BitwiseOp(op) => (1, 2, Op::Bitwise(op)),
             Instruction::Swap => {
                 let a = stack.len() - 2;
                 let b = stack.len() - 1;
@@ -385,6 +799,63 @@ impl Graph {
                 stack[len - 3..].rotate_right(1);
                 return;
             }
+            Instruction::Rot => {
+                let len = stack.len();
+                stack[len - 3..].rotate_left(1);
+                return;
+            }
+            Instruction::RotBack => {
+                let len = stack.len();
+                stack[len - 3..].rotate_right(1);
+                return;
+            }
+            Instruction::Pick(depth) => {
+                let index = stack.len() - 1 - usize::from(depth);
+                stack[index..].rotate_left(1);
+                self.add_instruction(
+                    (
+                        Instruction::Dup,
+                        Box::new([Box::into_iter(generics).next().unwrap()]),
+                    ),
+                    renames,
+                    value_generator,
+                    function_signatures,
+                    stack,
+                );
+                let len = stack.len();
+                stack[index..len - 1].rotate_right(1);
+                return;
+            }
+            Instruction::Roll(depth) => {
+                let index = stack.len() - 1 - usize::from(depth);
+                stack[index..].rotate_left(1);
+                return;
+            }
+            Instruction::Bury(depth) => {
+                let index = stack.len() - 1 - usize::from(depth);
+                stack[index..].rotate_right(1);
+                return;
+            }
+            Instruction::ToStash(_)
+            | Instruction::FromStash(_)
+            | Instruction::Let { .. }
+            | Instruction::Local(_) => {
+                unreachable!(
+                    "stash operations and locals are resolved by the type checker"
+                )
+            }
+            Instruction::FunctionPointer(name) => {
+                (1, 0, Op::FunctionPointer(name))
+            }
+            Instruction::Data(name) => (1, 0, Op::Data(name)),
+            Instruction::Location(span) => (0, 0, Op::Location(span)),
+            Instruction::Combinator(_)
+            | Instruction::Invoke(_)
+            | Instruction::MacroExpansion { .. } => {
+                unreachable!(
+                    "combinators and macro expansions are lowered by the type checker"
+                )
+            }
         };
         let to =
             value_generator.new_value_sequence(to_count.try_into().unwrap());
@@ -395,6 +866,108 @@ impl Graph {
         self.add(Assignment { to, args, op }, renames);
     }
 
+    /// Lowers `n times BODY end` to a `while` loop. The index and limit are
+    /// kept at the bottom of the stack, where the body can't reach them.
+    #[expect(
+        clippy::too_many_arguments,
+        reason = "the parts of the loop and the state of `add_instruction`"
+    )]
+    fn add_counted_loop(
+        &mut self,
+        body: Box<Block<Generics>>,
+        pushes_index: bool,
+        span: Span,
+        renames: &mut Renames,
+        value_generator: &mut ValueGenerator,
+        function_signatures: &BTreeMap<&str, FunctionSignature>,
+        stack: &mut Vec<Value>,
+    ) {
+        let limit = stack.pop().unwrap();
+        let index = value_generator.new_value_sequence(1) + 0;
+        self.i32(index, 0);
+        stack.splice(0..0, [index, limit]);
+        let input_count = stack.len().try_into().unwrap();
+
+        let condition_graph = Self::from_fn(
+            input_count,
+            value_generator,
+            |graph, renames, value_generator, stack| {
+                stack.rotate_left(2);
+                for instruction in [
+                    Instruction::Over,
+                    Instruction::Over,
+                    Instruction::Comparison(Comparison::Lt),
+                ] {
+                    graph.add_instruction(
+                        (instruction, Box::new([Type::I32, Type::I32])),
+                        renames,
+                        value_generator,
+                        function_signatures,
+                        stack,
+                    );
+                }
+                let len = stack.len();
+                stack[..len - 1].rotate_right(2);
+            },
+        );
+        let body_graph = Self::from_fn(
+            input_count,
+            value_generator,
+            |graph, renames, value_generator, stack| {
+                let limit = stack.remove(1);
+                let index = stack.remove(0);
+                stack.push(index);
+                // Incrementing the index first means that `continue` doesn't
+                // need to do anything special.
+                for instruction in [
+                    Instruction::Dup,
+                    Instruction::PushI32(1),
+                    Instruction::BinMathOp(BinMathOp::Add, span),
+                ] {
+                    graph.add_instruction(
+                        (instruction, Box::new([Type::I32])),
+                        renames,
+                        value_generator,
+                        function_signatures,
+                        stack,
+                    );
+                }
+                let next_index = stack.pop().unwrap();
+                let index = stack.pop().unwrap();
+                stack.splice(0..0, [next_index, limit]);
+                if pushes_index {
+                    stack.push(index);
+                } else {
+                    graph.drop(index, renames);
+                }
+                for instruction in body {
+                    graph.add_instruction(
+                        instruction,
+                        renames,
+                        value_generator,
+                        function_signatures,
+                        stack,
+                    );
+                }
+            },
+        );
+
+        let to = value_generator.new_value_sequence(input_count);
+        let args = std::mem::take(stack).into();
+        self.add(
+            Assignment {
+                to,
+                args,
+                op: Op::While(Box::new(condition_graph), Box::new(body_graph)),
+            },
+            renames,
+        );
+        stack.extend(to.iter().skip(2));
+        for hidden in to.iter().take(2) {
+            self.drop(hidden, renames);
+        }
+    }
+
     fn drop(&mut self, value: Value, renames: &mut Renames) {
         self.add(
             Assignment {
@@ -430,6 +1003,10 @@ impl Graph {
         });
     }
 
+    #[expect(
+        clippy::too_many_lines,
+        reason = "every operation is simplified in its own arm"
+    )]
     fn add(
         &mut self,
         Assignment {
@@ -442,12 +1019,15 @@ impl Graph {
         renames.apply_to_slice(&mut args);
 
         match op {
+            // Bodies that diverge can't be inlined since the rest of the graph
+            // would become unreachable, but they can still be skipped.
             Op::Then(ref mut body) => {
                 let (&condition_value, args) = args.split_last().unwrap();
-                if let Some(Op::Bool(condition)) =
-                    self.source_op(condition_value)
+                if let Some(&Op::Bool(condition)) =
+                    self.source_op(condition_value).filter(|op| {
+                        op.as_bool() == Some(false) || !body.diverges()
+                    })
                 {
-                    let condition = *condition;
                     self.drop(condition_value, renames);
                     if condition {
                         renames.extend(
@@ -468,10 +1048,12 @@ impl Graph {
             }
             Op::ThenElse(ref mut then, ref mut else_) => {
                 let (&condition_value, args) = args.split_last().unwrap();
-                if let Some(Op::Bool(condition)) =
-                    self.source_op(condition_value)
+                if let Some(body) = self
+                    .source_op(condition_value)
+                    .and_then(Op::as_bool)
+                    .map(|condition| if condition { then } else { else_ })
+                    .filter(|body| !body.diverges())
                 {
-                    let body = if *condition { then } else { else_ };
                     self.drop(condition_value, renames);
                     renames
                         .extend(body.inputs.iter().zip(args.iter().copied()));
@@ -483,6 +1065,92 @@ impl Graph {
                     return;
                 }
             }
+            Op::Match(ref mut cases) => {
+                let (&tag_value, args) = args.split_last().unwrap();
+                if let Some(body) = self
+                    .source_op(tag_value)
+                    .and_then(|op| match *op {
+                        Op::I32(tag) => usize::try_from(tag).ok(),
+                        _ => None,
+                    })
+                    .and_then(|tag| cases.get_mut(tag))
+                    .filter(|body| !body.diverges())
+                {
+                    self.drop(tag_value, renames);
+                    renames
+                        .extend(body.inputs.iter().zip(args.iter().copied()));
+                    for assignment in mem::take(&mut body.assignments) {
+                        self.add(assignment, renames);
+                    }
+                    renames.apply_to_slice(&mut body.outputs);
+                    renames.extend(to.iter().zip(body.outputs.iter().copied()));
+                    return;
+                }
+            }
+            Op::Switch(ref values, ref mut cases) => {
+                let (&value, args) = args.split_last().unwrap();
+                if let Some(body) = self
+                    .i32_literal(value)
+                    .map(|value| {
+                        let index = values.iter().position(|&v| v == value);
+                        &mut cases[index.unwrap_or(values.len())]
+                    })
+                    .filter(|body| !body.diverges())
+                {
+                    self.drop(value, renames);
+                    renames
+                        .extend(body.inputs.iter().zip(args.iter().copied()));
+                    for assignment in mem::take(&mut body.assignments) {
+                        self.add(assignment, renames);
+                    }
+                    renames.apply_to_slice(&mut body.outputs);
+                    renames.extend(to.iter().zip(body.outputs.iter().copied()));
+                    return;
+                }
+            }
+            // Calling a quotation that was just pushed is the same as running
+            // its body right here, and calling a function pointer that was
+            // just pushed is the same as calling the function directly. Since
+            // every value is used exactly once, nothing else needs the
+            // quotation.
+            Op::CallQuotation(_) => {
+                let (&quotation, args) = args.split_last().unwrap();
+                if let Some(index) =
+                    self.assignments.iter().position(|assignment| {
+                        assignment.to.range().contains(&quotation)
+                            && match &assignment.op {
+                                Op::Quotation(_, body) => !body.diverges(),
+                                Op::FunctionPointer(_) => true,
+                                _ => false,
+                            }
+                    })
+                {
+                    let body = match self.assignments.remove(index).op {
+                        Op::Quotation(_, body) => body,
+                        Op::FunctionPointer(name) => {
+                            self.assignments.push(Assignment {
+                                to,
+                                args: args.into(),
+                                op: Op::Call(name),
+                            });
+                            return;
+                        }
+                        _ => unreachable!(),
+                    };
+                    let Self {
+                        inputs,
+                        assignments,
+                        mut outputs,
+                    } = *body;
+                    renames.extend(inputs.iter().zip(args.iter().copied()));
+                    for assignment in assignments {
+                        self.add(assignment, renames);
+                    }
+                    renames.apply_to_slice(&mut outputs);
+                    renames.extend(to.iter().zip(outputs));
+                    return;
+                }
+            }
             Op::Dup => {
                 if let Some(source) =
                     self.source_op(args[0]).filter(|op| op.trivially_dupable())
@@ -523,6 +1191,12 @@ impl Graph {
                             (1, 1) => Some(1),
                             _ => a.checked_add(*b),
                         },
+                        BinMathOp::SaturatingAdd => Some(a.saturating_add(*b)),
+                        BinMathOp::SaturatingSub => Some(a.saturating_sub(*b)),
+                        BinMathOp::SaturatingMul => Some(a.saturating_mul(*b)),
+                        BinMathOp::WrappingAdd => Some(a.wrapping_add(*b)),
+                        BinMathOp::WrappingSub => Some(a.wrapping_sub(*b)),
+                        BinMathOp::WrappingMul => Some(a.wrapping_mul(*b)),
                     } {
                         self.drop(args[0], renames);
                         self.drop(args[1], renames);
@@ -535,6 +1209,7 @@ impl Graph {
                         BinMathOp::Sub => *a - *b,
                         BinMathOp::Mul => *a * *b,
                         BinMathOp::Div => *a / *b,
+                        BinMathOp::SillyAdd => ir::silly_add_f32(*a, *b),
                         _ => unreachable!(),
                     };
                     self.drop(args[0], renames);
@@ -543,23 +1218,30 @@ impl Graph {
                     return;
                 }
             }
-            Op::Compare(comparison) => {
-                let a = self.source_op(args[0]);
-                let b = self.source_op(args[1]);
-                if let (Some(Op::I32(a)), Some(Op::I32(b))) = (a, b) {
-                    let res = match comparison {
-                        Comparison::Lt => *a < *b,
-                        Comparison::Le => *a <= *b,
-                        Comparison::Eq => *a == *b,
-                        Comparison::Ge => *a >= *b,
-                        Comparison::Gt => *a > *b,
+            Op::Compare { comparison, .. } => {
+                let res =
+                    match (self.source_op(args[0]), self.source_op(args[1])) {
+                        (Some(Op::I32(a)), Some(Op::I32(b))) => {
+                            Some(comparison.apply(a, b))
+                        }
+                        (Some(Op::F32(a)), Some(Op::F32(b))) => {
+                            Some(comparison.apply(a, b))
+                        }
+                        _ => None,
                     };
+                if let Some(res) = res {
                     self.drop(args[0], renames);
                     self.drop(args[1], renames);
                     self.bool(to + 0, res);
                     return;
                 }
             }
+            Op::Assert(_) => {
+                if matches!(self.source_op(args[0]), Some(Op::Bool(true))) {
+                    self.drop(args[0], renames);
+                    return;
+                }
+            }
             Op::Sqrt => {
                 if let Some(Op::F32(num)) = self.source_op(args[0]) {
                     let num = *num;
@@ -568,6 +1250,63 @@ impl Graph {
                     return;
                 }
             }
+            Op::Math { function, .. } => {
+                if let Some(result) = self.fold_math(function, &args) {
+                    for &arg in &*args {
+                        self.drop(arg, renames);
+                    }
+                    self.assignments.push(Assignment {
+                        to,
+                        args: [].into(),
+                        op: result,
+                    });
+                    return;
+                }
+            }
+            Op::Round(rounding) => {
+                if let Some(&Op::F32(n)) = self.source_op(args[0]) {
+                    self.drop(args[0], renames);
+                    self.f32(to + 0, rounding.apply(n));
+                    return;
+                }
+            }
+            Op::I32ToF32 => {
+                if let Some(n) = self.i32_literal(args[0]) {
+                    self.drop(args[0], renames);
+                    self.f32(to + 0, ir::i32_to_f32(n));
+                    return;
+                }
+            }
+            Op::F32ToI32(rounding) => {
+                if let Some(&Op::F32(n)) = self.source_op(args[0]) {
+                    self.drop(args[0], renames);
+                    self.i32(to + 0, rounding.to_i32(n));
+                    return;
+                }
+            }
+            Op::Ptr => {
+                if let Some(Op::Type(pointee)) = self.source_op(args[0]) {
+                    let typ = Type::Ptr(Box::new(pointee.clone()));
+                    self.drop(args[0], renames);
+                    self.assignments.push(Assignment {
+                        to,
+                        args: [].into(),
+                        op: Op::Type(typ),
+                    });
+                    return;
+                }
+            }
+            Op::TypeEq => {
+                if let (Some(Op::Type(a)), Some(Op::Type(b))) =
+                    (self.source_op(args[0]), self.source_op(args[1]))
+                {
+                    let res = a == b;
+                    self.drop(args[0], renames);
+                    self.drop(args[1], renames);
+                    self.bool(to + 0, res);
+                    return;
+                }
+            }
             Op::Not => {
                 if let Some(Op::Bool(operand)) = self.source_op(args[0]) {
                     let res = !*operand;
@@ -604,6 +1343,23 @@ impl Graph {
                     return;
                 }
             }
+            Op::BitNot => {
+                if let Some(n) = self.i32_literal(args[0]) {
+                    self.drop(args[0], renames);
+                    self.i32(to + 0, !n);
+                    return;
+                }
+            }
+            Op::Bitwise(operation) => {
+                if let (Some(a), Some(b)) =
+                    (self.i32_literal(args[0]), self.i32_literal(args[1]))
+                {
+                    self.drop(args[0], renames);
+                    self.drop(args[1], renames);
+                    self.i32(to + 0, operation.apply(a, b));
+                    return;
+                }
+            }
             _ => {}
         }
         self.assignments.push(Assignment { to, args, op });
@@ -629,29 +1385,123 @@ pub enum Op {
     Drop,
     Then(Box<Graph>),
     ThenElse(Box<Graph>, Box<Graph>),
+    /// Runs the graph at the index given by the `i32` on top of the stack.
+    Match(Box<[Graph]>),
+    /// Runs the graph for the `i32` on top of the stack, which is at the same
+    /// index as it is among the values, or the last graph if it isn't one of
+    /// them.
+    Switch(Box<[i32]>, Box<[Graph]>),
     Repeat(Box<Graph>),
+    /// Produces a pointer to an anonymous function with the given flattened
+    /// signature. The graph is its body, which doesn't see any of the values
+    /// around it.
+    Quotation(FunctionSignature, Box<Graph>),
+    /// Calls the quotation on top of the stack, which has the given flattened
+    /// signature.
+    CallQuotation(FunctionSignature),
+    /// Produces a pointer to a function, which can be called like a quotation.
+    FunctionPointer(Box<str>),
+    /// Produces a pointer to the first element of a data table.
+    Data(Box<str>),
+    /// Marks that the operations after it come from the word at the span.
+    /// Impure so that it stays where it is.
+    Location(Span),
+    /// The condition graph produces the loop-carried values followed by a
+    /// boolean, while the body only produces the loop-carried values.
+    While(Box<Graph>, Box<Graph>),
+    /// Jumps out of the loop that is the given number of loops around the
+    /// innermost one, taking the entire stack with it. The loop only keeps the
+    /// values at the top that it carries, since counted loops inside of it
+    /// keep their indices and limits at the bottom.
+    Break(usize),
+    /// Jumps back to the start of a loop like `Break` jumps out of it.
+    Continue(usize),
+    /// Jumps back to the start of the current function with new arguments,
+    /// which is what a function calling itself right before returning turns
+    /// into.
+    Recurse,
+    /// Returns from the current function, taking the entire stack with it as
+    /// the outputs.
+    Return,
+    /// Ends the program with the exit code on top of the stack, taking the
+    /// entire stack with it.
+    Exit,
     Call(Box<str>),
+    /// Calls a function declared with `extern fn`, which has the given
+    /// calling convention and flattened signature.
+    CallExtern(Box<str>, Abi, FunctionSignature),
     I32(i32),
     F32(f32),
     Bool(bool),
-    Type,
+    Type(Type),
     PrintChar,
     PrintI32,
     PrintF32,
+    PrintBool,
     PrintlnI32,
     PrintlnF32,
+    PrintlnBool,
+    PrintType,
+    /// Reads from stdin, producing the value read and whether that worked.
+    ReadChar,
+    ReadI32,
+    /// Skips the rest of the current line of stdin, producing whether there
+    /// was one.
+    ReadLine,
+    ArgCount,
+    /// Gets a pointer to a command line argument, or a null pointer if there
+    /// is no argument with that index.
+    Arg,
+    Open,
+    Read,
+    Write,
+    Close,
+    /// Stops the program if the boolean is false, reporting the location of
+    /// the assertion.
+    Assert(Span),
     Sqrt,
-    TypeOf,
+    Round(Rounding),
+    I32ToF32,
+    F32ToI32(Rounding),
+    Math {
+        function: MathFunction,
+        typ: Type,
+    },
+    /// Drops a value of the given type and produces the type.
+    TypeOf(Type),
     Ptr,
+    TypeEq,
     Not,
     BinMath {
         operation: BinMathOp,
-        typ: Option<Type>,
+        typ: Type,
+        /// Where division by zero and overflow are reported.
+        span: Span,
     },
     BinLogic(BinLogicOp),
-    Compare(Comparison),
+    BitNot,
+    Bitwise(BitwiseOp),
+    Compare {
+        comparison: Comparison,
+        typ: Type,
+    },
     AddrOf(Type),
     ReadPtr(Type),
+    ReadSmall(SmallInt),
+    WritePtr,
+    WriteSmall(SmallInt),
+    /// Bulk memory operations, which are calls to the C library.
+    MemCopy,
+    MemFill,
+    MemCompare,
+    Null,
+    /// Moves a pointer by a number of bytes.
+    PtrAdd,
+    PtrSub,
+    PtrDiff,
+    /// Produces the value of a variable of the given type.
+    Load(Box<str>, Type),
+    Store(Box<str>),
 }
 
 impl Op {
@@ -666,18 +1516,63 @@ impl Op {
             self,
             Self::Then(_)
                 | Self::ThenElse(..)
+                | Self::Match(_)
+                | Self::Switch(..)
                 | Self::Repeat(_)
+                | Self::While(..)
+                | Self::Break(_)
+                | Self::Continue(_)
+                | Self::Recurse
+                | Self::Return
+                | Self::Exit
                 | Self::Call(_)
+                | Self::CallExtern(..)
+                | Self::CallQuotation(_)
                 | Self::PrintChar
                 | Self::PrintI32
                 | Self::PrintF32
+                | Self::PrintBool
                 | Self::PrintlnI32
                 | Self::PrintlnF32
-                // Division by zero and maybe overflow?
-                | Self::BinMath { typ: Some(Type::I32), .. }
+                | Self::PrintlnBool
+                | Self::PrintType
+                | Self::ReadChar
+                | Self::ReadI32
+                | Self::ReadLine
+                | Self::Open
+                | Self::Read
+                | Self::Write
+                | Self::Close
+                | Self::Load(..)
+                | Self::Store(_)
+                | Self::WritePtr
+                | Self::WriteSmall(_)
+                | Self::MemCopy
+                | Self::MemFill
+                | Self::Assert(_)
+                | Self::Location(_)
+        ) && !matches!(
+            self,
+            // Division by zero and overflow, unless `Intervals` proves
+            // otherwise.
+            Self::BinMath { operation, typ: Type::I32, .. }
+                if !operation.is_total()
         )
     }
 
+    /// Whether the operation can be removed when its results are unused,
+    /// which unlike `pure` also holds for branches that only do pure things.
+    fn removable(&self) -> bool {
+        match self {
+            Self::Then(body) => body.is_pure(),
+            Self::ThenElse(then, else_) => then.is_pure() && else_.is_pure(),
+            Self::Match(cases) | Self::Switch(_, cases) => {
+                cases.iter().all(Graph::is_pure)
+            }
+            _ => self.pure(),
+        }
+    }
+
     const fn as_bool(&self) -> Option<bool> {
         if let Self::Bool(v) = *self {
             Some(v)
@@ -693,7 +1588,23 @@ pub fn rebuild_graph_inlining(
     value_generator: &mut ValueGenerator,
 ) {
     let mut renames = Renames::default();
-    for assignment in mem::take(&mut graph.assignments) {
+    for mut assignment in mem::take(&mut graph.assignments) {
+        match &mut assignment.op {
+            Op::Then(body) | Op::Repeat(body) | Op::Quotation(_, body) => {
+                rebuild_graph_inlining(body, function, value_generator);
+            }
+            Op::ThenElse(then, else_) | Op::While(then, else_) => {
+                rebuild_graph_inlining(then, function, value_generator);
+                rebuild_graph_inlining(else_, function, value_generator);
+            }
+            Op::Match(cases) | Op::Switch(_, cases) => {
+                for case in &mut **cases {
+                    rebuild_graph_inlining(case, function, value_generator);
+                }
+            }
+            _ => {}
+        }
+
         if matches!(&assignment.op, Op::Call(name) if **name == *function.name)
         {
             let mut function = function.body.clone();
@@ -738,13 +1649,18 @@ fn refresh_graph(
         assignment.to = to;
 
         match &mut assignment.op {
-            Op::Then(body) | Op::Repeat(body) => {
+            Op::Then(body) | Op::Repeat(body) | Op::Quotation(_, body) => {
                 refresh_graph(body, value_generator, true);
             }
-            Op::ThenElse(then, else_) => {
+            Op::ThenElse(then, else_) | Op::While(then, else_) => {
                 refresh_graph(then, value_generator, true);
                 refresh_graph(else_, value_generator, true);
             }
+            Op::Match(cases) | Op::Switch(_, cases) => {
+                for case in &mut **cases {
+                    refresh_graph(case, value_generator, true);
+                }
+            }
             _ => {}
         }
     }
@@ -758,18 +1674,27 @@ pub fn propagate_drops(graph: &mut Graph) -> bool {
     // Recurse.
     for assignment in &mut graph.assignments {
         did_something |= match &mut assignment.op {
-            Op::Then(body) | Op::Repeat(body) => propagate_drops(body),
-            Op::ThenElse(then, else_) => {
+            Op::Then(body) | Op::Repeat(body) | Op::Quotation(_, body) => {
+                propagate_drops(body)
+            }
+            Op::ThenElse(then, else_) | Op::While(then, else_) => {
                 propagate_drops(then) || propagate_drops(else_)
             }
+            Op::Match(cases) | Op::Switch(_, cases) => {
+                cases.iter_mut().any(propagate_drops)
+            }
             _ => false,
         }
     }
 
+    // Whether overflow stops the program isn't known here, so it is assumed
+    // to wrap around.
+    let intervals = Intervals::of(graph, false);
     let mut useless_values = BTreeSet::new();
     let mut out = Vec::new();
     for assignment in mem::take(&mut graph.assignments).into_iter().rev() {
-        if assignment.op.pure()
+        if (assignment.op.removable()
+            || intervals.proves_infallible(&assignment))
             && assignment
                 .to
                 .iter()
@@ -803,3 +1728,626 @@ pub fn propagate_drops(graph: &mut Graph) -> bool {
 
     did_something
 }
+
+/// Replaces `read-ptr`s of pointers that were created by `addr-of` in the same
+/// graph with copies of the original value. Since there is nothing that could
+/// have written to the stack slot in between, the value must be unchanged and
+/// the stack slot can be avoided entirely.
+pub fn forward_pointers(graph: &mut Graph) -> bool {
+    let mut did_something = false;
+
+    // Recurse.
+    for assignment in &mut graph.assignments {
+        did_something |= match &mut assignment.op {
+            Op::Then(body) | Op::Repeat(body) | Op::Quotation(_, body) => {
+                forward_pointers(body)
+            }
+            Op::ThenElse(then, else_) | Op::While(then, else_) => {
+                forward_pointers(then) | forward_pointers(else_)
+            }
+            Op::Match(cases) | Op::Switch(_, cases) => {
+                cases.iter_mut().fold(false, |did_something, case| {
+                    forward_pointers(case) | did_something
+                })
+            }
+            _ => false,
+        }
+    }
+
+    // Maps pointers to the index of the `addr-of` that created them.
+    let mut pointers = BTreeMap::new();
+    let mut forwarded = BTreeSet::new();
+    // Maps the `read-ptr`s to remove to the `addr-of` of their pointer.
+    let mut removed = BTreeMap::new();
+    // Pointers that are used for anything else, such as being copied, moved
+    // with `ptr+` or passed to a function, must keep pointing to memory.
+    let mut escaped = BTreeSet::new();
+    for (i, assignment) in graph.assignments.iter().enumerate() {
+        match assignment.op {
+            Op::AddrOf(_) => {
+                pointers.insert(assignment.to + 1, i);
+            }
+            Op::ReadPtr(_) | Op::Drop => {
+                if let Some(&addr_of) = pointers.get(&assignment.args[0]) {
+                    forwarded.insert(addr_of);
+                    if matches!(assignment.op, Op::ReadPtr(_)) {
+                        removed.insert(i, addr_of);
+                    }
+                }
+            }
+            _ => escaped.extend(
+                assignment
+                    .args
+                    .iter()
+                    .filter_map(|arg| pointers.get(arg).copied()),
+            ),
+        }
+    }
+    escaped.extend(
+        graph
+            .outputs
+            .iter()
+            .filter_map(|output| pointers.get(output).copied()),
+    );
+    forwarded.retain(|addr_of| !escaped.contains(addr_of));
+    removed.retain(|_, addr_of| !escaped.contains(addr_of));
+
+    if forwarded.is_empty() {
+        return did_something;
+    }
+
+    let mut renames = Renames::default();
+    graph.assignments = mem::take(&mut graph.assignments)
+        .into_iter()
+        .enumerate()
+        .filter_map(|(i, mut assignment)| {
+            renames.apply_to_slice(&mut assignment.args);
+            if forwarded.contains(&i) {
+                // The "pointer" is now just a copy of the value.
+                assignment.op = Op::Dup;
+            }
+            if removed.contains_key(&i) {
+                renames.insert(assignment.to + 0, assignment.args[0]);
+                None
+            } else {
+                Some(assignment)
+            }
+        })
+        .collect();
+    renames.apply_to_slice(&mut graph.outputs);
+
+    true
+}
+
+/// Turns calls of function pointers that are known to point to a certain
+/// function into direct calls, which can then be inlined. `Graph::add` already
+/// does this for a pointer that is called right away, but inlining can leave
+/// calls of copies of a pointer, like when `&square` is passed to a function
+/// that calls it twice.
+pub fn devirtualize(graph: &mut Graph) -> bool {
+    let mut did_something = false;
+
+    // Recurse.
+    for assignment in &mut graph.assignments {
+        did_something |= match &mut assignment.op {
+            Op::Then(body) | Op::Repeat(body) | Op::Quotation(_, body) => {
+                devirtualize(body)
+            }
+            Op::ThenElse(then, else_) | Op::While(then, else_) => {
+                devirtualize(then) | devirtualize(else_)
+            }
+            Op::Match(cases) | Op::Switch(_, cases) => {
+                cases.iter_mut().fold(false, |did_something, case| {
+                    devirtualize(case) | did_something
+                })
+            }
+            _ => false,
+        }
+    }
+
+    // Maps function pointers and copies of them to the function.
+    let mut pointers = BTreeMap::<Value, Box<str>>::new();
+    let mut out = Vec::with_capacity(graph.assignments.len());
+    for mut assignment in mem::take(&mut graph.assignments) {
+        match &assignment.op {
+            Op::FunctionPointer(name) => {
+                pointers.insert(assignment.to + 0, name.clone());
+            }
+            Op::Dup => {
+                if let Some(name) = pointers.get(&assignment.args[0]).cloned() {
+                    pointers.insert(assignment.to + 0, name.clone());
+                    pointers.insert(assignment.to + 1, name);
+                }
+            }
+            Op::CallQuotation(_) => {
+                let (&pointer, args) = assignment.args.split_last().unwrap();
+                if let Some(name) = pointers.get(&pointer) {
+                    did_something = true;
+                    // Every value must be used exactly once.
+                    out.push(Assignment {
+                        to: ValueSequence::default(),
+                        args: [pointer].into(),
+                        op: Op::Drop,
+                    });
+                    assignment.op = Op::Call(name.clone());
+                    assignment.args = args.into();
+                }
+            }
+            _ => {}
+        }
+        out.push(assignment);
+    }
+    graph.assignments = out;
+
+    did_something
+}
+
+/// Simplifies arithmetic and logic with only some operands known, which
+/// `Graph::add` can't fold on its own: chains like `1 + 2 +` become a single
+/// addition, identities like `0 +` and `true and` disappear and `not` is
+/// merged into the comparison or logic operation that produced its operand.
+pub fn fold_constants(graph: &mut Graph) -> bool {
+    let mut did_something = false;
+
+    // Recurse.
+    for assignment in &mut graph.assignments {
+        did_something |= match &mut assignment.op {
+            Op::Then(body) | Op::Repeat(body) | Op::Quotation(_, body) => {
+                fold_constants(body)
+            }
+            Op::ThenElse(then, else_) | Op::While(then, else_) => {
+                fold_constants(then) | fold_constants(else_)
+            }
+            Op::Match(cases) | Op::Switch(_, cases) => {
+                cases.iter_mut().fold(false, |did_something, case| {
+                    fold_constants(case) | did_something
+                })
+            }
+            _ => false,
+        }
+    }
+
+    let mut renames = Renames::default();
+    for mut assignment in mem::take(&mut graph.assignments) {
+        renames.apply_to_slice(&mut assignment.args);
+        did_something |= graph.fold_partially_known(assignment, &mut renames);
+    }
+    renames.apply_to_slice(&mut graph.outputs);
+
+    did_something
+}
+
+impl Graph {
+    fn position_of(&self, value: Value) -> Option<usize> {
+        self.assignments
+            .iter()
+            .position(|assignment| assignment.to.range().contains(&value))
+    }
+
+    fn i32_literal(&self, value: Value) -> Option<i32> {
+        match self.source_op(value) {
+            Some(&Op::I32(n)) => Some(n),
+            _ => None,
+        }
+    }
+
+    /// Computes a math function whose operands are all known.
+    fn fold_math(&self, function: MathFunction, args: &[Value]) -> Option<Op> {
+        if let Some(operands) = args
+            .iter()
+            .map(|&arg| self.i32_literal(arg))
+            .collect::<Option<Vec<_>>>()
+        {
+            return Some(Op::I32(function.apply_i32(&operands)));
+        }
+        let operands = args
+            .iter()
+            .map(|&arg| match self.source_op(arg) {
+                Some(&Op::F32(n)) => Some(n),
+                _ => None,
+            })
+            .collect::<Option<Vec<_>>>()?;
+        Some(Op::F32(function.apply_f32(&operands)))
+    }
+
+    /// Finds the known operand of the integer operation that produced
+    /// `value`, along with what it becomes when `operation` with `n` is merged
+    /// into that operation.
+    ///
+    /// Operations are only merged when the merged one overflows exactly when
+    /// either of the original ones would have, so that `--overflow-checks`
+    /// still catches the same overflows.
+    fn reassociate(
+        &self,
+        operation: BinMathOp,
+        value: Value,
+        n: i32,
+    ) -> Option<(usize, i32)> {
+        use BinMathOp as M;
+
+        let inner = &self.assignments[self.position_of(value)?];
+        let Op::BinMath {
+            operation: inner_operation,
+            typ: Type::I32,
+            ..
+        } = inner.op
+        else {
+            return None;
+        };
+        let [a, b] = *inner.args else { unreachable!() };
+        // The left operand of a subtraction can't be merged with.
+        let literal = if self.i32_literal(b).is_some() {
+            b
+        } else if inner_operation.is_commutative() {
+            a
+        } else {
+            return None;
+        };
+        let inner_n = self.i32_literal(literal)?;
+        // Moving further in the same direction can't undo an overflow, and
+        // neither can multiplying by anything other than -1, 0 or 1, which
+        // have been folded away already unless it's -1.
+        let same_direction = (inner_n < 0) == (n < 0);
+        let combined = match (inner_operation, operation) {
+            (M::Mul, M::Mul) if n != -1 => inner_n.checked_mul(n),
+            (M::Add, M::Add) | (M::Sub, M::Sub) if same_direction => {
+                inner_n.checked_add(n)
+            }
+            (M::Add, M::Sub) | (M::Sub, M::Add) if !same_direction => {
+                inner_n.checked_sub(n)
+            }
+            (M::WrappingMul, M::WrappingMul) => Some(inner_n.wrapping_mul(n)),
+            (M::WrappingAdd, M::WrappingAdd)
+            | (M::WrappingSub, M::WrappingSub) => Some(inner_n.wrapping_add(n)),
+            (M::WrappingAdd, M::WrappingSub)
+            | (M::WrappingSub, M::WrappingAdd) => Some(inner_n.wrapping_sub(n)),
+            _ => None,
+        }?;
+        Some((self.position_of(literal)?, combined))
+    }
+
+    /// Adds an assignment whose arguments have already been renamed, unless
+    /// it can be folded away. Returns whether it was.
+    fn fold_partially_known(
+        &mut self,
+        assignment: Assignment,
+        renames: &mut Renames,
+    ) -> bool {
+        let to = assignment.to;
+        match assignment.op {
+            // Integer addition, subtraction and multiplication can be
+            // reordered as long as that doesn't change whether they overflow.
+            Op::BinMath { typ: Type::I32, .. } => {
+                return self.fold_partially_known_i32(assignment, renames)
+            }
+            Op::Not => {
+                if let Some(index) = self.position_of(assignment.args[0]) {
+                    let inverse = match self.assignments[index].op {
+                        Op::Compare {
+                            comparison,
+                            typ: Type::I32,
+                        } => {
+                            negated(comparison).map(|comparison| Op::Compare {
+                                comparison,
+                                typ: Type::I32,
+                            })
+                        }
+                        Op::BinLogic(operation) => {
+                            Some(Op::BinLogic(match operation {
+                                BinLogicOp::And => BinLogicOp::Nand,
+                                BinLogicOp::Or => BinLogicOp::Nor,
+                                BinLogicOp::Xor => BinLogicOp::Xnor,
+                                BinLogicOp::Nand => BinLogicOp::And,
+                                BinLogicOp::Nor => BinLogicOp::Or,
+                                BinLogicOp::Xnor => BinLogicOp::Xor,
+                            }))
+                        }
+                        Op::Not => {
+                            let operand =
+                                self.assignments.remove(index).args[0];
+                            renames.insert(to + 0, operand);
+                            return true;
+                        }
+                        _ => None,
+                    };
+                    if let Some(inverse) = inverse {
+                        self.assignments[index].op = inverse;
+                        renames.insert(to + 0, assignment.args[0]);
+                        return true;
+                    }
+                }
+            }
+            Op::BinLogic(operation) => {
+                use BinLogicOp as B;
+
+                let [a, b] = *assignment.args else {
+                    unreachable!()
+                };
+                let (other, known, value) = match (
+                    self.source_op(a).and_then(Op::as_bool),
+                    self.source_op(b).and_then(Op::as_bool),
+                ) {
+                    (None, Some(value)) => (a, b, value),
+                    (Some(value), None) => (b, a, value),
+                    _ => {
+                        self.assignments.push(Assignment {
+                            to,
+                            args: [a, b].into(),
+                            op: assignment.op,
+                        });
+                        return false;
+                    }
+                };
+                // Whether the result is the negation of the other operand.
+                let negated = match (operation, value) {
+                    (B::And | B::Xnor, true) | (B::Or | B::Xor, false) => false,
+                    (B::Nand | B::Xor, true) | (B::Nor | B::Xnor, false) => {
+                        true
+                    }
+                    _ => {
+                        self.assignments.push(Assignment {
+                            to,
+                            args: [a, b].into(),
+                            op: assignment.op,
+                        });
+                        return false;
+                    }
+                };
+                self.drop(known, renames);
+                if negated {
+                    self.fold_partially_known(
+                        Assignment {
+                            to,
+                            args: [other].into(),
+                            op: Op::Not,
+                        },
+                        renames,
+                    );
+                } else {
+                    renames.insert(to + 0, other);
+                }
+                return true;
+            }
+            _ => {}
+        }
+        self.assignments.push(assignment);
+        false
+    }
+
+    /// Folds integer arithmetic with a known operand, which like
+    /// `fold_partially_known` adds the assignment unless it was folded away.
+    fn fold_partially_known_i32(
+        &mut self,
+        assignment: Assignment,
+        renames: &mut Renames,
+    ) -> bool {
+        use BinMathOp as M;
+
+        let to = assignment.to;
+        let Op::BinMath { operation, .. } = assignment.op else {
+            unreachable!()
+        };
+        let [a, b] = *assignment.args else {
+            unreachable!()
+        };
+        let known_a = self.i32_literal(a);
+        let known_b = self.i32_literal(b);
+        // The operand that the result is equal to, and the one that
+        // becomes unused.
+        let kept = match (operation, known_a, known_b) {
+            (
+                M::Add
+                | M::Sub
+                | M::SaturatingAdd
+                | M::SaturatingSub
+                | M::WrappingAdd
+                | M::WrappingSub,
+                _,
+                Some(0),
+            )
+            | (
+                M::Mul | M::Div | M::SaturatingMul | M::WrappingMul,
+                _,
+                Some(1),
+            )
+            | (M::Mul | M::SaturatingMul | M::WrappingMul, Some(0), _) => {
+                Some((a, b))
+            }
+            (M::Add | M::SaturatingAdd | M::WrappingAdd, Some(0), _)
+            | (M::Mul | M::SaturatingMul | M::WrappingMul, Some(1), _)
+            | (M::Mul | M::SaturatingMul | M::WrappingMul, _, Some(0)) => {
+                Some((b, a))
+            }
+            _ => None,
+        };
+        if let Some((kept, unused)) = kept {
+            self.drop(unused, renames);
+            renames.insert(to + 0, kept);
+            return true;
+        }
+
+        // Addition and multiplication are commutative, so the known
+        // operand can be on either side.
+        let operands = match (known_a, known_b) {
+            (_, Some(n)) => Some((a, b, n)),
+            (Some(n), None) if operation.is_commutative() => Some((b, a, n)),
+            _ => None,
+        };
+        if let Some((unknown, known, (literal, combined))) =
+            operands.and_then(|(unknown, known, n)| {
+                Some((unknown, known, self.reassociate(operation, unknown, n)?))
+            })
+        {
+            self.assignments[literal].op = Op::I32(combined);
+            self.drop(known, renames);
+            renames.insert(to + 0, unknown);
+            return true;
+        }
+        self.assignments.push(Assignment {
+            to,
+            args: [a, b].into(),
+            op: assignment.op,
+        });
+        false
+    }
+}
+
+/// Replaces computations that have already been done in the same graph with
+/// copies of the earlier results. Copies made with `dup` count as the same
+/// value, which is what repeated macro expansions tend to work on.
+pub fn eliminate_common_subexpressions(
+    graph: &mut Graph,
+    value_generator: &mut ValueGenerator,
+) -> bool {
+    let mut did_something = false;
+
+    // Recurse.
+    for assignment in &mut graph.assignments {
+        let mut eliminate = |graph: &mut Graph| {
+            eliminate_common_subexpressions(graph, value_generator)
+        };
+        did_something |= match &mut assignment.op {
+            Op::Then(body) | Op::Repeat(body) | Op::Quotation(_, body) => {
+                eliminate(body)
+            }
+            Op::ThenElse(then, else_) | Op::While(then, else_) => {
+                eliminate(then) | eliminate(else_)
+            }
+            Op::Match(cases) | Op::Switch(_, cases) => {
+                cases.iter_mut().fold(false, |did_something, case| {
+                    eliminate(case) | did_something
+                })
+            }
+            _ => false,
+        }
+    }
+
+    // Maps copies made with `dup` to the value that they're copies of.
+    let mut originals = BTreeMap::<Value, Value>::new();
+    // Maps computations to the value holding their result, which is yet to
+    // be used.
+    let mut available = BTreeMap::<(String, Vec<Value>), Value>::new();
+    // Literals are cheaper to repeat than to copy, so they're left alone but
+    // numbered alike.
+    let mut literals = BTreeMap::<String, Value>::new();
+    let mut renames = Renames::default();
+    for mut assignment in mem::take(&mut graph.assignments) {
+        renames.apply_to_slice(&mut assignment.args);
+        let original =
+            |value: Value| originals.get(&value).copied().unwrap_or(value);
+
+        if matches!(assignment.op, Op::Dup) {
+            let copied = original(assignment.args[0]);
+            originals.extend(assignment.to.iter().map(|to| (to, copied)));
+        } else if let Some(literal) = match assignment.op {
+            Op::I32(n) => Some(format!("i32 {n}")),
+            Op::F32(n) => Some(format!("f32 {}", n.to_bits())),
+            Op::Bool(b) => Some(format!("bool {b}")),
+            _ => None,
+        } {
+            let first = *literals.entry(literal).or_insert(assignment.to + 0);
+            originals.insert(assignment.to + 0, first);
+        } else if let Some((computation, commutative)) =
+            computation(&assignment.op)
+        {
+            let mut args = assignment
+                .args
+                .iter()
+                .map(|&arg| original(arg))
+                .collect::<Vec<_>>();
+            if commutative {
+                args.sort();
+            }
+            let key = (computation, args);
+            if let Some(&earlier) = available.get(&key) {
+                let copies = value_generator.new_value_sequence(2);
+                let index = graph.position_of(earlier).unwrap();
+                graph.assignments.insert(
+                    index + 1,
+                    Assignment {
+                        to: copies,
+                        args: [earlier].into(),
+                        op: Op::Dup,
+                    },
+                );
+                // Whatever used the earlier result gets one of the copies.
+                if let Some(user) = graph.assignments[index + 2..]
+                    .iter_mut()
+                    .flat_map(|assignment| assignment.args.iter_mut())
+                    .find(|arg| **arg == earlier)
+                {
+                    *user = copies + 0;
+                } else {
+                    renames.insert(earlier, copies + 0);
+                }
+                renames.insert(assignment.to + 0, copies + 1);
+                graph.assignments.extend(assignment.args.iter().map(|&arg| {
+                    Assignment {
+                        to: ValueSequence::default(),
+                        args: [arg].into(),
+                        op: Op::Drop,
+                    }
+                }));
+                let result = original(earlier);
+                originals.extend(copies.iter().map(|copy| (copy, result)));
+                available.insert(key, copies + 0);
+                did_something = true;
+                continue;
+            }
+            available.insert(key, assignment.to + 0);
+        }
+        graph.assignments.push(assignment);
+    }
+    renames.apply_to_slice(&mut graph.outputs);
+
+    did_something
+}
+
+/// The comparison of `i32`s that is true exactly when `comparison` is false.
+/// Comparisons with NaN are always false, so `f32`s have no such thing.
+const fn negated(comparison: Comparison) -> Option<Comparison> {
+    match comparison {
+        Comparison::Lt => Some(Comparison::Ge),
+        Comparison::Le => Some(Comparison::Gt),
+        Comparison::Ge => Some(Comparison::Lt),
+        Comparison::Gt => Some(Comparison::Le),
+        // There is no inequality operation.
+        Comparison::Eq => None,
+    }
+}
+
+/// Identifies operations that always produce the same single result from the
+/// same arguments, along with whether the order of the arguments matters.
+fn computation(op: &Op) -> Option<(String, bool)> {
+    Some(match op {
+        // The span only says where errors are reported, and a computation
+        // that fails does so the first time it's done.
+        Op::BinMath { operation, typ, .. } => {
+            (format!("{operation:?} {typ}"), operation.is_commutative())
+        }
+        Op::Compare { comparison, typ } => (
+            format!("compare {comparison:?} {typ}"),
+            matches!(comparison, Comparison::Eq),
+        ),
+        Op::BinLogic(operation) => (format!("logic {operation:?}"), true),
+        Op::Math { function, typ } => (
+            format!("{function:?} {typ}"),
+            matches!(function, MathFunction::Min | MathFunction::Max),
+        ),
+        Op::Bitwise(operation) => {
+            (format!("bitwise {operation:?}"), operation.is_commutative())
+        }
+        Op::TypeEq => (format!("{op:?}"), true),
+        Op::Not
+        | Op::BitNot
+        | Op::Sqrt
+        | Op::Round(_)
+        | Op::I32ToF32
+        | Op::F32ToI32(_)
+        | Op::Ptr
+        | Op::PtrAdd
+        | Op::PtrSub
+        | Op::PtrDiff => (format!("{op:?}"), false),
+        _ => return None,
+    })
+}
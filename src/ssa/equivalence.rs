@@ -0,0 +1,786 @@
+//! Checks that optimization passes don't change what functions do by
+//! evaluating them before and after each pass on random inputs.
+
+use super::{Graph, Op, Value};
+use crate::{
+    ir::{self, BinLogicOp, BinMathOp},
+    typ::{FunctionSignature, Type},
+};
+use itertools::Itertools;
+use std::{collections::BTreeMap, fmt, mem, ops::ControlFlow};
+
+/// How many sets of random inputs each changed function is evaluated with.
+const RUNS_PER_FUNCTION: usize = 32;
+/// How many operations an evaluation may take before it is abandoned, since
+/// random inputs can easily make a program loop forever.
+const FUEL: u32 = 100_000;
+const MAX_CALL_DEPTH: usize = 200;
+
+/// The bodies of every function in a program.
+pub type Bodies<'src> = BTreeMap<&'src str, Graph>;
+
+pub struct Checker<'a> {
+    function_signatures: &'a BTreeMap<&'a str, FunctionSignature>,
+    rng: Rng,
+}
+
+impl<'a> Checker<'a> {
+    pub const fn new(
+        function_signatures: &'a BTreeMap<&'a str, FunctionSignature>,
+    ) -> Self {
+        Self {
+            function_signatures,
+            rng: Rng(0x2545_f491_4f6c_dd1d),
+        }
+    }
+
+    /// Panics if a function that `pass` changed behaves differently from how
+    /// it did before.
+    pub fn check(&mut self, pass: &str, before: &Bodies, after: &Bodies) {
+        for (name, body) in after {
+            let Some(old_body) = before.get(name) else {
+                continue;
+            };
+            if format!("{old_body:?}") == format!("{body:?}") {
+                continue;
+            }
+            let parameters = &self.function_signatures[name].parameters;
+            // There is no making up a random function.
+            if parameters.iter().any(mentions_quotation) {
+                continue;
+            }
+            let runs = if parameters.is_empty() {
+                1
+            } else {
+                RUNS_PER_FUNCTION
+            };
+            for _ in 0..runs {
+                let inputs = parameters
+                    .iter()
+                    .map(|typ| self.rng.datum(typ))
+                    .collect::<Vec<_>>();
+                let old = Outcome::of(before, name, inputs.clone());
+                let new = Outcome::of(after, name, inputs.clone());
+                if [&old, &new].iter().any(|outcome| {
+                    matches!(
+                        outcome.result,
+                        Err(Stop::OutOfFuel
+                            | Stop::CalledExtern
+                            | Stop::RawPointer)
+                    )
+                }) {
+                    continue;
+                }
+                assert!(
+                    old.is_identical_to(&new),
+                    "`{pass}` changed the behavior of `{name}` for inputs [{}]\nbefore: {old:?}\nafter: {new:?}",
+                    inputs.iter().format(", "),
+                );
+            }
+        }
+    }
+}
+
+/// A value that an SSA value can hold while evaluating.
+#[derive(Clone, Debug)]
+enum Datum {
+    Bool(bool),
+    I32(i32),
+    F32(f32),
+    Type(Type),
+    Ptr(Box<Self>),
+    Quotation(Box<Graph>),
+    Function(Box<str>),
+}
+
+impl fmt::Display for Datum {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Bool(b) => b.fmt(f),
+            Self::I32(n) => n.fmt(f),
+            Self::F32(n) => n.fmt(f),
+            Self::Type(typ) => typ.fmt(f),
+            Self::Ptr(pointee) => write!(f, "&{pointee}"),
+            Self::Quotation(_) | Self::Function(_) => f.write_str("quotation"),
+        }
+    }
+}
+
+impl Datum {
+    /// The value that variables of the type start out as.
+    fn zero(typ: &Type) -> Self {
+        match typ {
+            Type::I32 => Self::I32(0),
+            Type::F32 => Self::F32(0.0),
+            Type::Bool => Self::Bool(false),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Equality that considers NaNs with the same bits to be identical.
+    fn is_identical_to(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Self::Bool(a), Self::Bool(b)) => a == b,
+            (Self::I32(a), Self::I32(b)) => a == b,
+            (Self::F32(a), Self::F32(b)) => a.to_bits() == b.to_bits(),
+            (Self::Type(a), Self::Type(b)) => a == b,
+            // Optimizations may change the body of a quotation or which
+            // function a pointer points to but not what it does, which would
+            // have to be checked by calling it.
+            (
+                Self::Quotation(_) | Self::Function(_),
+                Self::Quotation(_) | Self::Function(_),
+            ) => true,
+            (Self::Ptr(a), Self::Ptr(b)) => a.is_identical_to(b),
+            _ => false,
+        }
+    }
+
+    fn i32(self) -> i32 {
+        match self {
+            Self::I32(n) => n,
+            _ => unreachable!(),
+        }
+    }
+
+    fn f32(self) -> f32 {
+        match self {
+            Self::F32(n) => n,
+            _ => unreachable!(),
+        }
+    }
+
+    fn bool(self) -> bool {
+        match self {
+            Self::Bool(b) => b,
+            _ => unreachable!(),
+        }
+    }
+
+    fn typ(self) -> Type {
+        match self {
+            Self::Type(typ) => typ,
+            _ => unreachable!(),
+        }
+    }
+}
+
+/// Why an evaluation ended before the function returned.
+#[derive(Debug, PartialEq, Eq)]
+enum Stop {
+    Exit(i32),
+    Trap(&'static str),
+    OutOfFuel,
+    /// C functions can't be evaluated, so there is nothing to compare.
+    CalledExtern,
+    /// Pointers are evaluated as the value they point to, which doesn't work
+    /// for pointers that don't come from `addr-of`.
+    RawPointer,
+}
+
+/// How control leaves a graph. `Break` and `Continue` have how many loops
+/// around the innermost one they leave as well.
+enum Flow {
+    Normal,
+    Break(usize, Vec<Datum>),
+    Continue(usize, Vec<Datum>),
+    Recurse(Vec<Datum>),
+    Return(Vec<Datum>),
+}
+
+impl Flow {
+    /// How control leaves the loop that was left with this.
+    fn outer(self) -> Self {
+        match self {
+            Self::Break(depth, values) => Self::Break(depth - 1, values),
+            Self::Continue(depth, values) => Self::Continue(depth - 1, values),
+            flow => flow,
+        }
+    }
+}
+
+/// The graph that a `match` or `case` statement runs for its tag or value.
+fn chosen_case(op: &Op, tag: i32) -> Result<&Graph, Stop> {
+    match op {
+        // Random inputs can have tags that no real enum has.
+        Op::Match(cases) => usize::try_from(tag)
+            .ok()
+            .and_then(|tag| cases.get(tag))
+            .ok_or(Stop::Trap("invalid enum tag")),
+        Op::Switch(values, cases) => {
+            let index = values.iter().position(|&value| value == tag);
+            Ok(&cases[index.unwrap_or(values.len())])
+        }
+        _ => unreachable!(),
+    }
+}
+
+/// The values that a loop carries out of the entire stack at a `break` or
+/// `continue`, since the values below them belong to loops inside of it.
+fn loop_carried(mut values: Vec<Datum>, graph: &Graph) -> Vec<Datum> {
+    values.split_off(values.len() - usize::from(graph.inputs.count()))
+}
+
+#[derive(Debug)]
+struct Outcome {
+    printed: String,
+    /// The variables that were stored to, with what they ended up holding.
+    variables: BTreeMap<Box<str>, Datum>,
+    result: Result<Vec<Datum>, Stop>,
+}
+
+impl Outcome {
+    fn of(program: &Bodies, name: &str, inputs: Vec<Datum>) -> Self {
+        let mut evaluator = Evaluator {
+            program,
+            values: BTreeMap::new(),
+            printed: String::new(),
+            fuel: FUEL,
+            call_depth: 0,
+            variables: BTreeMap::new(),
+        };
+        let result = evaluator.call(name, inputs);
+        Self {
+            printed: evaluator.printed,
+            variables: evaluator.variables,
+            result,
+        }
+    }
+
+    fn is_identical_to(&self, other: &Self) -> bool {
+        self.printed == other.printed
+            && self.variables.len() == other.variables.len()
+            && std::iter::zip(&self.variables, &other.variables).all(
+                |((a_name, a), (b_name, b))| {
+                    a_name == b_name && a.is_identical_to(b)
+                },
+            )
+            && match (&self.result, &other.result) {
+                (Ok(a), Ok(b)) => {
+                    a.len() == b.len()
+                        && std::iter::zip(a, b)
+                            .all(|(a, b)| a.is_identical_to(b))
+                }
+                (Err(a), Err(b)) => a == b,
+                _ => false,
+            }
+    }
+}
+
+struct Evaluator<'p> {
+    program: &'p Bodies<'p>,
+    values: BTreeMap<Value, Datum>,
+    printed: String,
+    fuel: u32,
+    call_depth: usize,
+    /// Variables that haven't been stored to are zero.
+    variables: BTreeMap<Box<str>, Datum>,
+}
+
+impl Evaluator<'_> {
+    fn take(&mut self, value: Value) -> Datum {
+        self.values
+            .remove(&value)
+            .unwrap_or_else(|| panic!("{value:?} is not defined"))
+    }
+
+    fn take_all(&mut self, values: &[Value]) -> Vec<Datum> {
+        values.iter().map(|&value| self.take(value)).collect()
+    }
+
+    fn set_all(
+        &mut self,
+        values: impl IntoIterator<Item = Value>,
+        data: Vec<Datum>,
+    ) {
+        self.values.extend(values.into_iter().zip(data));
+    }
+
+    fn call(
+        &mut self,
+        name: &str,
+        args: Vec<Datum>,
+    ) -> Result<Vec<Datum>, Stop> {
+        self.call_body(&self.program[name], args)
+    }
+
+    /// Evaluates the body of a function or quotation.
+    fn call_body(
+        &mut self,
+        body: &Graph,
+        mut args: Vec<Datum>,
+    ) -> Result<Vec<Datum>, Stop> {
+        if self.call_depth == MAX_CALL_DEPTH {
+            return Err(Stop::OutOfFuel);
+        }
+        self.call_depth += 1;
+        // Recursive calls use the same values, so each call needs its own.
+        let caller_values = mem::take(&mut self.values);
+        let result = loop {
+            self.set_all(body.inputs, args);
+            match self.graph(body)? {
+                Flow::Normal => break self.take_all(&body.outputs),
+                Flow::Recurse(new_args) => args = new_args,
+                Flow::Return(values) => break values,
+                Flow::Break(..) | Flow::Continue(..) => unreachable!(),
+            }
+        };
+        self.values = caller_values;
+        self.call_depth -= 1;
+        Ok(result)
+    }
+
+    fn graph(&mut self, graph: &Graph) -> Result<Flow, Stop> {
+        for assignment in &graph.assignments {
+            self.fuel = self.fuel.checked_sub(1).ok_or(Stop::OutOfFuel)?;
+            let to = assignment.to;
+            let args = &assignment.args;
+            let result = match &assignment.op {
+                Op::Break(depth) => {
+                    return Ok(Flow::Break(*depth, self.take_all(args)));
+                }
+                Op::Continue(depth) => {
+                    return Ok(Flow::Continue(*depth, self.take_all(args)));
+                }
+                Op::Recurse => return Ok(Flow::Recurse(self.take_all(args))),
+                Op::Return => return Ok(Flow::Return(self.take_all(args))),
+                Op::Exit => {
+                    let mut args = self.take_all(args);
+                    return Err(Stop::Exit(args.pop().unwrap().i32()));
+                }
+                Op::Then(body) => {
+                    let (&condition, args) = args.split_last().unwrap();
+                    let args = self.take_all(args);
+                    if self.take(condition).bool() {
+                        self.set_all(body.inputs, args);
+                        match self.graph(body)? {
+                            Flow::Normal => self.take_all(&body.outputs),
+                            flow => return Ok(flow),
+                        }
+                    } else {
+                        args
+                    }
+                }
+                Op::ThenElse(then, else_) => {
+                    let (&condition, args) = args.split_last().unwrap();
+                    let args = self.take_all(args);
+                    let body = if self.take(condition).bool() {
+                        then
+                    } else {
+                        else_
+                    };
+                    self.set_all(body.inputs, args);
+                    match self.graph(body)? {
+                        Flow::Normal => self.take_all(&body.outputs),
+                        flow => return Ok(flow),
+                    }
+                }
+                op @ (Op::Match(_) | Op::Switch(..)) => {
+                    let (&tag, args) = args.split_last().unwrap();
+                    let args = self.take_all(args);
+                    let body = chosen_case(op, self.take(tag).i32())?;
+                    self.set_all(body.inputs, args);
+                    match self.graph(body)? {
+                        Flow::Normal => self.take_all(&body.outputs),
+                        flow => return Ok(flow),
+                    }
+                }
+                Op::Repeat(body) => {
+                    let state = self.take_all(args);
+                    match self.repeat(body, state)? {
+                        ControlFlow::Continue(values) => values,
+                        ControlFlow::Break(flow) => return Ok(flow),
+                    }
+                }
+                Op::While(condition, body) => {
+                    let state = self.take_all(args);
+                    match self.while_(condition, body, state)? {
+                        ControlFlow::Continue(values) => values,
+                        ControlFlow::Break(flow) => return Ok(flow),
+                    }
+                }
+                Op::Call(name) => {
+                    let args = self.take_all(args);
+                    self.call(name, args)?
+                }
+                Op::CallExtern(..) => return Err(Stop::CalledExtern),
+                Op::CallQuotation(_) => {
+                    let (&quotation, args) = args.split_last().unwrap();
+                    let args = self.take_all(args);
+                    match self.take(quotation) {
+                        Datum::Quotation(body) => {
+                            self.call_body(&body, args)?
+                        }
+                        Datum::Function(name) => self.call(&name, args)?,
+                        _ => unreachable!(),
+                    }
+                }
+                op => {
+                    let args = self.take_all(args);
+                    self.simple_op(op, args)?
+                }
+            };
+            self.set_all(to, result);
+        }
+        Ok(Flow::Normal)
+    }
+
+    /// Runs a `repeat` loop, giving the values it ends with, or how control
+    /// leaves the function from inside of it.
+    fn repeat(
+        &mut self,
+        body: &Graph,
+        mut state: Vec<Datum>,
+    ) -> Result<ControlFlow<Flow, Vec<Datum>>, Stop> {
+        loop {
+            self.set_all(body.inputs, state);
+            match self.graph(body)? {
+                Flow::Normal => {
+                    let (&condition, outputs) =
+                        body.outputs.split_last().unwrap();
+                    state = self.take_all(outputs);
+                    if !self.take(condition).bool() {
+                        return Ok(ControlFlow::Continue(state));
+                    }
+                }
+                Flow::Break(0, values) => {
+                    return Ok(ControlFlow::Continue(loop_carried(
+                        values, body,
+                    )));
+                }
+                Flow::Continue(0, values) => {
+                    state = loop_carried(values, body);
+                }
+                flow => return Ok(ControlFlow::Break(flow.outer())),
+            }
+        }
+    }
+
+    /// Runs a `while` loop like `repeat` does.
+    fn while_(
+        &mut self,
+        condition: &Graph,
+        body: &Graph,
+        mut state: Vec<Datum>,
+    ) -> Result<ControlFlow<Flow, Vec<Datum>>, Stop> {
+        loop {
+            self.set_all(condition.inputs, state);
+            // The condition isn't inside of the loop as far as `break` and
+            // `continue` are concerned.
+            match self.graph(condition)? {
+                Flow::Normal => {}
+                flow => return Ok(ControlFlow::Break(flow)),
+            }
+            let (&keep_going, outputs) =
+                condition.outputs.split_last().unwrap();
+            state = self.take_all(outputs);
+            if !self.take(keep_going).bool() {
+                return Ok(ControlFlow::Continue(state));
+            }
+            self.set_all(body.inputs, state);
+            state = match self.graph(body)? {
+                Flow::Normal => self.take_all(&body.outputs),
+                Flow::Break(0, values) => {
+                    return Ok(ControlFlow::Continue(loop_carried(
+                        values, body,
+                    )));
+                }
+                Flow::Continue(0, values) => loop_carried(values, body),
+                flow => return Ok(ControlFlow::Break(flow.outer())),
+            };
+        }
+    }
+
+    /// Evaluates an operation that doesn't affect control flow.
+    fn simple_op(
+        &mut self,
+        op: &Op,
+        args: Vec<Datum>,
+    ) -> Result<Vec<Datum>, Stop> {
+        let mut args = args.into_iter();
+        let mut arg = || args.next().unwrap();
+        Ok(match op {
+            Op::Dup => {
+                let datum = arg();
+                vec![datum.clone(), datum]
+            }
+            Op::Drop | Op::Close | Op::Location(_) => Vec::new(),
+            Op::I32(n) => vec![Datum::I32(*n)],
+            Op::F32(n) => vec![Datum::F32(*n)],
+            Op::Bool(b) => vec![Datum::Bool(*b)],
+            Op::Type(typ) => vec![Datum::Type(typ.clone())],
+            Op::Quotation(_, body) => vec![Datum::Quotation(body.clone())],
+            Op::FunctionPointer(name) => vec![Datum::Function(name.clone())],
+            #[expect(
+                clippy::cast_sign_loss,
+                reason = "Spackel doesn't have an unsigned integer type"
+            )]
+            Op::PrintChar => {
+                let c = (arg().i32() as u32)
+                    .try_into()
+                    .unwrap_or(char::REPLACEMENT_CHARACTER);
+                self.printed.push(c);
+                Vec::new()
+            }
+            Op::PrintI32 | Op::PrintF32 | Op::PrintBool | Op::PrintType => {
+                self.printed.push_str(&arg().to_string());
+                Vec::new()
+            }
+            Op::PrintlnI32 | Op::PrintlnF32 | Op::PrintlnBool => {
+                self.printed.push_str(&arg().to_string());
+                self.printed.push('\n');
+                Vec::new()
+            }
+            Op::ReadChar
+            | Op::ReadI32
+            | Op::ReadLine
+            | Op::ArgCount
+            | Op::Arg
+            | Op::Open
+            | Op::Read
+            | Op::Write => input_op(op, arg),
+            Op::Assert(_) => {
+                if !arg().bool() {
+                    return Err(Stop::Trap("assertion failed"));
+                }
+                Vec::new()
+            }
+            Op::AddrOf(_)
+            | Op::ReadPtr(_)
+            | Op::Null
+            | Op::PtrAdd
+            | Op::PtrSub
+            | Op::PtrDiff
+            | Op::ReadSmall(_)
+            | Op::WritePtr
+            | Op::WriteSmall(_)
+            | Op::MemCopy
+            | Op::MemFill
+            | Op::MemCompare
+            | Op::Data(_) => pointer_op(op, arg)?,
+            Op::Load(name, typ) => vec![self
+                .variables
+                .get(name)
+                .cloned()
+                .unwrap_or_else(|| Datum::zero(typ))],
+            Op::Store(name) => {
+                self.variables.insert(name.clone(), arg());
+                Vec::new()
+            }
+            Op::TypeOf(_)
+            | Op::Ptr
+            | Op::TypeEq
+            | Op::Sqrt
+            | Op::Round(_)
+            | Op::I32ToF32
+            | Op::F32ToI32(_)
+            | Op::Math { .. }
+            | Op::Not
+            | Op::BinMath { .. }
+            | Op::Compare { .. }
+            | Op::BinLogic(_)
+            | Op::BitNot
+            | Op::Bitwise(_) => vec![compute(op, arg)?],
+            Op::Then(_)
+            | Op::ThenElse(..)
+            | Op::Match(_)
+            | Op::Switch(..)
+            | Op::Repeat(_)
+            | Op::While(..)
+            | Op::Break(_)
+            | Op::Continue(_)
+            | Op::Recurse
+            | Op::Return
+            | Op::Exit
+            | Op::Call(_)
+            | Op::CallExtern(..)
+            | Op::CallQuotation(_) => unreachable!(),
+        })
+    }
+}
+
+/// Evaluates an operation that reads from outside of the program.
+fn input_op(op: &Op, mut arg: impl FnMut() -> Datum) -> Vec<Datum> {
+    match op {
+        // Functions are evaluated without any input.
+        Op::ReadChar | Op::ReadI32 => vec![Datum::I32(0), Datum::Bool(false)],
+        Op::ReadLine => vec![Datum::Bool(false)],
+        // Only the name of the program is passed.
+        Op::ArgCount => vec![Datum::I32(1)],
+        Op::Arg => vec![Datum::Ptr(Box::new(Datum::I32(arg().i32())))],
+        // There is no file system either, so files are always invalid handles.
+        Op::Open => {
+            arg();
+            arg();
+            vec![Datum::I32(-1), Datum::Bool(false)]
+        }
+        Op::Read => vec![arg(), Datum::I32(0), Datum::Bool(false)],
+        Op::Write => vec![arg()],
+        _ => unreachable!(),
+    }
+}
+
+/// Evaluates an operation on pointers. Only pointers made by `addr-of` can be
+/// followed, since they are modelled as the value that they point to.
+fn pointer_op(
+    op: &Op,
+    mut arg: impl FnMut() -> Datum,
+) -> Result<Vec<Datum>, Stop> {
+    match op {
+        Op::AddrOf(_) => {
+            let datum = arg();
+            Ok(vec![datum.clone(), Datum::Ptr(Box::new(datum))])
+        }
+        Op::ReadPtr(_) => match arg() {
+            Datum::Ptr(pointee) => Ok(vec![*pointee]),
+            _ => unreachable!(),
+        },
+        _ => Err(Stop::RawPointer),
+    }
+}
+
+/// Evaluates an operation that produces a single value from its arguments.
+fn compute(op: &Op, mut arg: impl FnMut() -> Datum) -> Result<Datum, Stop> {
+    Ok(match op {
+        Op::TypeOf(typ) => {
+            arg();
+            Datum::Type(typ.clone())
+        }
+        Op::Ptr => Datum::Type(Type::Ptr(Box::new(arg().typ()))),
+        Op::TypeEq => Datum::Bool(arg().typ() == arg().typ()),
+        Op::Sqrt => Datum::F32(arg().f32().sqrt()),
+        Op::Round(rounding) => Datum::F32(rounding.apply(arg().f32())),
+        Op::I32ToF32 => Datum::F32(ir::i32_to_f32(arg().i32())),
+        Op::F32ToI32(rounding) => Datum::I32(rounding.to_i32(arg().f32())),
+        Op::Math {
+            function,
+            typ: Type::I32,
+        } => {
+            let operands = (0..function.arity())
+                .map(|_| arg().i32())
+                .collect::<Vec<_>>();
+            Datum::I32(function.apply_i32(&operands))
+        }
+        Op::Math { function, .. } => {
+            let operands = (0..function.arity())
+                .map(|_| arg().f32())
+                .collect::<Vec<_>>();
+            Datum::F32(function.apply_f32(&operands))
+        }
+        Op::Not => Datum::Bool(!arg().bool()),
+        Op::BinMath { operation, typ, .. } => {
+            bin_math(*operation, typ, arg(), arg())?
+        }
+        Op::Compare {
+            comparison,
+            typ: Type::F32,
+        } => Datum::Bool(comparison.apply(arg().f32(), arg().f32())),
+        Op::Compare { comparison, .. } => {
+            Datum::Bool(comparison.apply(arg().i32(), arg().i32()))
+        }
+        Op::BinLogic(op) => bin_logic(*op, arg().bool(), arg().bool()),
+        Op::BitNot => Datum::I32(!arg().i32()),
+        Op::Bitwise(op) => Datum::I32(op.apply(arg().i32(), arg().i32())),
+        _ => unreachable!(),
+    })
+}
+
+fn bin_math(
+    operation: BinMathOp,
+    typ: &Type,
+    a: Datum,
+    b: Datum,
+) -> Result<Datum, Stop> {
+    if *typ == Type::F32 {
+        let a = a.f32();
+        let b = b.f32();
+        return Ok(Datum::F32(match operation {
+            BinMathOp::Add => a + b,
+            BinMathOp::Sub => a - b,
+            BinMathOp::Mul => a * b,
+            BinMathOp::Div => a / b,
+            _ => unreachable!(),
+        }));
+    }
+    let a = a.i32();
+    let b = b.i32();
+    let divide = |op: fn(i32, i32) -> Option<i32>| {
+        op(a, b).ok_or(Stop::Trap("division by zero or overflow"))
+    };
+    Ok(Datum::I32(match operation {
+        BinMathOp::Add | BinMathOp::WrappingAdd => a.wrapping_add(b),
+        BinMathOp::Sub | BinMathOp::WrappingSub => a.wrapping_sub(b),
+        BinMathOp::Mul | BinMathOp::WrappingMul => a.wrapping_mul(b),
+        BinMathOp::Div => divide(i32::checked_div)?,
+        BinMathOp::Rem => divide(i32::checked_rem)?,
+        BinMathOp::SillyAdd => match (a, b) {
+            (9, 10) | (10, 9) => 21,
+            (1, 1) => 1,
+            _ => a.wrapping_add(b),
+        },
+        BinMathOp::SaturatingAdd => a.saturating_add(b),
+        BinMathOp::SaturatingSub => a.saturating_sub(b),
+        BinMathOp::SaturatingMul => a.saturating_mul(b),
+    }))
+}
+
+const fn bin_logic(op: BinLogicOp, a: bool, b: bool) -> Datum {
+    Datum::Bool(match op {
+        BinLogicOp::And => a && b,
+        BinLogicOp::Or => a || b,
+        BinLogicOp::Xor => a ^ b,
+        BinLogicOp::Nand => !(a && b),
+        BinLogicOp::Nor => !(a || b),
+        BinLogicOp::Xnor => !(a ^ b),
+    })
+}
+
+fn mentions_quotation(typ: &Type) -> bool {
+    match typ {
+        Type::Quotation(_) => true,
+        Type::Ptr(pointee) => mentions_quotation(pointee),
+        _ => false,
+    }
+}
+
+/// A xorshift generator, which is plenty for making up inputs.
+struct Rng(u64);
+
+impl Rng {
+    const fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn datum(&mut self, typ: &Type) -> Datum {
+        // Edge cases are far more likely to reveal bugs than arbitrary
+        // numbers.
+        const INTERESTING_I32S: [i32; 8] =
+            [0, 1, -1, 2, 10, 255, i32::MIN, i32::MAX];
+        let bits = self.next();
+        match typ {
+            Type::Bool => Datum::Bool(bits & 1 == 0),
+            Type::I32 if bits & 1 == 0 => {
+                Datum::I32(INTERESTING_I32S[(bits >> 1) as usize % 8])
+            }
+            Type::I32 => Datum::I32((bits >> 32) as i32),
+            Type::F32 => Datum::F32(f32::from_bits((bits >> 32) as u32)),
+            Type::Type => Datum::Type(
+                [Type::Bool, Type::I32, Type::F32, Type::Type]
+                    [(bits >> 1) as usize % 4]
+                    .clone(),
+            ),
+            // Structs and enums have been flattened, except for behind
+            // pointers, which can't be read.
+            Type::Struct(_) | Type::Enum(_) => Datum::Type(typ.clone()),
+            Type::Quotation(_) => {
+                unreachable!("functions taking quotations aren't checked")
+            }
+            Type::Var(_) => unreachable!("generic functions have no body"),
+            Type::File => Datum::I32(-1),
+            Type::Ptr(pointee) => Datum::Ptr(Box::new(self.datum(pointee))),
+        }
+    }
+}
@@ -0,0 +1,540 @@
+use super::{Assignment, Graph, Op, Value, ValueSequence};
+use crate::{
+    ir::{BinMathOp, Comparison},
+    typ::Type,
+};
+use std::{collections::BTreeMap, ops::ControlFlow};
+
+/// An inclusive range that an `i32` value is known to lie within.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Interval {
+    min: i32,
+    max: i32,
+}
+
+impl Interval {
+    const FULL: Self = Self {
+        min: i32::MIN,
+        max: i32::MAX,
+    };
+
+    const fn constant(n: i32) -> Self {
+        Self { min: n, max: n }
+    }
+
+    const fn contains(self, n: i32) -> bool {
+        self.min <= n && n <= self.max
+    }
+
+    const fn includes(self, other: Self) -> bool {
+        self.min <= other.min && other.max <= self.max
+    }
+
+    fn union(self, other: Self) -> Self {
+        Self {
+            min: self.min.min(other.min),
+            max: self.max.max(other.max),
+        }
+    }
+
+    /// Like `union`, except that bounds that move at all move all the way, so
+    /// that a loop only needs to be analyzed a few times.
+    const fn widen(self, other: Self) -> Self {
+        Self {
+            min: if other.min < self.min {
+                i32::MIN
+            } else {
+                self.min
+            },
+            max: if other.max > self.max {
+                i32::MAX
+            } else {
+                self.max
+            },
+        }
+    }
+
+    /// The part of the interval where comparing a value in it to a value in
+    /// `other` gives true.
+    fn assuming(self, comparison: Comparison, other: Self) -> Self {
+        let (min, max) = match comparison {
+            Comparison::Lt => {
+                (self.min, self.max.min(other.max.saturating_sub(1)))
+            }
+            Comparison::Le => (self.min, self.max.min(other.max)),
+            Comparison::Eq => {
+                (self.min.max(other.min), self.max.min(other.max))
+            }
+            Comparison::Ge => (self.min.max(other.min), self.max),
+            Comparison::Gt => {
+                (self.min.max(other.min.saturating_add(1)), self.max)
+            }
+        };
+        // An empty interval means that the comparison can't give true, in
+        // which case the value doesn't matter.
+        if min <= max {
+            Self { min, max }
+        } else {
+            self
+        }
+    }
+
+    /// Applies `f` to every combination of bounds, returning `None` if any of
+    /// them overflow.
+    fn corners(
+        self,
+        other: Self,
+        f: impl Fn(i32, i32) -> Option<i32>,
+    ) -> Option<Self> {
+        let corners = [
+            f(self.min, other.min)?,
+            f(self.min, other.max)?,
+            f(self.max, other.min)?,
+            f(self.max, other.max)?,
+        ];
+        Some(Self {
+            min: corners.into_iter().min()?,
+            max: corners.into_iter().max()?,
+        })
+    }
+
+    fn bin_math(self, operation: BinMathOp, other: Self) -> Option<Self> {
+        match operation {
+            BinMathOp::Add => self.corners(other, i32::checked_add),
+            BinMathOp::Sub => {
+                // The smallest difference comes from the largest subtrahend.
+                Some(Self {
+                    min: self.min.checked_sub(other.max)?,
+                    max: self.max.checked_sub(other.min)?,
+                })
+            }
+            BinMathOp::Mul => self.corners(other, i32::checked_mul),
+            // Division is monotonic in both operands as long as the divisor
+            // doesn't cross zero.
+            BinMathOp::Div if !other.contains(0) => {
+                self.corners(other, i32::checked_div)
+            }
+            BinMathOp::Rem if !other.contains(0) => {
+                let largest_divisor =
+                    other.min.unsigned_abs().max(other.max.unsigned_abs()) - 1;
+                let largest_divisor =
+                    i32::try_from(largest_divisor).unwrap_or(i32::MAX);
+                // The remainder has the same sign as the dividend.
+                Some(Self {
+                    min: if self.min >= 0 {
+                        0
+                    } else {
+                        self.min.max(-largest_divisor)
+                    },
+                    max: if self.max <= 0 {
+                        0
+                    } else {
+                        self.max.min(largest_divisor)
+                    },
+                })
+            }
+            BinMathOp::SaturatingAdd => {
+                self.corners(other, |a, b| Some(a.saturating_add(b)))
+            }
+            BinMathOp::SaturatingSub => Some(Self {
+                min: self.min.saturating_sub(other.max),
+                max: self.max.saturating_sub(other.min),
+            }),
+            BinMathOp::SaturatingMul => {
+                self.corners(other, |a, b| Some(a.saturating_mul(b)))
+            }
+            // Wrapping around only matters when the result could overflow,
+            // in which case it could be anything.
+            BinMathOp::WrappingAdd => {
+                Some(self.bin_math(BinMathOp::Add, other).unwrap_or(Self::FULL))
+            }
+            BinMathOp::WrappingSub => {
+                Some(self.bin_math(BinMathOp::Sub, other).unwrap_or(Self::FULL))
+            }
+            BinMathOp::WrappingMul => {
+                Some(self.bin_math(BinMathOp::Mul, other).unwrap_or(Self::FULL))
+            }
+            BinMathOp::Div | BinMathOp::Rem | BinMathOp::SillyAdd => None,
+        }
+    }
+
+    /// Like `bin_math`, except that an operation that stops the program when
+    /// it overflows only has results that fit.
+    fn trapping_bin_math(self, operation: BinMathOp, other: Self) -> Self {
+        let saturating = match operation {
+            BinMathOp::Add => BinMathOp::SaturatingAdd,
+            BinMathOp::Sub => BinMathOp::SaturatingSub,
+            BinMathOp::Mul => BinMathOp::SaturatingMul,
+            _ => operation,
+        };
+        self.bin_math(operation, other)
+            .or_else(|| self.bin_math(saturating, other))
+            .unwrap_or(Self::FULL)
+    }
+}
+
+/// The comparison that gives true exactly when `comparison` gives false.
+const fn negated(comparison: Comparison) -> Option<Comparison> {
+    match comparison {
+        Comparison::Lt => Some(Comparison::Ge),
+        Comparison::Le => Some(Comparison::Gt),
+        Comparison::Eq => None,
+        Comparison::Ge => Some(Comparison::Lt),
+        Comparison::Gt => Some(Comparison::Le),
+    }
+}
+
+/// The comparison that gives the same result with the operands swapped.
+const fn flipped(comparison: Comparison) -> Comparison {
+    match comparison {
+        Comparison::Lt => Comparison::Gt,
+        Comparison::Le => Comparison::Ge,
+        Comparison::Eq => Comparison::Eq,
+        Comparison::Ge => Comparison::Le,
+        Comparison::Gt => Comparison::Lt,
+    }
+}
+
+/// The results of a simple interval analysis over an SSA graph, used to prove
+/// that integer operations can't overflow or divide by zero.
+#[derive(Clone, Default)]
+pub struct Intervals {
+    values: BTreeMap<Value, Interval>,
+    /// Whether `+`, `-` and `*` on `i32`s stop the program when they
+    /// overflow instead of wrapping around.
+    overflow_traps: bool,
+}
+
+impl Intervals {
+    pub fn of(graph: &Graph, overflow_traps: bool) -> Self {
+        let mut intervals = Self {
+            values: BTreeMap::new(),
+            overflow_traps,
+        };
+        intervals.analyze(graph);
+        intervals
+    }
+
+    fn get(&self, value: Value) -> Interval {
+        self.values.get(&value).copied().unwrap_or(Interval::FULL)
+    }
+
+    fn set_all(&mut self, values: ValueSequence, intervals: &[Interval]) {
+        for (value, &interval) in values.iter().zip(intervals) {
+            self.values.insert(value, interval);
+        }
+    }
+
+    fn analyze(&mut self, graph: &Graph) {
+        for assignment in &graph.assignments {
+            self.analyze_assignment(assignment);
+        }
+    }
+
+    fn analyze_assignment(&mut self, assignment: &Assignment) {
+        let to = assignment.to;
+        let args = &assignment.args;
+        match &assignment.op {
+            Op::I32(n) => {
+                self.values.insert(to + 0, Interval::constant(*n));
+            }
+            Op::Dup => {
+                let interval = self.get(args[0]);
+                self.values.insert(to + 0, interval);
+                self.values.insert(to + 1, interval);
+            }
+            Op::BinMath {
+                operation,
+                typ: Type::I32,
+                ..
+            } => {
+                let (a, b) = (self.get(args[0]), self.get(args[1]));
+                let interval = if self.overflow_traps {
+                    Some(a.trapping_bin_math(*operation, b))
+                } else {
+                    a.bin_math(*operation, b)
+                };
+                if let Some(interval) = interval {
+                    self.values.insert(to + 0, interval);
+                }
+            }
+            Op::Then(body) => {
+                let (_condition, args) = args.split_last().unwrap();
+                self.pass_inputs(args, body);
+                self.analyze(body);
+                for ((value, &arg), &out) in
+                    to.iter().zip(args).zip(&body.outputs)
+                {
+                    self.values
+                        .insert(value, self.get(arg).union(self.get(out)));
+                }
+            }
+            Op::ThenElse(then, else_) => {
+                let (_condition, args) = args.split_last().unwrap();
+                self.pass_inputs(args, then);
+                self.pass_inputs(args, else_);
+                self.analyze(then);
+                self.analyze(else_);
+                for ((value, &then_out), &else_out) in
+                    to.iter().zip(&then.outputs).zip(&else_.outputs)
+                {
+                    self.values.insert(
+                        value,
+                        self.get(then_out).union(self.get(else_out)),
+                    );
+                }
+            }
+            Op::Match(cases) | Op::Switch(_, cases) => {
+                let (_tag, args) = args.split_last().unwrap();
+                for case in &**cases {
+                    self.pass_inputs(args, case);
+                    self.analyze(case);
+                }
+                for (i, value) in to.iter().enumerate() {
+                    if let Some(Some(interval)) = cases
+                        .iter()
+                        .map(|case| {
+                            case.outputs.get(i).map(|&out| self.get(out))
+                        })
+                        .reduce(|a, b| Some(a?.union(b?)))
+                    {
+                        self.values.insert(value, interval);
+                    }
+                }
+            }
+            Op::Repeat(body) => self.analyze_repeat(to, args, body),
+            Op::While(condition, body) => {
+                self.analyze_while(to, args, condition, body);
+            }
+            _ => {}
+        }
+    }
+
+    fn pass_inputs(&mut self, args: &[Value], body: &Graph) {
+        for (&arg, input) in args.iter().zip(body.inputs) {
+            if let Some(&interval) = self.values.get(&arg) {
+                self.values.insert(input, interval);
+            }
+        }
+    }
+
+    fn analyze_repeat(
+        &mut self,
+        to: ValueSequence,
+        args: &[Value],
+        body: &Graph,
+    ) {
+        let carried = self.loop_inputs(args, &[body], |intervals, inputs| {
+            intervals.repeat_iteration(body, inputs, true)
+        });
+        let exit = self.repeat_iteration(body, &carried, false);
+        if let Some(exit) = exit.filter(|_| !breaks(body)) {
+            self.set_all(to, &exit);
+        }
+    }
+
+    fn analyze_while(
+        &mut self,
+        to: ValueSequence,
+        args: &[Value],
+        condition: &Graph,
+        body: &Graph,
+    ) {
+        let carried =
+            self.loop_inputs(args, &[condition, body], |intervals, inputs| {
+                intervals.while_iteration(condition, body, inputs)?;
+                Some(
+                    body.outputs
+                        .iter()
+                        .map(|&out| intervals.get(out))
+                        .collect(),
+                )
+            });
+        let exit = self.while_iteration(condition, body, &carried);
+        if let Some(exit) = exit.filter(|_| !breaks(condition) && !breaks(body))
+        {
+            self.set_all(to, &exit);
+        }
+    }
+
+    /// Finds bounds for the values that a loop carries from one iteration to
+    /// the next, which start out as `args`. `iterate` analyzes an iteration
+    /// that starts with the given bounds and returns the bounds that the next
+    /// one starts with, if it knows them.
+    fn loop_inputs(
+        &self,
+        args: &[Value],
+        graphs: &[&Graph],
+        iterate: impl Fn(&mut Self, &[Interval]) -> Option<Vec<Interval>>,
+    ) -> Vec<Interval> {
+        let initial = args.iter().map(|&arg| self.get(arg)).collect::<Vec<_>>();
+        let unknown = vec![Interval::FULL; args.len()];
+        // `continue` starts the next iteration with values that aren't
+        // tracked.
+        if graphs.iter().any(|graph| continues(graph)) {
+            return unknown;
+        }
+        let step = |inputs: &[Interval]| {
+            let next = iterate(&mut self.clone(), inputs)?;
+            (next.len() == initial.len()).then(|| {
+                std::iter::zip(&initial, next)
+                    .map(|(initial, next)| initial.union(next))
+                    .collect::<Vec<_>>()
+            })
+        };
+        let mut inputs = initial.clone();
+        loop {
+            let Some(next) = step(&inputs) else {
+                return unknown;
+            };
+            if std::iter::zip(&inputs, &next)
+                .all(|(old, new)| old.includes(*new))
+            {
+                break;
+            }
+            inputs = std::iter::zip(&inputs, next)
+                .map(|(old, new)| old.widen(new))
+                .collect();
+        }
+        // Widening overshoots, which one more iteration without it makes up
+        // for. That is what bounds a loop counter by its limit.
+        step(&inputs).unwrap_or(unknown)
+    }
+
+    /// Analyzes the body of a `repeat` loop, returning the bounds of the
+    /// values that it carries if it goes on or stops depending on `again`.
+    fn repeat_iteration(
+        &mut self,
+        body: &Graph,
+        inputs: &[Interval],
+        again: bool,
+    ) -> Option<Vec<Interval>> {
+        self.set_all(body.inputs, inputs);
+        self.analyze(body);
+        let (&condition, outputs) = body.outputs.split_last()?;
+        Some(self.refine(body, condition, again, outputs))
+    }
+
+    /// Analyzes a `while` loop, returning the bounds of the values that it
+    /// carries when the condition is false.
+    fn while_iteration(
+        &mut self,
+        condition: &Graph,
+        body: &Graph,
+        inputs: &[Interval],
+    ) -> Option<Vec<Interval>> {
+        self.set_all(condition.inputs, inputs);
+        self.analyze(condition);
+        let (&keep_going, outputs) = condition.outputs.split_last()?;
+        let body_inputs = self.refine(condition, keep_going, true, outputs);
+        self.set_all(body.inputs, &body_inputs);
+        self.analyze(body);
+        Some(self.refine(condition, keep_going, false, outputs))
+    }
+
+    /// The bounds of `values` where the boolean `condition` that `graph`
+    /// computes is `holds`, which are narrower if it compares one of them.
+    fn refine(
+        &self,
+        graph: &Graph,
+        condition: Value,
+        holds: bool,
+        values: &[Value],
+    ) -> Vec<Interval> {
+        let mut originals = BTreeMap::new();
+        let mut comparison = None;
+        for assignment in &graph.assignments {
+            match assignment.op {
+                Op::Dup => {
+                    let arg = assignment.args[0];
+                    let original = *originals.get(&arg).unwrap_or(&arg);
+                    for value in assignment.to {
+                        originals.insert(value, original);
+                    }
+                }
+                Op::Compare {
+                    comparison: operation,
+                    typ: Type::I32,
+                } if assignment.to + 0 == condition => {
+                    comparison = Some((
+                        operation,
+                        assignment.args[0],
+                        assignment.args[1],
+                    ));
+                }
+                _ => {}
+            }
+        }
+        let original = |value| *originals.get(&value).unwrap_or(&value);
+        let comparison = comparison.and_then(|(operation, a, b)| {
+            let operation = if holds {
+                Some(operation)
+            } else {
+                negated(operation)
+            };
+            Some((operation?, a, b))
+        });
+        values
+            .iter()
+            .map(|&value| {
+                let interval = self.get(value);
+                match comparison {
+                    Some((operation, a, b))
+                        if original(value) == original(a) =>
+                    {
+                        interval.assuming(operation, self.get(b))
+                    }
+                    Some((operation, a, b))
+                        if original(value) == original(b) =>
+                    {
+                        interval.assuming(flipped(operation), self.get(a))
+                    }
+                    _ => interval,
+                }
+            })
+            .collect()
+    }
+
+    /// Whether an assignment that would normally be considered impure because
+    /// it might trap is known to never do so.
+    pub fn proves_infallible(&self, assignment: &Assignment) -> bool {
+        match assignment.op {
+            Op::BinMath {
+                operation,
+                typ: Type::I32,
+                ..
+            } => {
+                let a = self.get(assignment.args[0]);
+                let b = self.get(assignment.args[1]);
+                match operation {
+                    BinMathOp::Div | BinMathOp::Rem => {
+                        let overflows = a.contains(i32::MIN) && b.contains(-1);
+                        !b.contains(0) && !overflows
+                    }
+                    _ => a.bin_math(operation, b).is_some(),
+                }
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Whether the graph can `continue` any loop.
+fn continues(graph: &Graph) -> bool {
+    graph
+        .each_op(&mut |op| match op {
+            Op::Continue(_) => ControlFlow::Break(()),
+            _ => ControlFlow::Continue(()),
+        })
+        .is_break()
+}
+
+/// Whether the graph can `break` out of any loop.
+fn breaks(graph: &Graph) -> bool {
+    graph
+        .each_op(&mut |op| match op {
+            Op::Break(_) => ControlFlow::Break(()),
+            _ => ControlFlow::Continue(()),
+        })
+        .is_break()
+}
@@ -0,0 +1,168 @@
+//! Checks that graphs are well-formed, so that a mistake in SSA construction
+//! or in an optimization is reported where it happens rather than as a
+//! Cranelift verifier error or a miscompilation.
+
+use super::{Graph, Op, Value};
+use anyhow::{ensure, Result};
+use std::collections::BTreeSet;
+
+impl Graph {
+    /// Checks that every value is defined exactly once and only used after its
+    /// definition, either in the same graph or in one around it, and that
+    /// nested graphs take and produce as many values as the operations they
+    /// belong to expect.
+    pub fn validate(&self) -> Result<()> {
+        Validator::default().graph(self)
+    }
+}
+
+#[derive(Default)]
+struct Validator {
+    /// Every value that has been defined anywhere, including in graphs that
+    /// have already been left.
+    defined: BTreeSet<Value>,
+    /// The values that can be used at the current point.
+    in_scope: BTreeSet<Value>,
+}
+
+impl Validator {
+    fn graph(&mut self, graph: &Graph) -> Result<()> {
+        let mut defined_here = Vec::new();
+        for value in graph.inputs {
+            self.define(value, &mut defined_here)?;
+        }
+        for assignment in &graph.assignments {
+            for &arg in &*assignment.args {
+                self.use_value(arg)?;
+            }
+            self.nested_graphs(
+                &assignment.op,
+                assignment.args.len(),
+                usize::from(assignment.to.count()),
+            )?;
+            for value in assignment.to {
+                self.define(value, &mut defined_here)?;
+            }
+        }
+        for &output in &graph.outputs {
+            self.use_value(output)?;
+        }
+        for value in defined_here {
+            self.in_scope.remove(&value);
+        }
+        Ok(())
+    }
+
+    fn define(
+        &mut self,
+        value: Value,
+        defined_here: &mut Vec<Value>,
+    ) -> Result<()> {
+        ensure!(self.defined.insert(value), "`{value:?}` is defined twice");
+        self.in_scope.insert(value);
+        defined_here.push(value);
+        Ok(())
+    }
+
+    fn use_value(&self, value: Value) -> Result<()> {
+        ensure!(
+            self.in_scope.contains(&value),
+            "`{value:?}` is used where it isn't defined"
+        );
+        Ok(())
+    }
+
+    /// Checks the graphs that an operation contains, given how many arguments
+    /// it takes and how many results it produces.
+    fn nested_graphs(&mut self, op: &Op, args: usize, to: usize) -> Result<()> {
+        match op {
+            Op::Then(body) => {
+                let inputs = condition_and_inputs(args, "then")?;
+                ensure!(to == inputs, "`then` changes the number of values");
+                self.branch(body, inputs, to, "then")
+            }
+            Op::ThenElse(then, else_) => {
+                let inputs = condition_and_inputs(args, "then else")?;
+                self.branch(then, inputs, to, "then")?;
+                self.branch(else_, inputs, to, "else")
+            }
+            Op::Match(cases) => {
+                let inputs = condition_and_inputs(args, "match")?;
+                cases
+                    .iter()
+                    .try_for_each(|case| self.branch(case, inputs, to, "case"))
+            }
+            Op::Switch(values, cases) => {
+                ensure!(
+                    cases.len() == values.len() + 1,
+                    "`case` has {} arms for {} values",
+                    cases.len(),
+                    values.len()
+                );
+                let inputs = condition_and_inputs(args, "case")?;
+                cases
+                    .iter()
+                    .try_for_each(|arm| self.branch(arm, inputs, to, "arm"))
+            }
+            Op::Repeat(body) => {
+                ensure!(to == args, "`repeat` changes the number of values");
+                self.branch(body, args, args + 1, "repeat")
+            }
+            Op::While(condition, body) => {
+                ensure!(to == args, "`while` changes the number of values");
+                self.branch(condition, args, args + 1, "while condition")?;
+                self.branch(body, args, args, "while")
+            }
+            Op::Quotation(signature, body) => {
+                check_shape(
+                    body,
+                    signature.parameters.len(),
+                    signature.returns.len(),
+                    "quotation",
+                )?;
+                // Quotations don't see any of the values around them.
+                let in_scope = std::mem::take(&mut self.in_scope);
+                let result = self.graph(body);
+                self.in_scope = in_scope;
+                result
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn branch(
+        &mut self,
+        graph: &Graph,
+        inputs: usize,
+        outputs: usize,
+        what: &str,
+    ) -> Result<()> {
+        check_shape(graph, inputs, outputs, what)?;
+        self.graph(graph)
+    }
+}
+
+/// The number of arguments besides the condition or tag on top of them.
+fn condition_and_inputs(args: usize, what: &str) -> Result<usize> {
+    args.checked_sub(1)
+        .ok_or_else(|| anyhow::anyhow!("`{what}` has no condition"))
+}
+
+fn check_shape(
+    graph: &Graph,
+    inputs: usize,
+    outputs: usize,
+    what: &str,
+) -> Result<()> {
+    ensure!(
+        usize::from(graph.inputs.count()) == inputs,
+        "`{what}` body takes {} values instead of {inputs}",
+        graph.inputs.count()
+    );
+    ensure!(
+        graph.diverges() || graph.outputs.len() == outputs,
+        "`{what}` body produces {} values instead of {outputs}",
+        graph.outputs.len()
+    );
+    Ok(())
+}
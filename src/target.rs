@@ -0,0 +1,171 @@
+use crate::ir::Abi;
+use anyhow::{anyhow, Context, Result};
+use cranelift::prelude::{
+    isa::{self, CallConv, TargetIsa},
+    settings,
+};
+use std::{str::FromStr, sync::Arc};
+use target_lexicon::Triple;
+
+/// A platform that Spackel can compile for, one for each architecture that
+/// Cranelift has a backend for and the common operating systems for it.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Target {
+    X86_64Linux,
+    X86_64MacOs,
+    X86_64Windows,
+    Aarch64Linux,
+    Aarch64MacOs,
+    Riscv64Linux,
+    S390xLinux,
+}
+
+impl Target {
+    pub const ALL: [Self; 7] = [
+        Self::X86_64Linux,
+        Self::X86_64MacOs,
+        Self::X86_64Windows,
+        Self::Aarch64Linux,
+        Self::Aarch64MacOs,
+        Self::Riscv64Linux,
+        Self::S390xLinux,
+    ];
+
+    #[must_use]
+    pub const fn triple(self) -> &'static str {
+        match self {
+            Self::X86_64Linux => "x86_64-unknown-linux-gnu",
+            Self::X86_64MacOs => "x86_64-apple-darwin",
+            Self::X86_64Windows => "x86_64-pc-windows-msvc",
+            Self::Aarch64Linux => "aarch64-unknown-linux-gnu",
+            Self::Aarch64MacOs => "aarch64-apple-darwin",
+            Self::Riscv64Linux => "riscv64gc-unknown-linux-gnu",
+            Self::S390xLinux => "s390x-unknown-linux-gnu",
+        }
+    }
+
+    /// The machine that Spackel is running on, if it is one of the known
+    /// targets.
+    #[must_use]
+    pub fn host() -> Option<Self> {
+        let host = Triple::host();
+        Self::ALL.into_iter().find(|target| {
+            let triple = target.parsed_triple();
+            triple.architecture == host.architecture
+                && triple.operating_system == host.operating_system
+        })
+    }
+
+    fn parsed_triple(self) -> Triple {
+        self.triple().parse().unwrap()
+    }
+
+    #[must_use]
+    pub const fn object_format(self) -> &'static str {
+        match self {
+            Self::X86_64MacOs | Self::Aarch64MacOs => "Mach-O",
+            Self::X86_64Windows => "COFF",
+            Self::X86_64Linux
+            | Self::Aarch64Linux
+            | Self::Riscv64Linux
+            | Self::S390xLinux => "ELF",
+        }
+    }
+
+    /// The calling convention of C functions, which the runtime and `main`
+    /// use.
+    #[must_use]
+    pub const fn call_conv(self) -> CallConv {
+        match self {
+            Self::X86_64Windows => CallConv::WindowsFastcall,
+            Self::Aarch64MacOs => CallConv::AppleAarch64,
+            Self::X86_64Linux
+            | Self::X86_64MacOs
+            | Self::Aarch64Linux
+            | Self::Riscv64Linux
+            | Self::S390xLinux => CallConv::SystemV,
+        }
+    }
+
+    /// The calling convention that an extern function with the ABI uses on
+    /// the target.
+    ///
+    /// # Errors
+    ///
+    /// Some calling conventions only exist on `x86_64`.
+    pub fn extern_call_conv(self, abi: Abi) -> Result<CallConv> {
+        let is_x86_64 = matches!(
+            self,
+            Self::X86_64Linux | Self::X86_64MacOs | Self::X86_64Windows
+        );
+        match abi {
+            Abi::C | Abi::System => Ok(self.call_conv()),
+            Abi::SysV64 if is_x86_64 => Ok(CallConv::SystemV),
+            Abi::Fastcall if is_x86_64 => Ok(CallConv::WindowsFastcall),
+            Abi::SysV64 | Abi::Fastcall => Err(anyhow!(
+                "the `\"{}\"` calling convention only exists on x86_64, not on `{}`",
+                abi.name(),
+                self.triple()
+            )),
+        }
+    }
+
+    /// Whether this build of Cranelift includes the backend for the target.
+    #[must_use]
+    pub fn is_included(self) -> bool {
+        isa::lookup(self.parsed_triple()).is_ok()
+    }
+
+    /// Whether `make` builds the runtime for the target, which only happens
+    /// when it is the host.
+    #[must_use]
+    pub fn has_runtime(self) -> bool {
+        Self::host() == Some(self)
+    }
+
+    /// Looks up the Cranelift backend for the target.
+    ///
+    /// # Errors
+    ///
+    /// This build of Cranelift may not include the backend, or the flags may
+    /// not suit it.
+    pub fn isa(self, flags: settings::Flags) -> Result<Arc<dyn TargetIsa>> {
+        let isa = isa::lookup(self.parsed_triple())
+            .with_context(|| {
+                format!(
+                    "cannot compile for `{}` since this build of Cranelift doesn't include its backend",
+                    self.triple()
+                )
+            })?
+            .finish(flags)?;
+        debug_assert_eq!(isa.default_call_conv(), self.call_conv());
+        Ok(isa)
+    }
+}
+
+impl Default for Target {
+    /// The host if it's known, since that is what the runtime is built for.
+    fn default() -> Self {
+        Self::host().unwrap_or(Self::X86_64Linux)
+    }
+}
+
+impl FromStr for Target {
+    type Err = anyhow::Error;
+
+    fn from_str(triple: &str) -> Result<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|target| target.triple() == triple)
+            .ok_or_else(|| {
+                anyhow!(
+                    "unknown target `{triple}`, the supported targets are {}",
+                    Self::ALL
+                        .iter()
+                        .map(|target| format!("`{}`", target.triple()))
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                )
+            })
+    }
+}
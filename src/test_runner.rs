@@ -0,0 +1,98 @@
+//! `spackel test`, which runs every `test "name" ... end` block of a program
+//! and reports which of them didn't leave `true` on the stack.
+
+use crate::{
+    cli::{self, Flags},
+    diagnostics::{self, primary_label, secondary_label},
+    interpreter::{self, Snapshot, Value},
+    ir::{Function, Instruction},
+    parser,
+    typ::{self, Type},
+};
+use anyhow::{bail, Result};
+use codemap::{CodeMap, File};
+use std::{process::ExitCode, sync::Arc};
+
+/// Runs the tests in name order. Each one starts with an empty stack and
+/// fresh variables.
+pub fn run(
+    code_map: &CodeMap,
+    files: &[Arc<File>],
+    flags: &Flags,
+) -> Result<ExitCode> {
+    let mut program = parser::parse(files)?;
+    let tests = std::mem::take(&mut program.tests);
+    let mut spans = Vec::new();
+    // Tests are checked as functions that return a `bool`. Their names are
+    // string literals, which don't usually name functions.
+    for (name, test) in tests {
+        if let Some(function) = program.functions.get(name) {
+            bail!(diagnostics::error(
+                format!("test {name} has the same name as a function"),
+                vec![
+                    primary_label(test.declaration_span, ""),
+                    secondary_label(function.declaration_span, "function"),
+                ],
+            ));
+        }
+        spans.push((name, test.declaration_span));
+        program.functions.insert(
+            name,
+            Function {
+                declaration_span: test.declaration_span,
+                doc: "".into(),
+                parameters: Box::new([]),
+                returns: Box::new([(
+                    Instruction::PushType(Type::Bool),
+                    test.declaration_span,
+                )]),
+                body: test.body,
+                end_span: test.end_span,
+                inline: false,
+                is_unsafe: false,
+            },
+        );
+    }
+    let mut program = typ::check_library(program, false)?;
+    let warnings = cli::lint(&mut program, files, flags)?;
+    diagnostics::emit(&warnings, code_map);
+
+    let total = spans.len();
+    let mut failed = 0;
+    for (name, span) in spans {
+        let (result, state) = interpreter::interpret_function(
+            &program,
+            name,
+            Snapshot::default(),
+            &mut std::io::stdin().lock(),
+            &mut std::io::stdout().lock(),
+        );
+        let labels = match result {
+            Ok(()) if state.stack == im::Vector::unit(Value::Bool(true)) => {
+                println!("test {name} ... ok");
+                continue;
+            }
+            Ok(()) => vec![primary_label(span, "left `false` on the stack")],
+            Err(halt) => halt.span().map_or_else(
+                || vec![primary_label(span, halt.to_string())],
+                |halt_span| {
+                    vec![
+                        primary_label(halt_span, halt.to_string()),
+                        secondary_label(span, "in this test"),
+                    ]
+                },
+            ),
+        };
+        println!("test {name} ... FAILED");
+        diagnostics::error(format!("test {name} failed"), labels)
+            .emit(code_map);
+        failed += 1;
+    }
+
+    println!("{} passed, {failed} failed", total - failed);
+    Ok(if failed == 0 {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    })
+}
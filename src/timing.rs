@@ -0,0 +1,62 @@
+//! `--time-passes`, which reports how long each part of compiling a program
+//! took and how large the program was along the way.
+
+use std::{
+    fmt,
+    time::{Duration, Instant},
+};
+
+#[derive(Default)]
+pub struct Timings {
+    /// The total time spent in each pass, in the order that they first ran.
+    passes: Vec<(&'static str, Duration)>,
+    counts: Vec<(&'static str, usize)>,
+}
+
+impl Timings {
+    /// Runs a pass, adding how long it took to the time spent in it so far
+    /// since passes such as optimizations can run several times.
+    pub fn time<T>(
+        &mut self,
+        pass: &'static str,
+        run: impl FnOnce() -> T,
+    ) -> T {
+        let start = Instant::now();
+        let result = run();
+        self.add(pass, start.elapsed());
+        result
+    }
+
+    pub fn add(&mut self, pass: &'static str, duration: Duration) {
+        if let Some((_, total)) =
+            self.passes.iter_mut().find(|(name, _)| *name == pass)
+        {
+            *total += duration;
+        } else {
+            self.passes.push((pass, duration));
+        }
+    }
+
+    /// Records how many of something there were, such as functions.
+    pub fn count(&mut self, what: &'static str, count: usize) {
+        self.counts.push((what, count));
+    }
+}
+
+impl fmt::Display for Timings {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (pass, duration) in &self.passes {
+            writeln!(f, "{:>10.3} ms  {pass}", duration.as_secs_f64() * 1e3)?;
+        }
+        let total = self
+            .passes
+            .iter()
+            .map(|(_, duration)| *duration)
+            .sum::<Duration>();
+        writeln!(f, "{:>10.3} ms  total", total.as_secs_f64() * 1e3)?;
+        for (what, count) in &self.counts {
+            writeln!(f, "{count:>10}     {what}")?;
+        }
+        Ok(())
+    }
+}
@@ -1,13 +1,21 @@
 use crate::{
-    diagnostics::{self, primary_label},
-    ir::{BinMathOp, Block, Function, Instruction, Program},
+    diagnostics::{
+        self, internal_error, primary_label, secondary_label, Collector,
+        Diagnostic, Lint,
+    },
+    ir::{
+        self, BinMathOp, Block, Combinator, ExternFunction, Function, Global,
+        Instruction, Program, TypeDefinition,
+    },
+    ssa,
 };
-use anyhow::{ensure, Result};
+use anyhow::{bail, ensure, Result};
 use codemap::Span;
 use itertools::Itertools;
 use std::{
-    collections::BTreeMap,
+    collections::{BTreeMap, BTreeSet},
     fmt::{self, Write as _},
+    sync::{Arc, Mutex},
 };
 
 #[derive(Clone, PartialEq, Eq, Debug)]
@@ -18,6 +26,220 @@ pub enum Type {
     #[expect(clippy::enum_variant_names, reason = "`Type` is a type")]
     Type,
     Ptr(Box<Self>),
+    /// A file opened with `open`.
+    File,
+    Struct(Arc<Struct>),
+    Enum(Arc<Enum>),
+    /// A pointer to an anonymous function with this signature.
+    Quotation(Arc<FunctionSignature>),
+    /// A type variable of a generic function, written `'a`. These only exist
+    /// in the signatures of generic functions, since every call instantiates
+    /// the function with the variables replaced.
+    Var(Box<str>),
+}
+
+/// A struct, whose values are the values of its fields lying next to each
+/// other on the stack.
+#[derive(PartialEq, Eq, Debug)]
+pub struct Struct {
+    pub name: Box<str>,
+    pub fields: Box<[(Box<str>, Type)]>,
+    /// How an `extern struct` is laid out in memory. Other structs have no
+    /// layout since they never leave the stack.
+    pub layout: Option<Layout>,
+}
+
+/// Where the fields of an `extern struct` are in memory, which is where C puts
+/// the same fields so that C code can read and write them through pointers.
+#[derive(PartialEq, Eq, Debug)]
+pub struct Layout {
+    pub size: u64,
+    pub align: u64,
+    /// How many bytes after the start of the struct each field starts.
+    pub offsets: Box<[u64]>,
+}
+
+impl Layout {
+    /// Lays out fields like C does: each one goes at the first offset after
+    /// the previous one that is a multiple of its alignment, and the size is
+    /// rounded up to a multiple of the largest alignment so that the struct
+    /// can be put in arrays. Returns the field without a C layout otherwise.
+    fn of_c_struct(fields: &[(Box<str>, Type)]) -> Result<Self, &str> {
+        let mut size = 0_u64;
+        let mut align = 1;
+        let offsets = fields
+            .iter()
+            .map(|(field, typ)| {
+                let (field_size, field_align) =
+                    c_size_and_align(typ).ok_or(&**field)?;
+                let offset = size.next_multiple_of(field_align);
+                size = offset + field_size;
+                align = align.max(field_align);
+                Ok(offset)
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Self {
+            size: size.next_multiple_of(align),
+            align,
+            offsets,
+        })
+    }
+}
+
+/// The size and alignment that C gives a type, if it has a C equivalent.
+/// Every supported target is 64-bit, so pointers take up 8 bytes, but only
+/// pointers to something that C could read have an equivalent.
+fn c_size_and_align(typ: &Type) -> Option<(u64, u64)> {
+    match typ {
+        Type::I32 | Type::F32 => Some((4, 4)),
+        Type::Bool => Some((1, 1)),
+        Type::Ptr(pointee) => c_size_and_align(pointee).map(|_| (8, 8)),
+        Type::Struct(struct_) => {
+            let layout = struct_.layout.as_ref()?;
+            Some((layout.size, layout.align))
+        }
+        _ => None,
+    }
+}
+
+/// An enum, whose values are an `i32` tag saying which variant it is,
+/// followed by the payloads of every variant. Only the payload of the tagged
+/// variant is meaningful, while the others hold placeholder values.
+#[derive(PartialEq, Eq, Debug)]
+pub struct Enum {
+    pub name: Box<str>,
+    pub variants: Box<[(Box<str>, Option<Type>)]>,
+}
+
+impl Enum {
+    fn payload_values(
+        variants: &[(Box<str>, Option<Type>)],
+    ) -> impl Iterator<Item = Type> + '_ {
+        variants
+            .iter()
+            .flat_map(|(_, payload)| payload.iter().flat_map(Type::flatten))
+    }
+}
+
+impl Type {
+    /// The types of the values that make up a value of this type, which is
+    /// just the type itself unless it's a struct or an enum.
+    pub fn flatten(&self) -> Vec<Self> {
+        match self {
+            Self::Struct(struct_) => struct_
+                .fields
+                .iter()
+                .flat_map(|(_, typ)| typ.flatten())
+                .collect(),
+            Self::Enum(enum_) => std::iter::once(Self::I32)
+                .chain(Enum::payload_values(&enum_.variants))
+                .collect(),
+            _ => vec![self.clone()],
+        }
+    }
+
+    fn width(&self) -> usize {
+        match self {
+            Self::Struct(struct_) => {
+                struct_.fields.iter().map(|(_, typ)| typ.width()).sum()
+            }
+            Self::Enum(enum_) => {
+                1 + enum_
+                    .variants
+                    .iter()
+                    .flat_map(|(_, payload)| payload)
+                    .map(Self::width)
+                    .sum::<usize>()
+            }
+            _ => 1,
+        }
+    }
+
+    /// Whether values of both types are the same kind of machine value once
+    /// compiled, like any two pointers are, so that code that only passes
+    /// them around compiles the same for either.
+    pub fn compiles_like(&self, other: &Self) -> bool {
+        let is_i32 =
+            |typ: &Self| matches!(typ, Self::I32 | Self::File | Self::Type);
+        let is_pointer =
+            |typ: &Self| matches!(typ, Self::Ptr(_) | Self::Quotation(_));
+        (is_i32(self) && is_i32(other))
+            || (is_pointer(self) && is_pointer(other))
+            || self == other
+    }
+
+    /// Whether values of this type are made up of several values.
+    const fn is_composite(&self) -> bool {
+        matches!(self, Self::Struct(_) | Self::Enum(_))
+    }
+
+    /// Adds the type variables in this type to `variables`, in order of first
+    /// appearance.
+    fn type_variables<'a>(&'a self, variables: &mut Vec<&'a str>) {
+        match self {
+            Self::Var(name) if !variables.contains(&&**name) => {
+                variables.push(name);
+            }
+            Self::Ptr(pointee) => pointee.type_variables(variables),
+            Self::Quotation(signature) => {
+                for variable in signature.type_variables() {
+                    if !variables.contains(&variable) {
+                        variables.push(variable);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Matches a type that may contain type variables against the type of an
+    /// actual value, recording what each variable stands for. Fails if a
+    /// variable would have to stand for two different types.
+    fn unify(
+        &self,
+        typ: &Self,
+        substitution: &mut BTreeMap<Box<str>, Self>,
+    ) -> bool {
+        match (self, typ) {
+            (Self::Var(name), _) => {
+                if let Some(bound) = substitution.get(name) {
+                    return bound == typ;
+                }
+                substitution.insert(name.clone(), typ.clone());
+                true
+            }
+            (Self::Ptr(pattern), Self::Ptr(pointee)) => {
+                pattern.unify(pointee, substitution)
+            }
+            (Self::Quotation(pattern), Self::Quotation(signature)) => {
+                pattern.parameters.len() == signature.parameters.len()
+                    && pattern.returns.len() == signature.returns.len()
+                    && std::iter::zip(
+                        pattern.parameters.iter().chain(&*pattern.returns),
+                        signature.parameters.iter().chain(&*signature.returns),
+                    )
+                    .all(|(pattern, typ)| pattern.unify(typ, substitution))
+            }
+            _ => self == typ,
+        }
+    }
+
+    /// Replaces the type variables that `substitution` knows about.
+    fn substitute(&self, substitution: &BTreeMap<Box<str>, Self>) -> Self {
+        match self {
+            Self::Var(name) => substitution
+                .get(name)
+                .cloned()
+                .unwrap_or_else(|| self.clone()),
+            Self::Ptr(pointee) => {
+                Self::Ptr(Box::new(pointee.substitute(substitution)))
+            }
+            Self::Quotation(signature) => {
+                Self::Quotation(Arc::new(signature.substitute(substitution)))
+            }
+            _ => self.clone(),
+        }
+    }
 }
 
 impl fmt::Display for Type {
@@ -31,6 +253,11 @@ impl fmt::Display for Type {
             Self::F32 => f.write_str("f32"),
             Self::Type => f.write_str("type"),
             Self::Ptr(inner) => write!(f, "{inner} ptr"),
+            Self::File => f.write_str("file"),
+            Self::Struct(struct_) => f.write_str(&struct_.name),
+            Self::Enum(enum_) => f.write_str(&enum_.name),
+            Self::Quotation(signature) => write!(f, "[{signature}]"),
+            Self::Var(name) => write!(f, "'{name}"),
         }?;
         if f.alternate() {
             f.write_char('`')?;
@@ -41,98 +268,1381 @@ impl fmt::Display for Type {
 
 pub type Generics = Box<[Type]>;
 
+/// The elements of a `data` table, which all have the same type.
+#[derive(Debug)]
+pub enum DataTable {
+    I32(Box<[i32]>),
+    F32(Box<[f32]>),
+    Bool(Box<[bool]>),
+}
+
+impl DataTable {
+    pub const fn element_type(&self) -> Type {
+        match self {
+            Self::I32(_) => Type::I32,
+            Self::F32(_) => Type::F32,
+            Self::Bool(_) => Type::Bool,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            Self::I32(elements) => elements.len(),
+            Self::F32(elements) => elements.len(),
+            Self::Bool(elements) => elements.len(),
+        }
+    }
+}
+
+#[derive(Debug)]
 pub struct CheckedProgram<'src> {
+    /// The signatures of all functions, including generic and extern ones,
+    /// which have no body. Each instance of a generic function is a separate
+    /// function.
     pub function_signatures: BTreeMap<&'src str, FunctionSignature>,
     pub function_bodies: BTreeMap<&'src str, Box<Block<Generics>>>,
+    pub variables: BTreeMap<&'src str, Type>,
+    pub data: BTreeMap<&'src str, DataTable>,
+    /// The functions that are marked `inline`, including instances of
+    /// generic ones.
+    pub inline_functions: BTreeSet<&'src str>,
+    pub warnings: Vec<Diagnostic>,
 }
 
-#[derive(Clone)]
+impl CheckedProgram<'_> {
+    /// A hash of the checked program that stays the same between runs, so
+    /// that build systems can tell whether anything needs to be rebuilt.
+    /// Spans are left out, so moving code around or reformatting it keeps the
+    /// checksum, even though the line numbers in runtime error messages
+    /// change along with it.
+    #[must_use]
+    pub fn checksum(&self) -> u64 {
+        let mut canonical = String::new();
+        for (name, signature) in &self.function_signatures {
+            let inline = self.inline_functions.contains(name);
+            writeln!(canonical, "fn {name} {inline} {signature:?}").unwrap();
+            if let Some(body) = self.function_bodies.get(name) {
+                write_canonical(&mut canonical, body);
+                canonical.push('\n');
+            }
+        }
+        for (name, typ) in &self.variables {
+            writeln!(canonical, "var {name} {typ:?}").unwrap();
+        }
+        for (name, table) in &self.data {
+            writeln!(canonical, "data {name} {table:?}").unwrap();
+        }
+        crate::stable_hash(&canonical)
+    }
+
+    /// How many instructions there are in all function bodies, counting those
+    /// in nested blocks, for `--time-passes`.
+    #[must_use]
+    pub fn instruction_count(&self) -> usize {
+        self.function_bodies
+            .values()
+            .map(|body| count_instructions(body))
+            .sum()
+    }
+}
+
+fn count_instructions(block: &Block<Generics>) -> usize {
+    block
+        .iter()
+        .map(|(instruction, _)| {
+            1 + match instruction {
+                Instruction::Then(body)
+                | Instruction::Repeat { body, .. }
+                | Instruction::Times { body, .. }
+                | Instruction::Unsafe(body)
+                | Instruction::Stash { body, .. }
+                | Instruction::Let { body, .. }
+                | Instruction::MacroExpansion { body, .. } => {
+                    count_instructions(body)
+                }
+                Instruction::Match(cases) => cases
+                    .iter()
+                    .map(|case| count_instructions(&case.body))
+                    .sum(),
+                Instruction::Switch { arms, else_ } => {
+                    arms.iter()
+                        .map(|arm| count_instructions(&arm.body))
+                        .sum::<usize>()
+                        + count_instructions(else_)
+                }
+                Instruction::ThenElse(first, second)
+                | Instruction::While {
+                    condition: first,
+                    body: second,
+                    ..
+                } => count_instructions(first) + count_instructions(second),
+                Instruction::Quotation(quotation) => {
+                    quotation.body.as_deref().map_or(0, count_instructions)
+                }
+                _ => 0,
+            }
+        })
+        .sum()
+}
+
+/// Writes a block for `CheckedProgram::checksum`, with everything but the
+/// spans in it.
+fn write_canonical(out: &mut String, block: &Block<Generics>) {
+    out.push('(');
+    for (instruction, generics) in block {
+        match instruction {
+            Instruction::Then(body) => {
+                out.push_str("Then");
+                write_canonical(out, body);
+            }
+            Instruction::ThenElse(then, else_) => {
+                out.push_str("ThenElse");
+                write_canonical(out, then);
+                write_canonical(out, else_);
+            }
+            Instruction::Match(cases) => {
+                out.push_str("Match");
+                for case in cases {
+                    write!(out, " {}", case.variant).unwrap();
+                    write_canonical(out, &case.body);
+                }
+            }
+            Instruction::Switch { arms, else_ } => {
+                out.push_str("Switch");
+                for arm in arms {
+                    write!(out, " {}", arm.value).unwrap();
+                    write_canonical(out, &arm.body);
+                }
+                write_canonical(out, else_);
+            }
+            Instruction::Repeat { body, label, .. } => {
+                write!(out, "Repeat {label:?}").unwrap();
+                write_canonical(out, body);
+            }
+            Instruction::While {
+                condition,
+                body,
+                label,
+                ..
+            } => {
+                write!(out, "While {label:?}").unwrap();
+                write_canonical(out, condition);
+                write_canonical(out, body);
+            }
+            Instruction::Times {
+                body,
+                pushes_index,
+                label,
+                ..
+            } => {
+                write!(out, "Times {pushes_index} {label:?}").unwrap();
+                write_canonical(out, body);
+            }
+            Instruction::Unsafe(body) => {
+                out.push_str("Unsafe");
+                write_canonical(out, body);
+            }
+            // The parameters and returns are in the generics as the type of
+            // the quotation.
+            Instruction::Quotation(quotation) => {
+                out.push_str("Quotation");
+                if let Some(body) = &quotation.body {
+                    write_canonical(out, body);
+                }
+            }
+            Instruction::MacroExpansion { name, body } => {
+                write!(out, "MacroExpansion {name}").unwrap();
+                write_canonical(out, body);
+            }
+            Instruction::Stash { name, body } => {
+                write!(out, "Stash {name}").unwrap();
+                write_canonical(out, body);
+            }
+            Instruction::Let { names, body } => {
+                write!(out, "Let {names:?}").unwrap();
+                write_canonical(out, body);
+            }
+            Instruction::Null(body) => {
+                out.push_str("Null");
+                write_canonical(out, body);
+            }
+            Instruction::Location(_) => continue,
+            Instruction::Assert(_) => out.push_str("Assert"),
+            Instruction::BinMathOp(operation, _) => {
+                write!(out, "{operation:?}").unwrap();
+            }
+            // None of the others contain spans or blocks.
+            _ => write!(out, "{instruction:?}").unwrap(),
+        }
+        writeln!(out, " {generics:?}").unwrap();
+    }
+    out.push(')');
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
 pub struct FunctionSignature {
     pub parameters: Box<[Type]>,
     pub returns: Box<[Type]>,
+    /// Whether the function is declared with `unsafe fn`, which makes its
+    /// body an unsafe context and calling it unsafe. Quotation types are
+    /// never unsafe.
+    pub is_unsafe: bool,
+}
+
+impl fmt::Display for FunctionSignature {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            self.parameters
+                .iter()
+                .map(ToString::to_string)
+                .chain(["→".to_owned()])
+                .chain(self.returns.iter().map(ToString::to_string))
+                .format(" ")
+        )
+    }
+}
+
+impl FunctionSignature {
+    /// Replaces structs and enums with the values that they are made up of,
+    /// which is how they are passed.
+    pub fn flatten(&self) -> Self {
+        Self {
+            parameters: self
+                .parameters
+                .iter()
+                .flat_map(Type::flatten)
+                .collect(),
+            returns: self.returns.iter().flat_map(Type::flatten).collect(),
+            is_unsafe: self.is_unsafe,
+        }
+    }
+
+    /// How a function with this signature is declared, such as
+    /// `unsafe fn name : i32 → i32`.
+    pub fn declaration(&self, name: &str) -> String {
+        let unsafe_ = if self.is_unsafe { "unsafe " } else { "" };
+        format!("{unsafe_}fn {name} : {self}")
+    }
+
+    /// Whether the function has type variables, which must be replaced
+    /// before it can be called.
+    pub fn is_generic(&self) -> bool {
+        !self.type_variables().is_empty()
+    }
+
+    fn type_variables(&self) -> Vec<&str> {
+        let mut variables = Vec::new();
+        for typ in self.parameters.iter().chain(&*self.returns) {
+            typ.type_variables(&mut variables);
+        }
+        variables
+    }
+
+    fn substitute(&self, substitution: &BTreeMap<Box<str>, Type>) -> Self {
+        Self {
+            parameters: self
+                .parameters
+                .iter()
+                .map(|typ| typ.substitute(substitution))
+                .collect(),
+            returns: self
+                .returns
+                .iter()
+                .map(|typ| typ.substitute(substitution))
+                .collect(),
+            is_unsafe: self.is_unsafe,
+        }
+    }
 }
 
 pub fn check(program: Program) -> Result<CheckedProgram> {
+    require_main(&program)?;
+    check_program(program, false)
+}
+
+/// Like `check`, but marks where every instruction came from with
+/// `Instruction::Location`.
+pub fn check_annotated(program: Program) -> Result<CheckedProgram> {
+    require_main(&program)?;
+    check_program(program, true)
+}
+
+/// Like `check` or `check_annotated`, but for a library, which doesn't need a
+/// `main` function.
+pub fn check_library(
+    program: Program,
+    annotate: bool,
+) -> Result<CheckedProgram> {
+    check_program(program, annotate)
+}
+
+fn require_main(program: &Program) -> Result<()> {
     ensure!(
         program.functions.contains_key("main"),
         "program has no `main` function"
     );
+    Ok(())
+}
+
+/// Macros that are used at least this many times are turned into functions
+/// where possible.
+const OUTLINE_MIN_USES: usize = 2;
+
+/// How many instances of the same generic function may need each other
+/// before giving up, since a generic function can call itself with a larger
+/// type each time.
+const MAX_RECURSIVE_INSTANCES: usize = 8;
 
-    let function_signatures = program
+/// How many parameters `infer_effects` tries before giving up, since every
+/// combination of their types is tried.
+const MAX_INFERRED_PARAMETERS: usize = 4;
+
+/// Gives the name of an instance of a generic function the lifetime of the
+/// source code. Names are kept so that checking the same program again, like
+/// the language server does, doesn't leak any more memory.
+fn intern(name: String) -> &'static str {
+    static NAMES: Mutex<BTreeSet<&'static str>> = Mutex::new(BTreeSet::new());
+    let mut names = NAMES.lock().unwrap();
+    if let Some(&name) = names.get(&*name) {
+        return name;
+    }
+    let name = Box::leak(name.into_boxed_str());
+    names.insert(name);
+    name
+}
+
+/// Checks a program along with a line entered into the REPL, which has been
+/// parsed as the function `name`. The line starts out with values of the
+/// given types on the stack and may leave anything on it, which becomes the
+/// signature of the function.
+pub fn check_line<'src>(
+    mut program: Program<'src>,
+    name: &'src str,
+    stack: &[Type],
+) -> Result<CheckedProgram<'src>> {
+    let line = program.functions.remove(name).ok_or_else(|| {
+        internal_error(format!("the line `{name}` wasn't parsed"))
+    })?;
+    let mut checker = checker(&program, false)?;
+    let mut function_bodies = checker.check(program)?;
+    checker.stack = stack.to_vec();
+    let body = checker.check_block(line.body)?;
+    let signature = FunctionSignature {
+        parameters: stack.into(),
+        returns: std::mem::take(&mut checker.stack).into(),
+        is_unsafe: false,
+    };
+    checker.function_signatures.insert(name, signature);
+    function_bodies.insert(name, body);
+    Ok(checker.finish(function_bodies))
+}
+
+/// Works out the stack effects that a snippet of code, parsed as the function
+/// `name`, can have. A snippet that is just the name of a function has the
+/// signature of that function. Otherwise every combination of `i32`, `f32`,
+/// `bool` and type variables is tried as parameters, starting with the
+/// fewest. Only the most general effects are kept, so `dup` is `'a → 'a 'a`
+/// while `dup × +` is both `i32 i32 → i32` and `f32 f32 → f32`.
+pub fn infer_effects<'src>(
+    mut program: Program<'src>,
+    name: &'src str,
+) -> Result<Vec<FunctionSignature>> {
+    let snippet = program
         .functions
+        .remove(name)
+        .ok_or_else(|| {
+            internal_error(format!("the snippet `{name}` wasn't parsed"))
+        })?
+        .body;
+    let mut checker = checker(&program, false)?;
+    checker.check(program)?;
+    if let [(Instruction::Call(callee), _)] = &*snippet {
+        if let Some(signature) = checker.function_signatures.get(&**callee) {
+            return Ok(vec![signature.clone()]);
+        }
+    }
+
+    checker.generic = true;
+    let mut first_error = None;
+    for parameter_count in 0..=MAX_INFERRED_PARAMETERS {
+        let mut effects = Vec::<FunctionSignature>::new();
+        for parameters in parameter_candidates(parameter_count) {
+            checker.type_variables = parameters
+                .iter()
+                .filter_map(|typ| match typ {
+                    Type::Var(variable) => {
+                        Some((variable.clone(), typ.clone()))
+                    }
+                    _ => None,
+                })
+                .collect();
+            checker.stack.clone_from(&parameters);
+            match checker.check_block(snippet.clone()) {
+                Ok(_) => effects.push(FunctionSignature {
+                    parameters: parameters.into(),
+                    returns: std::mem::take(&mut checker.stack).into(),
+                    is_unsafe: false,
+                }),
+                Err(err) => {
+                    first_error.get_or_insert(err);
+                }
+            }
+            checker.recover();
+        }
+        let most_general = effects
+            .iter()
+            .filter(|effect| {
+                !effects.iter().any(|other| {
+                    other != *effect
+                        && std::iter::zip(
+                            &*other.parameters,
+                            &*effect.parameters,
+                        )
+                        .all(|(general, specific)| {
+                            matches!(general, Type::Var(_))
+                                || general == specific
+                        })
+                })
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+        if !most_general.is_empty() {
+            return Ok(most_general);
+        }
+    }
+    // The error without any parameters says what the snippet expected to
+    // find on the stack.
+    Err(first_error.unwrap())
+}
+
+/// Every combination of the types that `infer_effects` tries as parameters.
+fn parameter_candidates(count: usize) -> impl Iterator<Item = Vec<Type>> {
+    const CHOICES: usize = 4;
+    (0..CHOICES.pow(count.try_into().unwrap())).map(move |mut combination| {
+        (0..count)
+            .map(|position| {
+                let choice = combination % CHOICES;
+                combination /= CHOICES;
+                match choice {
+                    0 => Type::Var(
+                        char::from(b'a' + u8::try_from(position).unwrap())
+                            .to_string()
+                            .into(),
+                    ),
+                    1 => Type::I32,
+                    2 => Type::F32,
+                    _ => Type::Bool,
+                }
+            })
+            .collect()
+    })
+}
+
+fn check_program(program: Program, annotate: bool) -> Result<CheckedProgram> {
+    let mut checker = checker(&program, annotate)?;
+    let function_bodies = checker.check(program)?;
+    Ok(checker.finish(function_bodies))
+}
+
+/// Resolves the types and signatures of a program to check its functions
+/// with.
+fn checker<'src>(
+    program: &Program<'src>,
+    annotate: bool,
+) -> Result<Checker<'src>> {
+    let types = resolve_types(program)?;
+    let variants = resolve_variants(program, &types)?;
+    check_global_names(program, &variants)?;
+    let variables = program
+        .variables
         .iter()
-        .map(|(name, function)| {
-            Ok((*name, check_function_signature(name, function)?))
+        .map(|(&name, variable)| {
+            Ok((name, check_variable_type(variable, &types)?))
         })
         .collect::<Result<_>>()?;
+    let mut macro_uses = program.macros.keys().map(|&name| (name, 0)).collect();
+    for function in program.functions.values() {
+        count_macro_uses(&function.body, &mut macro_uses);
+    }
+
+    let mut errors = Collector::default();
+    let mut function_signatures = BTreeMap::new();
+    for (&name, function) in &program.functions {
+        if let Some(signature) =
+            errors.record(check_function_signature(name, function, &types))?
+        {
+            function_signatures.insert(name, signature);
+        }
+    }
+    for (&name, function) in &program.extern_functions {
+        if let Some(signature) =
+            errors.record(check_extern_signature(name, function, &types))?
+        {
+            function_signatures.insert(name, signature);
+        }
+    }
+    errors.finish()?;
 
-    Checker {
+    Ok(Checker {
         stack: Vec::new(),
+        origins: Vec::new(),
+        files: program.files.clone(),
         function_signatures,
+        types,
+        variants,
         unsafe_layers: 0,
+        stashes: Vec::new(),
+        loops: Vec::new(),
+        returns: None,
+        diverged: false,
+        annotate,
+        macro_uses,
+        outlined: BTreeMap::new(),
+        generic_bodies: BTreeMap::new(),
+        instances: BTreeMap::new(),
+        type_variables: BTreeMap::new(),
+        generic: false,
+        instantiating: Vec::new(),
+        failed_generics: BTreeSet::new(),
+        warnings: Vec::new(),
+        constant_bodies: BTreeMap::new(),
+        constants: BTreeMap::new(),
+        evaluating: Vec::new(),
+        variables,
+        data: BTreeMap::new(),
+        inline_functions: BTreeSet::new(),
+        extern_functions: program
+            .extern_functions
+            .iter()
+            .map(|(&name, function)| (name, function.abi))
+            .collect(),
+    })
+}
+
+/// Counts how many times each macro expansion that was kept together occurs.
+fn count_macro_uses(block: &Block, uses: &mut BTreeMap<&str, usize>) {
+    for (instruction, _) in block {
+        match instruction {
+            Instruction::MacroExpansion { name, body } => {
+                if let Some(count) = uses.get_mut(&**name) {
+                    *count += 1;
+                }
+                count_macro_uses(body, uses);
+            }
+            Instruction::Then(body)
+            | Instruction::Repeat { body, .. }
+            | Instruction::Times { body, .. }
+            | Instruction::Unsafe(body)
+            | Instruction::Stash { body, .. }
+            | Instruction::Let { body, .. } => count_macro_uses(body, uses),
+            Instruction::Match(cases) => {
+                for case in &**cases {
+                    count_macro_uses(&case.body, uses);
+                }
+            }
+            Instruction::Switch { arms, else_ } => {
+                for arm in &**arms {
+                    count_macro_uses(&arm.body, uses);
+                }
+                count_macro_uses(else_, uses);
+            }
+            Instruction::ThenElse(first, second)
+            | Instruction::While {
+                condition: first,
+                body: second,
+                ..
+            } => {
+                count_macro_uses(first, uses);
+                count_macro_uses(second, uses);
+            }
+            Instruction::Quotation(quotation) => {
+                if let Some(body) = &quotation.body {
+                    count_macro_uses(body, uses);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Whether a block uses a stash or local that isn't declared inside of it.
+fn uses_outer_stash(block: &Block, declared: &mut Vec<Box<str>>) -> bool {
+    block.iter().any(|(instruction, _)| match instruction {
+        Instruction::ToStash(name) | Instruction::FromStash(name) => {
+            !declared.contains(name)
+        }
+        Instruction::Local(name) => !declared.contains(&local_stash(name)),
+        Instruction::Stash { name, body } => {
+            declared.push(name.clone());
+            let uses = uses_outer_stash(body, declared);
+            declared.pop();
+            uses
+        }
+        Instruction::Let { names, body } => {
+            declared.extend(names.iter().map(|name| local_stash(name)));
+            let uses = uses_outer_stash(body, declared);
+            declared.truncate(declared.len() - names.len());
+            uses
+        }
+        Instruction::Then(body)
+        | Instruction::Repeat { body, .. }
+        | Instruction::Times { body, .. }
+        | Instruction::Unsafe(body)
+        | Instruction::MacroExpansion { body, .. } => {
+            uses_outer_stash(body, declared)
+        }
+        Instruction::Match(cases) => cases
+            .iter()
+            .any(|case| uses_outer_stash(&case.body, declared)),
+        Instruction::Switch { arms, else_ } => {
+            arms.iter().any(|arm| uses_outer_stash(&arm.body, declared))
+                || uses_outer_stash(else_, declared)
+        }
+        Instruction::ThenElse(first, second)
+        | Instruction::While {
+            condition: first,
+            body: second,
+            ..
+        } => {
+            uses_outer_stash(first, declared)
+                || uses_outer_stash(second, declared)
+        }
+        _ => false,
+    })
+}
+
+/// The name of the stash that holds a local from `let`, which can't be the
+/// name of an actual stash since those are single words.
+fn local_stash(name: &str) -> Box<str> {
+    format!("let {name}").into()
+}
+
+/// Resolves every struct and enum type, which is also how the language server
+/// finds the layouts of extern structs.
+pub fn resolve_types<'src>(
+    program: &Program<'src>,
+) -> Result<BTreeMap<&'src str, Type>> {
+    let mut resolved = BTreeMap::new();
+    for (name, definition) in &program.types {
+        if let Some(function) = program.functions.get(name) {
+            bail!(diagnostics::error(
+                format!(
+                    "`{name}` is defined as both {} and a function",
+                    if definition.is_enum {
+                        "an enum"
+                    } else {
+                        "a struct"
+                    }
+                ),
+                vec![
+                    primary_label(definition.declaration_span, ""),
+                    secondary_label(function.declaration_span, ""),
+                ],
+            ));
+        }
+        resolve_type(name, &program.types, &mut resolved, &mut Vec::new())?;
+    }
+    Ok(resolved)
+}
+
+/// Makes sure that constants, variables and data tables don't share their
+/// names with anything else.
+fn check_global_names(
+    program: &Program,
+    variants: &BTreeMap<&str, (Arc<Enum>, usize)>,
+) -> Result<()> {
+    for (kind, globals) in [
+        ("constant", &program.constants),
+        ("variable", &program.variables),
+        ("data table", &program.data),
+    ] {
+        for (name, global) in globals {
+            let clash = program
+                .functions
+                .get(name)
+                .map(|function| ("a function", function.declaration_span))
+                .or_else(|| {
+                    program.types.get(name).map(|other| {
+                        let kind =
+                            if other.is_enum { "an enum" } else { "a struct" };
+                        (kind, other.declaration_span)
+                    })
+                })
+                .or_else(|| {
+                    variants.get(name).map(|(enum_, _)| {
+                        let enum_span =
+                            program.types[&*enum_.name].declaration_span;
+                        ("a variant", enum_span)
+                    })
+                })
+                .or_else(|| {
+                    (kind != "constant")
+                        .then(|| program.constants.get(name))
+                        .flatten()
+                        .map(|other| ("a constant", other.declaration_span))
+                })
+                .or_else(|| {
+                    (kind == "data table")
+                        .then(|| program.variables.get(name))
+                        .flatten()
+                        .map(|other| ("a variable", other.declaration_span))
+                });
+            if let Some((other, other_span)) = clash {
+                bail!(diagnostics::error(
+                    format!("`{name}` is defined as both a {kind} and {other}"),
+                    vec![
+                        primary_label(global.declaration_span, ""),
+                        secondary_label(other_span, ""),
+                    ],
+                ));
+            }
+        }
+    }
+    for (name, function) in &program.extern_functions {
+        let clash = program
+            .functions
+            .get(name)
+            .map(|other| ("a function", other.declaration_span))
+            .or_else(|| {
+                program
+                    .types
+                    .get(name)
+                    .map(|other| ("a type", other.declaration_span))
+            })
+            .or_else(|| {
+                variants.get(name).map(|(enum_, _)| {
+                    let enum_span =
+                        program.types[&*enum_.name].declaration_span;
+                    ("a variant", enum_span)
+                })
+            })
+            .or_else(|| {
+                program
+                    .constants
+                    .get(name)
+                    .map(|other| ("a constant", other.declaration_span))
+            })
+            .or_else(|| {
+                program
+                    .variables
+                    .get(name)
+                    .map(|other| ("a variable", other.declaration_span))
+            })
+            .or_else(|| {
+                program
+                    .data
+                    .get(name)
+                    .map(|other| ("a data table", other.declaration_span))
+            });
+        if let Some((other, other_span)) = clash {
+            bail!(diagnostics::error(
+                format!(
+                    "`{name}` is defined as both an extern function and {other}"
+                ),
+                vec![
+                    primary_label(function.declaration_span, ""),
+                    secondary_label(other_span, ""),
+                ],
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Variables hold a single value that can be loaded and stored as is.
+fn check_variable_type(
+    variable: &Global,
+    types: &BTreeMap<&str, Type>,
+) -> Result<Type> {
+    let mut lookup = |name: &str| Ok(types.get(name).cloned());
+    let types = check_type_stack(&variable.body, "variable type", &mut lookup)?;
+    match &*types {
+        [typ @ (Type::I32 | Type::F32 | Type::Bool)] => Ok(typ.clone()),
+        _ => bail!(diagnostics::error(
+            format!(
+                "variables must be of type `i32`, `f32` or `bool`, not `{}`",
+                types.iter().format(" ")
+            ),
+            vec![primary_label(variable.declaration_span, "")],
+        )),
     }
-    .check(program)
+}
+
+/// Resolves the field or variant types of a struct or enum along with the
+/// types that it contains, which must not include itself.
+fn resolve_type<'src>(
+    name: &'src str,
+    definitions: &BTreeMap<&'src str, TypeDefinition<'src>>,
+    resolved: &mut BTreeMap<&'src str, Type>,
+    resolving: &mut Vec<&'src str>,
+) -> Result<Type> {
+    if let Some(typ) = resolved.get(name) {
+        return Ok(typ.clone());
+    }
+    let definition = &definitions[name];
+    let kind = if definition.is_enum { "enum" } else { "struct" };
+    if let Some(cycle_start) = resolving.iter().position(|&other| other == name)
+    {
+        bail!(diagnostics::error(
+            format!("{kind} `{name}` contains itself"),
+            vec![primary_label(definition.declaration_span, "")],
+        )
+        .note(format!(
+            "the types contain each other in a cycle: {} → {name}",
+            resolving[cycle_start..].iter().format(" → ")
+        )));
+    }
+
+    resolving.push(name);
+    let fields = definition
+        .fields
+        .iter()
+        .map(|field| {
+            let context = if definition.is_enum {
+                "variant type"
+            } else {
+                "field type"
+            };
+            let types = check_type_stack(&field.typ, context, &mut |name| {
+                definitions
+                    .get_key_value(name)
+                    .map(|(&name, _)| {
+                        resolve_type(name, definitions, resolved, resolving)
+                    })
+                    .transpose()
+            })?;
+            Ok((field.name.into(), types.into_vec().pop()))
+        })
+        .collect::<Result<Box<_>>>()?;
+    resolving.pop();
+
+    let typ = if definition.is_enum {
+        ensure!(
+            !Enum::payload_values(&fields)
+                .any(|typ| matches!(typ, Type::Ptr(_))),
+            diagnostics::error(
+                format!("enum `{name}` contains a pointer"),
+                vec![primary_label(definition.declaration_span, "")],
+            )
+            .note("variants that aren't in use hold placeholder values, which pointers don't have")
+        );
+        Type::Enum(Arc::new(Enum {
+            name: name.into(),
+            variants: fields,
+        }))
+    } else {
+        let fields = Box::into_iter(fields)
+            .map(|(field, typ)| {
+                // The parser requires every field to have a type.
+                let typ = typ.ok_or_else(|| {
+                    internal_error(format!(
+                        "field `{field}` of struct `{name}` has no type"
+                    ))
+                })?;
+                Ok((field, typ))
+            })
+            .collect::<Result<Box<_>>>()?;
+        let layout = if definition.is_extern {
+            Some(Layout::of_c_struct(&fields).map_err(|field| {
+                diagnostics::error(
+                    format!("field `{field}` of extern struct `{name}` has no C equivalent"),
+                    vec![primary_label(definition.declaration_span, "")],
+                )
+                .note("only `i32`, `f32`, `bool`, pointers and other extern structs can be shared with C")
+            })?)
+        } else {
+            None
+        };
+        Type::Struct(Arc::new(Struct {
+            name: name.into(),
+            fields,
+            layout,
+        }))
+    };
+    resolved.insert(name, typ.clone());
+    Ok(typ)
+}
+
+/// Finds the enum and index of every variant, whose names are used as
+/// instructions that construct them.
+fn resolve_variants<'src>(
+    program: &Program<'src>,
+    types: &BTreeMap<&'src str, Type>,
+) -> Result<BTreeMap<&'src str, (Arc<Enum>, usize)>> {
+    let mut variants = BTreeMap::<_, (Arc<Enum>, usize)>::new();
+    for (name, definition) in &program.types {
+        let Type::Enum(enum_) = &types[name] else {
+            continue;
+        };
+        for (index, variant) in definition.fields.iter().enumerate() {
+            let clash = program
+                .functions
+                .get(variant.name)
+                .map(|function| ("a function", function.declaration_span))
+                .or_else(|| {
+                    program.types.get(variant.name).map(|other| {
+                        let kind =
+                            if other.is_enum { "an enum" } else { "a struct" };
+                        (kind, other.declaration_span)
+                    })
+                })
+                .or_else(|| {
+                    variants.get(variant.name).map(|(other, _)| {
+                        let other_span =
+                            program.types[&*other.name].declaration_span;
+                        ("a variant of another enum", other_span)
+                    })
+                });
+            if let Some((other, other_span)) = clash {
+                bail!(diagnostics::error(
+                    format!(
+                        "`{}` is defined as both a variant of `{name}` and {other}",
+                        variant.name
+                    ),
+                    vec![
+                        primary_label(definition.declaration_span, ""),
+                        secondary_label(other_span, ""),
+                    ],
+                ));
+            }
+            variants.insert(variant.name, (enum_.clone(), index));
+        }
+    }
+    Ok(variants)
 }
 
 fn check_function_signature(
     name: &str,
     function: &Function,
+    types: &BTreeMap<&str, Type>,
 ) -> Result<FunctionSignature> {
-    let parameters = check_type_stack(&function.parameters)?;
-    let returns = check_type_stack(&function.returns)?;
+    let mut lookup = |name: &str| {
+        Ok(match name.strip_prefix('\'') {
+            Some(variable) if !variable.is_empty() => {
+                Some(Type::Var(variable.into()))
+            }
+            _ => types.get(name).cloned(),
+        })
+    };
+    let parameters = check_type_stack(
+        &function.parameters,
+        "function signature",
+        &mut lookup,
+    )?;
+    let returns =
+        check_type_stack(&function.returns, "function signature", &mut lookup)?;
+
+    let mut known = Vec::new();
+    for typ in &*parameters {
+        typ.type_variables(&mut known);
+    }
+    let mut unknown = Vec::new();
+    for typ in &*returns {
+        typ.type_variables(&mut unknown);
+    }
+    unknown.retain(|variable| !known.contains(variable));
+    ensure!(
+        unknown.is_empty(),
+        diagnostics::error(
+            format!(
+                "type variable `'{}` only appears in the return types",
+                unknown[0]
+            ),
+            vec![primary_label(function.declaration_span, "defined here")]
+        )
+        .note("what a type variable stands for is decided by the arguments")
+    );
 
     if name == "main" {
         ensure!(
-            parameters.is_empty() && returns.is_empty(),
+            parameters.is_empty() && matches!(*returns, [] | [Type::I32]),
             diagnostics::error(
                 "`main` function has wrong signature".to_owned(),
                 vec![primary_label(function.declaration_span, "defined here")]
             )
-            .note("`main` must have no parameters and no return values")
+            .note("`main` must have no parameters and either return nothing or an `i32` exit code")
         );
     }
 
     Ok(FunctionSignature {
         parameters,
         returns,
+        is_unsafe: function.is_unsafe,
+    })
+}
+
+/// Extern functions follow the C calling convention, so they can only take
+/// and return single values, and return at most one. Structs can only be
+/// passed through pointers, and only if they are laid out like in C.
+fn check_extern_signature(
+    name: &str,
+    function: &ExternFunction,
+    types: &BTreeMap<&str, Type>,
+) -> Result<FunctionSignature> {
+    ensure!(
+        name != "main",
+        diagnostics::error(
+            "`main` cannot be an extern function".to_owned(),
+            vec![primary_label(function.declaration_span, "")]
+        )
+        .note("`main` is where the program starts")
+    );
+    ensure!(
+        !name.starts_with("spkl_"),
+        diagnostics::error(
+            format!("the name of extern function `{name}` is reserved"),
+            vec![primary_label(function.declaration_span, "")]
+        )
+        .note("names starting with `spkl_` belong to the runtime")
+    );
+    let mut lookup = |name: &str| Ok(types.get(name).cloned());
+    let parameters = check_type_stack(
+        &function.parameters,
+        "function signature",
+        &mut lookup,
+    )?;
+    let returns =
+        check_type_stack(&function.returns, "function signature", &mut lookup)?;
+    let invalid = parameters.iter().chain(&*returns).find(|typ| {
+        matches!(typ, Type::Struct(_)) || c_size_and_align(typ).is_none()
+    });
+    if let Some(typ) = invalid {
+        bail!(diagnostics::error(
+            format!("extern functions cannot take or return `{typ}`"),
+            vec![primary_label(function.declaration_span, "")]
+        )
+        .note("only `i32`, `f32`, `bool` and pointers to those or to extern structs can be passed to C"));
+    }
+    ensure!(
+        returns.len() <= 1,
+        diagnostics::error(
+            "extern functions can return at most one value".to_owned(),
+            vec![primary_label(function.declaration_span, "")]
+        )
+    );
+    // Calling extern functions is always unsafe, which is checked separately
+    // so that the error can say why.
+    Ok(FunctionSignature {
+        parameters,
+        returns,
+        is_unsafe: false,
     })
 }
 
-fn check_type_stack(instructions: &Block<Span>) -> Result<Box<[Type]>> {
-    instructions
-        .iter()
-        .map(|(param, span)| match param {
-            Instruction::PushType(typ) => Ok(typ.clone()),
-            _ => Err(diagnostics::error(
-                "unsupported instruction in function signature".to_owned(),
-                vec![primary_label(*span, "")],
-            )
-            .into()),
-        })
-        .collect::<Result<Box<_>>>()
-}
+/// Reads types written as instructions, where `lookup` finds the struct and
+/// enum types.
+fn check_type_stack(
+    instructions: &Block<Span>,
+    context: &str,
+    lookup: &mut dyn FnMut(&str) -> Result<Option<Type>>,
+) -> Result<Box<[Type]>> {
+    let mut types = Vec::new();
+    for (instruction, span) in instructions {
+        let unsupported = || {
+            diagnostics::error(
+                format!("unsupported instruction in {context}"),
+                vec![primary_label(*span, "")],
+            )
+        };
+        match instruction {
+            Instruction::PushType(typ) => types.push(typ.clone()),
+            Instruction::Ptr => {
+                let pointee = types.pop().ok_or_else(|| {
+                    diagnostics::error(
+                        format!("`ptr` in {context} must follow a type"),
+                        vec![primary_label(*span, "")],
+                    )
+                })?;
+                types.push(Type::Ptr(Box::new(pointee)));
+            }
+            Instruction::Call(name) => {
+                types.push(lookup(name)?.ok_or_else(unsupported)?);
+            }
+            Instruction::Quotation(quotation) if quotation.body.is_none() => {
+                types.push(Type::Quotation(Arc::new(FunctionSignature {
+                    parameters: check_type_stack(
+                        &quotation.parameters,
+                        context,
+                        lookup,
+                    )?,
+                    returns: check_type_stack(
+                        &quotation.returns,
+                        context,
+                        lookup,
+                    )?,
+                    is_unsafe: false,
+                })));
+            }
+            _ => bail!(unsupported()),
+        }
+    }
+    Ok(types.into())
+}
+
+/// A loop that `break` and `continue` can refer to.
+struct Loop {
+    label: Option<Box<str>>,
+    /// The types on the stack and on each stash when entering the loop.
+    types: Vec<Type>,
+    stashes: Vec<Vec<Type>>,
+}
+
+struct Checker<'src> {
+    stack: Vec<Type>,
+    /// Where each value on the stack came from, if it is known. This is kept
+    /// the same length as `stack` but is only used for error messages, so
+    /// values that are moved around by anything but a signature may end up
+    /// with the wrong span.
+    origins: Vec<Option<Span>>,
+    files: Box<[Arc<codemap::File>]>,
+    function_signatures: BTreeMap<&'src str, FunctionSignature>,
+    types: BTreeMap<&'src str, Type>,
+    variants: BTreeMap<&'src str, (Arc<Enum>, usize)>,
+    unsafe_layers: usize,
+    /// The names and types of the stashes in scope, innermost last. Locals
+    /// from `let` are kept the same way, as stashes holding one element each.
+    stashes: Vec<(Box<str>, Vec<Type>)>,
+    /// The loops that `break` and `continue` could currently refer to,
+    /// innermost last.
+    loops: Vec<Loop>,
+    /// The return types of the function being checked, which `return` must
+    /// leave on the stack.
+    returns: Option<Box<[Type]>>,
+    /// Whether the current block has jumped elsewhere, making the rest of it
+    /// unreachable.
+    diverged: bool,
+    /// Whether to insert `Instruction::Location` before every word.
+    annotate: bool,
+    /// How many times each macro is used, counting each expansion that the
+    /// parser kept together.
+    macro_uses: BTreeMap<&'src str, usize>,
+    /// The functions that macros have been turned into.
+    outlined: BTreeMap<&'src str, (FunctionSignature, Box<Block<Generics>>)>,
+    /// The unchecked bodies of generic functions, which are checked again
+    /// for every instance.
+    generic_bodies: BTreeMap<&'src str, (Box<Block>, Span)>,
+    /// The checked bodies of the instances of generic functions.
+    instances: BTreeMap<&'src str, Box<Block<Generics>>>,
+    /// What the type variables of the function being checked stand for.
+    type_variables: BTreeMap<Box<str>, Type>,
+    /// Whether a generic function is being checked with its type variables
+    /// unknown. The result is only used for error messages, so nothing gets
+    /// instantiated.
+    generic: bool,
+    /// The generic functions whose instances are being checked, innermost
+    /// last.
+    instantiating: Vec<&'src str>,
+    /// The generic functions with mistakes found when checking them on their
+    /// own, which aren't instantiated.
+    failed_generics: BTreeSet<&'src str>,
+    /// The constants that haven't been evaluated yet.
+    constant_bodies: BTreeMap<&'src str, Global>,
+    /// The values of the evaluated constants, as literals of these types.
+    constants: BTreeMap<&'src str, (Instruction<Generics>, Type)>,
+    /// The constants being evaluated, innermost last.
+    evaluating: Vec<&'src str>,
+    variables: BTreeMap<&'src str, Type>,
+    data: BTreeMap<&'src str, DataTable>,
+    inline_functions: BTreeSet<&'src str>,
+    /// The calling conventions of the functions declared with `extern fn`,
+    /// whose signatures are among the others.
+    extern_functions: BTreeMap<&'src str, ir::Abi>,
+    warnings: Vec<Diagnostic>,
+}
+
+impl<'src> Checker<'src> {
+    /// Checks the constants and functions of a program, returning the bodies
+    /// of the functions that aren't generic.
+    fn check(
+        &mut self,
+        program: Program<'src>,
+    ) -> Result<BTreeMap<&'src str, Box<Block<Generics>>>> {
+        self.warnings = program.warnings;
+        self.constant_bodies = program.constants;
+        let constant_names =
+            self.constant_bodies.keys().copied().collect::<Vec<_>>();
+        for name in constant_names {
+            let span = self.constant_bodies[name].declaration_span;
+            self.constant(name, span)?;
+        }
+        for (name, table) in program.data {
+            let table = self.evaluate_data(name, table)?;
+            self.data.insert(name, table);
+        }
+
+        self.inline_functions = program
+            .functions
+            .iter()
+            .filter(|(_, function)| function.inline)
+            .map(|(&name, _)| name)
+            .collect();
+        let (generic, functions): (Vec<_>, Vec<_>) = program
+            .functions
+            .into_iter()
+            .partition(|(name, _)| self.function_signatures[name].is_generic());
+        self.generic_bodies = generic
+            .into_iter()
+            .map(|(name, function)| (name, (function.body, function.end_span)))
+            .collect();
+
+        // Each function is checked even if the ones before it have mistakes,
+        // so that all of them are reported at once.
+        let mut errors = Collector::default();
+
+        // Generic functions are checked once on their own so that mistakes
+        // in them are found even if they're never called.
+        let generic_names =
+            self.generic_bodies.keys().copied().collect::<Vec<_>>();
+        for name in generic_names {
+            let signature = self.function_signatures[name].clone();
+            let (body, end_span) = self.generic_bodies[name].clone();
+            self.type_variables = signature
+                .type_variables()
+                .into_iter()
+                .map(|variable| (variable.into(), Type::Var(variable.into())))
+                .collect();
+            self.generic = true;
+            if errors
+                .record(self.check_function_body(&signature, body, end_span))?
+                .is_none()
+            {
+                self.recover();
+                self.failed_generics.insert(name);
+            }
+        }
+        self.type_variables.clear();
+        self.generic = false;
+
+        let mut function_bodies = BTreeMap::new();
+        for (name, function) in functions {
+            match errors.record(self.check_function(name, function))? {
+                Some(body) => {
+                    function_bodies.insert(name, body);
+                }
+                None => self.recover(),
+            }
+        }
+        errors.finish()?;
+        Ok(function_bodies)
+    }
+
+    /// Adds the functions made from macros and the instances of generic
+    /// functions to the checked bodies.
+    fn finish(
+        self,
+        mut function_bodies: BTreeMap<&'src str, Box<Block<Generics>>>,
+    ) -> CheckedProgram<'src> {
+        let mut function_signatures = self.function_signatures;
+        for (name, (signature, body)) in self.outlined {
+            function_signatures.insert(name, signature);
+            function_bodies.insert(name, body);
+        }
+        function_bodies.extend(self.instances);
+        CheckedProgram {
+            function_signatures,
+            function_bodies,
+            variables: self.variables,
+            data: self.data,
+            inline_functions: self.inline_functions,
+            warnings: self.warnings,
+        }
+    }
+
+    /// Checks a macro expansion, turning it into a call to a function made
+    /// from the macro if the macro is used often enough. Otherwise, or if the
+    /// expansion depends on what's around it, such as by using a stash or
+    /// breaking out of a loop, it's checked as if it was written out.
+    fn check_macro_expansion(
+        &mut self,
+        name: &str,
+        body: Box<Block>,
+    ) -> Result<Vec<(Instruction<Generics>, Generics)>> {
+        if let Some((&name, _)) = self
+            .macro_uses
+            .get_key_value(name)
+            .filter(|(_, &uses)| uses >= OUTLINE_MIN_USES)
+        {
+            let signature = match self.outlined.get(name) {
+                Some((signature, _)) => Some(signature.clone()),
+                None => self.outline(name, &body),
+            };
+            if let Some(signature) = signature
+                .filter(|signature| self.stack.ends_with(&signature.parameters))
+            {
+                self.stack
+                    .truncate(self.stack.len() - signature.parameters.len());
+                self.stack.extend(signature.returns.iter().cloned());
+                return Ok(vec![(
+                    Instruction::Call(name.into()),
+                    Box::default(),
+                )]);
+            }
+        }
+        Ok(self.check_block(body)?.into_vec())
+    }
 
-struct Checker<'src> {
-    stack: Vec<Type>,
-    function_signatures: BTreeMap<&'src str, FunctionSignature>,
-    unsafe_layers: usize,
-}
+    /// Tries to turn a macro into a function, taking as few values from the
+    /// top of the current stack as it can. It has to work without the stashes
+    /// and loops around it, and without being in an `unsafe` block since the
+    /// function may be called from outside of one.
+    fn outline(
+        &mut self,
+        name: &'src str,
+        body: &Block,
+    ) -> Option<FunctionSignature> {
+        if uses_outer_stash(body, &mut Vec::new()) {
+            return None;
+        }
+        let stack = std::mem::take(&mut self.stack);
+        let origins = std::mem::take(&mut self.origins);
+        let stashes = std::mem::take(&mut self.stashes);
+        let loops = std::mem::take(&mut self.loops);
+        let returns = self.returns.take();
+        let unsafe_layers = std::mem::take(&mut self.unsafe_layers);
+        let mut outlined = None;
+        for parameter_count in 0..=stack.len() {
+            let parameters = &stack[stack.len() - parameter_count..];
+            self.stack = parameters.to_vec();
+            self.diverged = false;
+            if let Ok(checked) = self.check_block(body.into()) {
+                if !self.diverged {
+                    let signature = FunctionSignature {
+                        parameters: parameters.into(),
+                        returns: std::mem::take(&mut self.stack).into(),
+                        is_unsafe: false,
+                    };
+                    outlined = Some((signature, checked));
+                    break;
+                }
+            }
+        }
+        self.stack = stack;
+        self.origins = origins;
+        self.stashes = stashes;
+        self.loops = loops;
+        self.returns = returns;
+        self.unsafe_layers = unsafe_layers;
+        self.diverged = false;
 
-impl<'src> Checker<'src> {
-    fn check(mut self, program: Program<'src>) -> Result<CheckedProgram<'src>> {
-        let function_bodies = program
-            .functions
-            .into_iter()
-            .map(|(name, function)| {
-                let body = self.check_function(name, function)?;
-                Ok((name, body))
-            })
-            .collect::<Result<_>>()?;
+        let (signature, body) = outlined?;
+        self.outlined.insert(name, (signature.clone(), body));
+        Some(signature)
+    }
 
-        Ok(CheckedProgram {
-            function_signatures: self.function_signatures,
-            function_bodies,
-        })
+    /// Forgets what was being checked when an error happened so that the
+    /// next function starts from scratch.
+    fn recover(&mut self) {
+        self.stack.clear();
+        self.origins.clear();
+        self.stashes.clear();
+        self.loops.clear();
+        self.returns = None;
+        self.unsafe_layers = 0;
+        self.diverged = false;
+        self.instantiating.clear();
+        self.evaluating.clear();
     }
 
     fn check_function(
@@ -140,21 +1650,55 @@ impl<'src> Checker<'src> {
         name: &str,
         function: Function,
     ) -> Result<Box<Block<Generics>>> {
-        self.stack = self.function_signatures[name].parameters.to_vec();
-        let body = Box::into_iter(function.body)
-            .map(|instruction| self.check_instruction(instruction))
-            .collect::<Result<_>>()?;
+        let signature = self.function_signatures[name].clone();
+        self.check_function_body(&signature, function.body, function.end_span)
+    }
+
+    /// Checks the body of a function, where `return` may be used. The body
+    /// of an `unsafe fn` is an unsafe context.
+    fn check_function_body(
+        &mut self,
+        signature: &FunctionSignature,
+        body: Box<Block>,
+        end_span: Span,
+    ) -> Result<Box<Block<Generics>>> {
+        let outer_returns = self.returns.replace(signature.returns.clone());
+        let outer_unsafe_layers = std::mem::replace(
+            &mut self.unsafe_layers,
+            usize::from(signature.is_unsafe),
+        );
+        let body = self.check_body(signature, body, end_span);
+        self.returns = outer_returns;
+        self.unsafe_layers = outer_unsafe_layers;
+        body
+    }
+
+    /// Checks the body of a function or quotation, which starts out with just
+    /// the parameters on the stack and must end with just the return values.
+    fn check_body(
+        &mut self,
+        signature: &FunctionSignature,
+        body: Box<Block>,
+        end_span: Span,
+    ) -> Result<Box<Block<Generics>>> {
+        self.stack = signature.parameters.to_vec();
+        self.origins = vec![None; self.stack.len()];
+        let body = self.check_block(body)?;
+        // A function that always exits never gets to return anything.
+        if std::mem::take(&mut self.diverged) {
+            return Ok(body);
+        }
 
         self.transform(
             &[],
-            &self.function_signatures[name]
+            &signature
                 .returns
                 .iter()
                 .cloned()
                 .map(Pattern::Concrete)
                 .collect::<Box<_>>(),
             &[],
-            function.end_span,
+            end_span,
         )?;
         ensure!(
             self.stack.is_empty(),
@@ -163,13 +1707,225 @@ impl<'src> Checker<'src> {
                     "there are values left on the stack with the following types: `{}`",
                     self.stack.iter().format(" ")
                 ),
-                vec![primary_label(function.end_span, "")]
+                vec![primary_label(end_span, "")]
             )
         );
 
         Ok(body)
     }
 
+    /// Checks a call to a generic function, working out what its type
+    /// variables stand for from the arguments and turning it into a call to
+    /// the corresponding instance.
+    fn check_generic_call(
+        &mut self,
+        name: &str,
+        signature: &FunctionSignature,
+        span: Span,
+    ) -> Result<Vec<(Instruction<Generics>, Generics)>> {
+        let parameter_count = signature.parameters.len();
+        let mut substitution = BTreeMap::new();
+        ensure!(
+            self.stack.len() >= parameter_count,
+            self.underflow(parameter_count, span)
+        );
+        ensure!(
+            std::iter::zip(
+                &*signature.parameters,
+                &self.stack[self.stack.len() - parameter_count..],
+            )
+            .all(|(parameter, typ)| parameter.unify(typ, &mut substitution)),
+            diagnostics::error(
+                "type mismatch".to_owned(),
+                vec![primary_label(
+                    span,
+                    format!(
+                        "expected types `{}` but got `{}`",
+                        signature.parameters.iter().format(" "),
+                        self.stack.iter().format(" ")
+                    )
+                )],
+            )
+        );
+        self.stack.truncate(self.stack.len() - parameter_count);
+        self.stack.extend(
+            signature
+                .returns
+                .iter()
+                .map(|typ| typ.substitute(&substitution)),
+        );
+        self.origins
+            .truncate(self.stack.len() - signature.returns.len());
+        self.origins.resize(self.stack.len(), Some(span));
+
+        let callee = if self.generic {
+            name.into()
+        } else {
+            self.instantiate(name, signature, &substitution, span)?
+        };
+        Ok(vec![(Instruction::Call(callee), Box::default())])
+    }
+
+    /// Checks the instance of a generic function with its type variables
+    /// replaced according to `substitution` unless that has already been
+    /// done, returning the name of the instance.
+    fn instantiate(
+        &mut self,
+        name: &str,
+        signature: &FunctionSignature,
+        substitution: &BTreeMap<Box<str>, Type>,
+        span: Span,
+    ) -> Result<Box<str>> {
+        // The space keeps instance names from clashing with user-defined
+        // names, which are single tokens.
+        let instance = intern(format!(
+            "{name} for {}",
+            signature
+                .type_variables()
+                .into_iter()
+                .map(|variable| &substitution[variable])
+                .format(" ")
+        ));
+        // Mistakes in the generic function itself have already been reported
+        // and would only be repeated for the instance.
+        if self.function_signatures.contains_key(instance)
+            || self.failed_generics.contains(name)
+        {
+            return Ok(instance.into());
+        }
+        if self.inline_functions.contains(name) {
+            self.inline_functions.insert(instance);
+        }
+        let (&name, (body, end_span)) =
+            self.generic_bodies.get_key_value(name).unwrap();
+        let (body, end_span) = (body.clone(), *end_span);
+        ensure!(
+            self.instantiating
+                .iter()
+                .filter(|&&other| other == name)
+                .count()
+                < MAX_RECURSIVE_INSTANCES,
+            diagnostics::error(
+                format!("too many nested instances of `{name}`"),
+                vec![primary_label(span, "")],
+            )
+            .note("the function probably calls itself with ever larger types")
+        );
+
+        // Registered before checking the body so that the instance can call
+        // itself.
+        let instance_signature = signature.substitute(substitution);
+        self.function_signatures
+            .insert(instance, instance_signature.clone());
+
+        let stack = std::mem::take(&mut self.stack);
+        let origins = std::mem::take(&mut self.origins);
+        let stashes = std::mem::take(&mut self.stashes);
+        let loops = std::mem::take(&mut self.loops);
+        let unsafe_layers = std::mem::take(&mut self.unsafe_layers);
+        let diverged = std::mem::take(&mut self.diverged);
+        let type_variables =
+            std::mem::replace(&mut self.type_variables, substitution.clone());
+        self.instantiating.push(name);
+        let body =
+            self.check_function_body(&instance_signature, body, end_span);
+        self.instantiating.pop();
+        self.stack = stack;
+        self.origins = origins;
+        self.stashes = stashes;
+        self.loops = loops;
+        self.unsafe_layers = unsafe_layers;
+        self.diverged = diverged;
+        self.type_variables = type_variables;
+
+        self.instances.insert(instance, body?);
+        Ok(instance.into())
+    }
+
+    /// Checks the body of a quotation, which can't see anything around it
+    /// since it runs whenever it's called. Quotations may be inlined into
+    /// their callers, so they can't `return` either.
+    fn check_quotation(
+        &mut self,
+        signature: &FunctionSignature,
+        body: Box<Block>,
+        end_span: Span,
+    ) -> Result<Box<Block<Generics>>> {
+        let stack = std::mem::take(&mut self.stack);
+        let stashes = std::mem::take(&mut self.stashes);
+        let loops = std::mem::take(&mut self.loops);
+        let origins = std::mem::take(&mut self.origins);
+        let returns = self.returns.take();
+        let body = self.check_body(signature, body, end_span);
+        self.stack = stack;
+        self.origins = origins;
+        self.stashes = stashes;
+        self.loops = loops;
+        self.returns = returns;
+        body
+    }
+
+    /// Checks the instructions of a block in order. Those after the block
+    /// has been exited can never run, so they are left out.
+    fn check_block(
+        &mut self,
+        block: Box<Block>,
+    ) -> Result<Box<Block<Generics>>> {
+        self.lint_unused_values(&block);
+        let mut checked = Vec::new();
+        for instruction in Box::into_iter(block) {
+            let span = instruction.1;
+            if self.diverged {
+                self.warn(
+                    diagnostics::warning(
+                        Lint::UnreachableCode,
+                        "unreachable instruction".to_owned(),
+                        vec![primary_label(span, "")],
+                    )
+                    .note("the block has already been exited by `break`, `continue`, `return` or `exit`"),
+                );
+                break;
+            }
+            if self.annotate {
+                checked.push((Instruction::Location(span), Box::default()));
+            }
+            checked.extend(self.check_instruction(instruction)?);
+            // Values pushed without a signature come from the instruction.
+            self.origins.resize(self.stack.len(), Some(span));
+        }
+        Ok(checked.into())
+    }
+
+    /// Warns about values that are pushed only to be dropped right away.
+    /// Instructions from the same macro expansion are left alone since the
+    /// macro may be useful elsewhere.
+    fn lint_unused_values(&mut self, block: &Block) {
+        for pair in block.windows(2) {
+            let [(instruction, span), (Instruction::Drop, drop_span)] = pair
+            else {
+                continue;
+            };
+            if span != drop_span && only_computes_value(instruction) {
+                self.warn(diagnostics::warning(
+                    Lint::UnusedValue,
+                    "value is computed and then dropped".to_owned(),
+                    vec![
+                        primary_label(*span, ""),
+                        secondary_label(*drop_span, "dropped here"),
+                    ],
+                ));
+            }
+        }
+    }
+
+    /// Records a warning unless the same one has already been found, which
+    /// happens when generic functions and macros are checked more than once.
+    fn warn(&mut self, warning: Diagnostic) {
+        if !self.warnings.contains(&warning) {
+            self.warnings.push(warning);
+        }
+    }
+
     fn transform(
         &mut self,
         generics: &[Generic],
@@ -177,13 +1933,44 @@ impl<'src> Checker<'src> {
         returns: &[Pattern],
         span: Span,
     ) -> Result<Generics> {
-        Signature {
+        self.origins.resize(self.stack.len(), None);
+        if self.stack.len() < parameters.len() {
+            return Err(self.underflow(parameters.len(), span).into());
+        }
+        let consumed =
+            self.origins.split_off(self.stack.len() - parameters.len());
+        let result = Signature {
             generics,
             parameters,
             returns,
         }
-        .apply(self)
-        .map_err(|()| {
+        .apply(self);
+        if result.is_err() {
+            self.origins.extend(consumed);
+        } else {
+            // Values that the signature moves around without being able to
+            // look at them, such as those of `swap`, keep where they came
+            // from.
+            let origins = returns
+                .iter()
+                .map(|pattern| {
+                    std::iter::zip(parameters, &consumed)
+                        .find(|(parameter, _)| {
+                            matches!(
+                                (parameter, pattern),
+                                (Pattern::Generic(a), Pattern::Generic(b))
+                                    if a == b && matches!(
+                                        generics[usize::from(*a)].1,
+                                        Constraint::Any
+                                    )
+                            )
+                        })
+                        .map_or(Some(span), |(_, &origin)| origin)
+                })
+                .collect::<Vec<_>>();
+            self.origins.extend(origins);
+        }
+        result.map_err(|()| {
             let mut label = format!(
                 "expected types `{}` ",
                 parameters
@@ -206,14 +1993,467 @@ impl<'src> Checker<'src> {
         })
     }
 
+    /// The error for a word that takes more values than there are on the
+    /// stack, pointing out where the values that are there came from.
+    fn underflow(&self, needed: usize, span: Span) -> diagnostics::Error {
+        let mut pushed = Vec::<(Span, Vec<&Type>)>::new();
+        for (typ, origin) in std::iter::zip(&self.stack, &self.origins) {
+            let Some(origin) = *origin else { continue };
+            match pushed.iter_mut().find(|(span, _)| *span == origin) {
+                Some((_, types)) => types.push(typ),
+                None => pushed.push((origin, vec![typ])),
+            }
+        }
+        let (available, label) = if self.stack.is_empty() {
+            ("the stack is empty".to_owned(), String::new())
+        } else {
+            (
+                format!("the stack only has {}", self.stack.len()),
+                format!("the stack is `{}`", self.stack.iter().format(" ")),
+            )
+        };
+        diagnostics::error(
+            format!(
+                "`{}` needs {needed} value{} but {available}",
+                self.source_text(span),
+                if needed == 1 { "" } else { "s" },
+            ),
+            std::iter::once(primary_label(span, label))
+                .chain(pushed.into_iter().map(|(origin, types)| {
+                    secondary_label(
+                        origin,
+                        format!("pushed `{}`", types.iter().format(" ")),
+                    )
+                }))
+                .collect(),
+        )
+    }
+
+    /// The source code at a span, such as the word that an instruction comes
+    /// from.
+    fn source_text(&self, span: Span) -> &str {
+        self.files
+            .iter()
+            .find(|file| file.span.contains(span))
+            .map_or("", |file| file.source_slice(span))
+    }
+
+    /// Finds the value of a constant as a literal along with its type,
+    /// evaluating the constant first if it hasn't been already. Returns
+    /// `None` if there is no such constant.
+    fn constant(
+        &mut self,
+        name: &str,
+        span: Span,
+    ) -> Result<Option<(Instruction<Generics>, Type)>> {
+        if let Some(value) = self.constants.get(name) {
+            return Ok(Some(value.clone()));
+        }
+        ensure!(
+            !self.evaluating.contains(&name),
+            diagnostics::error(
+                format!("constant `{name}` is defined in terms of itself"),
+                vec![primary_label(span, "")],
+            )
+        );
+        let Some((name, constant)) = self.constant_bodies.remove_entry(name)
+        else {
+            return Ok(None);
+        };
+
+        self.evaluating.push(name);
+        let evaluated = self.evaluate(constant.body);
+        self.evaluating.pop();
+        let (types, graph) = evaluated?;
+        // Operations on constants have already been folded by now.
+        let literal = match (&*types, graph.as_literal()) {
+            ([Type::I32], Some(&ssa::Op::I32(n))) => Instruction::PushI32(n),
+            ([Type::F32], Some(&ssa::Op::F32(n))) => Instruction::PushF32(n),
+            ([Type::Bool], Some(&ssa::Op::Bool(b))) => {
+                Instruction::PushBool(b)
+            }
+            _ => bail!(diagnostics::error(
+                format!("the value of constant `{name}` cannot be computed at compile time"),
+                vec![primary_label(constant.declaration_span, "")],
+            )
+            .note(format!(
+                "constants must be a single `i32`, `f32` or `bool` computed from literals and other constants, but this one has the types `{}`",
+                types.iter().format(" ")
+            ))),
+        };
+        let value = (literal, types[0].clone());
+        self.constants.insert(name, value.clone());
+        Ok(Some(value))
+    }
+
+    /// Finds the elements of a data table, which are computed like the
+    /// values of constants.
+    fn evaluate_data(
+        &mut self,
+        name: &str,
+        table: Global,
+    ) -> Result<DataTable> {
+        let (types, graph) = self.evaluate(table.body)?;
+        let literals = graph.as_literals().unwrap_or_default();
+        let elements = match types.first() {
+            Some(Type::I32) => literals
+                .iter()
+                .map(|op| match op {
+                    ssa::Op::I32(n) => Some(*n),
+                    _ => None,
+                })
+                .collect::<Option<_>>()
+                .map(DataTable::I32),
+            Some(Type::F32) => literals
+                .iter()
+                .map(|op| match op {
+                    ssa::Op::F32(n) => Some(*n),
+                    _ => None,
+                })
+                .collect::<Option<_>>()
+                .map(DataTable::F32),
+            Some(Type::Bool) => literals
+                .iter()
+                .map(|op| match op {
+                    ssa::Op::Bool(b) => Some(*b),
+                    _ => None,
+                })
+                .collect::<Option<_>>()
+                .map(DataTable::Bool),
+            _ => None,
+        };
+        elements
+            .filter(|elements| {
+                elements.len() == types.len()
+                    && i32::try_from(elements.len()).is_ok()
+            })
+            .ok_or_else(|| {
+                diagnostics::error(
+                    format!("the elements of data table `{name}` cannot be computed at compile time"),
+                    vec![primary_label(table.declaration_span, "")],
+                )
+                .note(format!(
+                    "data tables must be one or more `i32`s, `f32`s or `bool`s of the same type computed from literals and constants, but this one has the types `{}`",
+                    types.iter().format(" ")
+                ))
+                .into()
+            })
+    }
+
+    /// Checks a block on its own and lowers it to SSA, where operations on
+    /// literals are folded. Returns the types that it leaves on the stack
+    /// along with the graph.
+    fn evaluate(
+        &mut self,
+        body: Box<Block>,
+    ) -> Result<(Vec<Type>, ssa::Graph)> {
+        // Constants are checked on their own, and turning macros into
+        // functions would keep them from being evaluated.
+        let stack = std::mem::take(&mut self.stack);
+        let stashes = std::mem::take(&mut self.stashes);
+        let loops = std::mem::take(&mut self.loops);
+        let returns = self.returns.take();
+        let diverged = std::mem::take(&mut self.diverged);
+        let annotate = std::mem::take(&mut self.annotate);
+        let macro_uses = std::mem::take(&mut self.macro_uses);
+        let origins = std::mem::take(&mut self.origins);
+        let body = self.check_block(body);
+        let types = std::mem::replace(&mut self.stack, stack);
+        self.origins = origins;
+        self.stashes = stashes;
+        self.loops = loops;
+        self.returns = returns;
+        self.diverged = diverged;
+        self.annotate = annotate;
+        self.macro_uses = macro_uses;
+
+        let function_signatures = self
+            .function_signatures
+            .iter()
+            .map(|(&name, signature)| (name, signature.flatten()))
+            .collect();
+        let graph = ssa::Graph::from_block(
+            body?,
+            0,
+            &function_signatures,
+            &mut ssa::ValueGenerator::default(),
+        );
+        Ok((types, graph))
+    }
+
+    /// Checks a `let` block, burying the bound elements below the stack for
+    /// the duration of the block.
+    fn check_let(
+        &mut self,
+        names: &[Box<str>],
+        body: Box<Block>,
+        span: Span,
+    ) -> Result<Vec<(Instruction<Generics>, Generics)>> {
+        ensure!(
+            self.stack.len() >= names.len(),
+            diagnostics::error(
+                "type mismatch".to_owned(),
+                vec![primary_label(
+                    span,
+                    format!(
+                        "expected {} elements to bind but got `{}`",
+                        names.len(),
+                        self.stack.iter().format(" ")
+                    )
+                )],
+            )
+        );
+        let mut lowered = Vec::new();
+        for name in names.iter().rev() {
+            let typ = self.stack.pop().unwrap();
+            self.stashes.push((local_stash(name), Vec::new()));
+            let index = self.stashes.len() - 1;
+            let depth = self.stash_depth(index, typ.width(), span)?;
+            lowered.extend(
+                typ.flatten().into_iter().rev().map(|typ| {
+                    (Instruction::Bury(depth), Box::new([typ]) as _)
+                }),
+            );
+            self.stashes[index].1.push(typ);
+        }
+
+        lowered.extend(self.check_block(body)?.into_vec());
+
+        for _ in names {
+            let index = self.stashes.len() - 1;
+            if !self.diverged {
+                lowered.extend(self.drop_local(index, span)?);
+            }
+            self.stashes.pop();
+        }
+        Ok(lowered)
+    }
+
+    /// Pushes a copy of the element bound to a local.
+    fn check_local(
+        &mut self,
+        name: &str,
+        span: Span,
+    ) -> Result<Vec<(Instruction<Generics>, Generics)>> {
+        let index = self.stash_index(&local_stash(name));
+        let typ = self.stashes[index].1[0].clone();
+        let depth = self.stash_depth(index, typ.width(), span)?;
+        let values = typ.flatten();
+        self.stack.push(typ);
+        Ok(values
+            .into_iter()
+            .map(|typ| (Instruction::Pick(depth), Box::new([typ]) as _))
+            .collect())
+    }
+
+    /// Drops the element of a local that goes out of scope, leaving the
+    /// local empty.
+    fn drop_local(
+        &mut self,
+        index: usize,
+        span: Span,
+    ) -> Result<Vec<(Instruction<Generics>, Generics)>> {
+        let Some(typ) = self.stashes[index].1.last().cloned() else {
+            return Ok(Vec::new());
+        };
+        let depth = self.stash_depth(index, typ.width(), span)?;
+        self.stashes[index].1.clear();
+        let values = typ.flatten();
+        Ok(std::iter::repeat_n(
+            (Instruction::Roll(depth), Box::default()),
+            values.len(),
+        )
+        .chain(
+            values
+                .into_iter()
+                .rev()
+                .map(|typ| (Instruction::Drop, Box::new([typ]) as _)),
+        )
+        .collect())
+    }
+
+    fn stash_types(&self) -> Vec<Vec<Type>> {
+        self.stashes
+            .iter()
+            .map(|(_, types)| types.clone())
+            .collect()
+    }
+
+    fn restore_stashes(&mut self, stash_types: Vec<Vec<Type>>) {
+        for ((_, types), old_types) in
+            std::iter::zip(&mut self.stashes, stash_types)
+        {
+            *types = old_types;
+        }
+    }
+
+    /// Makes sure that the stashes have the `expected` types, where stashes
+    /// missing from `expected` must be empty.
+    fn ensure_stashes(
+        &self,
+        expected: &[Vec<Type>],
+        span: Span,
+        message: impl Fn(&str, String, String) -> String,
+    ) -> Result<()> {
+        let no_types = Vec::new();
+        for (i, (name, types)) in self.stashes.iter().enumerate() {
+            let expected = expected.get(i).unwrap_or(&no_types);
+            ensure!(
+                types == expected,
+                diagnostics::error(
+                    message(
+                        name,
+                        expected.iter().format(" ").to_string(),
+                        types.iter().format(" ").to_string(),
+                    ),
+                    vec![primary_label(span, "")],
+                ),
+            );
+        }
+        Ok(())
+    }
+
+    fn stash_index(&self, name: &str) -> usize {
+        self.stashes
+            .iter()
+            .rposition(|(stash_name, _)| **stash_name == *name)
+            .unwrap()
+    }
+
+    /// Finds the position of the top of a stash relative to the top of the
+    /// stack, with the stashes declared after it and the stack itself above.
+    /// An element made up of `width` values is moved one value at a time, so
+    /// the values after the first one are that much deeper.
+    fn stash_depth(
+        &self,
+        index: usize,
+        width: usize,
+        span: Span,
+    ) -> Result<u8> {
+        let depth = self.stack.iter().map(Type::width).sum::<usize>()
+            + self.stashes[index + 1..]
+                .iter()
+                .flat_map(|(_, types)| types)
+                .map(Type::width)
+                .sum::<usize>();
+        u8::try_from((depth + width).saturating_sub(1)).map_err(|_| {
+            let name = &self.stashes[index].0;
+            let thing = name.strip_prefix("let ").map_or_else(
+                || format!("stash `{name}`"),
+                |local| format!("local `{local}`"),
+            );
+            diagnostics::error(
+                format!("{thing} is buried too deep"),
+                vec![primary_label(span, "")],
+            )
+            .note("at most 255 values may be on top of a stash or local")
+            .into()
+        })
+    }
+
+    /// The signature written out in a quotation or quotation type.
+    fn quotation_signature(
+        &self,
+        quotation: &ir::Quotation,
+    ) -> Result<Arc<FunctionSignature>> {
+        Ok(Arc::new(FunctionSignature {
+            parameters: self
+                .check_types(&quotation.parameters, "quotation signature")?,
+            returns: self
+                .check_types(&quotation.returns, "quotation signature")?,
+            is_unsafe: false,
+        }))
+    }
+
+    /// Reads types written within a function, which can refer to its type
+    /// variables.
+    fn check_types(
+        &self,
+        instructions: &Block<Span>,
+        context: &str,
+    ) -> Result<Box<[Type]>> {
+        check_type_stack(instructions, context, &mut |name| {
+            Ok(name.strip_prefix('\'').map_or_else(
+                || self.types.get(name).cloned(),
+                |variable| self.type_variables.get(variable).cloned(),
+            ))
+        })
+    }
+
+    /// Finds the struct on top of the stack and the index of its field that
+    /// `.field` refers to, or `None` if no struct has such a field.
+    fn field_access(
+        &self,
+        name: &str,
+        span: Span,
+    ) -> Result<Option<(Arc<Struct>, usize)>> {
+        let has_field = |struct_: &Struct, field: &str| {
+            struct_.fields.iter().position(|(name, _)| **name == *field)
+        };
+        let Some(field) = name.strip_prefix('.') else {
+            return Ok(None);
+        };
+        if !self.types.values().any(|typ| {
+            matches!(typ, Type::Struct(struct_) if has_field(struct_, field).is_some())
+        }) {
+            return Ok(None);
+        }
+        if let Some(Type::Struct(struct_)) = self.stack.last() {
+            if let Some(index) = has_field(struct_, field) {
+                return Ok(Some((struct_.clone(), index)));
+            }
+        }
+        bail!(diagnostics::error(
+            "type mismatch".to_owned(),
+            vec![primary_label(
+                span,
+                format!(
+                    "expected a struct with a field named `{field}` but got `{}`",
+                    self.stack.iter().format(" ")
+                ),
+            )],
+        ))
+    }
+
+    /// Calling an `unsafe fn` or taking a pointer to one is only allowed in an
+    /// unsafe context, since the pointer could be called from anywhere.
+    fn check_unsafe_function_use(&self, name: &str, span: Span) -> Result<()> {
+        let (function, note) = name.strip_prefix('&').map_or(
+            (name, "calling it is unsafe"),
+            |function| {
+                (
+                    function,
+                    "the pointer can be called from safe code, so taking it is unsafe",
+                )
+            },
+        );
+        ensure!(
+            self.unsafe_layers != 0
+                || !self
+                    .function_signatures
+                    .get(function)
+                    .is_some_and(|signature| signature.is_unsafe),
+            diagnostics::error(
+                "unsafe instruction used in safe context".to_owned(),
+                vec![primary_label(span, "")]
+            )
+            .note(format!("`{function}` is an `unsafe fn`, so {note}"))
+        );
+        Ok(())
+    }
+
+    #[expect(
+        clippy::too_many_lines,
+        reason = "every instruction is checked in its own arm"
+    )]
     fn check_instruction(
         &mut self,
         (instruction, span): (Instruction, Span),
-    ) -> Result<(Instruction<Generics>, Generics)> {
+    ) -> Result<Vec<(Instruction<Generics>, Generics)>> {
         use Constraint::Any;
         use Generic as any;
         use Pattern::{Concrete as C, Generic as G, Ptr};
-        use Type::{Bool, F32, I32};
+        use Type::{Bool, File, F32, I32};
 
         ensure!(
             !(instruction.is_unsafe() && self.unsafe_layers == 0),
@@ -223,8 +2463,36 @@ impl<'src> Checker<'src> {
             )
         );
 
+        if let Instruction::Call(name) = &instruction {
+            self.check_unsafe_function_use(name, span)?;
+        }
+
+        if let Instruction::MacroExpansion { name, body } = instruction {
+            return self.check_macro_expansion(&name, body);
+        }
+        if let Instruction::Let { names, body } = instruction {
+            return self.check_let(&names, body, span);
+        }
+        if let Instruction::Local(name) = instruction {
+            return self.check_local(&name, span);
+        }
+        if let Instruction::Call(name) = &instruction {
+            if let Some(signature) = self
+                .function_signatures
+                .get(&**name)
+                .filter(|signature| signature.is_generic())
+            {
+                let signature = signature.clone();
+                return self.check_generic_call(name, &signature, span);
+            }
+        }
+
+        let generics;
         let parameters;
         let returns;
+        let mut quotation_type = None;
+        // Instructions that structs are turned into.
+        let mut lowered_call = None;
         let (g, i, o): (&[_], &[Pattern], &[Pattern]) = match &instruction {
             Instruction::Call(name) => {
                 ensure!(
@@ -235,18 +2503,156 @@ impl<'src> Checker<'src> {
                     ).note("`main` implicitly returns the program exit code, making its signature not match up with what the source code indicates")
                 );
 
-                let signature =
-                    self.function_signatures.get(&**name).ok_or_else(|| {
+                if let Some(signature) = self.function_signatures.get(&**name) {
+                    if let Some(&abi) = self.extern_functions.get(&**name) {
+                        ensure!(
+                            self.unsafe_layers != 0,
+                            diagnostics::error(
+                                "unsafe instruction used in safe context"
+                                    .to_owned(),
+                                vec![primary_label(span, "")]
+                            )
+                            .note("extern functions can do anything, so calling them is unsafe")
+                        );
+                        lowered_call = Some(vec![(
+                            Instruction::CallExtern(name.clone(), abi),
+                            Box::default(),
+                        )]);
+                    }
+                    parameters = signature
+                        .parameters
+                        .iter()
+                        .cloned()
+                        .map(C)
+                        .collect::<Box<_>>();
+                    returns = signature
+                        .returns
+                        .iter()
+                        .cloned()
+                        .map(C)
+                        .collect::<Box<_>>();
+                } else if let Some(Type::Struct(struct_)) =
+                    self.types.get(&**name)
+                {
+                    // The fields are already lying on the stack in order.
+                    lowered_call = Some(Vec::new());
+                    parameters = struct_
+                        .fields
+                        .iter()
+                        .map(|(_, typ)| C(typ.clone()))
+                        .collect::<Box<_>>();
+                    returns = Box::new([C(Type::Struct(struct_.clone()))]);
+                } else if let Some((enum_, index)) = self.variants.get(&**name)
+                {
+                    lowered_call =
+                        Some(construct_variant(enum_, *index, span)?);
+                    parameters = enum_.variants[*index]
+                        .1
+                        .iter()
+                        .cloned()
+                        .map(C)
+                        .collect::<Box<_>>();
+                    returns = Box::new([C(Type::Enum(enum_.clone()))]);
+                } else if let Some((literal, typ)) =
+                    self.constant(name, span)?
+                {
+                    lowered_call = Some(vec![(literal, Box::default())]);
+                    parameters = Box::new([]);
+                    returns = Box::new([C(typ)]);
+                } else if let Some(table) = self.data.get(&**name) {
+                    let len = i32::try_from(table.len()).unwrap();
+                    lowered_call = Some(vec![
+                        (Instruction::Data(name.clone()), Box::default()),
+                        (Instruction::PushI32(len), Box::default()),
+                    ]);
+                    parameters = Box::new([]);
+                    returns = Box::new([
+                        C(Type::Ptr(Box::new(table.element_type()))),
+                        C(I32),
+                    ]);
+                } else if let Some((function, signature)) =
+                    name.strip_prefix('&').and_then(|function| {
+                        Some((
+                            function,
+                            self.function_signatures.get(function)?,
+                        ))
+                    })
+                {
+                    ensure!(
+                        function != "main",
                         diagnostics::error(
-                            format!("unknown instruction: `{name}`"),
-                            vec![primary_label(span, "")],
-                        )
-                    })?;
+                            "`main` cannot be pointed to".to_owned(),
+                            vec![primary_label(span, "")]
+                        ).note("`main` implicitly returns the program exit code, making its signature not match up with what the source code indicates")
+                    );
+                    ensure!(
+                        !self.extern_functions.contains_key(function),
+                        diagnostics::error(
+                            "extern functions cannot be pointed to".to_owned(),
+                            vec![primary_label(span, "")]
+                        ).note("quotations are called differently from C functions")
+                    );
+                    ensure!(
+                        !signature.is_generic(),
+                        diagnostics::error(
+                            "generic functions cannot be pointed to".to_owned(),
+                            vec![primary_label(span, "")]
+                        ).note("only instances of generic functions exist at runtime, and a pointer doesn't say which one it's for")
+                    );
+                    let typ = Type::Quotation(Arc::new(FunctionSignature {
+                        is_unsafe: false,
+                        ..signature.clone()
+                    }));
+                    lowered_call = Some(vec![(
+                        Instruction::FunctionPointer(function.into()),
+                        Box::new([typ.clone()]) as Generics,
+                    )]);
+                    parameters = Box::new([]);
+                    returns = Box::new([C(typ)]);
+                } else if let Some((struct_, index)) =
+                    self.field_access(name, span)?
+                {
+                    let start = struct_.fields[..index]
+                        .iter()
+                        .map(|(_, typ)| typ.width())
+                        .sum::<usize>();
+                    let field_type = &struct_.fields[index].1;
+                    let typ = Type::Struct(struct_.clone());
+                    lowered_call = Some(shuffle(
+                        typ.flatten(),
+                        &(start..start + field_type.width())
+                            .collect::<Vec<_>>(),
+                        span,
+                    )?);
+                    parameters = Box::new([C(typ)]);
+                    returns = Box::new([C(field_type.clone())]);
+                } else {
+                    bail!(diagnostics::error(
+                        format!("unknown instruction: `{name}`"),
+                        vec![primary_label(span, "")],
+                    ));
+                }
+                (&[], &*parameters, &*returns)
+            }
+            Instruction::Quotation(quotation) => {
+                let typ = Type::Quotation(self.quotation_signature(quotation)?);
+                // Without a body, it's just the type.
+                returns = Box::new([C(if quotation.body.is_some() {
+                    typ.clone()
+                } else {
+                    Type::Type
+                })]);
+                quotation_type = Some(typ);
+                (&[], &[], &*returns)
+            }
+            Instruction::Invoke(quotation) => {
+                let signature = self.quotation_signature(quotation)?;
                 parameters = signature
                     .parameters
                     .iter()
                     .cloned()
                     .map(C)
+                    .chain([C(Type::Quotation(signature.clone()))])
                     .collect::<Box<_>>();
                 returns = signature
                     .returns
@@ -254,50 +2660,210 @@ impl<'src> Checker<'src> {
                     .cloned()
                     .map(C)
                     .collect::<Box<_>>();
+                quotation_type = Some(Type::Quotation(signature));
                 (&[], &*parameters, &*returns)
             }
-            Instruction::Then(_) | Instruction::ThenElse(..) => {
-                (&[], &[C(Bool)], &[])
+            Instruction::FunctionPointer(_)
+            | Instruction::Data(_)
+            | Instruction::CallExtern(..)
+            | Instruction::Location(_)
+            | Instruction::MacroExpansion { .. } => unreachable!(),
+            Instruction::CallQuotation => {
+                let signature = self.quotation_at(0, span)?;
+                parameters = signature
+                    .parameters
+                    .iter()
+                    .cloned()
+                    .map(C)
+                    .chain([G(0)])
+                    .collect::<Box<_>>();
+                returns = signature
+                    .returns
+                    .iter()
+                    .cloned()
+                    .map(C)
+                    .collect::<Box<_>>();
+                (&[any('Q', Any)], &*parameters, &*returns)
+            }
+            Instruction::Combinator(combinator) => {
+                return self.check_combinator(*combinator, span);
+            }
+            Instruction::Then(_)
+            | Instruction::ThenElse(..)
+            | Instruction::Assert(_) => (&[], &[C(Bool)], &[]),
+            Instruction::Match(_) => {
+                ensure!(
+                    matches!(self.stack.last(), Some(Type::Enum(_))),
+                    diagnostics::error(
+                        "type mismatch".to_owned(),
+                        vec![primary_label(
+                            span,
+                            format!(
+                                "expected an enum but got `{}`",
+                                self.stack.iter().format(" ")
+                            ),
+                        )],
+                    )
+                );
+                (&[any('E', Any)], &[G(0)], &[])
+            }
+            Instruction::Repeat { .. }
+            | Instruction::While { .. }
+            | Instruction::Unsafe(_)
+            | Instruction::Stash { .. }
+            | Instruction::FromStash(_)
+            | Instruction::Break { .. }
+            | Instruction::Continue { .. }
+            | Instruction::Return => (&[], &[], &[]),
+            Instruction::Bury(_) => {
+                unreachable!("only created by the type checker")
+            }
+            Instruction::Let { .. } | Instruction::Local(_) => {
+                unreachable!("handled before")
+            }
+            Instruction::Load(name) | Instruction::Store(name) => {
+                let typ = self.variables.get(&**name).ok_or_else(|| {
+                    diagnostics::error(
+                        format!("unknown variable: `{name}`"),
+                        vec![primary_label(span, "")],
+                    )
+                })?;
+                let typ = Box::new([C(typ.clone())]) as Box<[_]>;
+                if matches!(instruction, Instruction::Load(_)) {
+                    returns = typ;
+                    (&[], &[], &*returns)
+                } else {
+                    parameters = typ;
+                    (&[], &*parameters, &[])
+                }
             }
-            Instruction::Repeat { .. } | Instruction::Unsafe(_) => {
-                (&[], &[], &[])
+            Instruction::PushI32(_) | Instruction::ArgCount => {
+                (&[], &[], &[C(I32)])
             }
-            Instruction::PushI32(_) => (&[], &[], &[C(I32)]),
             Instruction::PushF32(_) => (&[], &[], &[C(F32)]),
-            Instruction::PushBool(_) => (&[], &[], &[C(Bool)]),
+            Instruction::PushBool(_) | Instruction::ReadLine => {
+                (&[], &[], &[C(Bool)])
+            }
             Instruction::PushType(_) => (&[], &[], &[C(Type::Type)]),
             Instruction::Ptr => (&[], &[C(Type::Type)], &[C(Type::Type)]),
             Instruction::TypeOf => {
                 (&[any('T', Any)], &[G(0)], &[C(Type::Type)])
             }
+            Instruction::TypeEq => {
+                (&[], &[C(Type::Type), C(Type::Type)], &[C(Type::Bool)])
+            }
+            Instruction::PrintType => (&[], &[C(Type::Type)], &[]),
             Instruction::BinMathOp(
                 BinMathOp::Add
                 | BinMathOp::Sub
                 | BinMathOp::Mul
-                | BinMathOp::Div,
+                | BinMathOp::Div
+                | BinMathOp::SillyAdd,
+                _,
             ) => (
                 &[Generic('N', Constraint::OneOf(&[I32, F32]))],
                 &[G(0), G(0)],
                 &[G(0)],
             ),
-            Instruction::BinMathOp(_) => (&[], &[C(I32), C(I32)], &[C(I32)]),
-            Instruction::Sqrt => (&[], &[C(F32)], &[C(F32)]),
-            Instruction::Comparison(_) => (&[], &[C(I32), C(I32)], &[C(Bool)]),
+            Instruction::BinMathOp(..) | Instruction::BitwiseOp(_) => {
+                (&[], &[C(I32), C(I32)], &[C(I32)])
+            }
+            Instruction::Sqrt | Instruction::Round(_) => {
+                (&[], &[C(F32)], &[C(F32)])
+            }
+            Instruction::I32ToF32 => (&[], &[C(I32)], &[C(F32)]),
+            Instruction::Math(function) => {
+                match (function.is_generic(), function.arity()) {
+                    (true, 1) => (
+                        &[Generic('N', Constraint::OneOf(&[I32, F32]))],
+                        &[G(0)],
+                        &[G(0)],
+                    ),
+                    (true, _) => (
+                        &[Generic('N', Constraint::OneOf(&[I32, F32]))],
+                        &[G(0), G(0)],
+                        &[G(0)],
+                    ),
+                    (false, 1) => (&[], &[C(F32)], &[C(F32)]),
+                    (false, _) => (&[], &[C(F32), C(F32)], &[C(F32)]),
+                }
+            }
+            Instruction::F32ToI32(_) => (&[], &[C(F32)], &[C(I32)]),
+            Instruction::Comparison(_) => (
+                &[Generic('N', Constraint::OneOf(&[I32, F32]))],
+                &[G(0), G(0)],
+                &[C(Bool)],
+            ),
             Instruction::Print | Instruction::Println => (
-                &[Generic('T', Constraint::OneOf(&[I32, F32]))],
+                &[Generic('T', Constraint::OneOf(&[I32, F32, Bool]))],
                 &[G(0)],
                 &[],
             ),
-            Instruction::PrintChar => (&[], &[C(I32)], &[]),
+            Instruction::Times { .. }
+            | Instruction::Switch { .. }
+            | Instruction::PrintChar
+            | Instruction::Exit => (&[], &[C(I32)], &[]),
+            Instruction::ReadChar | Instruction::ReadI32 => {
+                (&[], &[], &[C(I32), C(Bool)])
+            }
             Instruction::Not => (&[], &[C(Bool)], &[C(Bool)]),
             Instruction::BinLogicOp(_) => {
                 (&[], &[C(Bool), C(Bool)], &[C(Bool)])
             }
+            Instruction::BitNot => (&[], &[C(I32)], &[C(I32)]),
             Instruction::AddrOf => {
                 (&[any('T', Any)], &[G(0)], &[G(0), Ptr(&G(0))])
             }
             Instruction::ReadPtr => (&[any('T', Any)], &[Ptr(&G(0))], &[G(0)]),
-            Instruction::Drop => (&[any('T', Any)], &[G(0)], &[]),
+            Instruction::ReadSmall(_) => {
+                (&[any('T', Any)], &[Ptr(&G(0))], &[C(I32)])
+            }
+            Instruction::WritePtr => {
+                (&[any('T', Any)], &[G(0), Ptr(&G(0))], &[])
+            }
+            Instruction::WriteSmall(_) => {
+                (&[any('T', Any)], &[C(I32), Ptr(&G(0))], &[])
+            }
+            Instruction::MemCopy => (
+                &[any('T', Any), any('U', Any)],
+                &[Ptr(&G(0)), Ptr(&G(1)), C(I32)],
+                &[],
+            ),
+            Instruction::MemFill => {
+                (&[any('T', Any)], &[Ptr(&G(0)), C(I32), C(I32)], &[])
+            }
+            Instruction::MemCompare => (
+                &[any('T', Any), any('U', Any)],
+                &[Ptr(&G(0)), Ptr(&G(1)), C(I32)],
+                &[C(I32)],
+            ),
+            Instruction::Null(typ) => {
+                // The parser only allows a single type.
+                let pointee = self
+                    .check_types(typ, "type of `null`")?
+                    .into_vec()
+                    .pop()
+                    .unwrap();
+                returns = Box::new([C(Type::Ptr(Box::new(pointee)))]);
+                (&[], &[], &*returns)
+            }
+            Instruction::PtrAdd | Instruction::PtrSub => {
+                (&[any('T', Any)], &[Ptr(&G(0)), C(I32)], &[Ptr(&G(0))])
+            }
+            Instruction::PtrDiff => {
+                (&[any('T', Any)], &[Ptr(&G(0)), Ptr(&G(0))], &[C(I32)])
+            }
+            // Spackel doesn't have a byte type for the characters.
+            Instruction::Arg => (&[], &[C(I32)], &[Ptr(&C(I32))]),
+            Instruction::Open => {
+                (&[], &[Ptr(&C(I32)), C(Bool)], &[C(File), C(Bool)])
+            }
+            Instruction::Read => (&[], &[C(File)], &[C(File), C(I32), C(Bool)]),
+            Instruction::Write => (&[], &[C(File), C(I32)], &[C(File)]),
+            Instruction::Close => (&[], &[C(File)], &[]),
+            Instruction::Drop | Instruction::ToStash(_) => {
+                (&[any('T', Any)], &[G(0)], &[])
+            }
             Instruction::Dup => (&[any('T', Any)], &[G(0)], &[G(0), G(0)]),
             Instruction::Swap => (
                 &[any('A', Any), any('B', Any)],
@@ -317,15 +2883,46 @@ impl<'src> Checker<'src> {
                 &[G(0), G(1)],
                 &[G(1), G(0), G(1)],
             ),
+            Instruction::Rot => (
+                &[any('A', Any), any('B', Any), any('C', Any)],
+                &[G(0), G(1), G(2)],
+                &[G(1), G(2), G(0)],
+            ),
+            Instruction::RotBack => (
+                &[any('A', Any), any('B', Any), any('C', Any)],
+                &[G(0), G(1), G(2)],
+                &[G(2), G(0), G(1)],
+            ),
+            Instruction::Pick(depth) | Instruction::Roll(depth) => {
+                generics = (0..=*depth)
+                    .map(|i| {
+                        let name = u32::from('A') + u32::from(i);
+                        any(char::from_u32(name).unwrap(), Any)
+                    })
+                    .collect::<Box<_>>();
+                parameters = (0..=*depth).map(G).collect::<Box<_>>();
+                returns = if matches!(instruction, Instruction::Pick(_)) {
+                    (0..=*depth).chain([0]).map(G).collect::<Box<_>>()
+                } else {
+                    (1..=*depth).chain([0]).map(G).collect::<Box<_>>()
+                };
+                (&*generics, &*parameters, &*returns)
+            }
         };
         let generics = self.transform(g, i, o, span)?;
 
         let instruction = match instruction {
             Instruction::Then(body) => {
                 let before = self.stack.clone();
-                let body = Box::into_iter(body)
-                    .map(|instruction| self.check_instruction(instruction))
-                    .collect::<Result<_>>()?;
+                let stashes_before = self.stash_types();
+                let body = self.check_block(body)?;
+                if std::mem::take(&mut self.diverged) {
+                    self.stack.clone_from(&before);
+                    self.restore_stashes(stashes_before.clone());
+                }
+                self.ensure_stashes(&stashes_before, span, |name, a, b| {
+                    format!("`then` statement changes stash `{name}` from `{a}` to `{b}`")
+                })?;
                 ensure!(
                     before == self.stack,
                     diagnostics::error(
@@ -340,82 +2937,1238 @@ impl<'src> Checker<'src> {
                 Instruction::Then(body)
             }
             Instruction::ThenElse(then, else_) => {
+                let then_span = block_span(&then);
+                let else_span = block_span(&else_);
                 let before = self.stack.clone();
-                let then = Box::into_iter(then)
-                    .map(|instruction| self.check_instruction(instruction))
-                    .collect::<Result<_>>()?;
+                let origins_before = self.origins.clone();
+                let stashes_before = self.stash_types();
+                let then = self.check_block(then)?;
+                let then_diverged = std::mem::take(&mut self.diverged);
                 let then_types = std::mem::replace(&mut self.stack, before);
-                let else_ = Box::into_iter(else_)
-                    .map(|instruction| self.check_instruction(instruction))
-                    .collect::<Result<_>>()?;
+                self.origins.resize(then_types.len(), Some(span));
+                let then_origins =
+                    std::mem::replace(&mut self.origins, origins_before);
+                let then_stashes = self.stash_types();
+                self.restore_stashes(stashes_before);
+                let else_ = self.check_block(else_)?;
+                let else_diverged = std::mem::take(&mut self.diverged);
+                // A branch that diverges doesn't need to agree with the other
+                // one since it never reaches the end of the statement.
+                self.origins.resize(self.stack.len(), Some(span));
+                match (then_diverged, else_diverged) {
+                    (false, true) => {
+                        self.stack.clone_from(&then_types);
+                        self.origins = then_origins;
+                        self.restore_stashes(then_stashes.clone());
+                    }
+                    (true, true) => self.diverged = true,
+                    (true, false) => {}
+                    // Values that differ between the branches come from the
+                    // whole statement.
+                    (false, false) => {
+                        for (origin, then_origin) in
+                            std::iter::zip(&mut self.origins, then_origins)
+                        {
+                            if *origin != then_origin {
+                                *origin = Some(span);
+                            }
+                        }
+                    }
+                }
+                if !(then_diverged || else_diverged) {
+                    self.ensure_stashes(&then_stashes, span, |name, a, b| {
+                        format!("`then else` statement diverges between types `{a}` and `{b}` on stash `{name}`")
+                    })?;
+                }
+                ensure!(
+                    then_diverged || else_diverged || then_types == self.stack,
+                    branch_mismatch(
+                        (&then_types, then_span.unwrap_or(span)),
+                        (&self.stack, else_span.unwrap_or(span)),
+                    ),
+                );
+                Instruction::ThenElse(then, else_)
+            }
+            Instruction::Match(cases) => {
+                let Type::Enum(enum_) = &generics[0] else {
+                    unreachable!()
+                };
+                return self.check_match(enum_, cases, span);
+            }
+            Instruction::Switch { arms, else_ } => {
+                return self.check_switch(arms, else_, span);
+            }
+            Instruction::Repeat {
+                body,
+                end_span,
+                label,
+            } => {
+                let before = self.stack.clone();
+                let stashes_before = self.stash_types();
+                self.loops.push(Loop {
+                    label: label.clone(),
+                    types: before.clone(),
+                    stashes: stashes_before.clone(),
+                });
+                let body = self.check_block(body)?;
+                self.loops.pop();
+                if std::mem::take(&mut self.diverged) {
+                    self.stack.clone_from(&before);
+                    self.restore_stashes(stashes_before.clone());
+                } else {
+                    self.transform(&[], &[C(Bool)], &[], end_span)?;
+                }
+                self.ensure_stashes(&stashes_before, span, |name, a, b| {
+                    format!("`repeat` loop changes stash `{name}` from `{a}` to `{b}`")
+                })?;
                 ensure!(
-                    then_types == self.stack,
+                    before == self.stack,
                     diagnostics::error(
                         format!(
-                            "`then else` statement diverges between types `{}` and `{}`",
-                            then_types.iter().format(" "),
+                            "`repeat` loop changes types from `{}` to `{}`",
+                            before.iter().format(" "),
                             self.stack.iter().format(" "),
                         ),
                         vec![primary_label(span, "")],
                     ),
                 );
-                Instruction::ThenElse(then, else_)
+                Instruction::Repeat {
+                    body,
+                    end_span,
+                    label,
+                }
             }
-            Instruction::Repeat { body, end_span } => {
+            Instruction::While {
+                condition,
+                body,
+                do_span,
+                label,
+            } => {
                 let before = self.stack.clone();
-                let body = Box::into_iter(body)
-                    .map(|instruction| self.check_instruction(instruction))
-                    .collect::<Result<_>>()?;
-                self.transform(&[], &[C(Bool)], &[], end_span)?;
+                let stashes_before = self.stash_types();
+                // `break` and `continue` in the condition would refer to an
+                // outer loop, which is more confusing than useful.
+                let outer_loops = std::mem::take(&mut self.loops);
+                let condition = self.check_block(condition);
+                self.loops = outer_loops;
+                let condition = condition?;
+                self.transform(&[], &[C(Bool)], &[], do_span)?;
+                self.ensure_stashes(&stashes_before, span, |name, a, b| {
+                    format!("`while` loop condition changes stash `{name}` from `{a}` to `{b}`")
+                })?;
                 ensure!(
                     before == self.stack,
                     diagnostics::error(
                         format!(
-                            "`repeat` loop changes types from `{}` to `{}`",
+                            "`while` loop condition changes types from `{}` to `{}`",
+                            before.iter().format(" "),
+                            self.stack.iter().format(" "),
+                        ),
+                        vec![primary_label(span, "")],
+                    ),
+                );
+                self.loops.push(Loop {
+                    label: label.clone(),
+                    types: before.clone(),
+                    stashes: stashes_before.clone(),
+                });
+                let body = self.check_block(body)?;
+                self.loops.pop();
+                if std::mem::take(&mut self.diverged) {
+                    self.stack.clone_from(&before);
+                    self.restore_stashes(stashes_before.clone());
+                }
+                self.ensure_stashes(&stashes_before, span, |name, a, b| {
+                    format!("`while` loop changes stash `{name}` from `{a}` to `{b}`")
+                })?;
+                ensure!(
+                    before == self.stack,
+                    diagnostics::error(
+                        format!(
+                            "`while` loop changes types from `{}` to `{}`",
+                            before.iter().format(" "),
+                            self.stack.iter().format(" "),
+                        ),
+                        vec![primary_label(span, "")],
+                    ),
+                );
+                Instruction::While {
+                    condition,
+                    body,
+                    do_span,
+                    label,
+                }
+            }
+            Instruction::Times {
+                body,
+                pushes_index,
+                span: keyword_span,
+                label,
+            } => {
+                let before = self.stack.clone();
+                let stashes_before = self.stash_types();
+                if pushes_index {
+                    self.stack.push(I32);
+                }
+                self.loops.push(Loop {
+                    label: label.clone(),
+                    types: before.clone(),
+                    stashes: stashes_before.clone(),
+                });
+                let body = self.check_block(body)?;
+                self.loops.pop();
+                if std::mem::take(&mut self.diverged) {
+                    self.stack.clone_from(&before);
+                    self.restore_stashes(stashes_before.clone());
+                }
+                let keyword = if pushes_index { "for" } else { "times" };
+                self.ensure_stashes(&stashes_before, span, |name, a, b| {
+                    format!("`{keyword}` loop changes stash `{name}` from `{a}` to `{b}`")
+                })?;
+                ensure!(
+                    before == self.stack,
+                    diagnostics::error(
+                        format!(
+                            "`{keyword}` loop changes types from `{}` to `{}`",
                             before.iter().format(" "),
                             self.stack.iter().format(" "),
                         ),
                         vec![primary_label(span, "")],
                     ),
                 );
-                Instruction::Repeat { body, end_span }
+                Instruction::Times {
+                    body,
+                    pushes_index,
+                    span: keyword_span,
+                    label,
+                }
             }
             Instruction::Unsafe(body) => {
                 self.unsafe_layers += 1;
-                let body = Box::into_iter(body)
-                    .map(|instruction| self.check_instruction(instruction))
-                    .collect::<Result<_>>()?;
+                let body = self.check_block(body)?;
                 self.unsafe_layers -= 1;
                 Instruction::Unsafe(body)
             }
-            Instruction::Call(name) => Instruction::Call(name),
+            Instruction::Quotation(quotation) => {
+                let quotation_type = quotation_type.unwrap();
+                let ir::Quotation {
+                    parameters: quotation_parameters,
+                    returns: quotation_returns,
+                    body,
+                    end_span,
+                } = *quotation;
+                let Some(body) = body else {
+                    return Ok(vec![(
+                        Instruction::PushType(quotation_type),
+                        generics,
+                    )]);
+                };
+                let Type::Quotation(signature) = &quotation_type else {
+                    unreachable!()
+                };
+                let body = self.check_quotation(signature, body, end_span)?;
+                return Ok(vec![(
+                    Instruction::Quotation(Box::new(ir::Quotation {
+                        parameters: quotation_parameters,
+                        returns: quotation_returns,
+                        body: Some(body),
+                        end_span,
+                    })),
+                    Box::new([quotation_type]),
+                )]);
+            }
+            Instruction::CallQuotation => Instruction::CallQuotation,
+            Instruction::Invoke(_) => {
+                return Ok(vec![(
+                    Instruction::CallQuotation,
+                    Box::new([quotation_type.unwrap()]),
+                )]);
+            }
+            Instruction::Combinator(_)
+            | Instruction::FunctionPointer(_)
+            | Instruction::Data(_)
+            | Instruction::CallExtern(..)
+            | Instruction::Location(_)
+            | Instruction::MacroExpansion { .. }
+            | Instruction::Let { .. }
+            | Instruction::Local(_) => {
+                unreachable!()
+            }
+            Instruction::Stash { name, body } => {
+                self.stashes.push((name, Vec::new()));
+                let body = self.check_block(body)?;
+                let (name, types) = self.stashes.pop().unwrap();
+                ensure!(
+                    self.diverged || types.is_empty(),
+                    diagnostics::error(
+                        format!(
+                            "there are values left on stash `{name}` with the following types: `{}`",
+                            types.iter().format(" ")
+                        ),
+                        vec![primary_label(span, "")],
+                    )
+                );
+                Instruction::Stash { name, body }
+            }
+            Instruction::ToStash(name) => {
+                // The type has already been popped off the stack.
+                let typ = generics[0].clone();
+                let index = self.stash_index(&name);
+                let depth = self.stash_depth(index, typ.width(), span)?;
+                let values = typ.flatten();
+                self.stashes[index].1.push(typ);
+                return Ok(values
+                    .into_iter()
+                    .rev()
+                    .map(|typ| (Instruction::Bury(depth), Box::new([typ]) as _))
+                    .collect());
+            }
+            Instruction::FromStash(name) => {
+                let index = self.stash_index(&name);
+                let typ = self.stashes[index].1.pop().ok_or_else(|| {
+                    diagnostics::error(
+                        format!("stash `{name}` is empty"),
+                        vec![primary_label(span, "")],
+                    )
+                })?;
+                let depth = self.stash_depth(index, typ.width(), span)?;
+                let width = typ.width();
+                self.stack.push(typ);
+                return Ok(std::iter::repeat_n(
+                    (Instruction::Roll(depth), generics),
+                    width,
+                )
+                .collect());
+            }
+            Instruction::Break { label, .. } => {
+                return self.check_jump("break", label, generics, span);
+            }
+            Instruction::Continue { label, .. } => {
+                return self.check_jump("continue", label, generics, span);
+            }
+            Instruction::Return => {
+                let returns = self.returns.clone().ok_or_else(|| {
+                    diagnostics::error(
+                        "`return` outside of a function".to_owned(),
+                        vec![primary_label(span, "")],
+                    )
+                    .note("quotations and constants can't `return`")
+                })?;
+                // Every local goes out of scope.
+                let mut lowered = Vec::new();
+                for index in (0..self.stashes.len()).rev() {
+                    if self.stashes[index].0.starts_with("let ") {
+                        lowered.extend(self.drop_local(index, span)?);
+                    }
+                }
+                self.ensure_stashes(&[], span, |name, _, b| {
+                    format!("`return` with types `{b}` on stash `{name}`")
+                })?;
+                ensure!(
+                    *self.stack == *returns,
+                    diagnostics::error(
+                        format!(
+                            "`return` with types `{}` in a function that returns {}",
+                            self.stack.iter().format(" "),
+                            if returns.is_empty() {
+                                "nothing".to_owned()
+                            } else {
+                                format!("`{}`", returns.iter().format(" "))
+                            },
+                        ),
+                        vec![primary_label(span, "")],
+                    ),
+                );
+                self.diverged = true;
+                // The values of the outputs, since loops can have values of
+                // their own below them.
+                lowered.push((
+                    Instruction::Return,
+                    returns.iter().flat_map(Type::flatten).collect(),
+                ));
+                return Ok(lowered);
+            }
+            Instruction::Call(name) => {
+                if let Some(lowered) = lowered_call {
+                    return Ok(lowered);
+                }
+                Instruction::Call(name)
+            }
+            Instruction::Load(name) => {
+                // The type of a variable isn't otherwise known after type
+                // checking.
+                let typ = self.variables[&*name].clone();
+                return Ok(vec![(Instruction::Load(name), Box::new([typ]))]);
+            }
+            Instruction::Store(name) => {
+                let typ = self.variables[&*name].clone();
+                return Ok(vec![(Instruction::Store(name), Box::new([typ]))]);
+            }
             Instruction::PushI32(n) => Instruction::PushI32(n),
             Instruction::PushF32(n) => Instruction::PushF32(n),
             Instruction::PushBool(b) => Instruction::PushBool(b),
             Instruction::PushType(typ) => Instruction::PushType(typ),
             Instruction::Ptr => Instruction::Ptr,
             Instruction::TypeOf => Instruction::TypeOf,
+            Instruction::TypeEq => Instruction::TypeEq,
+            Instruction::PrintType => Instruction::PrintType,
             Instruction::Print => Instruction::Print,
             Instruction::Println => Instruction::Println,
             Instruction::PrintChar => Instruction::PrintChar,
-            Instruction::BinMathOp(op) => Instruction::BinMathOp(op),
+            Instruction::ReadChar => Instruction::ReadChar,
+            Instruction::ReadI32 => Instruction::ReadI32,
+            Instruction::ReadLine => Instruction::ReadLine,
+            Instruction::ArgCount => Instruction::ArgCount,
+            Instruction::Arg => Instruction::Arg,
+            Instruction::Open => Instruction::Open,
+            Instruction::Read => Instruction::Read,
+            Instruction::Write => Instruction::Write,
+            Instruction::Close => Instruction::Close,
+            Instruction::Assert(span) => Instruction::Assert(span),
+            Instruction::Exit => {
+                self.diverged = true;
+                Instruction::Exit
+            }
+            Instruction::BinMathOp(op, span) => {
+                Instruction::BinMathOp(op, span)
+            }
             Instruction::Sqrt => Instruction::Sqrt,
+            Instruction::Round(rounding) => Instruction::Round(rounding),
+            Instruction::I32ToF32 => Instruction::I32ToF32,
+            Instruction::F32ToI32(rounding) => Instruction::F32ToI32(rounding),
+            Instruction::Math(function) => Instruction::Math(function),
             Instruction::Comparison(comparison) => {
                 Instruction::Comparison(comparison)
             }
             Instruction::Not => Instruction::Not,
             Instruction::BinLogicOp(op) => Instruction::BinLogicOp(op),
+            Instruction::BitNot => Instruction::BitNot,
+            Instruction::BitwiseOp(op) => Instruction::BitwiseOp(op),
             Instruction::AddrOf => Instruction::AddrOf,
             Instruction::ReadPtr => Instruction::ReadPtr,
+            Instruction::ReadSmall(int) => Instruction::ReadSmall(int),
+            Instruction::WritePtr => Instruction::WritePtr,
+            Instruction::WriteSmall(int) => Instruction::WriteSmall(int),
+            Instruction::MemCopy => Instruction::MemCopy,
+            Instruction::MemFill => Instruction::MemFill,
+            Instruction::MemCompare => Instruction::MemCompare,
+            Instruction::Null(_) => Instruction::Null(Box::default()),
+            Instruction::PtrAdd => Instruction::PtrAdd,
+            Instruction::PtrSub => Instruction::PtrSub,
+            Instruction::PtrDiff => Instruction::PtrDiff,
             Instruction::Drop => Instruction::Drop,
             Instruction::Dup => Instruction::Dup,
             Instruction::Swap => Instruction::Swap,
             Instruction::Over => Instruction::Over,
             Instruction::Nip => Instruction::Nip,
             Instruction::Tuck => Instruction::Tuck,
+            Instruction::Rot => Instruction::Rot,
+            Instruction::RotBack => Instruction::RotBack,
+            Instruction::Pick(depth) => Instruction::Pick(depth),
+            Instruction::Roll(depth) => Instruction::Roll(depth),
+            Instruction::Bury(depth) => Instruction::Bury(depth),
+        };
+
+        if !generics.iter().any(Type::is_composite) {
+            return Ok(vec![(instruction, generics)]);
+        }
+        match instruction {
+            Instruction::Drop
+            | Instruction::Dup
+            | Instruction::Swap
+            | Instruction::Over
+            | Instruction::Nip
+            | Instruction::Tuck
+            | Instruction::Rot
+            | Instruction::RotBack
+            | Instruction::Pick(_)
+            | Instruction::Roll(_) => {
+                let outputs = o
+                    .iter()
+                    .map(|pattern| match pattern {
+                        Pattern::Generic(i) => usize::from(*i),
+                        _ => unreachable!(),
+                    })
+                    .collect::<Vec<_>>();
+                rearrange(&generics, &outputs, span)
+            }
+            Instruction::TypeOf => {
+                let mut lowered = rearrange(&generics, &[], span)?;
+                lowered.push((
+                    Instruction::PushType(generics[0].clone()),
+                    Box::new([]),
+                ));
+                Ok(lowered)
+            }
+            Instruction::AddrOf
+            | Instruction::ReadPtr
+            | Instruction::WritePtr => {
+                bail!(diagnostics::error(
+                    "structs and enums cannot be accessed through pointers"
+                        .to_owned(),
+                    vec![primary_label(span, "")],
+                ))
+            }
+            _ => Ok(vec![(instruction, generics)]),
+        }
+    }
+
+    /// Finds the signature of the quotation `depth` elements below the top of
+    /// the stack.
+    fn quotation_at(
+        &self,
+        depth: usize,
+        span: Span,
+    ) -> Result<Arc<FunctionSignature>> {
+        if let Some(Type::Quotation(signature)) =
+            self.stack.iter().rev().nth(depth)
+        {
+            return Ok(signature.clone());
+        }
+        bail!(diagnostics::error(
+            "type mismatch".to_owned(),
+            vec![primary_label(
+                span,
+                format!(
+                    "expected a quotation but got `{}`",
+                    self.stack.iter().format(" ")
+                ),
+            )],
+        ))
+    }
+
+    /// Checks `dip`, `keep` or `bi` and lowers it to calls of the quotations
+    /// with the values shuffled around them.
+    fn check_combinator(
+        &mut self,
+        combinator: Combinator,
+        span: Span,
+    ) -> Result<Vec<(Instruction<Generics>, Generics)>> {
+        use Pattern::{Concrete as C, Generic as G};
+
+        let quotation = self.quotation_at(0, span)?;
+        let quotation_type = Type::Quotation(quotation.clone());
+        let n = quotation.parameters.len();
+        let mut parameters = quotation
+            .parameters
+            .iter()
+            .cloned()
+            .map(C)
+            .collect::<Vec<_>>();
+        let mut returns =
+            quotation.returns.iter().cloned().map(C).collect::<Vec<_>>();
+        let mut inputs = quotation.parameters.to_vec();
+
+        Ok(match combinator {
+            // `x q dip` runs `q` below `x`.
+            Combinator::Dip => {
+                parameters.extend([G(0), C(quotation_type.clone())]);
+                returns.push(G(0));
+                let generics = self.transform(
+                    &[Generic('T', Constraint::Any)],
+                    &parameters,
+                    &returns,
+                    span,
+                )?;
+                let x = generics[0].clone();
+                inputs.extend([x.clone(), quotation_type.clone()]);
+                let mut lowered = rearrange(
+                    &inputs,
+                    &std::iter::once(n)
+                        .chain(0..n)
+                        .chain([n + 1])
+                        .collect::<Vec<_>>(),
+                    span,
+                )?;
+                lowered.push(call_quotation(&quotation_type));
+                lowered.extend(raise(x, &quotation.returns, span)?);
+                lowered
+            }
+            // `x q keep` runs `q` on `x` and keeps a copy of it.
+            Combinator::Keep => {
+                let Some(x) = quotation.parameters.last().cloned() else {
+                    bail!(diagnostics::error(
+                        "type mismatch".to_owned(),
+                        vec![primary_label(
+                            span,
+                            "`keep` needs a quotation that takes a value to keep",
+                        )],
+                    ));
+                };
+                parameters.push(C(quotation_type.clone()));
+                returns.push(C(x.clone()));
+                self.transform(&[], &parameters, &returns, span)?;
+                inputs.push(quotation_type.clone());
+                let mut lowered = rearrange(
+                    &inputs,
+                    &std::iter::once(n - 1).chain(0..=n).collect::<Vec<_>>(),
+                    span,
+                )?;
+                lowered.push(call_quotation(&quotation_type));
+                lowered.extend(raise(x, &quotation.returns, span)?);
+                lowered
+            }
+            // `x p q bi` runs both `p` and `q` on `x`.
+            Combinator::Bi => {
+                let first = self.quotation_at(1, span)?;
+                let first_type = Type::Quotation(first.clone());
+                ensure_same_parameters(&first, &quotation, span)?;
+                parameters
+                    .extend([C(first_type.clone()), C(quotation_type.clone())]);
+                returns = first
+                    .returns
+                    .iter()
+                    .chain(&*quotation.returns)
+                    .cloned()
+                    .map(C)
+                    .collect();
+                self.transform(&[], &parameters, &returns, span)?;
+                inputs.extend([first_type.clone(), quotation_type.clone()]);
+                let mut lowered = rearrange(
+                    &inputs,
+                    &std::iter::once(n + 1)
+                        .chain(0..n)
+                        .chain(0..=n)
+                        .collect::<Vec<_>>(),
+                    span,
+                )?;
+                lowered.push(call_quotation(&first_type));
+                let types = std::iter::once(quotation_type.clone())
+                    .chain(quotation.parameters.iter().cloned())
+                    .chain(first.returns.iter().cloned())
+                    .collect::<Vec<_>>();
+                lowered.extend(rearrange(
+                    &types,
+                    &(n + 1..types.len())
+                        .chain(1..=n)
+                        .chain([0])
+                        .collect::<Vec<_>>(),
+                    span,
+                )?);
+                lowered.push(call_quotation(&quotation_type));
+                lowered
+            }
+        })
+    }
+
+    /// Checks a `break` or `continue`, which leaves the loop with the label if
+    /// there is one and otherwise the innermost loop.
+    fn check_jump(
+        &mut self,
+        keyword: &str,
+        label: Option<Box<str>>,
+        generics: Generics,
+        span: Span,
+    ) -> Result<Vec<(Instruction<Generics>, Generics)>> {
+        let index = match &label {
+            None => self.loops.len().checked_sub(1).ok_or_else(|| {
+                diagnostics::error(
+                    format!("`{keyword}` outside of a loop"),
+                    vec![primary_label(span, "")],
+                )
+            })?,
+            Some(label) => self
+                .loops
+                .iter()
+                .rposition(|loop_| loop_.label.as_ref() == Some(label))
+                .ok_or_else(|| {
+                    diagnostics::error(
+                        format!("`{keyword}:{label}` outside of a loop labeled `{label}`"),
+                        vec![primary_label(span, "")],
+                    )
+                    .note("`while` loop conditions and quotations can't refer to the loops around them")
+                })?,
+        };
+        // Locals declared inside of the loop go out of scope.
+        let outer_stash_count = self.loops[index].stashes.len();
+        let mut lowered = Vec::new();
+        for index in (outer_stash_count..self.stashes.len()).rev() {
+            if self.stashes[index].0.starts_with("let ") {
+                lowered.extend(self.drop_local(index, span)?);
+            }
+        }
+        let Loop {
+            types: loop_types,
+            stashes: loop_stashes,
+            ..
+        } = &self.loops[index];
+        // Stashes declared inside of the loop must be empty, since they go out
+        // of scope.
+        self.ensure_stashes(loop_stashes, span, |name, a, b| {
+            format!("`{keyword}` with types `{b}` on stash `{name}` in a loop over types `{a}`")
+        })?;
+        ensure!(
+            self.stack == *loop_types,
+            diagnostics::error(
+                format!(
+                    "`{keyword}` with types `{}` in a loop over types `{}`",
+                    self.stack.iter().format(" "),
+                    loop_types.iter().format(" "),
+                ),
+                vec![primary_label(span, "")],
+            ),
+        );
+        self.diverged = true;
+        let depth = self.loops.len() - 1 - index;
+        lowered.push((
+            if keyword == "break" {
+                Instruction::Break { label, depth }
+            } else {
+                Instruction::Continue { label, depth }
+            },
+            generics,
+        ));
+        Ok(lowered)
+    }
+
+    /// Checks a `match` statement on a value of the given enum, which has
+    /// already been popped off the stack, and lowers it to a `match` on the
+    /// tag with one case per variant in order.
+    fn check_match(
+        &mut self,
+        enum_: &Arc<Enum>,
+        cases: Box<[ir::Case]>,
+        span: Span,
+    ) -> Result<Vec<(Instruction<Generics>, Generics)>> {
+        let arms = order_cases(enum_, cases, span)?;
+
+        let before = self.stack.clone();
+        let origins_before = self.origins.clone();
+        let stashes_before = self.stash_types();
+        // The types after the first case that doesn't diverge, which the
+        // other ones have to agree with.
+        let mut after = None::<(Vec<Type>, Vec<Vec<Type>>, Span)>;
+        let payloads =
+            Enum::payload_values(&enum_.variants).collect::<Vec<_>>();
+        let mut payload_start = 0;
+        let mut lowered_cases = Vec::new();
+        for ((_, payload), case) in std::iter::zip(&*enum_.variants, arms) {
+            self.stack.clone_from(&before);
+            self.origins.clone_from(&origins_before);
+            self.restore_stashes(stashes_before.clone());
+            self.stack.extend(payload.clone());
+            self.origins.resize(self.stack.len(), Some(case.span));
+            let body = self.check_block(case.body)?;
+            if !std::mem::take(&mut self.diverged) {
+                if let Some((types, stashes, first_span)) = &after {
+                    self.ensure_stashes(stashes, case.span, |name, a, b| {
+                        format!("`match` statement diverges between types `{a}` and `{b}` on stash `{name}`")
+                    })?;
+                    ensure!(
+                        *types == self.stack,
+                        diagnostics::error(
+                            format!(
+                                "`match` statement diverges between types `{}` and `{}`",
+                                types.iter().format(" "),
+                                self.stack.iter().format(" "),
+                            ),
+                            vec![
+                                primary_label(case.span, ""),
+                                secondary_label(*first_span, ""),
+                            ],
+                        ),
+                    );
+                } else {
+                    after = Some((
+                        self.stack.clone(),
+                        self.stash_types(),
+                        case.span,
+                    ));
+                }
+            }
+
+            // Only the payload of the variant is kept.
+            let width = payload.as_ref().map_or(0, Type::width);
+            let mut lowered = shuffle(
+                payloads.clone(),
+                &(payload_start..payload_start + width).collect::<Vec<_>>(),
+                case.span,
+            )?;
+            payload_start += width;
+            lowered.extend(body.into_vec());
+            lowered_cases.push(ir::Case {
+                variant: case.variant,
+                span: case.span,
+                body: lowered.into(),
+            });
+        }
+        if let Some((types, stashes, _)) = after {
+            self.stack = types;
+            self.restore_stashes(stashes);
+        } else {
+            self.diverged = true;
+        }
+
+        // The tag is moved above the payloads so that it can be popped first.
+        let values = Type::Enum(enum_.clone()).flatten();
+        let mut lowered = shuffle(
+            values.clone(),
+            &(1..values.len()).chain([0]).collect::<Vec<_>>(),
+            span,
+        )?;
+        lowered.push((
+            Instruction::Match(lowered_cases.into()),
+            Box::new([]) as _,
+        ));
+        Ok(lowered)
+    }
+
+    /// Checks a `case` statement, whose value has already been popped off the
+    /// stack. The arms and `else` all start with the same types and have to
+    /// end with the same types unless they diverge.
+    fn check_switch(
+        &mut self,
+        arms: Box<[ir::Arm]>,
+        else_: Box<Block>,
+        span: Span,
+    ) -> Result<Vec<(Instruction<Generics>, Generics)>> {
+        for (i, arm) in arms.iter().enumerate() {
+            if let Some(previous) = arms[..i]
+                .iter()
+                .find(|previous| previous.value == arm.value)
+            {
+                bail!(diagnostics::error(
+                    format!("`{}` is matched more than once", arm.value),
+                    vec![
+                        primary_label(arm.span, ""),
+                        secondary_label(previous.span, "first matched here"),
+                    ],
+                ));
+            }
+        }
+
+        let before = self.stack.clone();
+        let origins_before = self.origins.clone();
+        let stashes_before = self.stash_types();
+        // The types after the first arm that doesn't diverge, which the other
+        // ones have to agree with, and where the values came from.
+        let mut after =
+            None::<(Vec<Type>, Vec<Vec<Type>>, Span, Vec<Option<Span>>)>;
+        let else_span = block_span(&else_).unwrap_or(span);
+        let mut checked_arms = Vec::new();
+        let mut check_arm = |this: &mut Self, body, arm_span| -> Result<_> {
+            this.stack.clone_from(&before);
+            this.origins.clone_from(&origins_before);
+            this.restore_stashes(stashes_before.clone());
+            let body = this.check_block(body)?;
+            if std::mem::take(&mut this.diverged) {
+                return Ok(body);
+            }
+            this.origins.resize(this.stack.len(), Some(span));
+            if let Some((types, stashes, first_span, origins)) = &mut after {
+                this.ensure_stashes(stashes, arm_span, |name, a, b| {
+                    format!("`case` statement diverges between types `{a}` and `{b}` on stash `{name}`")
+                })?;
+                ensure!(
+                    *types == this.stack,
+                    diagnostics::error(
+                        format!(
+                            "`case` statement diverges between types `{}` and `{}`",
+                            types.iter().format(" "),
+                            this.stack.iter().format(" "),
+                        ),
+                        vec![
+                            primary_label(arm_span, ""),
+                            secondary_label(*first_span, ""),
+                        ],
+                    ),
+                );
+                // Values that differ between the arms come from the whole
+                // statement.
+                for (origin, &arm_origin) in
+                    std::iter::zip(origins, &this.origins)
+                {
+                    if *origin != arm_origin {
+                        *origin = Some(span);
+                    }
+                }
+            } else {
+                after = Some((
+                    this.stack.clone(),
+                    this.stash_types(),
+                    arm_span,
+                    this.origins.clone(),
+                ));
+            }
+            Ok(body)
         };
-        Ok((instruction, generics))
+        for arm in arms {
+            let body = check_arm(self, arm.body, arm.span)?;
+            checked_arms.push(ir::Arm {
+                value: arm.value,
+                span: arm.span,
+                body,
+            });
+        }
+        let else_ = check_arm(self, else_, else_span)?;
+        if let Some((types, stashes, _, origins)) = after {
+            self.stack = types;
+            self.origins = origins;
+            self.restore_stashes(stashes);
+        } else {
+            self.diverged = true;
+        }
+        Ok(vec![(
+            Instruction::Switch {
+                arms: checked_arms.into(),
+                else_,
+            },
+            Box::new([]) as _,
+        )])
+    }
+}
+
+/// Puts the cases of a `match` statement in the same order as the variants of
+/// the enum, making sure that each variant is handled exactly once.
+fn order_cases(
+    enum_: &Enum,
+    cases: Box<[ir::Case]>,
+    span: Span,
+) -> Result<Vec<ir::Case>> {
+    let mut arms = enum_
+        .variants
+        .iter()
+        .map(|_| None)
+        .collect::<Vec<Option<ir::Case>>>();
+    for case in cases {
+        let index = enum_
+            .variants
+            .iter()
+            .position(|(name, _)| *name == case.variant)
+            .ok_or_else(|| {
+                diagnostics::error(
+                    format!(
+                        "enum `{}` has no variant named `{}`",
+                        enum_.name, case.variant
+                    ),
+                    vec![primary_label(case.span, "")],
+                )
+            })?;
+        if let Some(previous) = &arms[index] {
+            bail!(diagnostics::error(
+                format!("variant `{}` is matched more than once", case.variant),
+                vec![
+                    primary_label(case.span, ""),
+                    secondary_label(previous.span, "first matched here"),
+                ],
+            ));
+        }
+        arms[index] = Some(case);
+    }
+    let missing = std::iter::zip(&*enum_.variants, &arms)
+        .filter(|(_, arm)| arm.is_none())
+        .map(|((name, _), _)| format!("`{name}`"))
+        .collect::<Vec<_>>();
+    ensure!(
+        missing.is_empty(),
+        diagnostics::error(
+            format!(
+                "`match` statement doesn't handle {} {}",
+                if missing.len() == 1 {
+                    "variant"
+                } else {
+                    "variants"
+                },
+                missing.iter().format(", ")
+            ),
+            vec![primary_label(span, "")],
+        )
+    );
+    Ok(arms.into_iter().map(Option::unwrap).collect())
+}
+
+/// Whether an instruction does nothing but push a value, computed from what
+/// it pops.
+const fn only_computes_value(instruction: &Instruction) -> bool {
+    matches!(
+        instruction,
+        Instruction::PushI32(_)
+            | Instruction::PushF32(_)
+            | Instruction::PushBool(_)
+            | Instruction::PushType(_)
+            | Instruction::Quotation(_)
+            | Instruction::FunctionPointer(_)
+            | Instruction::Data(_)
+            | Instruction::Local(_)
+            | Instruction::Load(_)
+            | Instruction::Dup
+            | Instruction::Over
+            | Instruction::Comparison(_)
+            | Instruction::Not
+            | Instruction::BinLogicOp(_)
+            | Instruction::BitNot
+            | Instruction::BitwiseOp(_)
+            | Instruction::Round(_)
+            | Instruction::I32ToF32
+            | Instruction::F32ToI32(_)
+            | Instruction::Math(_)
+            | Instruction::TypeOf
+            | Instruction::TypeEq
+            | Instruction::Null(_)
+            | Instruction::PtrAdd
+            | Instruction::PtrSub
+            | Instruction::PtrDiff
+    )
+}
+
+/// The span from the first to the last instruction of a block, if it has any.
+fn block_span(block: &Block) -> Option<Span> {
+    let (_, first) = block.first()?;
+    let (_, last) = block.last()?;
+    Some(first.merge(*last))
+}
+
+/// Describes where the stacks that the branches of a `then else` statement
+/// leave behind stop agreeing, counting from the top.
+fn branch_mismatch(
+    (then, then_span): (&[Type], Span),
+    (else_, else_span): (&[Type], Span),
+) -> diagnostics::Error {
+    let describe = |stack: &[Type]| {
+        if stack.is_empty() {
+            "empty".to_owned()
+        } else {
+            format!("`{}`", stack.iter().format(" "))
+        }
+    };
+    let note = format!(
+        "the stack is {} after the `then` branch and {} after the `else` branch",
+        describe(then),
+        describe(else_),
+    );
+    let count = |stack: &[Type]| match stack.len() {
+        1 => "1 value".to_owned(),
+        n => format!("{n} values"),
+    };
+    let (message, then_label, else_label) = then
+        .iter()
+        .rev()
+        .zip(else_.iter().rev())
+        .position(|(a, b)| a != b)
+        .map_or_else(
+            || {
+                (
+                    "`then else` branches leave different numbers of values"
+                        .to_owned(),
+                    format!("`then` branch leaves {} on the stack", count(then)),
+                    format!("`else` branch leaves {} on the stack", count(else_)),
+                )
+            },
+            |position| {
+                (
+                    format!(
+                        "`then else` branches disagree on the type of the {} value from the top",
+                        ordinal(position + 1),
+                    ),
+                    format!(
+                        "`then` branch leaves `{}` there",
+                        then[then.len() - 1 - position]
+                    ),
+                    format!(
+                        "`else` branch leaves `{}` there",
+                        else_[else_.len() - 1 - position]
+                    ),
+                )
+            },
+        );
+    diagnostics::error(
+        message,
+        vec![
+            primary_label(then_span, then_label),
+            primary_label(else_span, else_label),
+        ],
+    )
+    .note(note)
+}
+
+/// Formats a positive number as `1st`, `2nd`, `3rd`, `4th` and so on.
+fn ordinal(n: usize) -> String {
+    let suffix = match (n % 10, n % 100) {
+        (_, 11..=13) => "th",
+        (1, _) => "st",
+        (2, _) => "nd",
+        (3, _) => "rd",
+        _ => "th",
+    };
+    format!("{n}{suffix}")
+}
+
+/// Lowers the constructor of an enum variant, which surrounds the payload
+/// with the tag and placeholders for the payloads of the other variants.
+fn construct_variant(
+    enum_: &Enum,
+    index: usize,
+    span: Span,
+) -> Result<Vec<(Instruction<Generics>, Generics)>> {
+    let payload = enum_.variants[index]
+        .1
+        .iter()
+        .flat_map(Type::flatten)
+        .collect::<Vec<_>>();
+    let before =
+        Enum::payload_values(&enum_.variants[..index]).collect::<Vec<_>>();
+    let after = Enum::payload_values(&enum_.variants[index + 1..]);
+
+    let mut lowered = vec![(
+        Instruction::PushI32(i32::try_from(index).unwrap()),
+        Box::new([]) as _,
+    )];
+    lowered.extend(before.iter().map(placeholder));
+    let width = payload.len();
+    let types = payload
+        .into_iter()
+        .chain([Type::I32])
+        .chain(before)
+        .collect::<Vec<_>>();
+    lowered.extend(shuffle(
+        types.clone(),
+        &(width..types.len()).chain(0..width).collect::<Vec<_>>(),
+        span,
+    )?);
+    lowered.extend(after.map(|typ| placeholder(&typ)));
+    Ok(lowered)
+}
+
+/// Pushes an arbitrary value of a type that isn't made up of other values.
+fn placeholder(typ: &Type) -> (Instruction<Generics>, Generics) {
+    let instruction = match typ {
+        Type::Bool => Instruction::PushBool(false),
+        Type::I32 => Instruction::PushI32(0),
+        // Files are represented as handles, of which -1 is invalid.
+        Type::File => Instruction::PushI32(-1),
+        Type::F32 => Instruction::PushF32(0.0),
+        Type::Type => Instruction::PushType(Type::Type),
+        Type::Ptr(_)
+        | Type::Struct(_)
+        | Type::Enum(_)
+        | Type::Quotation(_)
+        | Type::Var(_) => unreachable!(),
+    };
+    (instruction, Box::new([]))
+}
+
+fn call_quotation(quotation_type: &Type) -> (Instruction<Generics>, Generics) {
+    (
+        Instruction::CallQuotation,
+        Box::new([quotation_type.clone()]),
+    )
+}
+
+/// Moves the element below the results of a call back on top of them.
+fn raise(
+    below: Type,
+    results: &[Type],
+    span: Span,
+) -> Result<Vec<(Instruction<Generics>, Generics)>> {
+    let types = std::iter::once(below)
+        .chain(results.iter().cloned())
+        .collect::<Vec<_>>();
+    rearrange(
+        &types,
+        &(1..types.len()).chain([0]).collect::<Vec<_>>(),
+        span,
+    )
+}
+
+/// `bi` gives both quotations the same values, so they must take the same
+/// types.
+fn ensure_same_parameters(
+    first: &FunctionSignature,
+    second: &FunctionSignature,
+    span: Span,
+) -> Result<()> {
+    ensure!(
+        first.parameters == second.parameters,
+        diagnostics::error(
+            "type mismatch".to_owned(),
+            vec![primary_label(
+                span,
+                format!(
+                    "`bi` gives both quotations the same values, but they take `{}` and `{}`",
+                    first.parameters.iter().format(" "),
+                    second.parameters.iter().format(" "),
+                ),
+            )],
+        )
+    );
+    Ok(())
+}
+
+/// Lowers an instruction that rearranges elements of the `inputs` types into
+/// `outputs`, given as indices into `inputs`, to instructions that move the
+/// individual values that structs are made up of.
+fn rearrange(
+    inputs: &[Type],
+    outputs: &[usize],
+    span: Span,
+) -> Result<Vec<(Instruction<Generics>, Generics)>> {
+    let mut values = Vec::new();
+    let mut ranges = Vec::new();
+    for typ in inputs {
+        let start = values.len();
+        values.extend(typ.flatten());
+        ranges.push(start..values.len());
+    }
+    let outputs = outputs
+        .iter()
+        .flat_map(|&i| ranges[i].clone())
+        .collect::<Vec<_>>();
+    shuffle(values, &outputs, span)
+}
+
+/// Turns values of the given types on top of the stack into `outputs`, given
+/// as indices into `types`, using `pick`, `roll` and `drop`.
+fn shuffle(
+    types: Vec<Type>,
+    outputs: &[usize],
+    span: Span,
+) -> Result<Vec<(Instruction<Generics>, Generics)>> {
+    // Values that have reached their final position are marked with `None`.
+    let mut stack = types
+        .into_iter()
+        .enumerate()
+        .map(|(i, typ)| (Some(i), typ))
+        .collect::<Vec<_>>();
+    let mut lowered = Vec::new();
+    let locate = |stack: &[(Option<usize>, Type)], value| {
+        let index = stack.iter().position(|(v, _)| *v == Some(value)).unwrap();
+        let depth = u8::try_from(stack.len() - 1 - index).map_err(|_| {
+            diagnostics::error(
+                "too many values to rearrange".to_owned(),
+                vec![primary_label(span, "")],
+            )
+            .note("structs can be at most 255 values deep in the stack")
+        })?;
+        let generics = stack[index..]
+            .iter()
+            .map(|(_, typ)| typ.clone())
+            .collect::<Generics>();
+        anyhow::Ok((index, depth, generics))
+    };
+
+    for value in (0..stack.len()).filter(|i| !outputs.contains(i)) {
+        let (index, depth, generics) = locate(&stack, value)?;
+        if depth != 0 {
+            lowered.push((Instruction::Roll(depth), generics));
+        }
+        let (_, typ) = stack.remove(index);
+        lowered.push((Instruction::Drop, Box::new([typ]) as _));
+    }
+    for (i, &value) in outputs.iter().enumerate() {
+        let (index, depth, generics) = locate(&stack, value)?;
+        if outputs[i + 1..].contains(&value) {
+            lowered.push((Instruction::Pick(depth), generics));
+            let typ = stack[index].1.clone();
+            stack.push((None, typ));
+        } else {
+            if depth != 0 {
+                lowered.push((Instruction::Roll(depth), generics));
+            }
+            let (_, typ) = stack.remove(index);
+            stack.push((None, typ));
+        }
     }
+    Ok(lowered)
 }
 
 struct Signature<'a> {
@@ -2,6 +2,8 @@ pub fn prettify_token(token: &str) -> &str {
     match token {
         "->" => "→",
         "*" => "×",
+        "*sat" => "×sat",
+        "*wrap" => "×wrap",
         "/" => "÷",
         "<=" => "≤",
         ">=" => "≥",
@@ -0,0 +1,37 @@
+//! Checks that every example prints the same thing and exits with the same
+//! code whether it is interpreted or compiled, using `spackel selftest`.
+
+use std::process::Command;
+
+#[test]
+fn interpreted_and_compiled_examples_behave_the_same() {
+    let build_dir = std::env::temp_dir()
+        .join(format!("spackel-differential-{}", std::process::id()));
+    std::fs::create_dir_all(&build_dir).unwrap();
+    let runtime = build_dir.join("runtime.o");
+    // Like the makefile does.
+    let status = Command::new("rustc")
+        .args(["-C", "opt-level=3", "--crate-type=lib", "--emit=obj"])
+        .arg("runtime.rs")
+        .arg("-o")
+        .arg(&runtime)
+        .status()
+        .unwrap();
+    assert!(
+        status.success(),
+        "building the runtime failed with {status}"
+    );
+
+    let output = Command::new(env!("CARGO_BIN_EXE_spackel"))
+        .args(["selftest", "examples", "--runtime"])
+        .arg(&runtime)
+        .output()
+        .unwrap();
+    std::fs::remove_dir_all(&build_dir).ok();
+    assert!(
+        output.status.success(),
+        "{}{}",
+        String::from_utf8_lossy(&output.stdout),
+        String::from_utf8_lossy(&output.stderr)
+    );
+}
@@ -0,0 +1,53 @@
+//! Checks which runtime checks the interval analysis leaves out, by looking
+//! for the messages of the checks in the object file.
+
+use spackel::{compile_str, Options};
+
+/// What the checks for overflow and division by zero say when they fail.
+const MESSAGES: [&str; 3] = [
+    "integer overflow",
+    "division by zero",
+    "division overflowed",
+];
+
+/// The messages of the checks that are left in `main` with this body.
+fn checks(body: &str) -> Vec<&'static str> {
+    let options = Options {
+        overflow_checks: true,
+        ..Options::default()
+    };
+    let source = format!("fn main : → do {body} println end");
+    let object = compile_str(&source, &options).unwrap();
+    MESSAGES
+        .into_iter()
+        .filter(|message| {
+            object
+                .windows(message.len())
+                .any(|bytes| bytes == message.as_bytes())
+        })
+        .collect()
+}
+
+#[test]
+fn counters_bounded_by_their_loop_are_unchecked() {
+    assert!(checks("0 while dup 10 < do dup println 1 + end").is_empty());
+    assert_eq!(
+        checks("0 while dup 0 ≥ do dup println 1 + end"),
+        ["integer overflow"],
+    );
+}
+
+#[test]
+fn divisors_that_cannot_be_zero_are_unchecked() {
+    assert!(checks("read-i32 drop 8 % 9 + 100 swap /").is_empty());
+    assert_eq!(
+        checks("read-i32 drop 8 % 100 swap /"),
+        ["division by zero", "division overflowed"],
+    );
+}
+
+#[test]
+fn dividing_the_minimum_by_minus_one_stays_checked() {
+    assert!(checks("read-i32 drop -1 /").contains(&"division overflowed"));
+    assert!(checks("read-i32 drop 2 / -1 /").is_empty());
+}
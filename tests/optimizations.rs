@@ -0,0 +1,22 @@
+//! Checks that no optimization pass changes what the examples do.
+
+use spackel::{compile_str, Options};
+use std::fs;
+
+#[test]
+fn optimizations_keep_the_behavior_of_examples() {
+    for entry in fs::read_dir("examples").unwrap() {
+        let path = entry.unwrap().path();
+        let source = fs::read_to_string(&path).unwrap();
+        for optimize_for_size in [false, true] {
+            let options = Options {
+                optimize_for_size,
+                check_optimizations: true,
+                ..Options::default()
+            };
+            if let Err(err) = compile_str(&source, &options) {
+                panic!("failed to compile {}: {err}", path.display());
+            }
+        }
+    }
+}
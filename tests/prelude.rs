@@ -0,0 +1,32 @@
+//! Checks that programs can shadow the functions in the prelude, but not their
+//! own functions.
+
+use spackel::{run_source, Limits};
+
+#[test]
+fn functions_from_the_prelude_can_be_shadowed() {
+    let result = run_source(
+        "fn neg : i32 → i32 do 1 + end\nfn main : → do 5 neg print end\n",
+        "",
+        Limits::default(),
+    );
+    assert!(result.diagnostics.is_empty(), "{:?}", result.diagnostics);
+    assert_eq!(result.stdout, "6");
+}
+
+#[test]
+fn functions_from_the_program_cannot_be_redefined() {
+    let result = run_source(
+        "fn f : → do end\nfn f : → do end\nfn main : → do f end\n",
+        "",
+        Limits::default(),
+    );
+    assert!(
+        result
+            .diagnostics
+            .concat()
+            .contains("redefinition of function `f`"),
+        "{:?}",
+        result.diagnostics
+    );
+}
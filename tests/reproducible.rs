@@ -0,0 +1,29 @@
+//! Checks that compiling the same program twice gives the same object file,
+//! byte for byte.
+
+use spackel::{compile_str, Options};
+use std::fs;
+
+#[test]
+fn examples_compile_to_the_same_bytes_every_time() {
+    for entry in fs::read_dir("examples").unwrap() {
+        let path = entry.unwrap().path();
+        let source = fs::read_to_string(&path).unwrap();
+        for debug_info in [false, true] {
+            let options = Options {
+                debug_info,
+                ..Options::default()
+            };
+            let compile = || {
+                compile_str(&source, &options).unwrap_or_else(|err| {
+                    panic!("failed to compile {}: {err}", path.display())
+                })
+            };
+            assert!(
+                compile() == compile(),
+                "{} compiled to different bytes",
+                path.display()
+            );
+        }
+    }
+}
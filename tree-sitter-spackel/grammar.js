@@ -1,3 +1,10 @@
+// A loop keyword, which may have a label after it, as in `repeat:outer`.
+const loopKeyword = keyword =>
+  choice(
+    keyword,
+    alias(token(prec(1, new RegExp(`${keyword}:[^#\\s]+`))), keyword)
+  );
+
 module.exports = grammar({
   name: "spackel",
 
@@ -5,8 +12,17 @@ module.exports = grammar({
 
   word: $ => $.word,
 
+  // A number at the end of an arm's body can't be told apart from the value
+  // of the next arm until the `=>` after it.
+  conflicts: $ => [[$.case_arm], [$.match_case]],
+
   rules: {
-    source_file: $ => repeat(choice($.macro_definition, $._instruction)),
+    source_file: $ =>
+      repeat(
+        choice($.macro_definition, $.include_directive, $._instruction)
+      ),
+
+    include_directive: $ => seq("include", field("path", $.string_literal)),
 
     macro_definition: $ =>
       seq("macro", field("name", $.word), repeat($._instruction), "end"),
@@ -18,8 +34,22 @@ module.exports = grammar({
         $.then_statement,
         $.then_else_statement,
         $.repeat_loop,
+        $.counted_loop,
+        $.while_loop,
         $.unsafe_block,
+        $.stash_block,
+        $.let_block,
+        $.match_statement,
+        $.case_statement,
+        $.quotation,
         $.function_definition,
+        $.extern_function_declaration,
+        $.struct_definition,
+        $.enum_definition,
+        $.constant_definition,
+        $.variable_definition,
+        $.data_definition,
+        $.test_definition,
         $.word
       ),
 
@@ -34,24 +64,124 @@ module.exports = grammar({
         "end"
       ),
 
-    repeat_loop: $ => seq("repeat", repeat($._instruction), "end"),
+    repeat_loop: $ =>
+      seq(loopKeyword("repeat"), repeat($._instruction), "end"),
+
+    counted_loop: $ =>
+      seq(
+        choice(loopKeyword("times"), loopKeyword("for")),
+        repeat($._instruction),
+        "end"
+      ),
+
+    while_loop: $ =>
+      seq(
+        loopKeyword("while"),
+        field("condition", repeat($._instruction)),
+        "do",
+        field("body", repeat($._instruction)),
+        "end"
+      ),
+
+    match_statement: $ => seq("match", repeat($.match_case), "end"),
+
+    match_case: $ =>
+      seq("case", field("variant", $.word), repeat($._instruction)),
+
+    case_statement: $ =>
+      seq(
+        "case",
+        repeat1($.case_arm),
+        optional(seq("else", repeat($._instruction))),
+        "end"
+      ),
+
+    case_arm: $ =>
+      seq(field("value", $.int_literal), "=>", repeat($._instruction)),
+
+    quotation: $ =>
+      seq(
+        "[",
+        $.function_signature,
+        optional(seq("do", field("body", repeat($._instruction)))),
+        "]"
+      ),
 
     unsafe_block: $ => seq("unsafe", repeat($._instruction), "end"),
 
+    stash_block: $ =>
+      seq("stash", field("name", $.word), repeat($._instruction), "end"),
+
+    let_block: $ =>
+      seq(
+        "let",
+        repeat1(field("name", $.word)),
+        "in",
+        repeat($._instruction),
+        "end"
+      ),
+
     function_definition: $ =>
       seq(
+        optional("unsafe"),
         "fn",
         field("name", $.word),
+        optional("inline"),
         ":",
         $.function_signature,
         field("body", $.block)
       ),
 
+    extern_function_declaration: $ =>
+      seq(
+        "extern",
+        optional(field("abi", $.string_literal)),
+        "fn",
+        field("name", $.word),
+        ":",
+        repeat($._instruction),
+        choice("->", "→"),
+        repeat($._instruction),
+        "end"
+      ),
+
     function_signature: $ =>
       seq(repeat($._instruction), choice("->", "→"), repeat($._instruction)),
 
     block: $ => seq("do", repeat($._instruction), "end"),
 
+    struct_definition: $ =>
+      seq("struct", field("name", $.word), repeat($.word), "end"),
+
+    enum_definition: $ =>
+      seq("enum", field("name", $.word), repeat($.word), "end"),
+
+    constant_definition: $ =>
+      seq("const", field("name", $.word), repeat($._instruction), "end"),
+
+    variable_definition: $ =>
+      seq("var", field("name", $.word), repeat($._instruction), "end"),
+
+    data_definition: $ =>
+      seq(
+        "data",
+        field("name", $.word),
+        "[",
+        repeat($._instruction),
+        "]",
+        "end"
+      ),
+
+    test_definition: $ =>
+      seq(
+        "test",
+        field("name", $.string_literal),
+        repeat($._instruction),
+        "end"
+      ),
+
+    string_literal: $ => /"[^"\n]*"/,
+
     int_literal: $ => /[+-]?\d+/,
 
     float_literal: $ => /[+-]?(\d+(\.\d*)?|\d*\.\d+)([Ee][+-]?\d+)?/,
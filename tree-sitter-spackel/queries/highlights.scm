@@ -1,15 +1,35 @@
+((line_comment) @comment.block.documentation
+ (#match? @comment.block.documentation "^##"))
 (line_comment) @comment.line
 
+(string_literal) @string
+
 [
   (int_literal)
   (float_literal)
 ] @constant.numeric
 
 "macro" @keyword.function
+"stash" @keyword.storage
+[
+  "let"
+  "in"
+  "const"
+  "var"
+  "data"
+] @keyword.storage
+(let_block
+  name: (word) @variable.parameter)
+"include" @keyword.control.import
 "unsafe" @keyword.special
 (then_statement "end" @keyword.control.conditional .)
 (then_else_statement "end" @keyword.control.conditional .)
+(match_statement "end" @keyword.control.conditional .)
+(case_statement "end" @keyword.control.conditional .)
 (repeat_loop "end" @keyword.control.loop .)
+(counted_loop "end" @keyword.control.loop .)
+(while_loop "do" @keyword.control.loop)
+(while_loop "end" @keyword.control.loop .)
 [
   "do"
   "end"
@@ -17,11 +37,36 @@
 [
   "then"
   "else"
+  "match"
+  "case"
 ] @keyword.control.conditional
-"repeat" @keyword.control.loop
-"fn" @keyword.function
+[
+  "repeat"
+  "times"
+  "for"
+  "while"
+] @keyword.control.loop
+((word) @keyword.control.loop
+ (#match? @keyword.control.loop "^(break|continue)(:.+)?$"))
+((word) @keyword.control.return
+ (#eq? @keyword.control.return "return"))
+[
+  "fn"
+  "inline"
+  "extern"
+  "test"
+] @keyword.function
+[
+  "struct"
+  "enum"
+] @keyword.storage.type
 
 ":" @punctuation.delimiter
+"=>" @punctuation.delimiter
+[
+  "["
+  "]"
+] @punctuation.bracket
 [
   "->"
   "→"
@@ -33,9 +78,38 @@
 (function_definition
   name: (word) @function)
 
+(extern_function_declaration
+  name: (word) @function)
+
+(stash_block
+  name: (word) @variable)
+
+(struct_definition
+  name: (word) @type)
+
+(enum_definition
+  name: (word) @type)
+
+(constant_definition
+  name: (word) @constant)
+
+(variable_definition
+  name: (word) @variable)
+
+(data_definition
+  name: (word) @constant)
+
+(match_case
+  variant: (word) @constructor)
+
 (
  (word) @function.builtin
- (#match? @function.builtin "^(print|println|print-char|\\+|-|\\*|×|/|÷|%|ß|sqrt|√|<|<=|≤|=|>|>=|≥|not|¬|and|∧|or|∨|xor|⊕|nand|⊼|nor|⊽|xnor|⊙|addr-of|read-ptr|drop|dup|swap|over|nip|tuck|type-of)$")
+ (#match? @function.builtin "^(print|println|print-char|read-char|read-i32|read-line|arg-count|arg|open|read|write|close|assert|exit|call|invoke|dip|keep|bi|\\+|-|\\*|×|/|÷|%|\\+sat|-sat|\\*sat|×sat|\\+wrap|-wrap|\\*wrap|×wrap|ß|sqrt|√|abs|min|max|pow|sin|cos|ln|exp|round|floor|ceil|trunc|i32->f32|f32->i32|f32->i32-round|<|<=|≤|=|>|>=|≥|not|¬|and|∧|or|∨|xor|⊕|nand|⊼|nor|⊽|xnor|⊙|band|bor|bxor|bnot|shl|shr|sar|rotl|rotr|addr-of|read-ptr|read-u8|read-i8|read-u16|read-i16|write-ptr|write-u8|write-i8|write-u16|write-i16|ptr\\+|ptr-|ptr-diff|copy|fill|compare|null|load|store|drop|dup|swap|over|nip|tuck|rot|-rot|pick|roll|type-of|type=|print-type|2dup|2drop|neg|implies)$")
+)
+
+(
+ (word) @function
+ (#match? @function "^&.")
 )
 
 (
@@ -45,7 +119,12 @@
 
 (
  (word) @type.builtin
- (#match? @type.builtin "^(i32|bool|type|ptr)$")
+ (#match? @type.builtin "^(i32|bool|type|ptr|file)$")
+)
+
+(
+ (word) @type.parameter
+ (#match? @type.parameter "^'.")
 )
 
 (word) @variable
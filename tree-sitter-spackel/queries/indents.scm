@@ -1,9 +1,19 @@
 [
   (macro_definition)
   (function_definition)
+  (extern_function_declaration)
   (then_statement)
   (then_else_statement)
   (repeat_loop)
   (unsafe_block)
+  (stash_block)
+  (match_case)
+  (quotation)
+  (struct_definition)
+  (enum_definition)
+  (test_definition)
 ] @indent
-"end" @outdent
+[
+  "end"
+  "]"
+] @outdent